@@ -0,0 +1,55 @@
+//! Collects the versions of the migrations that are embedded in the binary, so that the server can
+//! tell on startup whether the database schema is newer than the binary, and the git commit the
+//! binary is built from.
+
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+fn migration_versions(dir: &str) -> Vec<String> {
+    println!("cargo:rerun-if-changed={}", dir);
+
+    // Diesel uses the part of the directory name before the first `_`, without dashes
+    let mut versions: Vec<String> = fs::read_dir(dir)
+        .unwrap_or_else(|e| panic!("could not read {}: {}", dir, e))
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().is_dir())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|name| !name.starts_with('.'))
+        .filter_map(|name| name.split('_').next().map(|v| v.replace('-', "")))
+        .collect();
+    versions.sort();
+    versions
+}
+
+// Builds without a git checkout, e.g. in docker, can pass the hash in GIT_HASH
+fn git_hash() -> String {
+    println!("cargo:rerun-if-env-changed=GIT_HASH");
+    if let Ok(hash) = env::var("GIT_HASH") {
+        return hash;
+    }
+
+    println!("cargo:rerun-if-changed=../.git/HEAD");
+    println!("cargo:rerun-if-changed=../.git/refs/heads");
+    Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|hash| hash.trim().to_string())
+        .unwrap_or_else(|| String::from("unknown"))
+}
+
+fn main() {
+    println!("cargo:rustc-env=GIT_HASH={}", git_hash());
+
+    let out_dir = env::var("OUT_DIR").unwrap();
+    let code = format!(
+        "pub const POSTGRES: &[&str] = &{:?};\npub const SQLITE: &[&str] = &{:?};\n",
+        migration_versions("migrations"),
+        migration_versions("migrations_sqlite"),
+    );
+    fs::write(Path::new(&out_dir).join("migration_versions.rs"), code).unwrap();
+}
@@ -0,0 +1,26 @@
+CREATE FUNCTION last_upd_trig() RETURNS trigger
+    LANGUAGE plpgsql AS
+$$BEGIN
+    NEW.updated := CLOCK_TIMESTAMP();
+    RETURN NEW;
+END;$$;
+
+CREATE TRIGGER user_update_date_trigger
+    BEFORE UPDATE ON users
+    FOR EACH ROW
+    EXECUTE PROCEDURE last_upd_trig();
+
+CREATE TRIGGER room_update_date_trigger
+    BEFORE UPDATE ON rooms
+    FOR EACH ROW
+    EXECUTE PROCEDURE last_upd_trig();
+
+CREATE TRIGGER rooms_users_updates_update_date_trigger
+    BEFORE UPDATE ON rooms_users
+    FOR EACH ROW
+    EXECUTE PROCEDURE last_upd_trig();
+
+CREATE TRIGGER message_update_date_trigger
+    BEFORE UPDATE ON messages
+    FOR EACH ROW
+    EXECUTE PROCEDURE last_upd_trig();
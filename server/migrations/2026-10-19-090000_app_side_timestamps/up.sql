@@ -0,0 +1,2 @@
+-- The application sets the updated timestamps, so that the same schema works on all backends
+DROP FUNCTION last_upd_trig CASCADE; -- Also removes triggers
@@ -0,0 +1,18 @@
+CREATE TABLE "sessions"
+(
+    id UUID PRIMARY KEY,
+    user_id UUID NOT NULL,
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE,
+    device_name VARCHAR,
+    user_agent VARCHAR,
+    ip VARCHAR,
+    access_token_hash VARCHAR NOT NULL UNIQUE,
+    access_expires TIMESTAMP NOT NULL,
+    refresh_token_hash VARCHAR NOT NULL UNIQUE,
+    previous_refresh_token_hash VARCHAR UNIQUE,
+    refresh_expires TIMESTAMP NOT NULL,
+    created TIMESTAMP NOT NULL,
+    last_seen TIMESTAMP NOT NULL
+);
+
+CREATE INDEX sessions_user_id_index ON sessions (user_id);
@@ -0,0 +1,26 @@
+CREATE TABLE "totp_secrets"
+(
+    user_id UUID PRIMARY KEY,
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE,
+    secret VARCHAR NOT NULL,
+    enabled BOOLEAN NOT NULL,
+    last_used_step BIGINT,
+    created TIMESTAMP NOT NULL
+);
+
+CREATE TABLE "recovery_codes"
+(
+    user_id UUID NOT NULL,
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE,
+    code_hash VARCHAR NOT NULL,
+    PRIMARY KEY (user_id, code_hash)
+);
+
+CREATE TABLE "login_challenges"
+(
+    token_hash VARCHAR PRIMARY KEY,
+    user_id UUID NOT NULL,
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE,
+    attempts INTEGER NOT NULL,
+    expires TIMESTAMP NOT NULL
+);
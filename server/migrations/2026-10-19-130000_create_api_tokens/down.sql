@@ -0,0 +1,3 @@
+DROP TABLE "api_tokens";
+ALTER TABLE "messages" DROP COLUMN bot;
+ALTER TABLE "users" DROP COLUMN bot;
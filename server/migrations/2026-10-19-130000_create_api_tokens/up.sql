@@ -0,0 +1,16 @@
+ALTER TABLE "users" ADD COLUMN bot BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE "messages" ADD COLUMN bot BOOLEAN NOT NULL DEFAULT FALSE;
+
+CREATE TABLE "api_tokens"
+(
+    id UUID PRIMARY KEY,
+    user_id UUID NOT NULL,
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE,
+    name VARCHAR NOT NULL,
+    token_hash VARCHAR NOT NULL UNIQUE,
+    scopes VARCHAR NOT NULL,
+    created TIMESTAMP NOT NULL,
+    last_used TIMESTAMP
+);
+
+CREATE INDEX api_tokens_user_id_index ON api_tokens (user_id);
@@ -0,0 +1,11 @@
+CREATE TABLE "identities"
+(
+    issuer VARCHAR NOT NULL,
+    subject VARCHAR NOT NULL,
+    user_id UUID NOT NULL,
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE,
+    created TIMESTAMP NOT NULL,
+    PRIMARY KEY (issuer, subject)
+);
+
+CREATE INDEX identities_user_id_index ON identities (user_id);
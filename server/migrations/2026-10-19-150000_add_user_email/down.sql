@@ -0,0 +1,4 @@
+DROP TABLE "email_tokens";
+DROP INDEX users_email_index;
+ALTER TABLE "users" DROP COLUMN email_verified;
+ALTER TABLE "users" DROP COLUMN email;
@@ -0,0 +1,15 @@
+ALTER TABLE "users" ADD COLUMN email VARCHAR;
+ALTER TABLE "users" ADD COLUMN email_verified BOOLEAN NOT NULL DEFAULT FALSE;
+CREATE UNIQUE INDEX users_email_index ON users (email);
+
+CREATE TABLE "email_tokens"
+(
+    token_hash VARCHAR PRIMARY KEY,
+    user_id UUID NOT NULL,
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE,
+    purpose VARCHAR NOT NULL,
+    email VARCHAR NOT NULL,
+    expires TIMESTAMP NOT NULL
+);
+
+CREATE INDEX email_tokens_user_id_index ON email_tokens (user_id);
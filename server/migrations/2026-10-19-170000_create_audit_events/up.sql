@@ -0,0 +1,27 @@
+-- No foreign keys, the events outlive the users and rooms they are about
+CREATE TABLE "audit_events"
+(
+    id UUID PRIMARY KEY,
+    created TIMESTAMP NOT NULL,
+    action VARCHAR NOT NULL,
+    actor_id UUID,
+    target_id UUID,
+    ip VARCHAR,
+    request_id VARCHAR,
+    details VARCHAR
+);
+
+CREATE INDEX audit_events_created_index ON audit_events (created);
+CREATE INDEX audit_events_actor_id_index ON audit_events (actor_id);
+CREATE INDEX audit_events_target_id_index ON audit_events (target_id);
+
+-- The log is append-only, events can be neither changed nor deleted
+CREATE FUNCTION audit_events_append_only() RETURNS trigger AS $$
+BEGIN
+    RAISE EXCEPTION 'audit_events is append-only';
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER audit_events_append_only
+    BEFORE UPDATE OR DELETE ON audit_events
+    FOR EACH ROW EXECUTE PROCEDURE audit_events_append_only();
@@ -0,0 +1,9 @@
+DROP INDEX users_deleted_index;
+ALTER TABLE "users" DROP COLUMN deleted;
+
+-- Messages without an author cannot be kept
+DELETE FROM "messages" WHERE author IS NULL;
+ALTER TABLE "messages" DROP CONSTRAINT messages_author_fkey;
+ALTER TABLE "messages"
+    ADD CONSTRAINT messages_author_fkey FOREIGN KEY (author) REFERENCES users(id) ON DELETE CASCADE;
+ALTER TABLE "messages" ALTER COLUMN author SET NOT NULL;
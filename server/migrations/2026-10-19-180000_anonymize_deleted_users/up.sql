@@ -0,0 +1,9 @@
+-- Messages of deleted users are kept without an author instead of being deleted with the user
+ALTER TABLE "messages" ALTER COLUMN author DROP NOT NULL;
+ALTER TABLE "messages" DROP CONSTRAINT messages_author_fkey;
+ALTER TABLE "messages"
+    ADD CONSTRAINT messages_author_fkey FOREIGN KEY (author) REFERENCES users(id) ON DELETE SET NULL;
+
+-- When the user asked for the deletion, the account is deleted for good after a grace period
+ALTER TABLE "users" ADD COLUMN deleted TIMESTAMP;
+CREATE INDEX users_deleted_index ON users (deleted);
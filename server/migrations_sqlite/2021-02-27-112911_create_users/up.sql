@@ -0,0 +1,7 @@
+-- Ids are generated by the application, SQLite stores them as text
+CREATE TABLE "users"
+(
+    id TEXT PRIMARY KEY NOT NULL,
+    username TEXT NOT NULL UNIQUE,
+    password TEXT NOT NULL
+);
@@ -0,0 +1,2 @@
+DROP TABLE "rooms_users";
+DROP TABLE "rooms";
@@ -0,0 +1,15 @@
+CREATE TABLE "rooms"
+(
+    id TEXT PRIMARY KEY NOT NULL,
+    name TEXT
+);
+
+CREATE TABLE "rooms_users"
+(
+    user_id TEXT NOT NULL,
+    room_id TEXT NOT NULL,
+    status TEXT,
+    PRIMARY KEY (user_id, room_id),
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE,
+    FOREIGN KEY (room_id) REFERENCES rooms(id) ON DELETE CASCADE
+);
@@ -0,0 +1,8 @@
+ALTER TABLE users DROP created;
+ALTER TABLE users DROP updated;
+
+ALTER TABLE rooms DROP created;
+ALTER TABLE rooms DROP updated;
+
+ALTER TABLE rooms_users DROP created;
+ALTER TABLE rooms_users DROP updated;
@@ -0,0 +1,9 @@
+-- SQLite only allows constant defaults when adding columns, the application always sets both dates
+ALTER TABLE users ADD created TIMESTAMP NOT NULL DEFAULT '1970-01-01 00:00:00';
+ALTER TABLE users ADD updated TIMESTAMP NOT NULL DEFAULT '1970-01-01 00:00:00';
+
+ALTER TABLE rooms ADD created TIMESTAMP NOT NULL DEFAULT '1970-01-01 00:00:00';
+ALTER TABLE rooms ADD updated TIMESTAMP NOT NULL DEFAULT '1970-01-01 00:00:00';
+
+ALTER TABLE rooms_users ADD created TIMESTAMP NOT NULL DEFAULT '1970-01-01 00:00:00';
+ALTER TABLE rooms_users ADD updated TIMESTAMP NOT NULL DEFAULT '1970-01-01 00:00:00';
@@ -0,0 +1,11 @@
+CREATE TABLE "messages"
+(
+    id TEXT PRIMARY KEY NOT NULL,
+    room_id TEXT NOT NULL,
+    author TEXT NOT NULL,
+    content TEXT NOT NULL,
+    created TIMESTAMP NOT NULL,
+    updated TIMESTAMP NOT NULL,
+    FOREIGN KEY (room_id) REFERENCES rooms(id) ON DELETE CASCADE,
+    FOREIGN KEY (author) REFERENCES users(id) ON DELETE CASCADE
+);
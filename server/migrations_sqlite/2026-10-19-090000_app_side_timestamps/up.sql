@@ -0,0 +1 @@
+-- Nothing to do, SQLite never had update triggers
@@ -0,0 +1,18 @@
+CREATE TABLE "sessions"
+(
+    id TEXT PRIMARY KEY NOT NULL,
+    user_id TEXT NOT NULL,
+    device_name TEXT,
+    user_agent TEXT,
+    ip TEXT,
+    access_token_hash TEXT NOT NULL UNIQUE,
+    access_expires TIMESTAMP NOT NULL,
+    refresh_token_hash TEXT NOT NULL UNIQUE,
+    previous_refresh_token_hash TEXT UNIQUE,
+    refresh_expires TIMESTAMP NOT NULL,
+    created TIMESTAMP NOT NULL,
+    last_seen TIMESTAMP NOT NULL,
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE INDEX sessions_user_id_index ON sessions (user_id);
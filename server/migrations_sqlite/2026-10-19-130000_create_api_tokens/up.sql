@@ -0,0 +1,16 @@
+ALTER TABLE "users" ADD COLUMN bot BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE "messages" ADD COLUMN bot BOOLEAN NOT NULL DEFAULT FALSE;
+
+CREATE TABLE "api_tokens"
+(
+    id TEXT PRIMARY KEY NOT NULL,
+    user_id TEXT NOT NULL,
+    name TEXT NOT NULL,
+    token_hash TEXT NOT NULL UNIQUE,
+    scopes TEXT NOT NULL,
+    created TIMESTAMP NOT NULL,
+    last_used TIMESTAMP,
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE INDEX api_tokens_user_id_index ON api_tokens (user_id);
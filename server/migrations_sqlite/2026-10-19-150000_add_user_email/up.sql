@@ -0,0 +1,15 @@
+ALTER TABLE "users" ADD COLUMN email TEXT;
+ALTER TABLE "users" ADD COLUMN email_verified BOOLEAN NOT NULL DEFAULT FALSE;
+CREATE UNIQUE INDEX users_email_index ON users (email);
+
+CREATE TABLE "email_tokens"
+(
+    token_hash TEXT PRIMARY KEY NOT NULL,
+    user_id TEXT NOT NULL,
+    purpose TEXT NOT NULL,
+    email TEXT NOT NULL,
+    expires TIMESTAMP NOT NULL,
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE INDEX email_tokens_user_id_index ON email_tokens (user_id);
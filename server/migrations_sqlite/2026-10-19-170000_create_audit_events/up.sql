@@ -0,0 +1,27 @@
+-- No foreign keys, the events outlive the users and rooms they are about
+CREATE TABLE "audit_events"
+(
+    id TEXT PRIMARY KEY NOT NULL,
+    created TIMESTAMP NOT NULL,
+    action TEXT NOT NULL,
+    actor_id TEXT,
+    target_id TEXT,
+    ip TEXT,
+    request_id TEXT,
+    details TEXT
+);
+
+CREATE INDEX audit_events_created_index ON audit_events (created);
+CREATE INDEX audit_events_actor_id_index ON audit_events (actor_id);
+CREATE INDEX audit_events_target_id_index ON audit_events (target_id);
+
+-- The log is append-only, events can be neither changed nor deleted
+CREATE TRIGGER audit_events_no_update BEFORE UPDATE ON audit_events
+BEGIN
+    SELECT RAISE(ABORT, 'audit_events is append-only');
+END;
+
+CREATE TRIGGER audit_events_no_delete BEFORE DELETE ON audit_events
+BEGIN
+    SELECT RAISE(ABORT, 'audit_events is append-only');
+END;
@@ -0,0 +1,22 @@
+DROP INDEX users_deleted_index;
+ALTER TABLE "users" DROP COLUMN deleted;
+
+-- Messages without an author cannot be kept
+CREATE TABLE "messages_with_author"
+(
+    id TEXT PRIMARY KEY NOT NULL,
+    room_id TEXT NOT NULL,
+    author TEXT NOT NULL,
+    content TEXT NOT NULL,
+    created TIMESTAMP NOT NULL,
+    updated TIMESTAMP NOT NULL,
+    bot BOOLEAN NOT NULL DEFAULT FALSE,
+    FOREIGN KEY (room_id) REFERENCES rooms(id) ON DELETE CASCADE,
+    FOREIGN KEY (author) REFERENCES users(id) ON DELETE CASCADE
+);
+
+INSERT INTO "messages_with_author" (id, room_id, author, content, created, updated, bot)
+    SELECT id, room_id, author, content, created, updated, bot FROM "messages"
+    WHERE author IS NOT NULL;
+DROP TABLE "messages";
+ALTER TABLE "messages_with_author" RENAME TO "messages";
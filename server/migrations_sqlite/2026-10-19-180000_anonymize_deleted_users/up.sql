@@ -0,0 +1,23 @@
+-- Messages of deleted users are kept without an author instead of being deleted with the user.
+-- SQLite cannot change a foreign key, so the table is copied.
+CREATE TABLE "messages_anonymized"
+(
+    id TEXT PRIMARY KEY NOT NULL,
+    room_id TEXT NOT NULL,
+    author TEXT,
+    content TEXT NOT NULL,
+    created TIMESTAMP NOT NULL,
+    updated TIMESTAMP NOT NULL,
+    bot BOOLEAN NOT NULL DEFAULT FALSE,
+    FOREIGN KEY (room_id) REFERENCES rooms(id) ON DELETE CASCADE,
+    FOREIGN KEY (author) REFERENCES users(id) ON DELETE SET NULL
+);
+
+INSERT INTO "messages_anonymized" (id, room_id, author, content, created, updated, bot)
+    SELECT id, room_id, author, content, created, updated, bot FROM "messages";
+DROP TABLE "messages";
+ALTER TABLE "messages_anonymized" RENAME TO "messages";
+
+-- When the user asked for the deletion, the account is deleted for good after a grace period
+ALTER TABLE "users" ADD COLUMN deleted TIMESTAMP;
+CREATE INDEX users_deleted_index ON users (deleted);
@@ -0,0 +1,44 @@
+use actix_web::{dev, FromRequest, HttpRequest};
+use futures::future::LocalBoxFuture;
+use uuid::Uuid;
+
+use crate::db::DbConn;
+use crate::errors::ServiceError;
+use crate::session::Identity;
+use crate::user::Role;
+
+/// A logged in user with the admin role.
+///
+/// Use this as an extractor in admin routes. Requests without a valid session fail with
+/// `401 Unauthorized`, requests of other users with `403 Forbidden`. API tokens are not accepted,
+/// so that a leaked bot token never grants admin rights.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Admin {
+    pub user_id: Uuid,
+}
+
+impl FromRequest for Admin {
+    type Error = ServiceError;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, payload: &mut dev::Payload) -> Self::Future {
+        let identity = Identity::from_request(req, payload);
+        let conn = DbConn::from_request(req, payload);
+
+        Box::pin(async move {
+            let identity = identity.await?;
+            let user = conn
+                .await?
+                .run(move |repo| repo.find_user_by_id(identity.user_id))
+                .await?;
+
+            match user {
+                Some(user) if user.role == Role::Admin && !user.is_disabled() => Ok(Admin {
+                    user_id: identity.user_id,
+                }),
+                _ => Err(ServiceError::Forbidden),
+            }
+        })
+    }
+}
@@ -0,0 +1,7 @@
+mod identity;
+mod model;
+mod routes;
+
+pub use identity::Admin;
+pub use model::*;
+pub use routes::init_routes;
@@ -0,0 +1,194 @@
+use std::collections::HashMap;
+
+use chrono::NaiveDateTime;
+use diesel::result::Error as DieselError;
+use diesel::sql_types::{Nullable, Text};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::query::prelude::*;
+use crate::schema::{api_tokens, messages, rooms, rooms_users, sessions, users};
+use crate::user::{Role, User};
+
+pub const DEFAULT_USER_LIMIT: i64 = 50;
+pub const MAX_USER_LIMIT: i64 = 200;
+
+fn default_limit() -> i64 {
+    DEFAULT_USER_LIMIT
+}
+
+sql_function!(fn lower(text: Nullable<Text>) -> Nullable<Text>);
+
+/// Which users an admin is looking for, e.g. `?q=jane&limit=20&offset=40`.
+#[derive(Clone, Deserialize, Debug)]
+pub struct UserQuery {
+    /// Part of the username or email address, case does not matter.
+    pub q: Option<String>,
+    #[serde(default = "default_limit")]
+    pub limit: i64,
+    #[serde(default)]
+    pub offset: i64,
+}
+
+impl Default for UserQuery {
+    fn default() -> Self {
+        UserQuery {
+            q: None,
+            limit: DEFAULT_USER_LIMIT,
+            offset: 0,
+        }
+    }
+}
+
+/// What admins see of a user, which is more than other users see but never the password.
+#[derive(Serialize, PartialEq, Debug)]
+pub struct UserSummary {
+    pub id: Uuid,
+    pub username: String,
+    pub email: Option<String>,
+    pub email_verified: bool,
+    pub role: Role,
+    pub bot: bool,
+    pub locked: bool,
+    /// When the user was deleted, the account can be restored until the grace period is over.
+    pub deleted: Option<NaiveDateTime>,
+    pub room_count: i64,
+    pub created: NaiveDateTime,
+    pub updated: NaiveDateTime,
+}
+
+impl UserSummary {
+    pub fn new(user: User, room_count: i64) -> UserSummary {
+        UserSummary {
+            id: user.id,
+            username: user.username,
+            email: user.email,
+            email_verified: user.email_verified,
+            role: user.role,
+            bot: user.bot,
+            locked: user.locked,
+            deleted: user.deleted,
+            room_count,
+            created: user.created,
+            updated: user.updated,
+        }
+    }
+
+    /// Users whose username or email address contains the query, ordered by username.
+    pub fn search(
+        conn: &impl SqlConnection,
+        query: &UserQuery,
+    ) -> Result<Vec<UserSummary>, AdminError> {
+        // Lower case on both sides, as LIKE ignores the case on SQLite but not on Postgres
+        let pattern = query.q.as_deref().map(|q| like_pattern(&q.to_lowercase()));
+        let found: Vec<User> = users::table
+            .filter(
+                lower(users::username.nullable())
+                    .like(&pattern)
+                    .escape('\\')
+                    .or(lower(users::email).like(&pattern).escape('\\'))
+                    .or(not_given(&pattern)),
+            )
+            .order(users::username.asc())
+            .limit(query.limit)
+            .offset(query.offset)
+            .load(conn)?;
+
+        let user_ids: Vec<Id> = found.iter().map(|user| Id(user.id)).collect();
+        let memberships: Vec<Uuid> = rooms_users::table
+            .filter(rooms_users::user_id.eq_any(user_ids))
+            .select(rooms_users::user_id)
+            .load(conn)?;
+        Ok(summarize(found, &memberships))
+    }
+}
+
+#[derive(Serialize, Default, PartialEq, Debug)]
+pub struct ServerStats {
+    pub users: i64,
+    pub admins: i64,
+    pub bots: i64,
+    pub locked_users: i64,
+    pub rooms: i64,
+    pub messages: i64,
+    pub sessions: i64,
+    pub api_tokens: i64,
+}
+
+#[derive(Debug)]
+pub enum AdminError {
+    DatabaseError,
+    GenericError,
+}
+
+/// A pattern for `LIKE` that matches the query anywhere, with the wildcards in it escaped.
+pub(crate) fn like_pattern(query: &str) -> String {
+    let mut pattern = String::from("%");
+    for c in query.chars() {
+        if matches!(c, '\\' | '%' | '_') {
+            pattern.push('\\');
+        }
+        pattern.push(c);
+    }
+    pattern.push('%');
+    pattern
+}
+
+/// Pairs the users with the number of rooms they are in, `memberships` has one user id per room.
+pub(crate) fn summarize(users: Vec<User>, memberships: &[Uuid]) -> Vec<UserSummary> {
+    let mut room_counts: HashMap<Uuid, i64> = HashMap::new();
+    for user_id in memberships {
+        *room_counts.entry(*user_id).or_insert(0) += 1;
+    }
+    users
+        .into_iter()
+        .map(|user| {
+            let room_count = room_counts.get(&user.id).copied().unwrap_or(0);
+            UserSummary::new(user, room_count)
+        })
+        .collect()
+}
+
+impl ServerStats {
+    pub fn collect(conn: &impl SqlConnection) -> Result<ServerStats, AdminError> {
+        Ok(ServerStats {
+            users: users::table.count().get_result(conn)?,
+            admins: users::table
+                .filter(users::role.eq(Role::Admin))
+                .count()
+                .get_result(conn)?,
+            bots: users::table
+                .filter(users::bot.eq(true))
+                .count()
+                .get_result(conn)?,
+            locked_users: users::table
+                .filter(users::locked.eq(true))
+                .count()
+                .get_result(conn)?,
+            rooms: rooms::table.count().get_result(conn)?,
+            messages: messages::table.count().get_result(conn)?,
+            sessions: sessions::table.count().get_result(conn)?,
+            api_tokens: api_tokens::table.count().get_result(conn)?,
+        })
+    }
+}
+
+impl From<DieselError> for AdminError {
+    fn from(error: DieselError) -> AdminError {
+        match error {
+            DieselError::DatabaseError(_, _) => AdminError::DatabaseError,
+            _ => AdminError::GenericError,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn like_pattern_escapes_wildcards() {
+        assert_eq!(like_pattern("jane"), "%jane%");
+        assert_eq!(like_pattern("50%_off\\"), "%50\\%\\_off\\\\%");
+    }
+}
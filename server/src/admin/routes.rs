@@ -0,0 +1,824 @@
+use actix_web::http::header;
+use actix_web::{delete, get, post, put, web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::admin::{Admin, UserQuery, MAX_USER_LIMIT};
+use crate::audit::{self, Action, AuditContext, AuditQuery, MAX_EVENT_LIMIT};
+use crate::config::MailConfig;
+use crate::db::DbConn;
+use crate::email::auth::reset_mail;
+use crate::email::{EmailToken, Purpose};
+use crate::errors::ServiceError;
+use crate::mail::{self, Mailer};
+use crate::session::token;
+use crate::user::{Role, UserData, UserError};
+
+#[derive(Deserialize, Debug)]
+pub struct RoleData {
+    pub role: Role,
+}
+
+#[derive(Serialize, Debug)]
+pub struct PasswordResetResponse {
+    /// Whether the user got a mail to choose a new password.
+    pub mail_sent: bool,
+    /// Only if no mail was sent, the admin has to hand it over.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temporary_password: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    Json,
+    Csv,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ExportQuery {
+    pub format: Option<ExportFormat>,
+}
+
+/// Admins must not lock themselves out, there may be no other admin to undo it.
+fn not_oneself(admin: &Admin, user_id: Uuid, action: &str) -> Result<(), ServiceError> {
+    if admin.user_id == user_id {
+        return Err(ServiceError::Validation(format!(
+            "Admins cannot {} themselves",
+            action
+        )));
+    }
+    Ok(())
+}
+
+#[get("/users")]
+pub async fn list_users(
+    conn: DbConn,
+    _admin: Admin,
+    query: web::Query<UserQuery>,
+) -> Result<HttpResponse, ServiceError> {
+    let query = query.into_inner();
+    if query.limit < 1 || query.limit > MAX_USER_LIMIT || query.offset < 0 {
+        return Err(ServiceError::Validation(format!(
+            "limit must be between 1 and {} and offset must not be negative",
+            MAX_USER_LIMIT
+        )));
+    }
+
+    let users = conn
+        .run(move |repo| repo.find_user_summaries(&query))
+        .await?;
+    Ok(HttpResponse::Ok().json(json!({ "users": users })))
+}
+
+/// Locks the user out: sessions end, logins and API tokens are rejected until unlocked.
+#[post("/users/{id}/lock")]
+pub async fn lock(
+    conn: DbConn,
+    admin: Admin,
+    audit: AuditContext,
+    user_id: web::Path<Uuid>,
+) -> Result<HttpResponse, ServiceError> {
+    let user_id = user_id.into_inner();
+    not_oneself(&admin, user_id, "lock")?;
+    conn.run(move |repo| -> Result<_, ServiceError> {
+        repo.set_user_locked(user_id, true)?;
+        repo.create_audit_event(audit.event(
+            Action::UserLocked,
+            Some(admin.user_id),
+            Some(user_id),
+        ))?;
+        Ok(())
+    })
+    .await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[post("/users/{id}/unlock")]
+pub async fn unlock(
+    conn: DbConn,
+    admin: Admin,
+    audit: AuditContext,
+    user_id: web::Path<Uuid>,
+) -> Result<HttpResponse, ServiceError> {
+    let user_id = user_id.into_inner();
+    conn.run(move |repo| -> Result<_, ServiceError> {
+        repo.set_user_locked(user_id, false)?;
+        repo.create_audit_event(audit.event(
+            Action::UserUnlocked,
+            Some(admin.user_id),
+            Some(user_id),
+        ))?;
+        Ok(())
+    })
+    .await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Restores a deleted user whose grace period is not over yet. The user has to log in again.
+#[post("/users/{id}/restore")]
+pub async fn restore(
+    conn: DbConn,
+    admin: Admin,
+    audit: AuditContext,
+    user_id: web::Path<Uuid>,
+) -> Result<HttpResponse, ServiceError> {
+    let user_id = user_id.into_inner();
+    conn.run(move |repo| -> Result<_, ServiceError> {
+        let user = repo
+            .find_user_by_id(user_id)?
+            .ok_or(ServiceError::NotFound)?;
+        if user.deleted.is_none() {
+            return Err(ServiceError::Conflict(
+                "The user is not deleted".to_string(),
+            ));
+        }
+        repo.set_user_deleted(user_id, None)?;
+        repo.create_audit_event(audit.event(
+            Action::UserRestored,
+            Some(admin.user_id),
+            Some(user_id),
+        ))?;
+        Ok(())
+    })
+    .await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[put("/users/{id}/role")]
+pub async fn set_role(
+    conn: DbConn,
+    admin: Admin,
+    audit: AuditContext,
+    user_id: web::Path<Uuid>,
+    data: web::Json<RoleData>,
+) -> Result<HttpResponse, ServiceError> {
+    let user_id = user_id.into_inner();
+    not_oneself(&admin, user_id, "change the role of")?;
+    let role = data.into_inner().role;
+    conn.run(move |repo| -> Result<_, ServiceError> {
+        repo.set_user_role(user_id, role)?;
+        let event = audit.event(Action::RoleChanged, Some(admin.user_id), Some(user_id));
+        repo.create_audit_event(event.with_details(json!({ "role": role })))?;
+        Ok(())
+    })
+    .await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Replaces the password with a random one, which ends all sessions of the user. Users with a
+/// verified address get a mail to choose a new password, for the others, and if the mail cannot
+/// be sent, the admin gets the random password.
+#[post("/users/{id}/password-reset")]
+pub async fn reset_password(
+    conn: DbConn,
+    admin: Admin,
+    audit: AuditContext,
+    mailer: web::Data<dyn Mailer>,
+    config: web::Data<MailConfig>,
+    user_id: web::Path<Uuid>,
+) -> Result<HttpResponse, ServiceError> {
+    let user_id = user_id.into_inner();
+    let password = token::generate();
+    let (email, reset_token) = {
+        let password = password.clone();
+        conn.run(move |repo| -> Result<_, ServiceError> {
+            let user = repo
+                .find_user_by_id(user_id)?
+                .ok_or(UserError::UserNotFound)?;
+            repo.update_user(
+                user_id,
+                UserData {
+                    username: user.username,
+                    password,
+                },
+            )?;
+            let reset = match user.email {
+                Some(email) if user.email_verified => {
+                    let reset_token =
+                        EmailToken::issue(repo, user_id, Purpose::ResetPassword, &email)?;
+                    (Some(email), Some(reset_token))
+                }
+                _ => (None, None),
+            };
+            let event = audit.event(Action::PasswordReset, Some(admin.user_id), Some(user_id));
+            let details = json!({ "mail_sent": reset.0.is_some() });
+            repo.create_audit_event(event.with_details(details))?;
+            Ok(reset)
+        })
+        .await?
+    };
+
+    let response = match (email, reset_token) {
+        (Some(email), Some(reset_token)) => {
+            let mail = reset_mail(&config, &email, &reset_token);
+            match mail::deliver(mailer, mail).await {
+                Ok(()) => PasswordResetResponse {
+                    mail_sent: true,
+                    temporary_password: None,
+                },
+                // The password is changed already, so the admin has to hand it over
+                Err(e) => {
+                    tracing::error!("Could not send a mail: {}", e);
+                    PasswordResetResponse {
+                        mail_sent: false,
+                        temporary_password: Some(password),
+                    }
+                }
+            }
+        }
+        _ => PasswordResetResponse {
+            mail_sent: false,
+            temporary_password: Some(password),
+        },
+    };
+    Ok(HttpResponse::Ok().json(response))
+}
+
+#[delete("/rooms/{id}")]
+pub async fn delete_room(
+    conn: DbConn,
+    admin: Admin,
+    audit: AuditContext,
+    room_id: web::Path<Uuid>,
+) -> Result<HttpResponse, ServiceError> {
+    let room_id = room_id.into_inner();
+    let count = conn
+        .run(move |repo| -> Result<_, ServiceError> {
+            let count = repo.destroy_room(room_id)?;
+            if count > 0 {
+                let event = audit.event(Action::RoomDeleted, Some(admin.user_id), Some(room_id));
+                repo.create_audit_event(event)?;
+            }
+            Ok(count)
+        })
+        .await?;
+
+    if count == 0 {
+        Err(ServiceError::NotFound)
+    } else {
+        Ok(HttpResponse::NoContent().finish())
+    }
+}
+
+#[delete("/messages/{id}")]
+pub async fn delete_message(
+    conn: DbConn,
+    admin: Admin,
+    audit: AuditContext,
+    message_id: web::Path<Uuid>,
+) -> Result<HttpResponse, ServiceError> {
+    let message_id = message_id.into_inner();
+    let count = conn
+        .run(move |repo| -> Result<_, ServiceError> {
+            let count = repo.destroy_message(message_id)?;
+            if count > 0 {
+                let event = audit.event(
+                    Action::MessageDeleted,
+                    Some(admin.user_id),
+                    Some(message_id),
+                );
+                repo.create_audit_event(event)?;
+            }
+            Ok(count)
+        })
+        .await?;
+
+    if count == 0 {
+        Err(ServiceError::NotFound)
+    } else {
+        Ok(HttpResponse::NoContent().finish())
+    }
+}
+
+#[get("/stats")]
+pub async fn stats(conn: DbConn, _admin: Admin) -> Result<HttpResponse, ServiceError> {
+    let stats = conn.run(|repo| repo.server_stats()).await?;
+    Ok(HttpResponse::Ok().json(stats))
+}
+
+/// The audit log, as JSON or with `?format=csv` as a CSV download.
+#[get("/audit")]
+pub async fn list_audit_events(
+    conn: DbConn,
+    _admin: Admin,
+    query: web::Query<AuditQuery>,
+    export: web::Query<ExportQuery>,
+) -> Result<HttpResponse, ServiceError> {
+    let query = query.into_inner();
+    if query.limit < 1 || query.limit > MAX_EVENT_LIMIT || query.offset < 0 {
+        return Err(ServiceError::Validation(format!(
+            "limit must be between 1 and {} and offset must not be negative",
+            MAX_EVENT_LIMIT
+        )));
+    }
+
+    let events = conn.run(move |repo| repo.find_audit_events(&query)).await?;
+    match export.format.unwrap_or(ExportFormat::Json) {
+        ExportFormat::Json => Ok(HttpResponse::Ok().json(json!({ "events": events }))),
+        ExportFormat::Csv => Ok(HttpResponse::Ok()
+            .content_type("text/csv; charset=utf-8")
+            .header(
+                header::CONTENT_DISPOSITION,
+                "attachment; filename=\"audit.csv\"",
+            )
+            .body(audit::csv::write_events(&events))),
+    }
+}
+
+pub fn init_routes(config: &mut web::ServiceConfig) {
+    config.service(
+        web::scope("/admin")
+            .service(list_users)
+            .service(lock)
+            .service(unlock)
+            .service(restore)
+            .service(set_role)
+            .service(reset_password)
+            .service(delete_room)
+            .service(delete_message)
+            .service(list_audit_events)
+            .service(stats),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api_token::{ApiToken, Scope};
+    use crate::config::AuthConfig;
+    use crate::db::Database;
+    use crate::mail::{FileMailer, Mail, MailError};
+    use crate::repository::{
+        AuditRepository, InMemoryRepository, MessageRepository, RoomRepository, SessionRepository,
+        UserRepository,
+    };
+    use crate::test_helpers::*;
+    use crate::user::LoginThrottle;
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App};
+    use serde_json::Value;
+    use std::sync::Arc;
+
+    fn admin(repo: &InMemoryRepository) -> (Uuid, String) {
+        let admin = repo.create_user(create_user_data("admin")).unwrap();
+        repo.set_user_role(admin.id, Role::Admin).unwrap();
+        (admin.id, bearer(repo, admin.id))
+    }
+
+    #[actix_rt::test]
+    async fn admin_routes_need_the_admin_role() {
+        let repo = InMemoryRepository::default();
+        let user = repo.create_user(create_user_data("testUser")).unwrap();
+        let user_bearer = bearer(&repo, user.id);
+        let (_, admin_bearer) = admin(&repo);
+        let mut app = test::init_service(
+            App::new()
+                .data(Database::Memory(repo))
+                .configure(init_routes),
+        )
+        .await;
+
+        let req = test::TestRequest::get().uri("/admin/stats").to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let req = test::TestRequest::get()
+            .uri("/admin/stats")
+            .header("Authorization", user_bearer.as_str())
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let req = test::TestRequest::get()
+            .uri("/admin/stats")
+            .header("Authorization", admin_bearer.as_str())
+            .to_request();
+        let body: Value = test::read_response_json(&mut app, req).await;
+        assert_eq!(body["users"], 2);
+        assert_eq!(body["admins"], 1);
+    }
+
+    #[actix_rt::test]
+    async fn locked_users_cannot_log_in_or_use_api_tokens() {
+        let repo = InMemoryRepository::default();
+        let (admin_id, admin_bearer) = admin(&repo);
+        let user = repo.create_user(create_user_data("testUser")).unwrap();
+        let user_bearer = bearer(&repo, user.id);
+        let api_token = ApiToken::issue(&repo, user.id, String::from("ci"), &[Scope::ReadRooms])
+            .unwrap()
+            .token;
+        let room = repo.create_room(create_room_data("testRoom")).unwrap();
+        let mut app = test::init_service(
+            App::new()
+                .data(Database::Memory(repo.clone()))
+                .data(AuthConfig::default())
+                .data(LoginThrottle::default())
+                .configure(init_routes)
+                .configure(crate::user::init_routes)
+                .configure(crate::message::init_routes),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri(&format!("/admin/users/{}/lock", admin_id))
+            .header("Authorization", admin_bearer.as_str())
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let req = test::TestRequest::post()
+            .uri(&format!("/admin/users/{}/lock", user.id))
+            .header("Authorization", admin_bearer.as_str())
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+        let req = test::TestRequest::get()
+            .uri("/admin/users?q=test")
+            .header("Authorization", admin_bearer.as_str())
+            .to_request();
+        let body: Value = test::read_response_json(&mut app, req).await;
+        assert_eq!(body["users"][0]["username"], "testUser");
+        assert_eq!(body["users"][0]["locked"], true);
+        assert!(body["users"][0].get("password").is_none());
+
+        // The session ended and the API token is rejected
+        let messages = format!("/rooms/{}/messages", room.id);
+        let req = test::TestRequest::get()
+            .uri(&messages)
+            .header("Authorization", user_bearer.as_str())
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let req = test::TestRequest::get()
+            .uri(&messages)
+            .header("Authorization", format!("Bearer {}", api_token))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let login_data = json!({ "username": "testUser", "password": "12345678" });
+        let req = test::TestRequest::post()
+            .uri("/auth")
+            .set_json(&login_data)
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let req = test::TestRequest::post()
+            .uri(&format!("/admin/users/{}/unlock", user.id))
+            .header("Authorization", admin_bearer.as_str())
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+        let req = test::TestRequest::post()
+            .uri("/auth")
+            .set_json(&login_data)
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[actix_rt::test]
+    async fn deleted_users_can_be_restored_during_the_grace_period() {
+        let repo = InMemoryRepository::default();
+        let (_, admin_bearer) = admin(&repo);
+        let user = repo.create_user(create_user_data("testUser")).unwrap();
+        let user_bearer = bearer(&repo, user.id);
+        let room = repo.create_room(create_room_data("testRoom")).unwrap();
+        repo.create_message(create_message_data("Hello thermit!", room.id, user.id))
+            .unwrap();
+        let mut app = test::init_service(
+            App::new()
+                .data(Database::Memory(repo.clone()))
+                .data(AuthConfig::default())
+                .data(LoginThrottle::default())
+                .configure(init_routes)
+                .configure(crate::user::init_routes),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri(&format!("/admin/users/{}/restore", user.id))
+            .header("Authorization", admin_bearer.as_str())
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+
+        let req = test::TestRequest::delete()
+            .uri(&format!("/users/{}", user.id))
+            .header("Authorization", user_bearer.as_str())
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::ACCEPTED);
+        let body: Value = test::read_body_json(resp).await;
+        assert!(body["restorable_until"].is_string());
+
+        // The session of the user ended, deleting twice is not possible and the messages are kept
+        let req = test::TestRequest::delete()
+            .uri(&format!("/users/{}", user.id))
+            .header("Authorization", user_bearer.as_str())
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let req = test::TestRequest::delete()
+            .uri(&format!("/users/{}", user.id))
+            .header("Authorization", admin_bearer.as_str())
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let messages = repo.find_messages_by_room(room.id).unwrap();
+        assert_eq!(messages[0].author, Some(user.id));
+
+        let login_data = json!({ "username": "testUser", "password": "12345678" });
+        let req = test::TestRequest::post()
+            .uri("/auth")
+            .set_json(&login_data)
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        assert!(repo.find_user_sessions(user.id).unwrap().is_empty());
+
+        let req = test::TestRequest::post()
+            .uri(&format!("/admin/users/{}/restore", user.id))
+            .header("Authorization", admin_bearer.as_str())
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+        let req = test::TestRequest::post()
+            .uri("/auth")
+            .set_json(&login_data)
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let events = repo.find_audit_events(&AuditQuery::default()).unwrap();
+        assert!(events
+            .iter()
+            .any(|event| event.action == Action::UserRestored && event.target_id == Some(user.id)));
+    }
+
+    #[actix_rt::test]
+    async fn list_users_rejects_out_of_range_limits() {
+        let repo = InMemoryRepository::default();
+        let (_, admin_bearer) = admin(&repo);
+        let mut app = test::init_service(
+            App::new()
+                .data(Database::Memory(repo))
+                .configure(init_routes),
+        )
+        .await;
+
+        for uri in &[
+            "/admin/users?limit=0",
+            "/admin/users?limit=1000",
+            "/admin/users?offset=-1",
+        ] {
+            let req = test::TestRequest::get()
+                .uri(uri)
+                .header("Authorization", admin_bearer.as_str())
+                .to_request();
+            let resp = test::call_service(&mut app, req).await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{}", uri);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn admins_change_roles_and_delete_rooms_and_messages() {
+        let repo = InMemoryRepository::default();
+        let (admin_id, admin_bearer) = admin(&repo);
+        let user = repo.create_user(create_user_data("testUser")).unwrap();
+        let room = repo.create_room(create_room_data("testRoom")).unwrap();
+        let message = repo
+            .create_message(create_message_data("Hello thermit!", room.id, user.id))
+            .unwrap();
+        let mut app = test::init_service(
+            App::new()
+                .data(Database::Memory(repo.clone()))
+                .configure(init_routes),
+        )
+        .await;
+
+        let req = test::TestRequest::put()
+            .uri(&format!("/admin/users/{}/role", admin_id))
+            .header("Authorization", admin_bearer.as_str())
+            .set_json(&json!({ "role": "user" }))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let req = test::TestRequest::put()
+            .uri(&format!("/admin/users/{}/role", user.id))
+            .header("Authorization", admin_bearer.as_str())
+            .set_json(&json!({ "role": "admin" }))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        let promoted = repo.find_user_by_id(user.id).unwrap().unwrap();
+        assert_eq!(promoted.role, Role::Admin);
+
+        let message_uri = format!("/admin/messages/{}", message.id);
+        let room_uri = format!("/admin/rooms/{}", room.id);
+        for uri in &[&message_uri, &room_uri] {
+            for expected in &[StatusCode::NO_CONTENT, StatusCode::NOT_FOUND] {
+                let req = test::TestRequest::delete()
+                    .uri(uri)
+                    .header("Authorization", admin_bearer.as_str())
+                    .to_request();
+                let resp = test::call_service(&mut app, req).await;
+                assert_eq!(resp.status(), *expected, "{}", uri);
+            }
+        }
+        assert!(repo.find_message(message.id).unwrap().is_none());
+        assert!(repo.find_room(room.id).unwrap().is_none());
+    }
+
+    #[actix_rt::test]
+    async fn password_reset_mails_verified_users_and_ends_sessions() {
+        let repo = InMemoryRepository::default();
+        let (_, admin_bearer) = admin(&repo);
+        let jane = repo.create_user(create_user_data("jane")).unwrap();
+        repo.set_user_email(jane.id, Some(String::from("jane@example.com")))
+            .unwrap();
+        repo.verify_user_email(jane.id, "jane@example.com").unwrap();
+        bearer(&repo, jane.id);
+        let bob = repo.create_user(create_user_data("bob")).unwrap();
+        let directory = std::env::temp_dir().join(format!("thermit-mail-{}", Uuid::new_v4()));
+        let mailer = Arc::new(FileMailer::new(&directory, "noreply@example.com").unwrap());
+        let mut app = test::init_service(
+            App::new()
+                .data(Database::Memory(repo.clone()))
+                .data(AuthConfig::default())
+                .data(MailConfig::default())
+                .data(LoginThrottle::default())
+                .app_data(web::Data::from(mailer.clone() as Arc<dyn Mailer>))
+                .configure(init_routes)
+                .configure(crate::user::init_routes),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri(&format!("/admin/users/{}/password-reset", jane.id))
+            .header("Authorization", admin_bearer.as_str())
+            .to_request();
+        let body: Value = test::read_response_json(&mut app, req).await;
+        assert_eq!(body, json!({ "mail_sent": true }));
+        assert!(repo.find_user_sessions(jane.id).unwrap().is_empty());
+        let mails = mailer.read_all();
+        assert_eq!(mails.len(), 1);
+        assert!(mails[0].contains("Subject: Reset your password\r\n"));
+
+        // Without a verified address the admin hands over the new password
+        let req = test::TestRequest::post()
+            .uri(&format!("/admin/users/{}/password-reset", bob.id))
+            .header("Authorization", admin_bearer.as_str())
+            .to_request();
+        let body: Value = test::read_response_json(&mut app, req).await;
+        assert_eq!(body["mail_sent"], false);
+        let password = body["temporary_password"].as_str().unwrap();
+        let req = test::TestRequest::post()
+            .uri("/auth")
+            .set_json(&json!({ "username": "bob", "password": "12345678" }))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let req = test::TestRequest::post()
+            .uri("/auth")
+            .set_json(&json!({ "username": "bob", "password": password }))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let req = test::TestRequest::post()
+            .uri(&format!("/admin/users/{}/password-reset", Uuid::new_v4()))
+            .header("Authorization", admin_bearer.as_str())
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    struct FailingMailer;
+
+    impl Mailer for FailingMailer {
+        fn send(&self, _mail: &Mail) -> Result<(), MailError> {
+            Err(MailError::Smtp(String::from("421 Service not available")))
+        }
+    }
+
+    #[actix_rt::test]
+    async fn password_reset_returns_the_password_when_the_mail_fails() {
+        let repo = InMemoryRepository::default();
+        let (_, admin_bearer) = admin(&repo);
+        let jane = repo.create_user(create_user_data("jane")).unwrap();
+        repo.set_user_email(jane.id, Some(String::from("jane@example.com")))
+            .unwrap();
+        repo.verify_user_email(jane.id, "jane@example.com").unwrap();
+        let mut app = test::init_service(
+            App::new()
+                .data(Database::Memory(repo.clone()))
+                .data(AuthConfig::default())
+                .data(MailConfig::default())
+                .data(LoginThrottle::default())
+                .app_data(web::Data::from(Arc::new(FailingMailer) as Arc<dyn Mailer>))
+                .configure(init_routes)
+                .configure(crate::user::init_routes),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri(&format!("/admin/users/{}/password-reset", jane.id))
+            .header("Authorization", admin_bearer.as_str())
+            .to_request();
+        let body: Value = test::read_response_json(&mut app, req).await;
+        assert_eq!(body["mail_sent"], false);
+        let password = body["temporary_password"].as_str().unwrap();
+        let req = test::TestRequest::post()
+            .uri("/auth")
+            .set_json(&json!({ "username": "jane", "password": password }))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[actix_rt::test]
+    async fn logins_and_admin_actions_are_audited() {
+        let repo = InMemoryRepository::default();
+        let (admin_id, admin_bearer) = admin(&repo);
+        let user = repo.create_user(create_user_data("testUser")).unwrap();
+        let mut app = test::init_service(
+            App::new()
+                .data(Database::Memory(repo.clone()))
+                .data(AuthConfig::default())
+                .data(LoginThrottle::default())
+                .configure(init_routes)
+                .configure(crate::user::init_routes),
+        )
+        .await;
+
+        for password in &["wrong password", "12345678"] {
+            let req = test::TestRequest::post()
+                .uri("/auth")
+                .set_json(&json!({ "username": "testUser", "password": password }))
+                .to_request();
+            test::call_service(&mut app, req).await;
+        }
+        let req = test::TestRequest::put()
+            .uri(&format!("/admin/users/{}/role", user.id))
+            .header("Authorization", admin_bearer.as_str())
+            .set_json(&json!({ "role": "admin" }))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+        let req = test::TestRequest::get()
+            .uri("/admin/audit")
+            .header("Authorization", admin_bearer.as_str())
+            .to_request();
+        let body: Value = test::read_response_json(&mut app, req).await;
+        let events = body["events"].as_array().unwrap();
+        let actions: Vec<&str> = events
+            .iter()
+            .map(|event| event["action"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            actions,
+            vec!["user.role_changed", "login.succeeded", "login.failed"]
+        );
+        assert_eq!(events[0]["actor_id"], json!(admin_id));
+        assert_eq!(events[0]["target_id"], json!(user.id));
+        assert_eq!(events[0]["details"], json!({ "role": "admin" }));
+        assert_eq!(events[1]["target_id"], json!(user.id));
+        assert_eq!(
+            events[2]["details"],
+            json!({ "method": "password", "username": "testUser" })
+        );
+
+        let req = test::TestRequest::get()
+            .uri("/admin/audit?action=login.failed&format=csv")
+            .header("Authorization", admin_bearer.as_str())
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get("content-type").unwrap(),
+            "text/csv; charset=utf-8"
+        );
+        let csv = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("id,created,action,"));
+        assert!(lines[1].contains(",login.failed,"));
+
+        let req = test::TestRequest::get()
+            .uri("/admin/audit?limit=1001")
+            .header("Authorization", admin_bearer.as_str())
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+}
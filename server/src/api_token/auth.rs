@@ -0,0 +1,122 @@
+use chrono::{NaiveDateTime, Utc};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::api_token::{ApiToken, ApiTokenError, ApiTokenResponse, Scope};
+use crate::repository::ApiTokenRepository;
+use crate::session::token;
+
+/// API tokens start with this, so that they can be told apart from access tokens of sessions.
+const TOKEN_PREFIX: &str = "thm_";
+
+/// Returned once when a token is created, afterwards only its hash is known.
+#[derive(Serialize, Debug)]
+pub struct IssuedApiToken {
+    #[serde(flatten)]
+    pub info: ApiTokenResponse,
+    pub token: String,
+}
+
+fn now() -> NaiveDateTime {
+    Utc::now().naive_utc()
+}
+
+impl ApiToken {
+    pub fn is_api_token(token: &str) -> bool {
+        token.starts_with(TOKEN_PREFIX)
+    }
+
+    /// Creates a token for the user with the given scopes.
+    pub fn issue<R: ApiTokenRepository + ?Sized>(
+        repo: &R,
+        user_id: Uuid,
+        name: String,
+        scopes: &[Scope],
+    ) -> Result<IssuedApiToken, ApiTokenError> {
+        let token = format!("{}{}", TOKEN_PREFIX, token::generate());
+        let mut scope_names: Vec<&str> = scopes.iter().map(Scope::as_str).collect();
+        scope_names.sort_unstable();
+        scope_names.dedup();
+
+        let api_token = repo.create_api_token(ApiToken {
+            id: Uuid::new_v4(),
+            user_id,
+            name,
+            token_hash: token::hash(&token),
+            scopes: scope_names.join(" "),
+            created: now(),
+            last_used: None,
+        })?;
+        Ok(IssuedApiToken {
+            info: ApiTokenResponse::from(api_token),
+            token,
+        })
+    }
+
+    /// Finds the token a request was made with.
+    pub fn authenticate<R: ApiTokenRepository + ?Sized>(
+        repo: &R,
+        token: &str,
+    ) -> Result<ApiToken, ApiTokenError> {
+        let api_token = repo
+            .find_api_token_by_hash(&token::hash(token))?
+            .ok_or(ApiTokenError::InvalidToken)?;
+
+        let now = now();
+        let outdated = api_token
+            .last_used
+            .is_none_or(|last_used| token::last_use_outdated(last_used, now));
+        if outdated {
+            repo.touch_api_token(api_token.id, now)?;
+        }
+        Ok(api_token)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::{InMemoryRepository, UserRepository};
+    use crate::test_helpers::*;
+
+    #[test]
+    fn issued_token_authenticates_with_its_scopes() {
+        let repo = InMemoryRepository::default();
+        let user = repo.create_user(create_user_data("ciBot")).unwrap();
+
+        let issued = ApiToken::issue(
+            &repo,
+            user.id,
+            String::from("ci"),
+            &[Scope::PostMessages, Scope::ReadRooms, Scope::PostMessages],
+        )
+        .unwrap();
+        assert!(ApiToken::is_api_token(&issued.token));
+        assert_eq!(
+            issued.info.scopes,
+            vec![Scope::PostMessages, Scope::ReadRooms]
+        );
+
+        let api_token = ApiToken::authenticate(&repo, &issued.token).unwrap();
+        assert_eq!(api_token.user_id, user.id);
+        assert_ne!(api_token.token_hash, issued.token);
+        assert!(repo.find_user_api_tokens(user.id).unwrap()[0]
+            .last_used
+            .is_some());
+    }
+
+    #[test]
+    fn revoked_token_is_invalid() {
+        let repo = InMemoryRepository::default();
+        let user = repo.create_user(create_user_data("ciBot")).unwrap();
+        let issued =
+            ApiToken::issue(&repo, user.id, String::from("ci"), &[Scope::ReadRooms]).unwrap();
+
+        repo.destroy_api_token(user.id, issued.info.id).unwrap();
+
+        assert!(matches!(
+            ApiToken::authenticate(&repo, &issued.token),
+            Err(ApiTokenError::InvalidToken)
+        ));
+    }
+}
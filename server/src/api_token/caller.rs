@@ -0,0 +1,123 @@
+use actix_web::{dev, FromRequest, HttpRequest};
+use futures::future::LocalBoxFuture;
+use uuid::Uuid;
+
+use crate::api_token::{ApiToken, ApiTokenError, Scope};
+use crate::db::DbConn;
+use crate::errors::ServiceError;
+use crate::session::{bearer_token, Session};
+use crate::telemetry;
+
+/// Who makes a request to a route that programs with API tokens may use as well.
+///
+/// Requests without an `Authorization: Bearer` header are anonymous, requests with an invalid
+/// token fail with `401 Unauthorized` and API tokens of locked or deleted users with
+/// `403 Forbidden`. Routes check the scope they need with `require`, which also turns anonymous
+/// callers away.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Caller {
+    Anonymous,
+    /// A user with a session, who may do everything.
+    User(Uuid),
+    /// A program with an API token, which may only do what the scopes of the token allow.
+    ApiToken {
+        user_id: Uuid,
+        scopes: Vec<Scope>,
+    },
+}
+
+impl Caller {
+    pub fn user_id(&self) -> Option<Uuid> {
+        match self {
+            Caller::Anonymous => None,
+            Caller::User(user_id) => Some(*user_id),
+            Caller::ApiToken { user_id, .. } => Some(*user_id),
+        }
+    }
+
+    /// Fails with `401 Unauthorized` for anonymous callers and with `403 Forbidden` for API
+    /// tokens without the scope. Returns the id of the calling user.
+    pub fn require(&self, scope: Scope) -> Result<Uuid, ServiceError> {
+        match self {
+            Caller::Anonymous => Err(ServiceError::Unauthorized),
+            Caller::ApiToken { scopes, .. } if !scopes.contains(&scope) => {
+                Err(ServiceError::Forbidden)
+            }
+            Caller::User(user_id) | Caller::ApiToken { user_id, .. } => Ok(*user_id),
+        }
+    }
+}
+
+impl FromRequest for Caller {
+    type Error = ServiceError;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, payload: &mut dev::Payload) -> Self::Future {
+        let token = bearer_token(req);
+        let conn = DbConn::from_request(req, payload);
+
+        Box::pin(async move {
+            let token = match token {
+                Some(token) => token,
+                None => return Ok(Caller::Anonymous),
+            };
+            let caller = conn
+                .await?
+                .run(move |repo| -> Result<Caller, ServiceError> {
+                    if ApiToken::is_api_token(&token) {
+                        match ApiToken::authenticate(repo, &token) {
+                            Ok(api_token) => {
+                                // Sessions end when a user is locked or deleted, API tokens stay
+                                let owner = repo.find_user_by_id(api_token.user_id)?;
+                                if owner.is_none_or(|user| user.is_disabled()) {
+                                    return Err(ServiceError::Forbidden);
+                                }
+                                return Ok(Caller::ApiToken {
+                                    scopes: api_token.scopes(),
+                                    user_id: api_token.user_id,
+                                });
+                            }
+                            // Access tokens of sessions are random and may start with the prefix
+                            Err(ApiTokenError::InvalidToken) => (),
+                            Err(e) => return Err(e.into()),
+                        }
+                    }
+                    let session = Session::authenticate(repo, &token)?;
+                    Ok(Caller::User(session.user_id))
+                })
+                .await?;
+
+            if let Some(user_id) = caller.user_id() {
+                telemetry::record_user_id(user_id);
+            }
+            Ok(caller)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn api_tokens_need_the_scope_and_anonymous_callers_are_rejected() {
+        let user_id = Uuid::new_v4();
+        let bot = Caller::ApiToken {
+            user_id,
+            scopes: vec![Scope::ReadRooms],
+        };
+
+        assert!(bot.require(Scope::ReadRooms).is_ok());
+        assert!(matches!(
+            bot.require(Scope::ManageRooms),
+            Err(ServiceError::Forbidden)
+        ));
+        assert!(Caller::User(user_id).require(Scope::ManageRooms).is_ok());
+        assert_eq!(Caller::Anonymous.user_id(), None);
+        assert!(matches!(
+            Caller::Anonymous.require(Scope::ReadRooms),
+            Err(ServiceError::Unauthorized)
+        ));
+    }
+}
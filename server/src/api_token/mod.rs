@@ -0,0 +1,8 @@
+pub(crate) mod auth;
+mod caller;
+mod model;
+mod routes;
+
+pub use caller::Caller;
+pub use model::*;
+pub use routes::init_routes;
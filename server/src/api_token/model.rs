@@ -0,0 +1,160 @@
+use chrono::NaiveDateTime;
+use diesel::result::Error as DieselError;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::query::prelude::*;
+use crate::schema::api_tokens;
+
+/// A long-lived token for programs like bots, limited to some scopes.
+///
+/// Only the hash of the token is stored. The scopes are stored as text separated by spaces, for
+/// the same reason as `Role`.
+#[derive(Clone, Queryable, PartialEq, Debug)]
+pub struct ApiToken {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub name: String,
+    pub token_hash: String,
+    pub scopes: String,
+    pub created: NaiveDateTime,
+    pub last_used: Option<NaiveDateTime>,
+}
+
+/// What an API token may be used for.
+#[derive(Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub enum Scope {
+    /// Reading rooms, their members and messages
+    #[serde(rename = "rooms:read")]
+    ReadRooms,
+    #[serde(rename = "messages:write")]
+    PostMessages,
+    /// Creating, changing and deleting rooms and their members
+    #[serde(rename = "rooms:manage")]
+    ManageRooms,
+}
+
+impl Scope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::ReadRooms => "rooms:read",
+            Scope::PostMessages => "messages:write",
+            Scope::ManageRooms => "rooms:manage",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Scope> {
+        match value {
+            "rooms:read" => Some(Scope::ReadRooms),
+            "messages:write" => Some(Scope::PostMessages),
+            "rooms:manage" => Some(Scope::ManageRooms),
+            _ => None,
+        }
+    }
+}
+
+// Do not return the token hash
+#[derive(Serialize, Debug)]
+pub struct ApiTokenResponse {
+    pub id: Uuid,
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    pub created: NaiveDateTime,
+    pub last_used: Option<NaiveDateTime>,
+}
+
+impl From<ApiToken> for ApiTokenResponse {
+    fn from(token: ApiToken) -> ApiTokenResponse {
+        ApiTokenResponse {
+            scopes: token.scopes(),
+            id: token.id,
+            name: token.name,
+            created: token.created,
+            last_used: token.last_used,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ApiTokenError {
+    TokenNotFound,
+    /// The token is unknown or was revoked.
+    InvalidToken,
+    DatabaseError,
+    GenericError,
+}
+
+impl ApiToken {
+    pub fn scopes(&self) -> Vec<Scope> {
+        self.scopes.split(' ').filter_map(Scope::parse).collect()
+    }
+
+    pub fn create(conn: &impl SqlConnection, token: ApiToken) -> Result<ApiToken, ApiTokenError> {
+        diesel::insert_into(api_tokens::table)
+            .values((
+                api_tokens::id.eq(Id(token.id)),
+                api_tokens::user_id.eq(Id(token.user_id)),
+                api_tokens::name.eq(&token.name),
+                api_tokens::token_hash.eq(&token.token_hash),
+                api_tokens::scopes.eq(&token.scopes),
+                api_tokens::created.eq(token.created),
+                api_tokens::last_used.eq(token.last_used),
+            ))
+            .execute(conn)?;
+        Ok(token)
+    }
+
+    pub fn find_by_hash(
+        conn: &impl SqlConnection,
+        token_hash: &str,
+    ) -> Result<Option<ApiToken>, ApiTokenError> {
+        Ok(api_tokens::table
+            .filter(api_tokens::token_hash.eq(token_hash))
+            .first(conn)
+            .optional()?)
+    }
+
+    pub fn find_by_user(
+        conn: &impl SqlConnection,
+        user_id: Uuid,
+    ) -> Result<Vec<ApiToken>, ApiTokenError> {
+        Ok(api_tokens::table
+            .filter(api_tokens::user_id.eq(Id(user_id)))
+            .order(api_tokens::created.desc())
+            .load(conn)?)
+    }
+
+    pub fn touch(
+        conn: &impl SqlConnection,
+        token_id: Uuid,
+        last_used: NaiveDateTime,
+    ) -> Result<(), ApiTokenError> {
+        diesel::update(api_tokens::table.find(Id(token_id)))
+            .set(api_tokens::last_used.eq(last_used))
+            .execute(conn)?;
+        Ok(())
+    }
+
+    pub fn destroy(
+        conn: &impl SqlConnection,
+        user_id: Uuid,
+        token_id: Uuid,
+    ) -> Result<usize, ApiTokenError> {
+        Ok(diesel::delete(
+            api_tokens::table
+                .find(Id(token_id))
+                .filter(api_tokens::user_id.eq(Id(user_id))),
+        )
+        .execute(conn)?)
+    }
+}
+
+impl From<DieselError> for ApiTokenError {
+    fn from(error: DieselError) -> ApiTokenError {
+        match error {
+            DieselError::DatabaseError(_, _) => ApiTokenError::DatabaseError,
+            DieselError::NotFound => ApiTokenError::TokenNotFound,
+            _ => ApiTokenError::GenericError,
+        }
+    }
+}
@@ -0,0 +1,179 @@
+use actix_web::{delete, get, post, web, HttpResponse};
+use serde::Deserialize;
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::api_token::{ApiToken, ApiTokenResponse, Scope};
+use crate::db::DbConn;
+use crate::errors::ServiceError;
+use crate::session::Identity;
+
+const MAX_NAME_LENGTH: usize = 64;
+
+#[derive(Deserialize, Debug)]
+pub struct ApiTokenData {
+    /// Shown in the list of tokens, e.g. "CI notifier".
+    pub name: String,
+    pub scopes: Vec<Scope>,
+}
+
+// Tokens are managed with a session only, so that a leaked token cannot create more tokens
+#[get("/me/tokens")]
+pub async fn list(conn: DbConn, identity: Identity) -> Result<HttpResponse, ServiceError> {
+    let tokens = conn
+        .run(move |repo| repo.find_user_api_tokens(identity.user_id))
+        .await?;
+
+    let tokens: Vec<ApiTokenResponse> = tokens.into_iter().map(ApiTokenResponse::from).collect();
+    Ok(HttpResponse::Ok().json(json!({ "tokens": tokens })))
+}
+
+#[post("/me/tokens")]
+pub async fn create(
+    conn: DbConn,
+    identity: Identity,
+    data: web::Json<ApiTokenData>,
+) -> Result<HttpResponse, ServiceError> {
+    let ApiTokenData { name, scopes } = data.into_inner();
+    if name.trim().is_empty() || name.chars().count() > MAX_NAME_LENGTH {
+        return Err(ServiceError::Validation(format!(
+            "name must have 1 to {} characters",
+            MAX_NAME_LENGTH
+        )));
+    }
+    if scopes.is_empty() {
+        return Err(ServiceError::Validation(String::from(
+            "scopes must not be empty",
+        )));
+    }
+
+    let issued = conn
+        .run(move |repo| ApiToken::issue(repo, identity.user_id, name, &scopes))
+        .await?;
+    Ok(HttpResponse::Ok().json(issued))
+}
+
+#[delete("/me/tokens/{id}")]
+pub async fn delete(
+    conn: DbConn,
+    identity: Identity,
+    token_id: web::Path<Uuid>,
+) -> Result<HttpResponse, ServiceError> {
+    let count = conn
+        .run(move |repo| repo.destroy_api_token(identity.user_id, token_id.into_inner()))
+        .await?;
+
+    if count == 0 {
+        Err(ServiceError::NotFound)
+    } else {
+        Ok(HttpResponse::NoContent().finish())
+    }
+}
+
+pub fn init_routes(config: &mut web::ServiceConfig) {
+    config.service(list);
+    config.service(create);
+    config.service(delete);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AuthConfig;
+    use crate::db::Database;
+    use crate::repository::{InMemoryRepository, UserRepository};
+    use crate::session::auth::ClientInfo;
+    use crate::session::Session;
+    use crate::test_helpers::*;
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App};
+    use serde_json::Value;
+
+    #[actix_rt::test]
+    async fn tokens_can_be_created_listed_and_revoked() {
+        let repo = InMemoryRepository::default();
+        let user = repo.create_user(create_user_data("ciBot")).unwrap();
+        let session = Session::start(
+            &repo,
+            user.id,
+            ClientInfo::default(),
+            &AuthConfig::default(),
+        )
+        .unwrap();
+        let bearer = format!("Bearer {}", session.access_token);
+        let mut app = test::init_service(
+            App::new()
+                .data(Database::Memory(repo))
+                .configure(init_routes),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/me/tokens")
+            .header("Authorization", bearer.as_str())
+            .set_json(&json!({ "name": "ci", "scopes": ["rooms:read", "messages:write"] }))
+            .to_request();
+        let issued: Value = test::read_response_json(&mut app, req).await;
+        let token = issued["token"].as_str().unwrap();
+        assert!(token.starts_with("thm_"));
+
+        // API tokens cannot manage tokens
+        let req = test::TestRequest::get()
+            .uri("/me/tokens")
+            .header("Authorization", format!("Bearer {}", token))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let req = test::TestRequest::get()
+            .uri("/me/tokens")
+            .header("Authorization", bearer.as_str())
+            .to_request();
+        let body: Value = test::read_response_json(&mut app, req).await;
+        let tokens = body["tokens"].as_array().unwrap();
+        assert_eq!(tokens.len(), 1);
+        assert_eq!(tokens[0]["scopes"], json!(["messages:write", "rooms:read"]));
+        assert!(tokens[0].get("token").is_none() && tokens[0].get("token_hash").is_none());
+
+        let req = test::TestRequest::delete()
+            .uri(&format!("/me/tokens/{}", issued["id"].as_str().unwrap()))
+            .header("Authorization", bearer.as_str())
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+        let req = test::TestRequest::delete()
+            .uri(&format!("/me/tokens/{}", issued["id"].as_str().unwrap()))
+            .header("Authorization", bearer.as_str())
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_rt::test]
+    async fn create_rejects_tokens_without_scopes() {
+        let repo = InMemoryRepository::default();
+        let user = repo.create_user(create_user_data("ciBot")).unwrap();
+        let session = Session::start(
+            &repo,
+            user.id,
+            ClientInfo::default(),
+            &AuthConfig::default(),
+        )
+        .unwrap();
+        let mut app = test::init_service(
+            App::new()
+                .data(Database::Memory(repo))
+                .configure(init_routes),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/me/tokens")
+            .header("Authorization", format!("Bearer {}", session.access_token))
+            .set_json(&json!({ "name": "ci", "scopes": [] }))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+}
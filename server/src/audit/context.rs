@@ -0,0 +1,79 @@
+use actix_web::{dev, FromRequest, HttpRequest};
+use futures::future::LocalBoxFuture;
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::audit::{Action, AuditEvent};
+use crate::errors::ServiceError;
+use crate::repository::AuditRepository;
+use crate::telemetry;
+
+/// Where a request comes from, for the audit events it causes.
+///
+/// Use this as an extractor in routes that record events.
+#[derive(Clone, Default, Debug)]
+pub struct AuditContext {
+    pub ip: Option<String>,
+    pub request_id: Option<String>,
+}
+
+impl AuditContext {
+    pub fn event(
+        &self,
+        action: Action,
+        actor_id: Option<Uuid>,
+        target_id: Option<Uuid>,
+    ) -> AuditEvent {
+        AuditEvent {
+            ip: self.ip.clone(),
+            request_id: self.request_id.clone(),
+            ..AuditEvent::new(action, actor_id, target_id)
+        }
+    }
+
+    /// Records the outcome of a login and passes it on. `result` has the id of the user that
+    /// logged in. Only rejected logins count as failed, not e.g. database errors, and a failure
+    /// to record them does not hide why the login failed.
+    pub fn record_login<T, R: AuditRepository + ?Sized>(
+        &self,
+        repo: &R,
+        method: &str,
+        username: Option<&str>,
+        result: Result<(Uuid, T), ServiceError>,
+    ) -> Result<T, ServiceError> {
+        let details = json!({ "method": method, "username": username });
+        match result {
+            Ok((user_id, value)) => {
+                let event = self.event(Action::LoginSucceeded, Some(user_id), Some(user_id));
+                repo.create_audit_event(event.with_details(details))?;
+                Ok(value)
+            }
+            Err(e @ ServiceError::Unauthorized) | Err(e @ ServiceError::Forbidden) => {
+                let event = self.event(Action::LoginFailed, None, None);
+                if let Err(error) = repo.create_audit_event(event.with_details(details)) {
+                    tracing::error!("Failed to record a failed login: {:?}", error);
+                }
+                Err(e)
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl FromRequest for AuditContext {
+    type Error = ServiceError;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _payload: &mut dev::Payload) -> Self::Future {
+        let ip = req.peer_addr().map(|addr| addr.ip().to_string());
+
+        // The request id is only known while the request is handled, not yet in here
+        Box::pin(async move {
+            Ok(AuditContext {
+                ip,
+                request_id: telemetry::current_request_id(),
+            })
+        })
+    }
+}
@@ -0,0 +1,80 @@
+use crate::audit::AuditEvent;
+
+const HEADER: &str = "id,created,action,actor_id,target_id,ip,request_id,details";
+
+/// Writes the events as CSV for spreadsheets, one event per line after a header line.
+pub fn write_events(events: &[AuditEvent]) -> String {
+    let mut csv = String::from(HEADER);
+    csv.push_str("\r\n");
+    for event in events {
+        let fields = [
+            event.id.to_string(),
+            event.created.format("%Y-%m-%dT%H:%M:%S%.6f").to_string(),
+            event.action.as_str().to_string(),
+            optional(event.actor_id),
+            optional(event.target_id),
+            event.ip.clone().unwrap_or_default(),
+            event.request_id.clone().unwrap_or_default(),
+            event.details.clone().unwrap_or_default(),
+        ];
+        let fields: Vec<String> = fields.iter().map(|field| escape(field)).collect();
+        csv.push_str(&fields.join(","));
+        csv.push_str("\r\n");
+    }
+    csv
+}
+
+fn optional<T: ToString>(value: Option<T>) -> String {
+    value.map(|value| value.to_string()).unwrap_or_default()
+}
+
+/// Quotes the field if needed. Fields that a spreadsheet would run as a formula get a leading
+/// `'`, request ids and usernames in the details come from clients.
+fn escape(field: &str) -> String {
+    let field = if field.starts_with(['=', '+', '-', '@']) {
+        format!("'{}", field)
+    } else {
+        String::from(field)
+    };
+    if field.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audit::Action;
+    use serde_json::json;
+
+    #[test]
+    fn fields_are_quoted_and_formulas_defused() {
+        assert_eq!(escape("plain"), "plain");
+        assert_eq!(escape("a,b"), "\"a,b\"");
+        assert_eq!(escape("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(escape("=SUM(A1:A9)"), "'=SUM(A1:A9)");
+        assert_eq!(escape("-1,2"), "\"'-1,2\"");
+    }
+
+    #[test]
+    fn events_are_written_one_per_line() {
+        let mut event = AuditEvent::new(Action::LoginFailed, None, None)
+            .with_details(json!({ "method": "password" }));
+        event.request_id = Some(String::from("=cmd"));
+        let csv = write_events(&[event.clone()]);
+
+        let lines: Vec<&str> = csv.split("\r\n").collect();
+        assert_eq!(lines[0], HEADER);
+        assert_eq!(
+            lines[1],
+            format!(
+                "{},{},login.failed,,,,'=cmd,\"{{\"\"method\"\":\"\"password\"\"}}\"",
+                event.id,
+                event.created.format("%Y-%m-%dT%H:%M:%S%.6f")
+            )
+        );
+        assert_eq!(lines[2], "");
+    }
+}
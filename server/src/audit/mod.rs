@@ -0,0 +1,6 @@
+mod context;
+pub mod csv;
+mod model;
+
+pub use context::AuditContext;
+pub use model::*;
@@ -0,0 +1,303 @@
+use std::io::Write;
+
+use chrono::{NaiveDateTime, Utc};
+use diesel::backend::Backend;
+use diesel::deserialize::{self, FromSql};
+use diesel::result::Error as DieselError;
+use diesel::serialize::{self, Output, ToSql};
+use diesel::sql_types::Text;
+use serde::{Deserialize, Serialize, Serializer};
+use uuid::Uuid;
+
+use crate::query::prelude::*;
+use crate::schema::audit_events;
+
+pub const DEFAULT_EVENT_LIMIT: i64 = 100;
+pub const MAX_EVENT_LIMIT: i64 = 1000;
+
+/// Something security relevant that happened, written once and never changed.
+///
+/// Events do not reference users or rooms by foreign key, so that they outlive what they are
+/// about.
+#[derive(Clone, Queryable, Serialize, PartialEq, Debug)]
+pub struct AuditEvent {
+    pub id: Uuid,
+    pub created: NaiveDateTime,
+    pub action: Action,
+    /// The user who did it, `None` for anonymous requests and admin commands.
+    pub actor_id: Option<Uuid>,
+    /// The user, room or message the action was about.
+    pub target_id: Option<Uuid>,
+    pub ip: Option<String>,
+    pub request_id: Option<String>,
+    /// A JSON object with what else is worth knowing, e.g. the new role of a user.
+    #[serde(serialize_with = "serialize_details")]
+    pub details: Option<String>,
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, AsExpression, FromSqlRow, PartialEq, Debug)]
+#[sql_type = "Text"]
+pub enum Action {
+    #[serde(rename = "login.succeeded")]
+    LoginSucceeded,
+    #[serde(rename = "login.failed")]
+    LoginFailed,
+    #[serde(rename = "user.password_changed")]
+    PasswordChanged,
+    #[serde(rename = "user.password_reset")]
+    PasswordReset,
+    #[serde(rename = "user.deleted")]
+    UserDeleted,
+    #[serde(rename = "user.restored")]
+    UserRestored,
+    #[serde(rename = "user.purged")]
+    UserPurged,
+    #[serde(rename = "user.role_changed")]
+    RoleChanged,
+    #[serde(rename = "user.locked")]
+    UserLocked,
+    #[serde(rename = "user.unlocked")]
+    UserUnlocked,
+    #[serde(rename = "room.deleted")]
+    RoomDeleted,
+    #[serde(rename = "room.users_added")]
+    RoomUsersAdded,
+    #[serde(rename = "room.users_removed")]
+    RoomUsersRemoved,
+    #[serde(rename = "message.deleted")]
+    MessageDeleted,
+}
+
+const ACTIONS: &[Action] = &[
+    Action::LoginSucceeded,
+    Action::LoginFailed,
+    Action::PasswordChanged,
+    Action::PasswordReset,
+    Action::UserDeleted,
+    Action::UserRestored,
+    Action::UserPurged,
+    Action::RoleChanged,
+    Action::UserLocked,
+    Action::UserUnlocked,
+    Action::RoomDeleted,
+    Action::RoomUsersAdded,
+    Action::RoomUsersRemoved,
+    Action::MessageDeleted,
+];
+
+impl Action {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Action::LoginSucceeded => "login.succeeded",
+            Action::LoginFailed => "login.failed",
+            Action::PasswordChanged => "user.password_changed",
+            Action::PasswordReset => "user.password_reset",
+            Action::UserDeleted => "user.deleted",
+            Action::UserRestored => "user.restored",
+            Action::UserPurged => "user.purged",
+            Action::RoleChanged => "user.role_changed",
+            Action::UserLocked => "user.locked",
+            Action::UserUnlocked => "user.unlocked",
+            Action::RoomDeleted => "room.deleted",
+            Action::RoomUsersAdded => "room.users_added",
+            Action::RoomUsersRemoved => "room.users_removed",
+            Action::MessageDeleted => "message.deleted",
+        }
+    }
+}
+
+// Stored as text, see `Role`
+impl<DB: Backend> ToSql<Text, DB> for Action
+where
+    str: ToSql<Text, DB>,
+{
+    fn to_sql<W: Write>(&self, out: &mut Output<W, DB>) -> serialize::Result {
+        self.as_str().to_sql(out)
+    }
+}
+
+impl<DB: Backend> FromSql<Text, DB> for Action
+where
+    String: FromSql<Text, DB>,
+{
+    fn from_sql(bytes: Option<&DB::RawValue>) -> deserialize::Result<Self> {
+        let action = String::from_sql(bytes)?;
+        ACTIONS
+            .iter()
+            .copied()
+            .find(|known| known.as_str() == action)
+            .ok_or_else(|| format!("unknown audit action {}", action).into())
+    }
+}
+
+// The details are stored as text, but clients get them as a JSON object
+fn serialize_details<S: Serializer>(
+    details: &Option<String>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    details
+        .as_deref()
+        .and_then(|details| serde_json::from_str::<serde_json::Value>(details).ok())
+        .serialize(serializer)
+}
+
+fn default_limit() -> i64 {
+    DEFAULT_EVENT_LIMIT
+}
+
+/// Which events an admin is looking for, e.g. `?action=login.failed&since=2026-10-01T00:00:00`.
+/// All filters have to match, the newest events come first.
+#[derive(Clone, Deserialize, Debug)]
+pub struct AuditQuery {
+    pub action: Option<Action>,
+    pub actor_id: Option<Uuid>,
+    pub target_id: Option<Uuid>,
+    /// Only events at or after this time, in UTC.
+    pub since: Option<NaiveDateTime>,
+    /// Only events before this time, in UTC.
+    pub until: Option<NaiveDateTime>,
+    #[serde(default = "default_limit")]
+    pub limit: i64,
+    #[serde(default)]
+    pub offset: i64,
+}
+
+impl Default for AuditQuery {
+    fn default() -> Self {
+        AuditQuery {
+            action: None,
+            actor_id: None,
+            target_id: None,
+            since: None,
+            until: None,
+            limit: DEFAULT_EVENT_LIMIT,
+            offset: 0,
+        }
+    }
+}
+
+impl AuditQuery {
+    // Only the in-memory repository filters in Rust, see `AuditEvent::find` for the SQL backends
+    #[cfg(test)]
+    pub fn matches(&self, event: &AuditEvent) -> bool {
+        self.action.is_none_or(|action| event.action == action)
+            && self.actor_id.is_none_or(|id| event.actor_id == Some(id))
+            && self.target_id.is_none_or(|id| event.target_id == Some(id))
+            && self.since.is_none_or(|since| event.created >= since)
+            && self.until.is_none_or(|until| event.created < until)
+    }
+}
+
+#[derive(Debug)]
+pub enum AuditError {
+    DatabaseError,
+    GenericError,
+}
+
+impl AuditEvent {
+    /// An event that happened just now, without a request. Routes use `AuditContext::event`,
+    /// which adds where the request came from.
+    pub fn new(action: Action, actor_id: Option<Uuid>, target_id: Option<Uuid>) -> AuditEvent {
+        AuditEvent {
+            id: Uuid::new_v4(),
+            created: Utc::now().naive_utc(),
+            action,
+            actor_id,
+            target_id,
+            ip: None,
+            request_id: None,
+            details: None,
+        }
+    }
+
+    pub fn with_details(mut self, details: serde_json::Value) -> AuditEvent {
+        self.details = Some(details.to_string());
+        self
+    }
+
+    pub fn create(conn: &impl SqlConnection, event: AuditEvent) -> Result<AuditEvent, AuditError> {
+        diesel::insert_into(audit_events::table)
+            .values((
+                audit_events::id.eq(Id(event.id)),
+                audit_events::created.eq(event.created),
+                audit_events::action.eq(event.action),
+                audit_events::actor_id.eq(event.actor_id.map(Id)),
+                audit_events::target_id.eq(event.target_id.map(Id)),
+                audit_events::ip.eq(&event.ip),
+                audit_events::request_id.eq(&event.request_id),
+                audit_events::details.eq(&event.details),
+            ))
+            .execute(conn)?;
+        Ok(event)
+    }
+
+    pub fn find(
+        conn: &impl SqlConnection,
+        query: &AuditQuery,
+    ) -> Result<Vec<AuditEvent>, AuditError> {
+        let actor_id = query.actor_id.map(Id);
+        let target_id = query.target_id.map(Id);
+        Ok(audit_events::table
+            .filter(
+                audit_events::action
+                    .nullable()
+                    .eq(query.action)
+                    .or(not_given(&query.action)),
+            )
+            .filter(audit_events::actor_id.eq(actor_id).or(not_given(&actor_id)))
+            .filter(
+                audit_events::target_id
+                    .eq(target_id)
+                    .or(not_given(&target_id)),
+            )
+            .filter(
+                audit_events::created
+                    .nullable()
+                    .ge(query.since)
+                    .or(not_given(&query.since)),
+            )
+            .filter(
+                audit_events::created
+                    .nullable()
+                    .lt(query.until)
+                    .or(not_given(&query.until)),
+            )
+            .order(audit_events::created.desc())
+            .limit(query.limit)
+            .offset(query.offset)
+            .load(conn)?)
+    }
+}
+
+impl From<DieselError> for AuditError {
+    fn from(error: DieselError) -> AuditError {
+        match error {
+            DieselError::DatabaseError(_, _) => AuditError::DatabaseError,
+            _ => AuditError::GenericError,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn actions_are_serialized_as_stored() {
+        for action in ACTIONS {
+            assert_eq!(
+                serde_json::to_value(action).unwrap(),
+                serde_json::json!(action.as_str())
+            );
+        }
+    }
+
+    #[test]
+    fn details_are_serialized_as_json() {
+        let event = AuditEvent::new(Action::RoleChanged, None, None)
+            .with_details(serde_json::json!({ "role": "admin" }));
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(json["action"], "user.role_changed");
+        assert_eq!(json["details"]["role"], "admin");
+    }
+}
@@ -0,0 +1,386 @@
+//! The command line interface of the server binary.
+//!
+//! Besides serving HTTP, the binary has commands for operational tasks. They use the same
+//! repositories as the routes, so they go through the same checks as the API.
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+
+use derive_more::{Display, From};
+use serde_json::json;
+use structopt::StructOpt;
+use uuid::Uuid;
+
+use crate::audit::{Action, AuditError, AuditEvent};
+use crate::config::ConfigOverrides;
+use crate::db::Database;
+use crate::errors::ServiceError;
+use crate::migrations::{self, MigrationError};
+use crate::repository::Repository;
+use crate::room::{RoomData, RoomError};
+use crate::user::{Role, UserData, UserError};
+
+#[derive(StructOpt, Debug)]
+#[structopt(about = "The thermit server")]
+pub struct Opt {
+    #[structopt(flatten)]
+    pub overrides: ConfigOverrides,
+    #[structopt(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(StructOpt, Debug, PartialEq)]
+pub enum Command {
+    /// Starts the server, this is the default if no command is given
+    Serve,
+    /// Applies all pending database migrations
+    Migrate,
+    /// Creates a user, the password is read from stdin if it is not given
+    CreateUser {
+        username: String,
+        #[structopt(long)]
+        password: Option<String>,
+        /// Marks the user as a bot, e.g. for notifiers that post with API tokens
+        #[structopt(long)]
+        bot: bool,
+    },
+    /// Sets a new password for a user, the password is read from stdin if it is not given
+    ResetPassword {
+        username: String,
+        #[structopt(long)]
+        password: Option<String>,
+    },
+    /// Gives a user the admin role
+    PromoteAdmin { username: String },
+    /// Lists all rooms with the number of their members
+    ListRooms,
+    /// Creates example users and rooms for development, all users have `123456` as password
+    Seed,
+}
+
+#[derive(Debug, Display, From)]
+pub enum CommandError {
+    #[display(fmt = "{}", _0)]
+    #[from(ignore)]
+    Failed(String),
+    #[display(fmt = "{}", _0)]
+    Service(ServiceError),
+    #[display(fmt = "{}", _0)]
+    Migration(MigrationError),
+    #[display(fmt = "could not get a database connection: {}", _0)]
+    Pool(r2d2::Error),
+    #[display(fmt = "{}", _0)]
+    Io(io::Error),
+}
+
+impl From<UserError> for CommandError {
+    fn from(error: UserError) -> CommandError {
+        CommandError::Service(error.into())
+    }
+}
+
+impl From<AuditError> for CommandError {
+    fn from(error: AuditError) -> CommandError {
+        CommandError::Service(error.into())
+    }
+}
+
+impl From<RoomError> for CommandError {
+    fn from(error: RoomError) -> CommandError {
+        CommandError::Service(error.into())
+    }
+}
+
+/// Runs every command except `serve`, printing the results to stdout.
+pub fn run(command: Command, database: &Database) -> Result<(), CommandError> {
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+
+    match command {
+        Command::Serve => unreachable!("serve is handled by main"),
+        Command::Migrate => {
+            migrations::migrate(database, true)?;
+            writeln!(out, "The database is up to date")?;
+            Ok(())
+        }
+        Command::CreateUser {
+            username,
+            password,
+            bot,
+        } => {
+            let password = read_password(password)?;
+            database.run_blocking(|repo| create_user(repo, &mut out, username, password, bot))?
+        }
+        Command::ResetPassword { username, password } => {
+            let password = read_password(password)?;
+            database.run_blocking(|repo| reset_password(repo, &mut out, &username, password))?
+        }
+        Command::PromoteAdmin { username } => {
+            database.run_blocking(|repo| promote_admin(repo, &mut out, &username))?
+        }
+        Command::ListRooms => database.run_blocking(|repo| list_rooms(repo, &mut out))?,
+        Command::Seed => database.run_blocking(|repo| seed(repo, &mut out))?,
+    }
+}
+
+fn read_password(password: Option<String>) -> Result<String, CommandError> {
+    let password = match password {
+        Some(password) => password,
+        None => {
+            eprint!("Password: ");
+            let mut line = String::new();
+            io::stdin().lock().read_line(&mut line)?;
+            line.trim_end_matches(&['\r', '\n'][..]).to_string()
+        }
+    };
+
+    if password.is_empty() {
+        return Err(CommandError::Failed(String::from(
+            "The password must not be empty",
+        )));
+    }
+    Ok(password)
+}
+
+fn find_user_id(repo: &dyn Repository, username: &str) -> Result<Uuid, CommandError> {
+    repo.find_user_by_username(username)?
+        .map(|user| user.id)
+        .ok_or_else(|| CommandError::Failed(format!("The user {} does not exist", username)))
+}
+
+fn create_user(
+    repo: &dyn Repository,
+    out: &mut dyn Write,
+    username: String,
+    password: String,
+    bot: bool,
+) -> Result<(), CommandError> {
+    let mut user = repo.create_user(UserData { username, password })?;
+    if bot {
+        user = repo.set_user_bot(user.id, true)?;
+    }
+    let kind = if user.bot { "bot" } else { "user" };
+    writeln!(
+        out,
+        "Created {} {} with id {}",
+        kind, user.username, user.id
+    )?;
+    Ok(())
+}
+
+fn reset_password(
+    repo: &dyn Repository,
+    out: &mut dyn Write,
+    username: &str,
+    password: String,
+) -> Result<(), CommandError> {
+    let user_id = find_user_id(repo, username)?;
+    let user_data = UserData {
+        username: String::from(username),
+        password,
+    };
+    repo.update_user(user_id, user_data)?;
+    let event = AuditEvent::new(Action::PasswordChanged, None, Some(user_id));
+    repo.create_audit_event(event.with_details(json!({ "command": "reset-password" })))?;
+    writeln!(out, "Changed the password of {}", username)?;
+    Ok(())
+}
+
+fn promote_admin(
+    repo: &dyn Repository,
+    out: &mut dyn Write,
+    username: &str,
+) -> Result<(), CommandError> {
+    let user_id = find_user_id(repo, username)?;
+    repo.set_user_role(user_id, Role::Admin)?;
+    let event = AuditEvent::new(Action::RoleChanged, None, Some(user_id));
+    let details = json!({ "role": Role::Admin, "command": "promote-admin" });
+    repo.create_audit_event(event.with_details(details))?;
+    writeln!(out, "{} is now an admin", username)?;
+    Ok(())
+}
+
+fn list_rooms(repo: &dyn Repository, out: &mut dyn Write) -> Result<(), CommandError> {
+    for room in repo.find_all_rooms()? {
+        let members = repo.get_room_user_ids(room.id)?.len();
+        writeln!(
+            out,
+            "{}\t{}\t{} members",
+            room.id,
+            room.name.as_deref().unwrap_or("(unnamed)"),
+            members
+        )?;
+    }
+    Ok(())
+}
+
+const SEED_PASSWORD: &str = "123456";
+
+const SEED_USERS: &[&str] = &["Tom", "Susanne", "Jerry", "Max", "Charlotte", "Zoe"];
+
+const SEED_ROOMS: &[(&str, &[&str])] = &[
+    ("The Hackerspace", &["Tom", "Max", "Zoe"]),
+    (
+        "Family Group",
+        &["Susanne", "Jerry", "Max", "Charlotte", "Zoe"],
+    ),
+    ("Super Secret Group", &["Tom", "Jerry"]),
+];
+
+/// Creates the example data. Users and rooms that already exist are reused, so seeding twice
+/// does not create duplicates.
+fn seed(repo: &dyn Repository, out: &mut dyn Write) -> Result<(), CommandError> {
+    let mut user_ids = HashMap::new();
+    for &username in SEED_USERS {
+        let id = match repo.find_user_by_username(username)? {
+            Some(user) => user.id,
+            None => {
+                let user_data = UserData {
+                    username: String::from(username),
+                    password: String::from(SEED_PASSWORD),
+                };
+                let user = repo.create_user(user_data)?;
+                writeln!(out, "Created user {}", username)?;
+                user.id
+            }
+        };
+        user_ids.insert(username, id);
+    }
+
+    let rooms = repo.find_all_rooms()?;
+    for &(name, members) in SEED_ROOMS {
+        let room_id = match rooms.iter().find(|room| room.name.as_deref() == Some(name)) {
+            Some(room) => room.id,
+            None => {
+                let room_data = RoomData {
+                    name: Some(String::from(name)),
+                };
+                let room = repo.create_room(room_data)?;
+                writeln!(out, "Created room {}", name)?;
+                room.id
+            }
+        };
+        let member_ids = members.iter().map(|member| user_ids[member]).collect();
+        let added_users = repo.add_room_users(room_id, member_ids)?;
+        if !added_users.is_empty() {
+            let event = AuditEvent::new(Action::RoomUsersAdded, None, Some(room_id));
+            let details = json!({ "user_ids": added_users, "command": "seed" });
+            repo.create_audit_event(event.with_details(details))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::{InMemoryRepository, UserRepository};
+    use crate::test_helpers::create_user_data;
+    use crate::user::User;
+
+    fn output<F>(f: F) -> String
+    where
+        F: FnOnce(&mut dyn Write) -> Result<(), CommandError>,
+    {
+        let mut out = Vec::new();
+        f(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn commands_are_parsed() {
+        let opt = Opt::from_iter(&["server", "create-user", "tom", "--password", "123456"]);
+        assert_eq!(
+            opt.command,
+            Some(Command::CreateUser {
+                username: String::from("tom"),
+                password: Some(String::from("123456")),
+                bot: false,
+            })
+        );
+
+        let opt = Opt::from_iter(&["server", "create-user", "ci", "--bot"]);
+        assert_eq!(
+            opt.command,
+            Some(Command::CreateUser {
+                username: String::from("ci"),
+                password: None,
+                bot: true,
+            })
+        );
+
+        let opt = Opt::from_iter(&["server"]);
+        assert_eq!(opt.command, None);
+    }
+
+    #[test]
+    fn create_user_fails_when_username_is_taken() {
+        let repo = InMemoryRepository::default();
+        repo.create_user(create_user_data("tom")).unwrap();
+
+        let mut out = Vec::new();
+        let result = create_user(&repo, &mut out, "tom".into(), "123456".into(), false);
+        assert!(matches!(
+            result,
+            Err(CommandError::Service(ServiceError::Conflict(_)))
+        ));
+    }
+
+    #[test]
+    fn reset_password_changes_password() {
+        let repo = InMemoryRepository::default();
+        output(|out| create_user(&repo, out, "tom".into(), "123456".into(), false));
+
+        output(|out| reset_password(&repo, out, "tom", "654321".into()));
+
+        let user_data = UserData {
+            username: "tom".into(),
+            password: "654321".into(),
+        };
+        assert!(User::authenticate(&repo, user_data).is_ok());
+    }
+
+    #[test]
+    fn create_user_creates_bot() {
+        let repo = InMemoryRepository::default();
+
+        let listing = output(|out| create_user(&repo, out, "ci".into(), "123456".into(), true));
+
+        assert!(listing.starts_with("Created bot ci"));
+        assert!(repo.find_user_by_username("ci").unwrap().unwrap().bot);
+    }
+
+    #[test]
+    fn promote_admin_sets_admin_role() {
+        let repo = InMemoryRepository::default();
+        repo.create_user(create_user_data("tom")).unwrap();
+
+        output(|out| promote_admin(&repo, out, "tom"));
+
+        let user = repo.find_user_by_username("tom").unwrap().unwrap();
+        assert_eq!(user.role, Role::Admin);
+    }
+
+    #[test]
+    fn promote_admin_fails_for_unknown_user() {
+        let repo = InMemoryRepository::default();
+
+        let mut out = Vec::new();
+        let result = promote_admin(&repo, &mut out, "tom");
+        assert!(matches!(result, Err(CommandError::Failed(_))));
+    }
+
+    #[test]
+    fn seed_can_run_twice() {
+        let repo = InMemoryRepository::default();
+
+        output(|out| seed(&repo, out));
+        let second = output(|out| seed(&repo, out));
+
+        assert!(second.is_empty());
+        assert_eq!(repo.find_all_users().unwrap().len(), SEED_USERS.len());
+        let listing = output(|out| list_rooms(&repo, out));
+        assert_eq!(listing.lines().count(), SEED_ROOMS.len());
+        assert!(listing.contains("Family Group\t5 members"));
+    }
+}
@@ -0,0 +1,954 @@
+//! The configuration of the server.
+//!
+//! The configuration is built in layers, each overriding the one before:
+//!
+//! 1. the defaults,
+//! 2. a TOML file, `thermit.toml` or the file given with `--config`,
+//! 3. environment variables, including the ones in `.env`,
+//! 4. command line flags.
+//!
+//! The result is validated once at startup, so that a broken configuration is reported with all
+//! of its problems instead of failing somewhere later.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use derive_more::Display;
+use serde::Deserialize;
+use structopt::StructOpt;
+
+use crate::db::PoolConfig;
+use crate::user::password;
+
+const DEFAULT_CONFIG_FILE: &str = "thermit.toml";
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub database: DatabaseConfig,
+    pub tls: TlsConfig,
+    pub limits: LimitsConfig,
+    pub features: FeatureConfig,
+    pub logging: LoggingConfig,
+    pub auth: AuthConfig,
+    pub mail: MailConfig,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub ip: String,
+    pub port: u16,
+    /// How long running requests may take after a shutdown signal, in seconds.
+    #[serde(deserialize_with = "seconds::deserialize")]
+    pub shutdown_timeout: Duration,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            ip: String::from("127.0.0.1"),
+            port: 8000,
+            shutdown_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    /// Has no default, the server does not start without it.
+    pub url: Option<String>,
+    /// Applies pending migrations at startup.
+    pub run_migrations: bool,
+    pub pool: PoolConfig,
+}
+
+impl DatabaseConfig {
+    /// The url of the database. Only call this after validating the configuration.
+    pub fn url(&self) -> &str {
+        self.url
+            .as_deref()
+            .expect("the configuration was not validated")
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    pub enabled: bool,
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+    /// CA of the client certificates that are accepted instead of a password.
+    pub client_ca_path: Option<PathBuf>,
+    /// How often the files are checked for changes, in seconds. 0 only reloads on `SIGHUP`.
+    #[serde(deserialize_with = "seconds::deserialize")]
+    pub watch_interval: Duration,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        TlsConfig {
+            enabled: false,
+            cert_path: PathBuf::from("cert.pem"),
+            key_path: PathBuf::from("key.pem"),
+            client_ca_path: None,
+            watch_interval: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// Maximum size of JSON request bodies in bytes.
+    pub json_payload: usize,
+    /// Maximum number of concurrent connections per worker.
+    pub max_connections: usize,
+    /// Number of worker threads, defaults to the number of CPUs.
+    pub workers: Option<usize>,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        LimitsConfig {
+            json_payload: 32 * 1024,
+            max_connections: 25_000,
+            workers: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct FeatureConfig {
+    /// Whether everyone can create an account with `POST /users`.
+    pub registration: bool,
+    /// Whether Prometheus metrics are collected and served at `/metrics`.
+    pub metrics: bool,
+}
+
+impl Default for FeatureConfig {
+    fn default() -> Self {
+        FeatureConfig {
+            registration: true,
+            metrics: true,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// The format of the log lines, the level is set with `RUST_LOG`.
+    pub format: LogFormat,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human readable lines.
+    #[default]
+    Text,
+    /// One JSON object per line, for log collectors.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// How long an access token is valid, in seconds.
+    #[serde(deserialize_with = "seconds::deserialize")]
+    pub access_token_lifetime: Duration,
+    /// How long a session can be refreshed after its last refresh, in seconds.
+    #[serde(deserialize_with = "seconds::deserialize")]
+    pub refresh_token_lifetime: Duration,
+    /// How long a deleted account can be restored before it is purged, in seconds.
+    #[serde(deserialize_with = "seconds::deserialize")]
+    pub deletion_grace_period: Duration,
+    pub password: PasswordConfig,
+    pub throttle: ThrottleConfig,
+    pub oidc: OidcConfig,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        AuthConfig {
+            access_token_lifetime: Duration::from_secs(15 * 60),
+            refresh_token_lifetime: Duration::from_secs(30 * 24 * 60 * 60),
+            deletion_grace_period: Duration::from_secs(30 * 24 * 60 * 60),
+            password: PasswordConfig::default(),
+            throttle: ThrottleConfig::default(),
+            oidc: OidcConfig::default(),
+        }
+    }
+}
+
+/// Parameters of Argon2id for new password hashes. Existing hashes are updated on login.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct PasswordConfig {
+    /// Memory used per hash in KiB.
+    pub memory_cost: u32,
+    /// Number of passes over the memory.
+    pub time_cost: u32,
+    /// Number of threads per hash.
+    pub parallelism: u32,
+}
+
+impl Default for PasswordConfig {
+    // The recommendation of OWASP
+    fn default() -> Self {
+        PasswordConfig {
+            memory_cost: 19 * 1024,
+            time_cost: 2,
+            parallelism: 1,
+        }
+    }
+}
+
+/// Limits of failed password logins. After the allowed failures, every further failure locks
+/// the account or IP out for twice as long as the one before, starting with one second.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ThrottleConfig {
+    /// Failed logins per username before it is locked.
+    pub account_attempts: u32,
+    /// Failed logins per IP before it is locked, higher as many users may share one IP.
+    pub ip_attempts: u32,
+    /// The longest lockout in seconds.
+    #[serde(deserialize_with = "seconds::deserialize")]
+    pub max_lockout: Duration,
+}
+
+impl Default for ThrottleConfig {
+    fn default() -> Self {
+        ThrottleConfig {
+            account_attempts: 5,
+            ip_attempts: 20,
+            max_lockout: Duration::from_secs(15 * 60),
+        }
+    }
+}
+
+/// Login with an OpenID Connect identity provider, e.g. the one of a company.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct OidcConfig {
+    pub enabled: bool,
+    /// The provider is discovered at `{issuer}/.well-known/openid-configuration`.
+    pub issuer: String,
+    pub client_id: String,
+    /// Not needed for public clients, which rely on PKCE alone.
+    pub client_secret: Option<String>,
+    /// Where the provider sends the user back to with `code` and `state`, usually a page of the
+    /// client that passes them on to `/auth/oidc/callback`.
+    pub redirect_uri: String,
+    pub scopes: Vec<String>,
+    /// The claim the username of new users is made from, the email is used if it is missing.
+    pub username_claim: String,
+    /// How long a login may take at the provider, in seconds.
+    #[serde(deserialize_with = "seconds::deserialize")]
+    pub login_timeout: Duration,
+}
+
+impl Default for OidcConfig {
+    fn default() -> Self {
+        OidcConfig {
+            enabled: false,
+            issuer: String::new(),
+            client_id: String::new(),
+            client_secret: None,
+            redirect_uri: String::new(),
+            scopes: vec![
+                String::from("openid"),
+                String::from("profile"),
+                String::from("email"),
+            ],
+            username_claim: String::from("preferred_username"),
+            login_timeout: Duration::from_secs(10 * 60),
+        }
+    }
+}
+
+/// How mails, e.g. for password resets, are sent.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct MailConfig {
+    pub transport: MailTransport,
+    /// The sender of all mails, e.g. `thermit <noreply@example.com>`.
+    pub from: String,
+    /// Where the `file` transport writes the mails to.
+    pub directory: PathBuf,
+    /// Links in the mails, `{token}` is replaced with the token. Without them, the mails only
+    /// contain the token.
+    pub verify_url: Option<String>,
+    pub reset_url: Option<String>,
+    pub smtp: SmtpConfig,
+}
+
+impl Default for MailConfig {
+    fn default() -> Self {
+        MailConfig {
+            transport: MailTransport::default(),
+            from: String::from("thermit <noreply@localhost>"),
+            directory: PathBuf::from("mail"),
+            verify_url: None,
+            reset_url: None,
+            smtp: SmtpConfig::default(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum MailTransport {
+    /// Writes the mails to the log, for development.
+    #[default]
+    Log,
+    /// Writes every mail to a file in `mail.directory`.
+    File,
+    Smtp,
+}
+
+impl FromStr for MailTransport {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "log" => Ok(MailTransport::Log),
+            "file" => Ok(MailTransport::File),
+            "smtp" => Ok(MailTransport::Smtp),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct SmtpConfig {
+    pub host: String,
+    pub port: u16,
+    pub security: SmtpSecurity,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// How long connecting and every command may take, in seconds.
+    #[serde(deserialize_with = "seconds::deserialize")]
+    pub timeout: Duration,
+}
+
+impl Default for SmtpConfig {
+    fn default() -> Self {
+        SmtpConfig {
+            host: String::from("localhost"),
+            port: 587,
+            security: SmtpSecurity::default(),
+            username: None,
+            password: None,
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpSecurity {
+    /// Plain text, only for relays on the same host.
+    None,
+    /// Upgrades the connection with `STARTTLS`, usually on port 587.
+    #[default]
+    StartTls,
+    /// TLS from the start, usually on port 465.
+    Tls,
+}
+
+/// Command line flags that override the configuration, available for every command.
+#[derive(StructOpt, Debug, Default, PartialEq)]
+pub struct ConfigOverrides {
+    /// The configuration file, defaults to `thermit.toml` if it exists
+    #[structopt(long, short, global = true, env = "THERMIT_CONFIG", parse(from_os_str))]
+    pub config: Option<PathBuf>,
+    /// Overrides `database.url`
+    #[structopt(long, global = true)]
+    pub database_url: Option<String>,
+    /// Overrides `server.ip`
+    #[structopt(long, global = true)]
+    pub ip: Option<String>,
+    /// Overrides `server.port`
+    #[structopt(long, global = true)]
+    pub port: Option<u16>,
+    /// Enables `database.run_migrations`
+    #[structopt(long, global = true)]
+    pub run_migrations: bool,
+    /// Enables `tls.enabled`
+    #[structopt(long, global = true)]
+    pub tls: bool,
+}
+
+#[derive(Debug, Display)]
+pub enum ConfigError {
+    #[display(fmt = "Could not read {}: {}", "_0.display()", _1)]
+    Read(PathBuf, std::io::Error),
+    #[display(fmt = "Could not parse {}: {}", "_0.display()", _1)]
+    Parse(PathBuf, toml::de::Error),
+    #[display(fmt = "{} is invalid: {}", _0, _1)]
+    Env(&'static str, String),
+    #[display(fmt = "The configuration is invalid:\n  - {}", "_0.join(\"\\n  - \")")]
+    Invalid(Vec<String>),
+}
+
+impl Config {
+    /// Loads and validates the configuration from all layers.
+    pub fn load(overrides: &ConfigOverrides) -> Result<Config, ConfigError> {
+        let mut config = match overrides.config {
+            Some(ref path) => Config::from_file(path)?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => {
+                Config::from_file(Path::new(DEFAULT_CONFIG_FILE))?
+            }
+            None => Config::default(),
+        };
+        config.apply_env(|key| std::env::var(key).ok())?;
+        config.apply_overrides(overrides);
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Config, ConfigError> {
+        let content =
+            fs::read_to_string(path).map_err(|e| ConfigError::Read(path.to_path_buf(), e))?;
+        toml::from_str(&content).map_err(|e| ConfigError::Parse(path.to_path_buf(), e))
+    }
+
+    /// Applies the environment variables returned by `var`. Flags like `USE_TLS` are enabled by
+    /// setting them to any value except `0` or `false`.
+    fn apply_env<F: Fn(&str) -> Option<String>>(&mut self, var: F) -> Result<(), ConfigError> {
+        if let Some(value) = var("SERVER_IP") {
+            self.server.ip = value;
+        }
+        if let Some(port) = env_parse(&var, "SERVER_PORT")? {
+            self.server.port = port;
+        }
+        if let Some(seconds) = env_parse(&var, "SERVER_SHUTDOWN_TIMEOUT")? {
+            self.server.shutdown_timeout = Duration::from_secs(seconds);
+        }
+
+        if let Some(value) = var("DATABASE_URL") {
+            self.database.url = Some(value);
+        }
+        if let Some(value) = var("RUN_MIGRATIONS") {
+            self.database.run_migrations = env_flag(&value);
+        }
+        let pool = &mut self.database.pool;
+        if let Some(max_size) = env_parse(&var, "DB_POOL_MAX_SIZE")? {
+            pool.max_size = max_size;
+        }
+        if let Some(min_idle) = env_parse(&var, "DB_POOL_MIN_IDLE")? {
+            pool.min_idle = Some(min_idle);
+        }
+        if let Some(seconds) = env_parse(&var, "DB_POOL_CONNECTION_TIMEOUT")? {
+            pool.connection_timeout = Duration::from_secs(seconds);
+        }
+        if let Some(seconds) = env_parse(&var, "DB_POOL_IDLE_TIMEOUT")? {
+            pool.idle_timeout = Some(Duration::from_secs(seconds));
+        }
+
+        if let Some(value) = var("USE_TLS") {
+            self.tls.enabled = env_flag(&value);
+        }
+        if let Some(value) = var("TLS_CERT_PATH") {
+            self.tls.cert_path = PathBuf::from(value);
+        }
+        if let Some(value) = var("TLS_KEY_PATH") {
+            self.tls.key_path = PathBuf::from(value);
+        }
+        if let Some(value) = var("TLS_CLIENT_CA_PATH") {
+            self.tls.client_ca_path = Some(PathBuf::from(value));
+        }
+        if let Some(seconds) = env_parse(&var, "TLS_WATCH_INTERVAL")? {
+            self.tls.watch_interval = Duration::from_secs(seconds);
+        }
+
+        if let Some(bytes) = env_parse(&var, "LIMIT_JSON_PAYLOAD")? {
+            self.limits.json_payload = bytes;
+        }
+        if let Some(max_connections) = env_parse(&var, "LIMIT_MAX_CONNECTIONS")? {
+            self.limits.max_connections = max_connections;
+        }
+        if let Some(workers) = env_parse(&var, "LIMIT_WORKERS")? {
+            self.limits.workers = Some(workers);
+        }
+
+        if let Some(value) = var("FEATURE_REGISTRATION") {
+            self.features.registration = env_flag(&value);
+        }
+        if let Some(value) = var("FEATURE_METRICS") {
+            self.features.metrics = env_flag(&value);
+        }
+
+        if let Some(value) = var("LOG_FORMAT") {
+            self.logging.format = value.parse().map_err(|_| {
+                ConfigError::Env(
+                    "LOG_FORMAT",
+                    format!("expected \"text\" or \"json\", got \"{}\"", value),
+                )
+            })?;
+        }
+
+        if let Some(seconds) = env_parse(&var, "AUTH_ACCESS_TOKEN_LIFETIME")? {
+            self.auth.access_token_lifetime = Duration::from_secs(seconds);
+        }
+        if let Some(seconds) = env_parse(&var, "AUTH_REFRESH_TOKEN_LIFETIME")? {
+            self.auth.refresh_token_lifetime = Duration::from_secs(seconds);
+        }
+        if let Some(seconds) = env_parse(&var, "AUTH_DELETION_GRACE_PERIOD")? {
+            self.auth.deletion_grace_period = Duration::from_secs(seconds);
+        }
+        if let Some(kib) = env_parse(&var, "AUTH_PASSWORD_MEMORY_COST")? {
+            self.auth.password.memory_cost = kib;
+        }
+        if let Some(passes) = env_parse(&var, "AUTH_PASSWORD_TIME_COST")? {
+            self.auth.password.time_cost = passes;
+        }
+        if let Some(threads) = env_parse(&var, "AUTH_PASSWORD_PARALLELISM")? {
+            self.auth.password.parallelism = threads;
+        }
+        if let Some(attempts) = env_parse(&var, "AUTH_THROTTLE_ACCOUNT_ATTEMPTS")? {
+            self.auth.throttle.account_attempts = attempts;
+        }
+        if let Some(attempts) = env_parse(&var, "AUTH_THROTTLE_IP_ATTEMPTS")? {
+            self.auth.throttle.ip_attempts = attempts;
+        }
+        if let Some(seconds) = env_parse(&var, "AUTH_THROTTLE_MAX_LOCKOUT")? {
+            self.auth.throttle.max_lockout = Duration::from_secs(seconds);
+        }
+        if let Some(value) = var("MAIL_TRANSPORT") {
+            self.mail.transport = value.parse().map_err(|_| {
+                ConfigError::Env(
+                    "MAIL_TRANSPORT",
+                    format!("expected \"log\", \"file\" or \"smtp\", got \"{}\"", value),
+                )
+            })?;
+        }
+        if let Some(value) = var("MAIL_FROM") {
+            self.mail.from = value;
+        }
+        if let Some(value) = var("SMTP_HOST") {
+            self.mail.smtp.host = value;
+        }
+        if let Some(port) = env_parse(&var, "SMTP_PORT")? {
+            self.mail.smtp.port = port;
+        }
+        if let Some(value) = var("SMTP_USERNAME") {
+            self.mail.smtp.username = Some(value);
+        }
+        if let Some(value) = var("SMTP_PASSWORD") {
+            self.mail.smtp.password = Some(value);
+        }
+
+        let oidc = &mut self.auth.oidc;
+        if let Some(value) = var("AUTH_OIDC_ENABLED") {
+            oidc.enabled = env_flag(&value);
+        }
+        if let Some(value) = var("AUTH_OIDC_ISSUER") {
+            oidc.issuer = value;
+        }
+        if let Some(value) = var("AUTH_OIDC_CLIENT_ID") {
+            oidc.client_id = value;
+        }
+        if let Some(value) = var("AUTH_OIDC_CLIENT_SECRET") {
+            oidc.client_secret = Some(value);
+        }
+        if let Some(value) = var("AUTH_OIDC_REDIRECT_URI") {
+            oidc.redirect_uri = value;
+        }
+        Ok(())
+    }
+
+    fn apply_overrides(&mut self, overrides: &ConfigOverrides) {
+        if let Some(ref url) = overrides.database_url {
+            self.database.url = Some(url.clone());
+        }
+        if let Some(ref ip) = overrides.ip {
+            self.server.ip = ip.clone();
+        }
+        if let Some(port) = overrides.port {
+            self.server.port = port;
+        }
+        if overrides.run_migrations {
+            self.database.run_migrations = true;
+        }
+        if overrides.tls {
+            self.tls.enabled = true;
+        }
+    }
+
+    /// Checks the whole configuration and reports every problem at once.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut problems = Vec::new();
+
+        if self.server.ip.is_empty() {
+            problems.push(String::from("server.ip must not be empty"));
+        }
+        if self.server.port == 0 {
+            problems.push(String::from("server.port must not be 0"));
+        }
+
+        match self.database.url {
+            None => problems.push(String::from(
+                "database.url must be set, e.g. with the DATABASE_URL environment variable",
+            )),
+            Some(ref url) if url.is_empty() => {
+                problems.push(String::from("database.url must not be empty"))
+            }
+            _ => (),
+        }
+        let pool = &self.database.pool;
+        if pool.max_size == 0 {
+            problems.push(String::from("database.pool.max_size must be at least 1"));
+        }
+        if pool
+            .min_idle
+            .is_some_and(|min_idle| min_idle > pool.max_size)
+        {
+            problems.push(String::from(
+                "database.pool.min_idle must not be larger than database.pool.max_size",
+            ));
+        }
+        if pool.connection_timeout.as_secs() == 0 {
+            problems.push(String::from(
+                "database.pool.connection_timeout must be at least 1 second",
+            ));
+        }
+
+        if self.tls.enabled {
+            let mut files = vec![
+                ("tls.cert_path", &self.tls.cert_path),
+                ("tls.key_path", &self.tls.key_path),
+            ];
+            if let Some(ref client_ca_path) = self.tls.client_ca_path {
+                files.push(("tls.client_ca_path", client_ca_path));
+            }
+            for (name, path) in files {
+                if !path.is_file() {
+                    problems.push(format!("{} {} does not exist", name, path.display()));
+                }
+            }
+        }
+
+        if self.limits.json_payload == 0 {
+            problems.push(String::from("limits.json_payload must be at least 1"));
+        }
+        if self.limits.max_connections == 0 {
+            problems.push(String::from("limits.max_connections must be at least 1"));
+        }
+        if self.limits.workers == Some(0) {
+            problems.push(String::from("limits.workers must be at least 1"));
+        }
+
+        if self.auth.access_token_lifetime.as_secs() == 0 {
+            problems.push(String::from(
+                "auth.access_token_lifetime must be at least 1 second",
+            ));
+        }
+        if self.auth.refresh_token_lifetime < self.auth.access_token_lifetime {
+            problems.push(String::from(
+                "auth.refresh_token_lifetime must not be shorter than auth.access_token_lifetime",
+            ));
+        }
+        if let Err(e) = password::params(&self.auth.password) {
+            problems.push(format!("auth.password: {}", e));
+        }
+        let throttle = &self.auth.throttle;
+        if throttle.account_attempts == 0 {
+            problems.push(String::from(
+                "auth.throttle.account_attempts must be at least 1",
+            ));
+        }
+        if throttle.ip_attempts == 0 {
+            problems.push(String::from("auth.throttle.ip_attempts must be at least 1"));
+        }
+        if throttle.max_lockout.as_secs() == 0 {
+            problems.push(String::from(
+                "auth.throttle.max_lockout must be at least 1 second",
+            ));
+        }
+        let oidc = &self.auth.oidc;
+        if oidc.enabled {
+            for (name, url) in &[
+                ("auth.oidc.issuer", &oidc.issuer),
+                ("auth.oidc.redirect_uri", &oidc.redirect_uri),
+            ] {
+                if !url.starts_with("https://") && !url.starts_with("http://") {
+                    problems.push(format!("{} must be an http(s) URL", name));
+                }
+            }
+            if oidc.client_id.is_empty() {
+                problems.push(String::from("auth.oidc.client_id must not be empty"));
+            }
+            if !oidc.scopes.iter().any(|scope| scope == "openid") {
+                problems.push(String::from("auth.oidc.scopes must contain openid"));
+            }
+            if oidc.login_timeout.as_secs() == 0 {
+                problems.push(String::from(
+                    "auth.oidc.login_timeout must be at least 1 second",
+                ));
+            }
+        }
+
+        if !self.mail.from.contains('@') {
+            problems.push(String::from("mail.from must contain an email address"));
+        }
+        if self.mail.transport == MailTransport::Smtp {
+            if self.mail.smtp.host.is_empty() {
+                problems.push(String::from("mail.smtp.host must not be empty"));
+            }
+            if self.mail.smtp.username.is_some() != self.mail.smtp.password.is_some() {
+                problems.push(String::from(
+                    "mail.smtp.username and mail.smtp.password must be set together",
+                ));
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Invalid(problems))
+        }
+    }
+}
+
+fn env_parse<F, T>(var: &F, key: &'static str) -> Result<Option<T>, ConfigError>
+where
+    F: Fn(&str) -> Option<String>,
+    T: FromStr,
+{
+    match var(key) {
+        None => Ok(None),
+        Some(value) => value.parse().map(Some).map_err(|_| {
+            ConfigError::Env(
+                key,
+                format!("expected a positive number, got \"{}\"", value),
+            )
+        }),
+    }
+}
+
+fn env_flag(value: &str) -> bool {
+    !matches!(value, "0" | "false")
+}
+
+/// Deserializes durations given in seconds.
+pub(crate) mod seconds {
+    use serde::{Deserialize, Deserializer};
+    use std::time::Duration;
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        u64::deserialize(deserializer).map(Duration::from_secs)
+    }
+
+    pub fn deserialize_option<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Duration>, D::Error> {
+        Option::<u64>::deserialize(deserializer).map(|seconds| seconds.map(Duration::from_secs))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    fn env(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        move |key| vars.get(key).cloned()
+    }
+
+    fn valid_config() -> Config {
+        let mut config = Config::default();
+        config.database.url = Some(String::from("postgres://localhost/thermit"));
+        config
+    }
+
+    #[test]
+    fn parses_toml() {
+        let config: Config = toml::from_str(
+            r#"
+            [server]
+            port = 9000
+
+            [database]
+            url = "postgres://localhost/thermit"
+
+            [database.pool]
+            max_size = 4
+            connection_timeout = 2
+
+            [features]
+            registration = false
+
+            [logging]
+            format = "json"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.server.port, 9000);
+        assert_eq!(config.server.ip, ServerConfig::default().ip);
+        assert_eq!(config.database.pool.max_size, 4);
+        assert_eq!(
+            config.database.pool.connection_timeout,
+            Duration::from_secs(2)
+        );
+        assert!(!config.features.registration);
+        assert_eq!(config.logging.format, LogFormat::Json);
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn rejects_unknown_keys() {
+        let result = toml::from_str::<Config>("[server]\nprot = 9000\n");
+        assert!(result.unwrap_err().to_string().contains("prot"));
+    }
+
+    #[test]
+    fn env_overrides_file_and_flags_override_env() {
+        let mut config = valid_config();
+        config
+            .apply_env(env(&[
+                ("SERVER_PORT", "9000"),
+                ("DB_POOL_MAX_SIZE", "20"),
+                ("USE_TLS", "1"),
+                ("RUN_MIGRATIONS", "false"),
+                ("SERVER_SHUTDOWN_TIMEOUT", "10"),
+            ]))
+            .unwrap();
+        assert_eq!(config.server.port, 9000);
+        assert_eq!(config.server.shutdown_timeout, Duration::from_secs(10));
+        assert_eq!(config.database.pool.max_size, 20);
+        assert!(config.tls.enabled);
+        assert!(!config.database.run_migrations);
+
+        config.apply_overrides(&ConfigOverrides {
+            port: Some(9001),
+            ..ConfigOverrides::default()
+        });
+        assert_eq!(config.server.port, 9001);
+    }
+
+    #[test]
+    fn invalid_env_number_names_variable() {
+        let mut config = valid_config();
+        let error = config
+            .apply_env(env(&[("DB_POOL_MAX_SIZE", "ten")]))
+            .unwrap_err();
+
+        assert_eq!(
+            error.to_string(),
+            "DB_POOL_MAX_SIZE is invalid: expected a positive number, got \"ten\""
+        );
+    }
+
+    #[test]
+    fn validate_reports_all_problems() {
+        let mut config = Config::default();
+        config.database.pool.max_size = 2;
+        config.database.pool.min_idle = Some(3);
+        config.tls.enabled = true;
+        config.tls.cert_path = PathBuf::from("does/not/exist.pem");
+        config.auth.refresh_token_lifetime = Duration::from_secs(60);
+
+        let problems = match config.validate() {
+            Err(ConfigError::Invalid(problems)) => problems,
+            other => panic!("expected validation errors, got {:?}", other),
+        };
+        assert!(problems.iter().any(|p| p.starts_with("database.url")));
+        assert!(problems
+            .iter()
+            .any(|p| p.starts_with("database.pool.min_idle")));
+        assert!(problems.iter().any(|p| p.contains("does/not/exist.pem")));
+        assert!(problems
+            .iter()
+            .any(|p| p.starts_with("auth.refresh_token_lifetime")));
+    }
+
+    #[test]
+    fn enabled_oidc_needs_a_provider() {
+        let mut config = valid_config();
+        config
+            .apply_env(env(&[
+                ("AUTH_OIDC_ENABLED", "1"),
+                ("AUTH_OIDC_CLIENT_ID", "thermit"),
+            ]))
+            .unwrap();
+
+        let problems = match config.validate() {
+            Err(ConfigError::Invalid(problems)) => problems,
+            other => panic!("expected validation errors, got {:?}", other),
+        };
+        assert_eq!(
+            problems,
+            vec![
+                "auth.oidc.issuer must be an http(s) URL",
+                "auth.oidc.redirect_uri must be an http(s) URL",
+            ]
+        );
+
+        config.auth.oidc.issuer = String::from("https://login.example.com");
+        config.auth.oidc.redirect_uri = String::from("https://chat.example.com/callback");
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn smtp_mail_needs_a_host_and_complete_credentials() {
+        let mut config = valid_config();
+        config
+            .apply_env(env(&[
+                ("MAIL_TRANSPORT", "smtp"),
+                ("SMTP_HOST", ""),
+                ("SMTP_USERNAME", "thermit"),
+            ]))
+            .unwrap();
+
+        let problems = match config.validate() {
+            Err(ConfigError::Invalid(problems)) => problems,
+            other => panic!("expected validation errors, got {:?}", other),
+        };
+        assert_eq!(
+            problems,
+            vec![
+                "mail.smtp.host must not be empty",
+                "mail.smtp.username and mail.smtp.password must be set together",
+            ]
+        );
+
+        config
+            .apply_env(env(&[
+                ("SMTP_HOST", "mail.example.com"),
+                ("SMTP_PASSWORD", "secret"),
+            ]))
+            .unwrap();
+        assert!(config.validate().is_ok());
+        assert!(config
+            .apply_env(env(&[("MAIL_TRANSPORT", "carrier pigeon")]))
+            .is_err());
+    }
+}
@@ -0,0 +1,362 @@
+use std::error::Error;
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use actix_web::error::BlockingError;
+use actix_web::{dev, web, FromRequest, HttpRequest};
+use diesel::prelude::*;
+use diesel::r2d2::{self, ConnectionManager};
+use futures::future::LocalBoxFuture;
+use serde::Deserialize;
+
+use crate::config::seconds;
+use crate::errors::ServiceError;
+use crate::metrics;
+#[cfg(feature = "sqlite")]
+use crate::repository::sqlite;
+#[cfg(test)]
+use crate::repository::InMemoryRepository;
+use crate::repository::Repository;
+
+pub type Pool = r2d2::Pool<ConnectionManager<PgConnection>>;
+pub type PooledConnection = r2d2::PooledConnection<ConnectionManager<PgConnection>>;
+#[cfg(feature = "sqlite")]
+pub type SqlitePool = r2d2::Pool<ConnectionManager<SqliteConnection>>;
+
+const SQLITE_URL_PREFIX: &str = "sqlite://";
+
+// Clients are asked to retry after this many seconds when no connection is available
+pub(crate) const RETRY_AFTER_SECONDS: u64 = 5;
+
+// Database work on the blocking thread pool that has not finished yet
+static BLOCKING_JOBS: AtomicUsize = AtomicUsize::new(0);
+
+/// Counts a job from its submission to the blocking thread pool until it finished or was dropped.
+struct BlockingJob(&'static AtomicUsize);
+
+impl BlockingJob {
+    fn start() -> BlockingJob {
+        BlockingJob::counted_in(&BLOCKING_JOBS)
+    }
+
+    fn counted_in(jobs: &'static AtomicUsize) -> BlockingJob {
+        jobs.fetch_add(1, Ordering::SeqCst);
+        BlockingJob(jobs)
+    }
+}
+
+impl Drop for BlockingJob {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Waits until all database work on the blocking thread pool is done, for at most `timeout`.
+///
+/// A job keeps running when the request that started it is cancelled, e.g. because the
+/// shutdown timeout ran out, so the server waits for it before the process exits. Returns the
+/// number of jobs that are still running.
+pub async fn wait_for_blocking_jobs(timeout: Duration) -> usize {
+    wait_until_done(&BLOCKING_JOBS, timeout).await
+}
+
+async fn wait_until_done(jobs: &AtomicUsize, timeout: Duration) -> usize {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let running = jobs.load(Ordering::SeqCst);
+        if running == 0 || Instant::now() >= deadline {
+            return running;
+        }
+        tokio::time::delay_for(Duration::from_millis(20)).await;
+    }
+}
+
+/// The `database.pool` section of the configuration, timeouts are given in seconds.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct PoolConfig {
+    pub max_size: u32,
+    pub min_idle: Option<u32>,
+    #[serde(deserialize_with = "seconds::deserialize")]
+    pub connection_timeout: Duration,
+    #[serde(deserialize_with = "seconds::deserialize_option")]
+    pub idle_timeout: Option<Duration>,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        PoolConfig {
+            max_size: 10,
+            min_idle: None,
+            connection_timeout: Duration::from_secs(5),
+            idle_timeout: Some(Duration::from_secs(10 * 60)),
+        }
+    }
+}
+
+fn pool_builder<M: r2d2::ManageConnection>(config: &PoolConfig) -> r2d2::Builder<M> {
+    r2d2::Pool::builder()
+        .max_size(config.max_size)
+        .min_idle(config.min_idle)
+        .connection_timeout(config.connection_timeout)
+        .idle_timeout(config.idle_timeout)
+}
+
+pub fn create_pool(database_url: &str, config: &PoolConfig) -> Result<Pool, r2d2::PoolError> {
+    let manager = ConnectionManager::<PgConnection>::new(database_url);
+    pool_builder(config).build(manager)
+}
+
+#[cfg(feature = "sqlite")]
+pub fn create_sqlite_pool(path: &str, config: &PoolConfig) -> Result<SqlitePool, r2d2::PoolError> {
+    let manager = ConnectionManager::<SqliteConnection>::new(path);
+    pool_builder(config)
+        .connection_customizer(Box::new(SqliteCustomizer))
+        .build(manager)
+}
+
+#[cfg(feature = "sqlite")]
+#[derive(Debug)]
+struct SqliteCustomizer;
+
+#[cfg(feature = "sqlite")]
+impl r2d2::CustomizeConnection<SqliteConnection, r2d2::Error> for SqliteCustomizer {
+    fn on_acquire(&self, conn: &mut SqliteConnection) -> Result<(), r2d2::Error> {
+        sqlite::prepare_connection(conn).map_err(r2d2::Error::QueryError)
+    }
+}
+
+/// The storage backend of the server, registered as app data.
+#[derive(Clone)]
+pub enum Database {
+    Postgres(Pool),
+    #[cfg(feature = "sqlite")]
+    Sqlite(SqlitePool),
+    #[cfg(test)]
+    Memory(InMemoryRepository),
+}
+
+impl Database {
+    /// Connects to the database at the given url. Urls starting with `sqlite://` select SQLite,
+    /// all others Postgres.
+    pub fn connect(database_url: &str, config: &PoolConfig) -> Result<Database, Box<dyn Error>> {
+        match database_url.strip_prefix(SQLITE_URL_PREFIX) {
+            Some(path) => Database::connect_sqlite(path, config),
+            None => Ok(Database::Postgres(create_pool(database_url, config)?)),
+        }
+    }
+
+    #[cfg(feature = "sqlite")]
+    fn connect_sqlite(path: &str, config: &PoolConfig) -> Result<Database, Box<dyn Error>> {
+        Ok(Database::Sqlite(create_sqlite_pool(path, config)?))
+    }
+
+    #[cfg(not(feature = "sqlite"))]
+    fn connect_sqlite(_path: &str, _config: &PoolConfig) -> Result<Database, Box<dyn Error>> {
+        Err("the server has to be built with the sqlite feature to use SQLite".into())
+    }
+
+    /// The state of the connection pool and its maximum size, if the backend has a pool.
+    pub fn pool_state(&self) -> Option<(r2d2::State, u32)> {
+        match self {
+            Database::Postgres(pool) => Some((pool.state(), pool.max_size())),
+            #[cfg(feature = "sqlite")]
+            Database::Sqlite(pool) => Some((pool.state(), pool.max_size())),
+            #[cfg(test)]
+            Database::Memory(_) => None,
+        }
+    }
+
+    /// Runs `f` with a connection from the pool on the current thread. Only for work outside of
+    /// requests, like the admin commands, routes use `DbConn` instead.
+    pub fn run_blocking<T>(
+        &self,
+        f: impl FnOnce(&dyn Repository) -> T,
+    ) -> Result<T, r2d2::PoolError> {
+        match self {
+            Database::Postgres(pool) => Ok(f(&*pool.get()?)),
+            #[cfg(feature = "sqlite")]
+            Database::Sqlite(pool) => Ok(f(&*pool.get()?)),
+            #[cfg(test)]
+            Database::Memory(repo) => Ok(f(repo)),
+        }
+    }
+}
+
+enum Connection {
+    Postgres(PooledConnection),
+    #[cfg(feature = "sqlite")]
+    Sqlite(r2d2::PooledConnection<ConnectionManager<SqliteConnection>>),
+    #[cfg(test)]
+    Memory(InMemoryRepository),
+}
+
+/// Takes a connection from the pool without blocking the server thread.
+async fn get_connection<M: r2d2::ManageConnection>(
+    pool: r2d2::Pool<M>,
+) -> Result<r2d2::PooledConnection<M>, ServiceError> {
+    let submitted = Instant::now();
+    let job = BlockingJob::start();
+    web::block(move || {
+        let _job = job;
+        metrics::observe_queue_time(submitted, || metrics::observe_pool_wait(|| pool.get()))
+    })
+    .await
+    .map_err(|e| match e {
+        BlockingError::Error(e) => {
+            tracing::error!("Could not get a database connection: {}", e);
+            ServiceError::ServiceUnavailable {
+                retry_after: RETRY_AFTER_SECONDS,
+            }
+        }
+        BlockingError::Canceled => ServiceError::InternalServerError,
+    })
+}
+
+/// A connection to the storage backend, taken from the pool.
+///
+/// Use this as an extractor in routes instead of accessing the pool directly. If no connection
+/// can be acquired within the configured timeout, the request fails with
+/// `503 Service Unavailable`.
+pub struct DbConn(Connection);
+
+impl DbConn {
+    fn repository(&self) -> &dyn Repository {
+        match self.0 {
+            Connection::Postgres(ref conn) => &**conn,
+            #[cfg(feature = "sqlite")]
+            Connection::Sqlite(ref conn) => &**conn,
+            #[cfg(test)]
+            Connection::Memory(ref repo) => repo,
+        }
+    }
+
+    /// Runs blocking database work on the thread pool, so that the server thread is not blocked.
+    ///
+    /// This is the only place where routes access the storage backend, so the errors of the
+    /// repositories are mapped to `ServiceError` here as well. The queries themselves still use
+    /// the synchronous Diesel driver, an async driver needs tokio 1, which actix-web 3 lacks.
+    pub async fn run<F, T, E>(self, f: F) -> Result<T, ServiceError>
+    where
+        F: FnOnce(&dyn Repository) -> Result<T, E> + Send + 'static,
+        T: Send + 'static,
+        E: Into<ServiceError> + Debug + Send + 'static,
+    {
+        let submitted = Instant::now();
+        // The work runs on another thread, the span keeps it attributed to the request
+        let span = tracing::Span::current();
+        let job = BlockingJob::start();
+        web::block(move || {
+            let _job = job;
+            let _entered = span.enter();
+            metrics::observe_queue_time(submitted, || f(self.repository()))
+        })
+        .await
+        .map_err(|e| match e {
+            BlockingError::Error(e) => e.into(),
+            BlockingError::Canceled => ServiceError::InternalServerError,
+        })
+    }
+}
+
+impl FromRequest for DbConn {
+    type Error = ServiceError;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _: &mut dev::Payload) -> Self::Future {
+        let database = req.app_data::<web::Data<Database>>().cloned();
+
+        Box::pin(async move {
+            let database = database.ok_or(ServiceError::InternalServerError)?;
+            let conn = match database.get_ref() {
+                Database::Postgres(pool) => {
+                    Connection::Postgres(get_connection(pool.clone()).await?)
+                }
+                #[cfg(feature = "sqlite")]
+                Database::Sqlite(pool) => Connection::Sqlite(get_connection(pool.clone()).await?),
+                #[cfg(test)]
+                Database::Memory(repo) => Connection::Memory(repo.clone()),
+            };
+            Ok(DbConn(conn))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[actix_rt::test]
+    async fn waits_for_running_jobs() {
+        static JOBS: AtomicUsize = AtomicUsize::new(0);
+        let job = BlockingJob::counted_in(&JOBS);
+
+        let timeout = Duration::from_millis(50);
+        assert_eq!(wait_until_done(&JOBS, timeout).await, 1);
+        drop(job);
+        assert_eq!(wait_until_done(&JOBS, timeout).await, 0);
+    }
+
+    // Postgres if `TEST_DATABASE_URL` is set, otherwise SQLite, see `test_helpers::connection`
+    fn test_database(config: &PoolConfig) -> Database {
+        match dotenv::var("TEST_DATABASE_URL") {
+            Ok(url) => Database::Postgres(create_pool(&url, config).unwrap()),
+            Err(_) => test_sqlite_database(config),
+        }
+    }
+
+    #[cfg(feature = "sqlite")]
+    fn test_sqlite_database(config: &PoolConfig) -> Database {
+        Database::Sqlite(create_sqlite_pool(":memory:", config).unwrap())
+    }
+
+    #[cfg(not(feature = "sqlite"))]
+    fn test_sqlite_database(_config: &PoolConfig) -> Database {
+        panic!("TEST_DATABASE_URL must be set, or the sqlite feature enabled, to test the pool")
+    }
+
+    #[actix_rt::test]
+    async fn extractor_returns_connection_from_pool() {
+        let (req, mut payload) = TestRequest::default()
+            .data(test_database(&PoolConfig::default()))
+            .to_http_parts();
+
+        assert!(DbConn::from_request(&req, &mut payload).await.is_ok());
+    }
+
+    #[actix_rt::test]
+    async fn extractor_returns_service_unavailable_when_pool_is_exhausted() {
+        let config = PoolConfig {
+            max_size: 1,
+            connection_timeout: Duration::from_millis(100),
+            ..PoolConfig::default()
+        };
+        let (req, mut payload) = TestRequest::default()
+            .data(test_database(&config))
+            .to_http_parts();
+        let _conn = DbConn::from_request(&req, &mut payload).await.unwrap();
+        // Other tests take connections concurrently, so the sum only has a lower bound
+        let waited = metrics::DB_POOL_WAIT_DURATION.get_sample_sum();
+
+        let result = DbConn::from_request(&req, &mut payload).await;
+        assert!(matches!(
+            result,
+            Err(ServiceError::ServiceUnavailable { .. })
+        ));
+        // The time until the pool gave up counts as waiting
+        assert!(metrics::DB_POOL_WAIT_DURATION.get_sample_sum() - waited >= 0.1);
+    }
+
+    #[actix_rt::test]
+    async fn run_maps_model_errors_to_service_errors() {
+        use crate::user::UserError;
+
+        let conn = DbConn(Connection::Memory(InMemoryRepository::default()));
+
+        let result = conn.run(|_| Err::<(), _>(UserError::UsernameTaken)).await;
+        assert!(matches!(result, Err(ServiceError::Conflict(_))));
+    }
+}
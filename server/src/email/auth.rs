@@ -0,0 +1,329 @@
+use chrono::{Duration, NaiveDateTime, Utc};
+use uuid::Uuid;
+
+use crate::config::MailConfig;
+use crate::email::{EmailError, EmailToken, Purpose};
+use crate::mail::Mail;
+use crate::repository::{EmailTokenRepository, UserRepository};
+use crate::session::token;
+use crate::user::UserData;
+
+const VERIFY_EMAIL_LIFETIME_HOURS: i64 = 24;
+const RESET_PASSWORD_LIFETIME_HOURS: i64 = 1;
+/// A user gets at most one reset mail in this many seconds, so that nobody can flood an inbox.
+const RESET_PASSWORD_INTERVAL_SECONDS: i64 = 60;
+const MAX_EMAIL_LENGTH: usize = 254;
+
+fn now() -> NaiveDateTime {
+    Utc::now().naive_utc()
+}
+
+fn lifetime(purpose: Purpose) -> Duration {
+    match purpose {
+        Purpose::VerifyEmail => Duration::hours(VERIFY_EMAIL_LIFETIME_HOURS),
+        Purpose::ResetPassword => Duration::hours(RESET_PASSWORD_LIFETIME_HOURS),
+    }
+}
+
+/// Checks the form of an address and returns it in lower case, which is how addresses are
+/// stored and looked up.
+pub fn normalize(email: &str) -> Result<String, EmailError> {
+    let email = email.trim().to_lowercase();
+    let valid = match email.split_once('@') {
+        Some((local, domain)) => {
+            !local.is_empty()
+                && !domain.is_empty()
+                && !domain.contains('@')
+                && !domain.starts_with('.')
+                && !domain.ends_with('.')
+        }
+        None => false,
+    };
+    // Addresses end up in mail headers, so line breaks and brackets are never allowed
+    let allowed = email
+        .chars()
+        .all(|c| !c.is_whitespace() && !c.is_control() && !matches!(c, '<' | '>' | ',' | '"'));
+    if !valid || !allowed || email.len() > MAX_EMAIL_LENGTH {
+        return Err(EmailError::InvalidEmail);
+    }
+    Ok(email)
+}
+
+/// The link of the configured template, e.g. `https://chat.example.com/reset?token={token}`.
+fn link(template: &Option<String>, token: &str) -> Option<String> {
+    template
+        .as_ref()
+        .map(|template| template.replace("{token}", token))
+}
+
+fn body(intro: &str, link: Option<String>, token: &str, outro: &str) -> String {
+    match link {
+        Some(link) => format!("{}\n\n{}\n\n{}\n", intro, link, outro),
+        None => format!("{}\n\nToken: {}\n\n{}\n", intro, token, outro),
+    }
+}
+
+pub fn verification_mail(config: &MailConfig, to: &str, token: &str) -> Mail {
+    Mail {
+        to: String::from(to),
+        subject: String::from("Verify your email address"),
+        body: body(
+            "Please verify that this is your email address:",
+            link(&config.verify_url, token),
+            token,
+            "If you did not add this address to your thermit account, ignore this mail.",
+        ),
+    }
+}
+
+pub fn reset_mail(config: &MailConfig, to: &str, token: &str) -> Mail {
+    Mail {
+        to: String::from(to),
+        subject: String::from("Reset your password"),
+        body: body(
+            "Someone asked to reset the password of your thermit account. To choose a new \
+             password, use this within an hour:",
+            link(&config.reset_url, token),
+            token,
+            "If it was not you, ignore this mail, your password stays the same.",
+        ),
+    }
+}
+
+impl EmailToken {
+    /// Creates a token for the address and returns it. Earlier tokens of the same purpose are
+    /// void afterwards, so only the latest mail works.
+    pub fn issue<R: EmailTokenRepository + ?Sized>(
+        repo: &R,
+        user_id: Uuid,
+        purpose: Purpose,
+        email: &str,
+    ) -> Result<String, EmailError> {
+        let token = token::generate();
+        repo.destroy_user_email_tokens(user_id, purpose)?;
+        repo.create_email_token(EmailToken {
+            token_hash: token::hash(&token),
+            user_id,
+            purpose,
+            email: String::from(email),
+            expires: now() + lifetime(purpose),
+        })?;
+        Ok(token)
+    }
+
+    /// Finds an unexpired token that was sent to the address the user still has.
+    fn redeem<R: EmailTokenRepository + UserRepository + ?Sized>(
+        repo: &R,
+        token: &str,
+        purpose: Purpose,
+    ) -> Result<EmailToken, EmailError> {
+        let email_token = repo
+            .find_email_token(&token::hash(token))?
+            .filter(|t| t.purpose == purpose && t.expires > now())
+            .ok_or(EmailError::InvalidToken)?;
+        let owner = repo.find_user_by_email(&email_token.email)?;
+        if owner.is_none_or(|user| user.id != email_token.user_id) {
+            return Err(EmailError::InvalidToken);
+        }
+        // Tokens are single use
+        repo.destroy_user_email_tokens(email_token.user_id, purpose)?;
+        Ok(email_token)
+    }
+
+    pub fn verify_email<R: EmailTokenRepository + UserRepository + ?Sized>(
+        repo: &R,
+        token: &str,
+    ) -> Result<(), EmailError> {
+        let email_token = EmailToken::redeem(repo, token, Purpose::VerifyEmail)?;
+        if !repo.verify_user_email(email_token.user_id, &email_token.email)? {
+            return Err(EmailError::InvalidToken);
+        }
+        Ok(())
+    }
+
+    /// Returns a token to mail to the address, or None if no user has the address verified or
+    /// a mail was sent to the user just before. Callers must not reveal which it was.
+    pub fn request_reset<R: EmailTokenRepository + UserRepository + ?Sized>(
+        repo: &R,
+        email: &str,
+    ) -> Result<Option<String>, EmailError> {
+        let user = match repo.find_user_by_email(email)? {
+            Some(user) if user.email_verified => user,
+            _ => return Ok(None),
+        };
+        let latest = now() + lifetime(Purpose::ResetPassword)
+            - Duration::seconds(RESET_PASSWORD_INTERVAL_SECONDS);
+        let recently_sent = repo
+            .find_user_email_tokens(user.id)?
+            .iter()
+            .any(|t| t.purpose == Purpose::ResetPassword && t.expires > latest);
+        if recently_sent {
+            return Ok(None);
+        }
+        Ok(Some(EmailToken::issue(
+            repo,
+            user.id,
+            Purpose::ResetPassword,
+            email,
+        )?))
+    }
+
+    /// Sets the new password, which ends all sessions of the user.
+    pub fn reset_password<R: EmailTokenRepository + UserRepository + ?Sized>(
+        repo: &R,
+        token: &str,
+        password: &str,
+    ) -> Result<Uuid, EmailError> {
+        if password.is_empty() {
+            return Err(EmailError::EmptyPassword);
+        }
+        let email_token = EmailToken::redeem(repo, token, Purpose::ResetPassword)?;
+        let user = repo
+            .find_user(email_token.user_id)?
+            .ok_or(EmailError::InvalidToken)?;
+        repo.update_user(
+            user.id,
+            UserData {
+                username: user.username,
+                password: String::from(password),
+            },
+        )?;
+        Ok(user.id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AuthConfig;
+    use crate::repository::{InMemoryRepository, SessionRepository};
+    use crate::session::auth::ClientInfo;
+    use crate::session::Session;
+    use crate::test_helpers::*;
+
+    fn user_with_email(repo: &InMemoryRepository, email: &str) -> Uuid {
+        let user = repo.create_user(create_user_data("testUser")).unwrap();
+        repo.set_user_email(user.id, Some(String::from(email)))
+            .unwrap();
+        user.id
+    }
+
+    #[test]
+    fn normalize_lowercases_and_rejects_malformed_addresses() {
+        assert_eq!(normalize(" Jane@Example.COM ").unwrap(), "jane@example.com");
+        for email in &[
+            "",
+            "jane",
+            "@example.com",
+            "jane@",
+            "a@b@c",
+            "jane doe@x.org",
+            "j<a>@x.org",
+        ] {
+            assert!(normalize(email).is_err(), "accepted {:?}", email);
+        }
+    }
+
+    #[test]
+    fn verification_token_verifies_the_address_once() {
+        let repo = InMemoryRepository::default();
+        let user_id = user_with_email(&repo, "jane@example.com");
+
+        let token =
+            EmailToken::issue(&repo, user_id, Purpose::VerifyEmail, "jane@example.com").unwrap();
+        EmailToken::verify_email(&repo, &token).unwrap();
+        assert!(
+            repo.find_user_by_email("jane@example.com")
+                .unwrap()
+                .unwrap()
+                .email_verified
+        );
+
+        let again = EmailToken::verify_email(&repo, &token);
+        assert!(matches!(again, Err(EmailError::InvalidToken)));
+    }
+
+    #[test]
+    fn tokens_for_an_old_address_are_void() {
+        let repo = InMemoryRepository::default();
+        let user_id = user_with_email(&repo, "jane@example.com");
+        let token =
+            EmailToken::issue(&repo, user_id, Purpose::VerifyEmail, "jane@example.com").unwrap();
+
+        repo.set_user_email(user_id, Some(String::from("jane@example.org")))
+            .unwrap();
+        let result = EmailToken::verify_email(&repo, &token);
+        assert!(matches!(result, Err(EmailError::InvalidToken)));
+    }
+
+    #[test]
+    fn reset_needs_a_verified_address_and_is_throttled() {
+        let repo = InMemoryRepository::default();
+        let user_id = user_with_email(&repo, "jane@example.com");
+        assert_eq!(
+            EmailToken::request_reset(&repo, "jane@example.com").unwrap(),
+            None
+        );
+        assert_eq!(
+            EmailToken::request_reset(&repo, "nobody@example.com").unwrap(),
+            None
+        );
+
+        repo.verify_user_email(user_id, "jane@example.com").unwrap();
+        let token = EmailToken::request_reset(&repo, "jane@example.com").unwrap();
+        assert!(token.is_some());
+        assert_eq!(
+            EmailToken::request_reset(&repo, "jane@example.com").unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn reset_sets_the_password_and_ends_sessions() {
+        let repo = InMemoryRepository::default();
+        let user_id = user_with_email(&repo, "jane@example.com");
+        repo.verify_user_email(user_id, "jane@example.com").unwrap();
+        Session::start(
+            &repo,
+            user_id,
+            ClientInfo::default(),
+            &AuthConfig::default(),
+        )
+        .unwrap();
+        let old_hash = repo
+            .find_user_by_username("testUser")
+            .unwrap()
+            .unwrap()
+            .password;
+
+        let token = EmailToken::request_reset(&repo, "jane@example.com")
+            .unwrap()
+            .unwrap();
+        let empty = EmailToken::reset_password(&repo, &token, "");
+        assert!(matches!(empty, Err(EmailError::EmptyPassword)));
+        EmailToken::reset_password(&repo, &token, "new password").unwrap();
+
+        let user = repo.find_user_by_username("testUser").unwrap().unwrap();
+        assert_ne!(user.password, old_hash);
+        assert!(repo.find_user_sessions(user_id).unwrap().is_empty());
+        let again = EmailToken::reset_password(&repo, &token, "other password");
+        assert!(matches!(again, Err(EmailError::InvalidToken)));
+    }
+
+    #[test]
+    fn expired_tokens_are_rejected() {
+        let repo = InMemoryRepository::default();
+        let user_id = user_with_email(&repo, "jane@example.com");
+        repo.create_email_token(EmailToken {
+            token_hash: token::hash("expired"),
+            user_id,
+            purpose: Purpose::VerifyEmail,
+            email: String::from("jane@example.com"),
+            expires: now() - Duration::seconds(1),
+        })
+        .unwrap();
+
+        let result = EmailToken::verify_email(&repo, "expired");
+        assert!(matches!(result, Err(EmailError::InvalidToken)));
+    }
+}
@@ -0,0 +1,6 @@
+pub(crate) mod auth;
+mod model;
+mod routes;
+
+pub use model::*;
+pub use routes::init_routes;
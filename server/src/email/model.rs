@@ -0,0 +1,155 @@
+use std::io::Write;
+
+use chrono::NaiveDateTime;
+use diesel::backend::Backend;
+use diesel::deserialize::{self, FromSql};
+use diesel::result::Error as DieselError;
+use diesel::serialize::{self, Output, ToSql};
+use diesel::sql_types::Text;
+use uuid::Uuid;
+
+use crate::mail::MailError;
+use crate::query::prelude::*;
+use crate::schema::email_tokens;
+use crate::user::UserError;
+
+/// A token sent to an email address, of which only the hash is stored.
+#[derive(Clone, Queryable, PartialEq, Debug)]
+pub struct EmailToken {
+    pub token_hash: String,
+    pub user_id: Uuid,
+    pub purpose: Purpose,
+    /// The address the token was sent to, the token is void once the user has another address.
+    pub email: String,
+    pub expires: NaiveDateTime,
+}
+
+#[derive(Clone, Copy, AsExpression, FromSqlRow, PartialEq, Debug)]
+#[sql_type = "Text"]
+pub enum Purpose {
+    VerifyEmail,
+    ResetPassword,
+}
+
+impl Purpose {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Purpose::VerifyEmail => "verify_email",
+            Purpose::ResetPassword => "reset_password",
+        }
+    }
+}
+
+// Stored as text, see `Role`
+impl<DB: Backend> ToSql<Text, DB> for Purpose
+where
+    str: ToSql<Text, DB>,
+{
+    fn to_sql<W: Write>(&self, out: &mut Output<W, DB>) -> serialize::Result {
+        self.as_str().to_sql(out)
+    }
+}
+
+impl<DB: Backend> FromSql<Text, DB> for Purpose
+where
+    String: FromSql<Text, DB>,
+{
+    fn from_sql(bytes: Option<&DB::RawValue>) -> deserialize::Result<Self> {
+        match String::from_sql(bytes)?.as_str() {
+            "verify_email" => Ok(Purpose::VerifyEmail),
+            "reset_password" => Ok(Purpose::ResetPassword),
+            other => Err(format!("unknown email token purpose {}", other).into()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum EmailError {
+    InvalidEmail,
+    EmailTaken,
+    /// The token is unknown, expired, used already or was sent to an old address.
+    InvalidToken,
+    EmptyPassword,
+    /// The mail could not be handed to the transport.
+    MailUnavailable,
+    UserNotFound,
+    DatabaseError,
+    GenericError,
+}
+
+impl EmailToken {
+    pub fn create(conn: &impl SqlConnection, token: EmailToken) -> Result<EmailToken, EmailError> {
+        diesel::insert_into(email_tokens::table)
+            .values((
+                email_tokens::token_hash.eq(&token.token_hash),
+                email_tokens::user_id.eq(Id(token.user_id)),
+                email_tokens::purpose.eq(token.purpose),
+                email_tokens::email.eq(&token.email),
+                email_tokens::expires.eq(token.expires),
+            ))
+            .execute(conn)?;
+        Ok(token)
+    }
+
+    pub fn find(
+        conn: &impl SqlConnection,
+        token_hash: &str,
+    ) -> Result<Option<EmailToken>, EmailError> {
+        Ok(email_tokens::table
+            .find(token_hash)
+            .first(conn)
+            .optional()?)
+    }
+
+    /// The tokens of the user, the latest to expire first.
+    pub fn find_by_user(
+        conn: &impl SqlConnection,
+        user_id: Uuid,
+    ) -> Result<Vec<EmailToken>, EmailError> {
+        Ok(email_tokens::table
+            .filter(email_tokens::user_id.eq(Id(user_id)))
+            .order(email_tokens::expires.desc())
+            .load(conn)?)
+    }
+
+    pub fn destroy_by_user(
+        conn: &impl SqlConnection,
+        user_id: Uuid,
+        purpose: Purpose,
+    ) -> Result<usize, EmailError> {
+        Ok(diesel::delete(
+            email_tokens::table
+                .filter(email_tokens::user_id.eq(Id(user_id)))
+                .filter(email_tokens::purpose.eq(purpose)),
+        )
+        .execute(conn)?)
+    }
+}
+
+impl From<DieselError> for EmailError {
+    fn from(error: DieselError) -> EmailError {
+        match error {
+            DieselError::DatabaseError(_, _) => EmailError::DatabaseError,
+            DieselError::NotFound => EmailError::InvalidToken,
+            _ => EmailError::GenericError,
+        }
+    }
+}
+
+impl From<UserError> for EmailError {
+    fn from(error: UserError) -> EmailError {
+        match error {
+            UserError::UserNotFound => EmailError::UserNotFound,
+            UserError::EmailTaken => EmailError::EmailTaken,
+            UserError::DatabaseError => EmailError::DatabaseError,
+            _ => EmailError::GenericError,
+        }
+    }
+}
+
+impl From<MailError> for EmailError {
+    fn from(error: MailError) -> EmailError {
+        tracing::error!("Could not send mail: {}", error);
+        EmailError::MailUnavailable
+    }
+}
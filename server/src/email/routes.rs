@@ -0,0 +1,316 @@
+use actix_web::{get, post, put, web, HttpResponse};
+use serde::{Deserialize, Serialize};
+
+use crate::audit::{Action, AuditContext};
+use crate::config::MailConfig;
+use crate::db::DbConn;
+use crate::email::auth::{self, normalize};
+use crate::email::{EmailError, EmailToken, Purpose};
+use crate::errors::ServiceError;
+use crate::mail::{self, Mailer};
+use crate::metrics;
+use crate::session::Identity;
+use crate::user::UserError;
+
+#[derive(Serialize, Debug)]
+pub struct EmailResponse {
+    pub email: Option<String>,
+    pub verified: bool,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct EmailData {
+    pub email: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TokenData {
+    pub token: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ResetData {
+    pub token: String,
+    pub password: String,
+}
+
+#[get("/me/email")]
+pub async fn find(conn: DbConn, identity: Identity) -> Result<HttpResponse, ServiceError> {
+    // The user response has no email, as it is public, so the user is read with the password
+    let user = conn
+        .run(move |repo| repo.find_user_by_id(identity.user_id))
+        .await?
+        .ok_or(UserError::UserNotFound)?;
+    Ok(HttpResponse::Ok().json(EmailResponse {
+        email: user.email,
+        verified: user.email_verified,
+    }))
+}
+
+/// Replaces the address of the user and sends a mail to verify it.
+#[put("/me/email")]
+pub async fn update(
+    conn: DbConn,
+    identity: Identity,
+    mailer: web::Data<dyn Mailer>,
+    config: web::Data<MailConfig>,
+    data: web::Json<EmailData>,
+) -> Result<HttpResponse, ServiceError> {
+    let email = normalize(&data.email)?;
+    let (email, token) = conn
+        .run(move |repo| -> Result<_, EmailError> {
+            repo.set_user_email(identity.user_id, Some(email.clone()))?;
+            let token = EmailToken::issue(repo, identity.user_id, Purpose::VerifyEmail, &email)?;
+            Ok((email, token))
+        })
+        .await?;
+
+    let mail = auth::verification_mail(&config, &email, &token);
+    mail::deliver(mailer, mail)
+        .await
+        .map_err(EmailError::from)?;
+    Ok(HttpResponse::Accepted().json(EmailResponse {
+        email: Some(email),
+        verified: false,
+    }))
+}
+
+#[post("/auth/verify-email")]
+pub async fn verify(
+    conn: DbConn,
+    data: web::Json<TokenData>,
+) -> Result<HttpResponse, ServiceError> {
+    conn.run(move |repo| EmailToken::verify_email(repo, &data.token))
+        .await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Sends a reset mail if a user has the address verified. The answer is the same either way and
+/// does not wait for the mail, so that nobody can find out which addresses have accounts.
+#[post("/auth/password-reset")]
+pub async fn request_reset(
+    conn: DbConn,
+    mailer: web::Data<dyn Mailer>,
+    config: web::Data<MailConfig>,
+    data: web::Json<EmailData>,
+) -> Result<HttpResponse, ServiceError> {
+    let email = match normalize(&data.email) {
+        Ok(email) => email,
+        Err(_) => return Ok(HttpResponse::Accepted().finish()),
+    };
+    let token = {
+        let email = email.clone();
+        conn.run(move |repo| EmailToken::request_reset(repo, &email))
+            .await?
+    };
+
+    if let Some(token) = token {
+        mail::deliver_later(mailer, auth::reset_mail(&config, &email, &token));
+    }
+    Ok(HttpResponse::Accepted().finish())
+}
+
+#[post("/auth/password-reset/confirm")]
+pub async fn reset(
+    conn: DbConn,
+    audit: AuditContext,
+    data: web::Json<ResetData>,
+) -> Result<HttpResponse, ServiceError> {
+    conn.run(move |repo| -> Result<(), ServiceError> {
+        let user_id = EmailToken::reset_password(repo, &data.token, &data.password)?;
+        let event = audit.event(Action::PasswordReset, Some(user_id), Some(user_id));
+        repo.create_audit_event(event)?;
+        Ok(())
+    })
+    .await
+    .map_err(|e| {
+        if let ServiceError::Unauthorized = e {
+            metrics::AUTH_FAILURES.inc();
+        }
+        e
+    })?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub fn init_routes(config: &mut web::ServiceConfig) {
+    config.service(find);
+    config.service(update);
+    config.service(verify);
+    config.service(request_reset);
+    config.service(reset);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AuthConfig;
+    use crate::db::Database;
+    use crate::mail::FileMailer;
+    use crate::repository::{InMemoryRepository, SessionRepository, UserRepository};
+    use crate::session::auth::ClientInfo;
+    use crate::session::Session;
+    use crate::test_helpers::*;
+    use crate::user::LoginThrottle;
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App};
+    use serde_json::json;
+    use std::sync::Arc;
+    use uuid::Uuid;
+
+    /// The mails sent so far, once there are `count` of them. Some routes send them later.
+    async fn wait_for_mails(mailer: &FileMailer, count: usize) -> Vec<String> {
+        for _ in 0..100 {
+            let mails = mailer.read_all();
+            if mails.len() >= count {
+                return mails;
+            }
+            actix_web::rt::time::delay_for(std::time::Duration::from_millis(10)).await;
+        }
+        mailer.read_all()
+    }
+
+    /// The token in the body of a mail sent without links.
+    fn token_in(mail: &str) -> String {
+        let line = mail
+            .lines()
+            .find(|line| line.starts_with("Token: "))
+            .expect("the mail has no token");
+        line["Token: ".len()..].to_string()
+    }
+
+    #[actix_rt::test]
+    async fn verify_email_and_reset_password() {
+        let repo = InMemoryRepository::default();
+        let user = repo.create_user(create_user_data("testUser")).unwrap();
+        let tokens = Session::start(
+            &repo,
+            user.id,
+            ClientInfo::default(),
+            &AuthConfig::default(),
+        )
+        .unwrap();
+        let bearer = format!("Bearer {}", tokens.access_token);
+        let directory = std::env::temp_dir().join(format!("thermit-mail-{}", Uuid::new_v4()));
+        let mailer = Arc::new(FileMailer::new(&directory, "noreply@example.com").unwrap());
+        let mut app = test::init_service(
+            App::new()
+                .data(Database::Memory(repo.clone()))
+                .data(AuthConfig::default())
+                .data(MailConfig::default())
+                .data(LoginThrottle::default())
+                .app_data(web::Data::from(mailer.clone() as Arc<dyn Mailer>))
+                .configure(init_routes)
+                .configure(crate::user::init_routes),
+        )
+        .await;
+
+        let req = test::TestRequest::put()
+            .uri("/me/email")
+            .header("Authorization", bearer.as_str())
+            .set_json(&json!({ "email": "not an address" }))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let req = test::TestRequest::put()
+            .uri("/me/email")
+            .header("Authorization", bearer.as_str())
+            .set_json(&json!({ "email": "Jane@Example.com" }))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::ACCEPTED);
+
+        // Unverified addresses get no reset mails
+        let req = test::TestRequest::post()
+            .uri("/auth/password-reset")
+            .set_json(&json!({ "email": "jane@example.com" }))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::ACCEPTED);
+        let mails = mailer.read_all();
+        assert_eq!(mails.len(), 1);
+        assert!(mails[0].contains("To: jane@example.com\r\n"));
+
+        let req = test::TestRequest::post()
+            .uri("/auth/verify-email")
+            .set_json(&json!({ "token": token_in(&mails[0]) }))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+        let req = test::TestRequest::get()
+            .uri("/me/email")
+            .header("Authorization", bearer.as_str())
+            .to_request();
+        let email: serde_json::Value = test::read_response_json(&mut app, req).await;
+        assert_eq!(
+            email,
+            json!({ "email": "jane@example.com", "verified": true })
+        );
+
+        let req = test::TestRequest::post()
+            .uri("/auth/password-reset")
+            .set_json(&json!({ "email": "JANE@example.com" }))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::ACCEPTED);
+        let mails = wait_for_mails(&mailer, 2).await;
+        assert_eq!(mails.len(), 2);
+        assert!(mails[1].contains("Subject: Reset your password\r\n"));
+
+        let req = test::TestRequest::post()
+            .uri("/auth/password-reset/confirm")
+            .set_json(&json!({ "token": token_in(&mails[1]), "password": "new password" }))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+        // The reset ended the session and the new password works
+        assert!(repo.find_user_sessions(user.id).unwrap().is_empty());
+        let req = test::TestRequest::get()
+            .uri("/me/email")
+            .header("Authorization", bearer.as_str())
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let req = test::TestRequest::post()
+            .uri("/auth")
+            .set_json(&json!({ "username": "testUser", "password": "new password" }))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let req = test::TestRequest::post()
+            .uri("/auth/password-reset/confirm")
+            .set_json(&json!({ "token": token_in(&mails[1]), "password": "again" }))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn reset_for_unknown_address_is_accepted_without_mail() {
+        let directory = std::env::temp_dir().join(format!("thermit-mail-{}", Uuid::new_v4()));
+        let mailer = Arc::new(FileMailer::new(&directory, "noreply@example.com").unwrap());
+        let mut app = test::init_service(
+            App::new()
+                .data(memory_database())
+                .data(MailConfig::default())
+                .app_data(web::Data::from(mailer.clone() as Arc<dyn Mailer>))
+                .configure(init_routes),
+        )
+        .await;
+
+        for email in &["nobody@example.com", "malformed"] {
+            let req = test::TestRequest::post()
+                .uri("/auth/password-reset")
+                .set_json(&json!({ "email": email }))
+                .to_request();
+            let resp = test::call_service(&mut app, req).await;
+            assert_eq!(resp.status(), StatusCode::ACCEPTED);
+        }
+        assert!(mailer.read_all().is_empty());
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -1,47 +1,212 @@
-use actix_web::http::StatusCode;
-use actix_web::{error::ResponseError, HttpResponse};
+use actix_web::http::{header, StatusCode};
+use actix_web::{error::ResponseError, HttpRequest, HttpResponse};
 use derive_more::{Display, Error};
 use serde::Serialize;
-use serde_json::json;
 
+use crate::admin::AdminError;
+use crate::api_token::ApiTokenError;
+use crate::audit::AuditError;
+use crate::email::EmailError;
+use crate::message::MessageError;
+use crate::oidc::OidcError;
 use crate::room::RoomError;
+use crate::session::SessionError;
+use crate::telemetry;
+use crate::two_factor::TwoFactorError;
 use crate::user::auth::AuthenticationError;
 use crate::user::UserError;
-use actix_web::error::BlockingError;
 
+// Not every variant is produced by a route yet, but they are part of the API contract
+#[allow(dead_code)]
 #[derive(Debug, Display, Error, Serialize)]
 pub enum ServiceError {
     #[display(fmt = "Internal Server Error")]
     InternalServerError,
 
-    #[display(fmt = "Internal Server Error")]
+    #[display(fmt = "Not found")]
     NotFound,
 
-    #[display(fmt = "Internal Server Error")]
+    #[display(fmt = "Access forbidden")]
     Forbidden,
+
+    #[display(fmt = "Authentication required")]
+    Unauthorized,
+
+    #[display(fmt = "{}", _0)]
+    Conflict(#[error(not(source))] String),
+
+    #[display(fmt = "{}", _0)]
+    Validation(#[error(not(source))] String),
+
+    #[display(fmt = "Too many requests")]
+    RateLimited { retry_after: u64 },
+
+    #[display(fmt = "Service temporarily unavailable")]
+    ServiceUnavailable { retry_after: u64 },
 }
+
+/// The body of every error response.
+///
+/// `code` is stable and meant for programmatic handling, `message` is meant for humans and may
+/// change at any time.
+#[derive(Serialize, Debug)]
+pub struct ErrorBody {
+    pub code: &'static str,
+    pub message: String,
+    pub details: Option<serde_json::Value>,
+    pub request_id: Option<String>,
+}
+
 impl ServiceError {
-    pub fn json_message(msg: &str) -> serde_json::Value {
-        json!({ "msg": msg })
+    pub fn code(&self) -> &'static str {
+        match *self {
+            ServiceError::InternalServerError => "internal_error",
+            ServiceError::NotFound => "not_found",
+            ServiceError::Forbidden => "forbidden",
+            ServiceError::Unauthorized => "unauthorized",
+            ServiceError::Conflict(_) => "conflict",
+            ServiceError::Validation(_) => "validation_failed",
+            ServiceError::RateLimited { .. } => "rate_limited",
+            ServiceError::ServiceUnavailable { .. } => "service_unavailable",
+        }
     }
-}
 
-impl From<BlockingError<UserError>> for ServiceError {
-    fn from(error: BlockingError<UserError>) -> ServiceError {
-        match error {
-            BlockingError::Error(e) => ServiceError::from(e),
-            BlockingError::Canceled => ServiceError::InternalServerError,
+    fn retry_after(&self) -> Option<u64> {
+        match *self {
+            ServiceError::RateLimited { retry_after } => Some(retry_after),
+            ServiceError::ServiceUnavailable { retry_after } => Some(retry_after),
+            _ => None,
         }
     }
+
+    pub fn body(&self) -> ErrorBody {
+        let details = self
+            .retry_after()
+            .map(|retry_after| serde_json::json!({ "retry_after": retry_after }));
+
+        ErrorBody {
+            code: self.code(),
+            message: self.to_string(),
+            details,
+            request_id: telemetry::current_request_id(),
+        }
+    }
+}
+
+/// Turns malformed request payloads and paths into validation errors, so that they use the same
+/// envelope as all other errors.
+pub fn payload_error_handler<E: std::fmt::Display>(
+    error: E,
+    _req: &HttpRequest,
+) -> actix_web::Error {
+    ServiceError::Validation(error.to_string()).into()
 }
 
 impl From<AuthenticationError> for ServiceError {
     fn from(error: AuthenticationError) -> ServiceError {
         match error {
-            AuthenticationError::IncorrectPassword => ServiceError::Forbidden,
-            AuthenticationError::UserNotFound => ServiceError::NotFound,
-            AuthenticationError::BcryptError(_) => ServiceError::InternalServerError,
-            AuthenticationError::DatabaseError(_) => ServiceError::InternalServerError,
+            AuthenticationError::InvalidCredentials => ServiceError::Unauthorized,
+            AuthenticationError::PasswordHashError(e) => {
+                tracing::error!("Could not verify a password: {}", e);
+                ServiceError::InternalServerError
+            }
+            AuthenticationError::DatabaseError(e) => {
+                tracing::error!("Could not load a user to authenticate: {:?}", e);
+                ServiceError::InternalServerError
+            }
+            AuthenticationError::SecondFactorError(e) => ServiceError::from(e),
+        }
+    }
+}
+
+impl From<AdminError> for ServiceError {
+    fn from(error: AdminError) -> ServiceError {
+        match error {
+            AdminError::DatabaseError => ServiceError::InternalServerError,
+            AdminError::GenericError => ServiceError::InternalServerError,
+        }
+    }
+}
+
+impl From<ApiTokenError> for ServiceError {
+    fn from(error: ApiTokenError) -> ServiceError {
+        match error {
+            ApiTokenError::TokenNotFound => ServiceError::NotFound,
+            ApiTokenError::InvalidToken => ServiceError::Unauthorized,
+            ApiTokenError::DatabaseError => ServiceError::InternalServerError,
+            ApiTokenError::GenericError => ServiceError::InternalServerError,
+        }
+    }
+}
+
+impl From<AuditError> for ServiceError {
+    fn from(error: AuditError) -> ServiceError {
+        match error {
+            AuditError::DatabaseError => ServiceError::InternalServerError,
+            AuditError::GenericError => ServiceError::InternalServerError,
+        }
+    }
+}
+
+impl From<EmailError> for ServiceError {
+    fn from(error: EmailError) -> ServiceError {
+        match error {
+            EmailError::InvalidEmail => {
+                ServiceError::Validation(String::from("Email address is malformed"))
+            }
+            EmailError::EmailTaken => {
+                ServiceError::Conflict(String::from("Email is already taken"))
+            }
+            EmailError::InvalidToken => ServiceError::Unauthorized,
+            EmailError::EmptyPassword => {
+                ServiceError::Validation(String::from("Password must not be empty"))
+            }
+            EmailError::MailUnavailable => ServiceError::ServiceUnavailable { retry_after: 60 },
+            EmailError::UserNotFound => ServiceError::NotFound,
+            EmailError::DatabaseError => ServiceError::InternalServerError,
+            EmailError::GenericError => ServiceError::InternalServerError,
+        }
+    }
+}
+
+impl From<OidcError> for ServiceError {
+    fn from(error: OidcError) -> ServiceError {
+        match error {
+            OidcError::Disabled => ServiceError::NotFound,
+            OidcError::ProviderUnavailable => ServiceError::ServiceUnavailable { retry_after: 30 },
+            OidcError::InvalidState => ServiceError::Unauthorized,
+            OidcError::InvalidLogin(_) => ServiceError::Unauthorized,
+            OidcError::DatabaseError => ServiceError::InternalServerError,
+            OidcError::GenericError => ServiceError::InternalServerError,
+        }
+    }
+}
+
+impl From<SessionError> for ServiceError {
+    fn from(error: SessionError) -> ServiceError {
+        match error {
+            SessionError::SessionNotFound => ServiceError::NotFound,
+            SessionError::InvalidToken => ServiceError::Unauthorized,
+            SessionError::TokenReused => ServiceError::Unauthorized,
+            SessionError::AccountLocked => ServiceError::Forbidden,
+            SessionError::AccountDeleted => ServiceError::Forbidden,
+            SessionError::DatabaseError => ServiceError::InternalServerError,
+            SessionError::GenericError => ServiceError::InternalServerError,
+        }
+    }
+}
+
+impl From<TwoFactorError> for ServiceError {
+    fn from(error: TwoFactorError) -> ServiceError {
+        match error {
+            TwoFactorError::NotEnrolled => ServiceError::NotFound,
+            TwoFactorError::AlreadyEnabled => {
+                ServiceError::Conflict("Two-factor authentication is already enabled".into())
+            }
+            TwoFactorError::InvalidCode => ServiceError::Unauthorized,
+            TwoFactorError::InvalidChallenge => ServiceError::Unauthorized,
+            TwoFactorError::DatabaseError => ServiceError::InternalServerError,
+            TwoFactorError::GenericError => ServiceError::InternalServerError,
         }
     }
 }
@@ -50,18 +215,23 @@ impl From<UserError> for ServiceError {
     fn from(error: UserError) -> ServiceError {
         match error {
             UserError::UserNotFound => ServiceError::NotFound,
-            UserError::UsernameTaken => ServiceError::Forbidden,
+            UserError::UsernameTaken => {
+                ServiceError::Conflict(String::from("Username is already taken"))
+            }
+            UserError::EmailTaken => ServiceError::Conflict(String::from("Email is already taken")),
+            UserError::PasswordHashError => ServiceError::InternalServerError,
             UserError::DatabaseError => ServiceError::InternalServerError,
             UserError::GenericError => ServiceError::InternalServerError,
         }
     }
 }
 
-impl From<BlockingError<RoomError>> for ServiceError {
-    fn from(error: BlockingError<RoomError>) -> ServiceError {
+impl From<MessageError> for ServiceError {
+    fn from(error: MessageError) -> ServiceError {
         match error {
-            BlockingError::Error(e) => ServiceError::from(e),
-            BlockingError::Canceled => ServiceError::InternalServerError,
+            MessageError::MessageNotFound => ServiceError::NotFound,
+            MessageError::DatabaseError => ServiceError::InternalServerError,
+            MessageError::GenericError => ServiceError::InternalServerError,
         }
     }
 }
@@ -82,20 +252,61 @@ impl ResponseError for ServiceError {
             ServiceError::InternalServerError => StatusCode::INTERNAL_SERVER_ERROR,
             ServiceError::NotFound => StatusCode::NOT_FOUND,
             ServiceError::Forbidden => StatusCode::FORBIDDEN,
+            ServiceError::Unauthorized => StatusCode::UNAUTHORIZED,
+            ServiceError::Conflict(_) => StatusCode::CONFLICT,
+            ServiceError::Validation(_) => StatusCode::BAD_REQUEST,
+            ServiceError::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+            ServiceError::ServiceUnavailable { .. } => StatusCode::SERVICE_UNAVAILABLE,
         }
     }
 
     fn error_response(&self) -> HttpResponse {
-        match *self {
-            ServiceError::InternalServerError => {
-                HttpResponse::InternalServerError().json("Internal Server Error, Please try later")
-            }
-            ServiceError::NotFound => {
-                HttpResponse::NotFound().json(ServiceError::json_message("Not found"))
-            }
-            ServiceError::Forbidden => {
-                HttpResponse::Forbidden().json(ServiceError::json_message("Access forbidden"))
-            }
+        let mut response = HttpResponse::build(self.status_code());
+        if let Some(retry_after) = self.retry_after() {
+            response.header(header::RETRY_AFTER, retry_after.to_string());
         }
+        response.json(self.body())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn username_taken_is_a_conflict() {
+        let error = ServiceError::from(UserError::UsernameTaken);
+        assert_eq!(error.status_code(), StatusCode::CONFLICT);
+        assert_eq!(error.code(), "conflict");
+    }
+
+    #[test]
+    fn bad_credentials_are_unauthorized() {
+        let error = ServiceError::from(AuthenticationError::InvalidCredentials);
+        assert_eq!(error.status_code(), StatusCode::UNAUTHORIZED);
+        assert_eq!(error.code(), "unauthorized");
+    }
+
+    #[test]
+    fn internal_error_uses_error_envelope() {
+        let body = serde_json::to_value(ServiceError::InternalServerError.body()).unwrap();
+        assert_eq!(body["code"], "internal_error");
+        assert_eq!(body["message"], "Internal Server Error");
+        assert!(body["details"].is_null());
+        assert!(body.get("request_id").is_some());
+    }
+
+    #[test]
+    fn rate_limited_sets_retry_after() {
+        let response = ServiceError::RateLimited { retry_after: 30 }.error_response();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers().get(header::RETRY_AFTER).unwrap(), "30");
+    }
+
+    #[test]
+    fn service_unavailable_sets_retry_after() {
+        let response = ServiceError::ServiceUnavailable { retry_after: 5 }.error_response();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers().get(header::RETRY_AFTER).unwrap(), "5");
     }
 }
@@ -0,0 +1,111 @@
+//! Probes for orchestrators, served outside of `/api/v1` and without authentication.
+
+use actix_web::{get, web, HttpResponse};
+use serde_json::json;
+
+use crate::db::{Database, RETRY_AFTER_SECONDS};
+use crate::errors::ServiceError;
+use crate::migrations;
+
+/// The paths of the probes, which are only logged at the debug level.
+pub const PROBE_PATHS: &[&str] = &["/healthz", "/readyz"];
+
+/// The process is up and serves requests.
+#[get("/healthz")]
+async fn healthz() -> HttpResponse {
+    HttpResponse::Ok().json(json!({ "status": "ok" }))
+}
+
+/// The database can hand out a connection and all migrations are applied.
+#[get("/readyz")]
+async fn readyz(database: web::Data<Database>) -> Result<HttpResponse, ServiceError> {
+    let database = database.get_ref().clone();
+    let not_ready = ServiceError::ServiceUnavailable {
+        retry_after: RETRY_AFTER_SECONDS,
+    };
+
+    let pending = match web::block(move || migrations::pending(&database)).await {
+        Ok(pending) => pending,
+        Err(e) => {
+            tracing::warn!("Not ready: {}", e);
+            return Err(not_ready);
+        }
+    };
+    if !pending.is_empty() {
+        tracing::warn!("Not ready, migrations {} are pending", pending.join(", "));
+        return Err(not_ready);
+    }
+
+    Ok(HttpResponse::Ok().json(json!({ "status": "ready" })))
+}
+
+#[get("/version")]
+async fn version() -> HttpResponse {
+    HttpResponse::Ok().json(json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "git_hash": env!("GIT_HASH"),
+        "schema_version": migrations::schema_version(),
+    }))
+}
+
+pub fn init_routes(config: &mut web::ServiceConfig) {
+    config.service(healthz);
+    config.service(readyz);
+    config.service(version);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::*;
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App};
+
+    #[actix_rt::test]
+    async fn healthz_returns_ok() {
+        let mut app = test::init_service(App::new().configure(init_routes)).await;
+
+        let req = test::TestRequest::get().uri("/healthz").to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[actix_rt::test]
+    async fn readyz_returns_ok_when_database_is_ready() {
+        let mut app =
+            test::init_service(App::new().data(memory_database()).configure(init_routes)).await;
+
+        let req = test::TestRequest::get().uri("/readyz").to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[actix_rt::test]
+    async fn readyz_returns_service_unavailable_when_migrations_are_pending() {
+        use crate::db::{create_sqlite_pool, PoolConfig};
+
+        let config = PoolConfig {
+            max_size: 1,
+            ..PoolConfig::default()
+        };
+        let database = Database::Sqlite(create_sqlite_pool(":memory:", &config).unwrap());
+        let mut app = test::init_service(App::new().data(database).configure(init_routes)).await;
+
+        let req = test::TestRequest::get().uri("/readyz").to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[actix_rt::test]
+    async fn version_returns_versions() {
+        let mut app = test::init_service(App::new().configure(init_routes)).await;
+
+        let req = test::TestRequest::get().uri("/version").to_request();
+        let body: serde_json::Value = test::read_response_json(&mut app, req).await;
+
+        assert_eq!(body["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(body["schema_version"], migrations::schema_version());
+        assert!(body["git_hash"].is_string());
+    }
+}
@@ -0,0 +1,76 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use chrono::Utc;
+use uuid::Uuid;
+
+use crate::mail::{format_message, Mail, MailError, Mailer};
+
+/// Writes every mail to an `.eml` file, e.g. for tests or to look at the mails locally.
+pub struct FileMailer {
+    directory: PathBuf,
+    from: String,
+}
+
+impl FileMailer {
+    pub fn new(directory: &Path, from: &str) -> Result<Self, MailError> {
+        fs::create_dir_all(directory)?;
+        Ok(FileMailer {
+            directory: directory.to_path_buf(),
+            from: String::from(from),
+        })
+    }
+
+    /// The sent mails, the oldest first.
+    #[cfg(test)]
+    pub fn read_all(&self) -> Vec<String> {
+        let mut paths: Vec<PathBuf> = fs::read_dir(&self.directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        paths.sort();
+        paths
+            .iter()
+            .map(|path| fs::read_to_string(path).unwrap())
+            .collect()
+    }
+}
+
+impl Mailer for FileMailer {
+    fn send(&self, mail: &Mail) -> Result<(), MailError> {
+        // Sorted by the time they were sent
+        let name = format!(
+            "{}-{}.eml",
+            Utc::now().format("%Y%m%dT%H%M%S%.6f"),
+            Uuid::new_v4()
+        );
+        fs::write(self.directory.join(name), format_message(&self.from, mail))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mails_are_written_to_files() {
+        let directory = std::env::temp_dir().join(format!("thermit-mail-{}", Uuid::new_v4()));
+        let mailer = FileMailer::new(&directory, "thermit <noreply@example.com>").unwrap();
+
+        for subject in &["first", "second"] {
+            let mail = Mail {
+                to: String::from("jane@example.com"),
+                subject: String::from(*subject),
+                body: String::from("Hello"),
+            };
+            mailer.send(&mail).unwrap();
+        }
+
+        let mails = mailer.read_all();
+        assert_eq!(mails.len(), 2);
+        assert!(mails[0].contains("Subject: first\r\n"));
+        assert!(mails[1].contains("Subject: second\r\n"));
+        fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,153 @@
+//! Sending of mails, e.g. for the verification of email addresses and password resets.
+//!
+//! Routes use the `Mailer` trait, so that the transport is picked by the configuration and tests
+//! can read the sent mails from files.
+
+mod file;
+mod smtp;
+
+use std::sync::Arc;
+
+use actix_web::error::BlockingError;
+use actix_web::web;
+use chrono::Utc;
+use derive_more::Display;
+use uuid::Uuid;
+
+use crate::config::{MailConfig, MailTransport};
+
+pub use file::FileMailer;
+pub use smtp::SmtpMailer;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Mail {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+#[derive(Debug, Display)]
+pub enum MailError {
+    #[display(fmt = "{}", _0)]
+    Io(std::io::Error),
+    #[display(fmt = "TLS failed: {}", _0)]
+    Tls(String),
+    /// The server answered a command with an error.
+    #[display(fmt = "The SMTP server answered {}", _0)]
+    Smtp(String),
+}
+
+impl From<std::io::Error> for MailError {
+    fn from(error: std::io::Error) -> MailError {
+        MailError::Io(error)
+    }
+}
+
+pub trait Mailer: Send + Sync {
+    /// Blocks until the mail is sent, use `deliver` in async code.
+    fn send(&self, mail: &Mail) -> Result<(), MailError>;
+}
+
+/// Writes the mails to the log, including their tokens, so it is only meant for development.
+pub struct LogMailer;
+
+impl Mailer for LogMailer {
+    fn send(&self, mail: &Mail) -> Result<(), MailError> {
+        tracing::info!(
+            to = mail.to.as_str(),
+            "Mail \"{}\":\n{}",
+            mail.subject,
+            mail.body
+        );
+        Ok(())
+    }
+}
+
+pub fn from_config(config: &MailConfig) -> Result<Arc<dyn Mailer>, MailError> {
+    Ok(match config.transport {
+        MailTransport::Log => Arc::new(LogMailer),
+        MailTransport::File => Arc::new(FileMailer::new(&config.directory, &config.from)?),
+        MailTransport::Smtp => Arc::new(SmtpMailer::new(&config.smtp, &config.from)),
+    })
+}
+
+/// Sends the mail on a blocking thread.
+pub async fn deliver(mailer: web::Data<dyn Mailer>, mail: Mail) -> Result<(), MailError> {
+    web::block(move || mailer.send(&mail))
+        .await
+        .map_err(|e| match e {
+            BlockingError::Error(e) => e,
+            BlockingError::Canceled => MailError::Smtp(String::from("sending was cancelled")),
+        })
+}
+
+/// Sends the mail on a blocking thread without waiting for it, failures are only logged.
+///
+/// For routes whose answer must not reveal whether a mail was sent, so that they take as long
+/// either way.
+pub fn deliver_later(mailer: web::Data<dyn Mailer>, mail: Mail) {
+    actix_web::rt::spawn(async move {
+        if let Err(e) = deliver(mailer, mail).await {
+            tracing::error!("Could not send a mail: {}", e);
+        }
+    });
+}
+
+/// The mail as RFC 5322 message with CRLF line endings.
+fn format_message(from: &str, mail: &Mail) -> String {
+    let domain = address(from).rsplit('@').next().unwrap_or("localhost");
+    let mut message = format!(
+        "From: {}\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\nMessage-ID: <{}@{}>\r\n\
+         MIME-Version: 1.0\r\nContent-Type: text/plain; charset=utf-8\r\n\
+         Content-Transfer-Encoding: 8bit\r\n\r\n",
+        from,
+        mail.to,
+        mail.subject,
+        Utc::now().to_rfc2822(),
+        Uuid::new_v4(),
+        domain
+    );
+    for line in mail.body.lines() {
+        message.push_str(line);
+        message.push_str("\r\n");
+    }
+    message
+}
+
+/// The address of a mailbox like `thermit <noreply@example.com>`.
+fn address(mailbox: &str) -> &str {
+    match (mailbox.rfind('<'), mailbox.rfind('>')) {
+        (Some(start), Some(end)) if start < end => &mailbox[start + 1..end],
+        _ => mailbox.trim(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn message_has_headers_and_crlf_lines() {
+        let mail = Mail {
+            to: String::from("jane@example.com"),
+            subject: String::from("Hello"),
+            body: String::from("first\nsecond"),
+        };
+
+        let message = format_message("thermit <noreply@example.com>", &mail);
+        assert!(
+            message.starts_with("From: thermit <noreply@example.com>\r\nTo: jane@example.com\r\n")
+        );
+        assert!(message.contains("@example.com>\r\n"));
+        assert!(message.ends_with("\r\n\r\nfirst\r\nsecond\r\n"));
+    }
+
+    #[test]
+    fn address_is_taken_from_mailbox() {
+        assert_eq!(
+            address("thermit <noreply@example.com>"),
+            "noreply@example.com"
+        );
+        assert_eq!(address(" noreply@example.com "), "noreply@example.com");
+    }
+}
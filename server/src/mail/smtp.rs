@@ -0,0 +1,282 @@
+//! A small SMTP client, enough to hand mails to a relay like the one of the mail provider.
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+
+use openssl::ssl::{SslConnector, SslMethod, SslStream};
+
+use crate::config::{SmtpConfig, SmtpSecurity};
+use crate::mail::{address, format_message, Mail, MailError, Mailer};
+
+/// Sends every mail over a new connection, as there are only a few of them.
+pub struct SmtpMailer {
+    config: SmtpConfig,
+    from: String,
+}
+
+enum Stream {
+    Plain(TcpStream),
+    Tls(Box<SslStream<TcpStream>>),
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.read(buf),
+            Stream::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.write(buf),
+            Stream::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(stream) => stream.flush(),
+            Stream::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+struct Connection {
+    stream: BufReader<Stream>,
+}
+
+impl Connection {
+    /// Reads a reply, which may span several lines, and checks its code.
+    fn expect(&mut self, code: &str) -> Result<(), MailError> {
+        let mut reply = String::new();
+        loop {
+            let mut line = String::new();
+            if self.stream.read_line(&mut line)? == 0 {
+                return Err(MailError::Smtp(String::from(
+                    "nothing, it closed the connection",
+                )));
+            }
+            reply.push_str(line.trim_end());
+            // The last line of a reply has a space after the code, the others a dash
+            if line.len() < 4 || line.as_bytes()[3] != b'-' {
+                break;
+            }
+            reply.push(' ');
+        }
+        if reply.starts_with(code) {
+            Ok(())
+        } else {
+            Err(MailError::Smtp(reply))
+        }
+    }
+
+    fn command(&mut self, command: &str, code: &str) -> Result<(), MailError> {
+        let stream = self.stream.get_mut();
+        stream.write_all(command.as_bytes())?;
+        stream.write_all(b"\r\n")?;
+        stream.flush()?;
+        self.expect(code)
+    }
+}
+
+/// Escapes lines starting with a dot, which would end the data otherwise.
+fn dot_stuff(message: &str) -> String {
+    let mut data = String::with_capacity(message.len() + 16);
+    for line in message.split_inclusive("\r\n") {
+        if line.starts_with('.') {
+            data.push('.');
+        }
+        data.push_str(line);
+    }
+    data
+}
+
+fn tls_error<E: std::fmt::Display>(error: E) -> MailError {
+    MailError::Tls(error.to_string())
+}
+
+impl SmtpMailer {
+    pub fn new(config: &SmtpConfig, from: &str) -> Self {
+        SmtpMailer {
+            config: config.clone(),
+            from: String::from(from),
+        }
+    }
+
+    fn connect(&self) -> Result<Stream, MailError> {
+        let address = (self.config.host.as_str(), self.config.port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "unknown SMTP host"))?;
+        let stream = TcpStream::connect_timeout(&address, self.config.timeout)?;
+        stream.set_read_timeout(Some(self.config.timeout))?;
+        stream.set_write_timeout(Some(self.config.timeout))?;
+        match self.config.security {
+            SmtpSecurity::Tls => self.upgrade(stream),
+            _ => Ok(Stream::Plain(stream)),
+        }
+    }
+
+    fn upgrade(&self, stream: TcpStream) -> Result<Stream, MailError> {
+        let connector = SslConnector::builder(SslMethod::tls())
+            .map_err(tls_error)?
+            .build();
+        let stream = connector
+            .connect(&self.config.host, stream)
+            .map_err(tls_error)?;
+        Ok(Stream::Tls(Box::new(stream)))
+    }
+}
+
+impl Mailer for SmtpMailer {
+    fn send(&self, mail: &Mail) -> Result<(), MailError> {
+        let mut connection = Connection {
+            stream: BufReader::new(self.connect()?),
+        };
+        connection.expect("220")?;
+        connection.command("EHLO thermit", "250")?;
+
+        if self.config.security == SmtpSecurity::StartTls {
+            connection.command("STARTTLS", "220")?;
+            let stream = match connection.stream.into_inner() {
+                Stream::Plain(stream) => stream,
+                Stream::Tls(_) => unreachable!("the connection is encrypted already"),
+            };
+            connection = Connection {
+                stream: BufReader::new(self.upgrade(stream)?),
+            };
+            connection.command("EHLO thermit", "250")?;
+        }
+
+        if let (Some(username), Some(password)) = (&self.config.username, &self.config.password) {
+            let credentials = base64::encode(format!("\0{}\0{}", username, password));
+            connection.command(&format!("AUTH PLAIN {}", credentials), "235")?;
+        }
+
+        connection.command(&format!("MAIL FROM:<{}>", address(&self.from)), "250")?;
+        connection.command(&format!("RCPT TO:<{}>", address(&mail.to)), "25")?;
+        connection.command("DATA", "354")?;
+        let data = dot_stuff(&format_message(&self.from, mail));
+        connection.command(&format!("{}.", data), "250")?;
+        // The mail is accepted at this point, the answer to QUIT does not matter
+        let _ = connection.command("QUIT", "221");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::Duration;
+
+    /// Accepts one mail like a relay and returns everything the client sent.
+    fn fake_server() -> (u16, thread::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut writer = stream;
+            let mut received = String::new();
+            writer.write_all(b"220 localhost ready\r\n").unwrap();
+            let mut in_data = false;
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 {
+                    break;
+                }
+                received.push_str(&line);
+                let reply: &[u8] = if in_data {
+                    if line != ".\r\n" {
+                        continue;
+                    }
+                    in_data = false;
+                    b"250 queued\r\n"
+                } else if line.starts_with("EHLO") {
+                    b"250-localhost\r\n250 AUTH PLAIN\r\n"
+                } else if line.starts_with("AUTH") {
+                    b"235 ok\r\n"
+                } else if line.starts_with("DATA") {
+                    in_data = true;
+                    b"354 go ahead\r\n"
+                } else if line.starts_with("QUIT") {
+                    writer.write_all(b"221 bye\r\n").unwrap();
+                    break;
+                } else {
+                    b"250 ok\r\n"
+                };
+                writer.write_all(reply).unwrap();
+            }
+            received
+        });
+        (port, handle)
+    }
+
+    #[test]
+    fn sends_mail_to_relay() {
+        let (port, server) = fake_server();
+        let config = SmtpConfig {
+            host: String::from("127.0.0.1"),
+            port,
+            security: SmtpSecurity::None,
+            username: Some(String::from("user")),
+            password: Some(String::from("secret")),
+            timeout: Duration::from_secs(5),
+        };
+        let mailer = SmtpMailer::new(&config, "thermit <noreply@example.com>");
+
+        let mail = Mail {
+            to: String::from("jane@example.com"),
+            subject: String::from("Hello"),
+            body: String::from("Hi Jane\n.hidden line\nBye"),
+        };
+        mailer.send(&mail).unwrap();
+
+        let received = server.join().unwrap();
+        assert!(received.contains(&format!(
+            "AUTH PLAIN {}\r\n",
+            base64::encode("\0user\0secret")
+        )));
+        assert!(received.contains("MAIL FROM:<noreply@example.com>\r\n"));
+        assert!(received.contains("RCPT TO:<jane@example.com>\r\n"));
+        assert!(received.contains("\r\nHi Jane\r\n..hidden line\r\nBye\r\n.\r\n"));
+    }
+
+    #[test]
+    fn rejected_recipient_is_an_error() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            stream.write_all(b"220 ready\r\n").unwrap();
+            for reply in &["250 hello\r\n", "250 ok\r\n", "550 no such user\r\n"] {
+                reader.read_line(&mut String::new()).unwrap();
+                stream.write_all(reply.as_bytes()).unwrap();
+            }
+        });
+        let config = SmtpConfig {
+            host: String::from("127.0.0.1"),
+            port,
+            security: SmtpSecurity::None,
+            ..SmtpConfig::default()
+        };
+
+        let mail = Mail {
+            to: String::from("nobody@example.com"),
+            subject: String::from("Hello"),
+            body: String::new(),
+        };
+        let result = SmtpMailer::new(&config, "noreply@example.com").send(&mail);
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "The SMTP server answered 550 no such user"
+        );
+        server.join().unwrap();
+    }
+}
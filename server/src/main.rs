@@ -1,70 +1,189 @@
+// The derives of diesel 1.x generate impls inside of functions
+#![allow(non_local_definitions)]
+
 #[macro_use]
 extern crate diesel;
+#[macro_use]
+extern crate diesel_migrations;
 extern crate dotenv;
 
-use actix_web::{middleware::Logger, web, App, HttpServer};
-use diesel::prelude::*;
-use diesel::r2d2::{self, ConnectionManager};
-use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use actix_web::middleware::Condition;
+use actix_web::rt::signal::unix::{signal, SignalKind};
+use actix_web::{web, App, HttpServer};
+use structopt::StructOpt;
 
+use cli::Command;
+use config::Config;
+
+mod admin;
+mod api_token;
+mod audit;
+mod cli;
+mod config;
+mod db;
+mod email;
 mod errors;
+mod health;
+mod mail;
 mod message;
+mod metrics;
+mod migrations;
+mod oidc;
+mod query;
+mod repository;
 mod room;
 mod schema;
+mod session;
+mod sql_types;
+mod telemetry;
+mod tls;
+mod two_factor;
 mod user;
 
 #[cfg(test)]
 mod test_helpers;
 
-pub type Pool = r2d2::Pool<ConnectionManager<PgConnection>>;
-
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
     dotenv::dotenv().ok();
-    let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+    let opt = cli::Opt::from_args();
+    let config = Config::load(&opt.overrides).unwrap_or_else(|e| exit_with_error(e));
+    telemetry::init(config.logging.format);
+    user::password::configure(&config.auth.password).unwrap_or_else(|e| exit_with_error(e));
 
     // create db connection pool
-    let manager = ConnectionManager::<PgConnection>::new(database_url);
-    let pool: Pool = r2d2::Pool::builder()
-        .build(manager)
-        .expect("Failed to create pool.");
-
-    // load tls
-    let mut using_tls = false;
-    let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
-    if std::env::var("USE_TLS").is_ok() {
-        let key_path =
-            std::env::var("TLS_KEY_PATH").expect("TLS_KEY_PATH must be set when using TLS");
-        let cert_path =
-            std::env::var("TLS_CERT_PATH").expect("TLS_CERT_PATH must be set when using TLS");
-        builder
-            .set_private_key_file(key_path, SslFiletype::PEM)
-            .unwrap();
-        builder.set_certificate_chain_file(cert_path).unwrap();
-
-        using_tls = true;
+    let database = db::Database::connect(config.database.url(), &config.database.pool)
+        .unwrap_or_else(|e| exit_with_error(format!("Could not connect to the database: {}", e)));
+
+    let command = opt.command.unwrap_or(Command::Serve);
+
+    // check the schema and apply pending migrations, the migrate command does this on its own
+    if command != Command::Migrate {
+        if let Err(e) = migrations::migrate(&database, config.database.run_migrations) {
+            exit_with_error(format!("Could not migrate the database: {}", e));
+        }
+    }
+
+    if command == Command::Serve {
+        return serve(config, database).await;
     }
+    if let Err(e) = cli::run(command, &database) {
+        exit_with_error(e);
+    }
+    Ok(())
+}
 
-    let server_ip = std::env::var("SERVER_IP").expect("SERVER_IP must be set");
-    let server_port = std::env::var("SERVER_PORT").expect("SERVER_PORT must be set");
+fn exit_with_error(error: impl std::fmt::Display) -> ! {
+    eprintln!("Error: {}", error);
+    std::process::exit(1);
+}
 
-    let server_address = format!("{}:{}", server_ip, server_port);
+/// Records when the first signal that stops the server arrived, the shutdown timeout counts from
+/// then on.
+fn watch_stop_signals() -> Arc<Mutex<Option<Instant>>> {
+    let received = Arc::new(Mutex::new(None));
+    for kind in [
+        SignalKind::terminate(),
+        SignalKind::interrupt(),
+        SignalKind::quit(),
+    ] {
+        let received = received.clone();
+        actix_web::rt::spawn(async move {
+            match signal(kind) {
+                Ok(mut signals) => {
+                    if signals.recv().await.is_some() {
+                        received.lock().unwrap().get_or_insert_with(Instant::now);
+                    }
+                }
+                Err(e) => tracing::error!("Could not listen for stop signals: {}", e),
+            }
+        });
+    }
+    received
+}
+
+async fn serve(config: Config, database: db::Database) -> std::io::Result<()> {
+    let server_address = format!("{}:{}", config.server.ip, config.server.port);
+    let json_limit = config.limits.json_payload;
+    let features = config.features.clone();
+    let auth = config.auth.clone();
+    let throttle = user::LoginThrottle::new(&config.auth.throttle);
+    let oidc = oidc::OidcProvider::new(&config.auth.oidc);
+    let mail_config = config.mail.clone();
+    let mailer = mail::from_config(&config.mail)
+        .unwrap_or_else(|e| exit_with_error(format!("Could not set up mail: {}", e)));
+    let shutdown_timeout = config.server.shutdown_timeout;
+    if features.metrics {
+        metrics::register();
+    }
+    user::deletion::spawn_purge(database.clone(), auth.deletion_grace_period);
 
     let server = HttpServer::new(move || {
         App::new()
-            .wrap(Logger::default())
-            .data(pool.clone())
+            .wrap(Condition::new(features.metrics, metrics::RequestMetrics))
+            .wrap(telemetry::RequestTracing)
+            .data(database.clone())
+            .data(features.clone())
+            .data(auth.clone())
+            .data(throttle.clone())
+            .data(oidc.clone())
+            .data(mail_config.clone())
+            .app_data(web::Data::from(mailer.clone()))
+            .app_data(
+                web::JsonConfig::default()
+                    .limit(json_limit)
+                    .error_handler(errors::payload_error_handler),
+            )
+            .app_data(web::PathConfig::default().error_handler(errors::payload_error_handler))
+            .configure(health::init_routes)
+            .configure(|config| {
+                if features.metrics {
+                    metrics::init_routes(config)
+                }
+            })
             .service(
                 web::scope("/api/v1")
                     .configure(user::init_routes)
-                    .configure(room::init_routes),
+                    .configure(session::init_routes)
+                    .configure(two_factor::init_routes)
+                    .configure(oidc::init_routes)
+                    .configure(email::init_routes)
+                    .configure(api_token::init_routes)
+                    .configure(room::init_routes)
+                    .configure(message::init_routes)
+                    .configure(admin::init_routes),
             )
-    });
+    })
+    .on_connect(tls::client_certificate)
+    .max_connections(config.limits.max_connections)
+    // SIGTERM stops accepting connections and gives running requests this long to finish
+    .shutdown_timeout(shutdown_timeout.as_secs());
+    let server = match config.limits.workers {
+        Some(workers) => server.workers(workers),
+        None => server,
+    };
 
-    if using_tls {
-        server.bind_openssl(server_address, builder)?.run().await
+    let server = if config.tls.enabled {
+        let certificates = tls::CertificateStore::load(&config.tls)?;
+        certificates.watch(config.tls.watch_interval);
+        server.bind_openssl(server_address, certificates.acceptor()?)?
     } else {
-        server.bind(server_address)?.run().await
+        server.bind(server_address)?
+    };
+    let stop_signal = watch_stop_signals();
+    server.run().await?;
+
+    // Requests cancelled by the timeout may have left database work behind. Waiting for it only
+    // gets what is left of the timeout, so that the whole shutdown takes no longer than it.
+    let stopping_since = stop_signal.lock().unwrap().unwrap_or_else(Instant::now);
+    let remaining = (stopping_since + shutdown_timeout).saturating_duration_since(Instant::now());
+    let running = db::wait_for_blocking_jobs(remaining).await;
+    if running > 0 {
+        tracing::warn!("Stopped with {} database jobs still running", running);
     }
+    tracing::info!("Server stopped");
+    Ok(())
 }
@@ -1,3 +1,6 @@
 mod model;
+mod routes;
 
+#[allow(unused_imports)]
 pub use model::*;
+pub use routes::init_routes;
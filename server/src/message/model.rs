@@ -1,29 +1,31 @@
-use diesel::prelude::*;
 use diesel::result::Error as DieselError;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-use crate::schema::messages;
-use chrono::NaiveDateTime;
+use crate::query::prelude::*;
+use chrono::{NaiveDateTime, Utc};
 
-#[derive(Serialize, Deserialize, Queryable, Insertable, PartialEq, Debug)]
-#[table_name = "messages"]
+#[derive(Clone, Serialize, Deserialize, Queryable, PartialEq, Debug)]
 pub struct Message {
     pub id: Uuid,
     pub room_id: Uuid,
-    pub author: Uuid,
+    /// `None` once the author has been deleted, the message stays in the conversation.
+    pub author: Option<Uuid>,
     pub content: String,
     pub created: NaiveDateTime,
     pub updated: NaiveDateTime,
+    /// Whether the author was a bot when the message was posted.
+    pub bot: bool,
 }
 
 // decode request data
-#[derive(Clone, Deserialize, Insertable, AsChangeset, Debug)]
-#[table_name = "messages"]
+#[derive(Clone, Deserialize, Debug)]
 pub struct MessageData {
     pub content: String,
     pub room_id: Uuid,
     pub author: Uuid,
+    #[serde(default)]
+    pub bot: bool,
 }
 
 #[derive(Debug)]
@@ -34,46 +36,82 @@ pub enum MessageError {
 }
 
 impl Message {
-    pub fn find(message_id: Uuid, conn: &PgConnection) -> Result<Option<Message>, MessageError> {
+    pub fn find(
+        message_id: Uuid,
+        conn: &impl SqlConnection,
+    ) -> Result<Option<Message>, MessageError> {
         use crate::schema::messages::dsl::*;
 
         Ok(messages
-            .find(message_id)
+            .find(Id(message_id))
             .get_result::<Message>(conn)
             .optional()?)
     }
 
-    pub fn find_all_by_room(room: Uuid, conn: &PgConnection) -> Result<Vec<Message>, MessageError> {
+    pub fn find_all_by_room(
+        room: Uuid,
+        conn: &impl SqlConnection,
+    ) -> Result<Vec<Message>, MessageError> {
         use crate::schema::messages::dsl::*;
-        Ok(messages.filter(room_id.eq(room)).load::<Message>(conn)?)
+        Ok(messages
+            .filter(room_id.eq(Id(room)))
+            .load::<Message>(conn)?)
     }
 
-    pub fn create(message_data: MessageData, conn: &PgConnection) -> Result<Message, MessageError> {
+    pub fn create(
+        message_data: MessageData,
+        conn: &impl SqlConnection,
+    ) -> Result<Message, MessageError> {
         use crate::schema::messages::dsl::*;
 
-        let new_message: Message = diesel::insert_into(messages)
-            .values(&message_data)
-            .get_result(conn)?;
+        // The id and timestamps are set here, see `User::create`
+        let timestamp = Utc::now().naive_utc();
+        let new_message = Message {
+            id: Uuid::new_v4(),
+            room_id: message_data.room_id,
+            author: Some(message_data.author),
+            content: message_data.content,
+            created: timestamp,
+            updated: timestamp,
+            bot: message_data.bot,
+        };
+        diesel::insert_into(messages)
+            .values((
+                id.eq(Id(new_message.id)),
+                room_id.eq(Id(new_message.room_id)),
+                author.eq(Id(message_data.author)),
+                content.eq(&new_message.content),
+                created.eq(new_message.created),
+                updated.eq(new_message.updated),
+                bot.eq(new_message.bot),
+            ))
+            .execute(conn)?;
         Ok(new_message)
     }
 
     pub fn update(
         message_id: Uuid,
         message_data: MessageData,
-        conn: &PgConnection,
+        conn: &impl SqlConnection,
     ) -> Result<Message, MessageError> {
         use crate::schema::messages::dsl::*;
 
-        let message: Message = diesel::update(messages.find(message_id))
-            .set(message_data)
-            .get_result(conn)?;
-        Ok(message)
+        diesel::update(messages.find(Id(message_id)))
+            .set((
+                room_id.eq(Id(message_data.room_id)),
+                author.eq(Id(message_data.author)),
+                content.eq(message_data.content),
+                bot.eq(message_data.bot),
+                updated.eq(Utc::now().naive_utc()),
+            ))
+            .execute(conn)?;
+        Message::find(message_id, conn)?.ok_or(MessageError::MessageNotFound)
     }
 
-    pub fn destroy(message_id: Uuid, conn: &PgConnection) -> Result<usize, MessageError> {
+    pub fn destroy(message_id: Uuid, conn: &impl SqlConnection) -> Result<usize, MessageError> {
         use crate::schema::messages::dsl::*;
 
-        let count = diesel::delete(messages.find(message_id)).execute(conn)?;
+        let count = diesel::delete(messages.find(Id(message_id))).execute(conn)?;
         Ok(count)
     }
 }
@@ -96,7 +134,7 @@ mod tests {
     static HELLO_THERMIT: &str = "Hello thermit!";
     static BYE_THERMIT: &str = "Bye thermit!";
 
-    fn setup_hello_thermit_message(conn: &PgConnection) -> Result<Message, MessageError> {
+    fn setup_hello_thermit_message(conn: &impl SqlConnection) -> Result<Message, MessageError> {
         let room = setup_room(conn);
         let user = setup_user(conn);
         let message_data = create_message_data(HELLO_THERMIT, room.id, user.id);
@@ -136,7 +174,7 @@ mod tests {
 
         let _message = setup_hello_thermit_message(&conn).unwrap();
         let found_message = Message::find(Uuid::new_v4(), &conn).unwrap();
-        assert!(matches!(found_message, None));
+        assert!(found_message.is_none());
     }
 
     #[test]
@@ -166,7 +204,7 @@ mod tests {
 
         let message = setup_hello_thermit_message(&conn).unwrap();
         let updated_message_data =
-            create_message_data(BYE_THERMIT, message.room_id, message.author);
+            create_message_data(BYE_THERMIT, message.room_id, message.author.unwrap());
         let updated_message = Message::update(message.id, updated_message_data, &conn).unwrap();
         assert_eq!(updated_message.id, message.id);
         assert_eq!(updated_message.content, BYE_THERMIT);
@@ -178,7 +216,7 @@ mod tests {
 
         let message = setup_hello_thermit_message(&conn).unwrap();
         let updated_message_data =
-            create_message_data(BYE_THERMIT, message.room_id, message.author);
+            create_message_data(BYE_THERMIT, message.room_id, message.author.unwrap());
         let updated_message = Message::update(Uuid::new_v4(), updated_message_data, &conn);
         assert!(matches!(
             updated_message,
@@ -194,7 +232,7 @@ mod tests {
         let deleted_count = Message::destroy(message.id, &conn).unwrap();
         assert_eq!(deleted_count, 1);
         let found_message = Message::find(message.id, &conn).unwrap();
-        assert!(matches!(found_message, None));
+        assert!(found_message.is_none());
     }
 
     #[test]
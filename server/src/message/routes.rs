@@ -0,0 +1,221 @@
+use actix_web::{get, post, web, HttpResponse};
+use serde::Deserialize;
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::api_token::{Caller, Scope};
+use crate::db::DbConn;
+use crate::errors::ServiceError;
+use crate::message::MessageData;
+use crate::metrics;
+
+#[derive(Deserialize, Debug)]
+pub struct NewMessage {
+    pub content: String,
+}
+
+/// Lists the messages of a room the caller is a member of.
+#[get("/rooms/{id}/messages")]
+pub async fn list(
+    conn: DbConn,
+    caller: Caller,
+    room_id: web::Path<Uuid>,
+) -> Result<HttpResponse, ServiceError> {
+    let user_id = caller.require(Scope::ReadRooms)?;
+    let room_id = room_id.into_inner();
+    let messages = conn
+        .run(move |repo| -> Result<_, ServiceError> {
+            // Fails with RoomNotFound if the room does not exist
+            if !repo.get_room_user_ids(room_id)?.contains(&user_id) {
+                return Err(ServiceError::Forbidden);
+            }
+            Ok(repo.find_messages_by_room(room_id)?)
+        })
+        .await?;
+
+    Ok(HttpResponse::Ok().json(json!({ "messages": messages })))
+}
+
+/// Posts a message as the caller, who has to be a member of the room.
+#[post("/rooms/{id}/messages")]
+pub async fn create(
+    conn: DbConn,
+    caller: Caller,
+    room_id: web::Path<Uuid>,
+    data: web::Json<NewMessage>,
+) -> Result<HttpResponse, ServiceError> {
+    let author = caller.require(Scope::PostMessages)?;
+    let content = data.into_inner().content;
+    if content.trim().is_empty() {
+        return Err(ServiceError::Validation(String::from(
+            "content must not be empty",
+        )));
+    }
+
+    let room_id = room_id.into_inner();
+    let message = conn
+        .run(move |repo| -> Result<_, ServiceError> {
+            // Fails with RoomNotFound if the room does not exist
+            if !repo.get_room_user_ids(room_id)?.contains(&author) {
+                return Err(ServiceError::Forbidden);
+            }
+            let user = repo.find_user(author)?.ok_or(ServiceError::Unauthorized)?;
+            Ok(repo.create_message(MessageData {
+                content,
+                room_id,
+                author,
+                bot: user.bot,
+            })?)
+        })
+        .await?;
+
+    metrics::MESSAGES_CREATED.inc();
+    Ok(HttpResponse::Ok().json(message))
+}
+
+pub fn init_routes(config: &mut web::ServiceConfig) {
+    config.service(list);
+    config.service(create);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api_token::ApiToken;
+    use crate::db::Database;
+    use crate::repository::{
+        InMemoryRepository, MessageRepository, RoomRepository, UserRepository,
+    };
+    use crate::test_helpers::*;
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App};
+    use serde_json::Value;
+
+    #[actix_rt::test]
+    async fn bot_messages_are_marked() {
+        let repo = InMemoryRepository::default();
+        let bot = repo.create_user(create_user_data("ciBot")).unwrap();
+        repo.set_user_bot(bot.id, true).unwrap();
+        let room = repo.create_room(create_room_data("builds")).unwrap();
+        repo.add_room_users(room.id, vec![bot.id]).unwrap();
+        let token = ApiToken::issue(
+            &repo,
+            bot.id,
+            String::from("ci"),
+            &[Scope::ReadRooms, Scope::PostMessages],
+        )
+        .unwrap()
+        .token;
+        let mut app = test::init_service(
+            App::new()
+                .data(Database::Memory(repo))
+                .configure(init_routes),
+        )
+        .await;
+
+        // Other tests create messages concurrently, so the counter only has a lower bound
+        let created = metrics::MESSAGES_CREATED.get();
+        let req = test::TestRequest::post()
+            .uri(&format!("/rooms/{}/messages", room.id))
+            .header("Authorization", format!("Bearer {}", token))
+            .set_json(&json!({ "content": "Build 42 passed" }))
+            .to_request();
+        let message: Value = test::read_response_json(&mut app, req).await;
+        assert_eq!(message["author"], bot.id.to_string());
+        assert_eq!(message["bot"], true);
+        assert!(metrics::MESSAGES_CREATED.get() > created);
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/rooms/{}/messages", room.id))
+            .header("Authorization", format!("Bearer {}", token))
+            .to_request();
+        let body: Value = test::read_response_json(&mut app, req).await;
+        assert_eq!(body["messages"][0]["content"], "Build 42 passed");
+        assert_eq!(body["messages"][0]["bot"], true);
+    }
+
+    #[actix_rt::test]
+    async fn create_needs_scope_and_membership() {
+        let repo = InMemoryRepository::default();
+        let user = repo.create_user(create_user_data("ciBot")).unwrap();
+        let room = repo.create_room(create_room_data("builds")).unwrap();
+        let read_only = ApiToken::issue(&repo, user.id, String::from("read"), &[Scope::ReadRooms])
+            .unwrap()
+            .token;
+        let writer = ApiToken::issue(
+            &repo,
+            user.id,
+            String::from("write"),
+            &[Scope::PostMessages],
+        )
+        .unwrap()
+        .token;
+        let mut app = test::init_service(
+            App::new()
+                .data(Database::Memory(repo))
+                .configure(init_routes),
+        )
+        .await;
+
+        let post = |token: &str| {
+            test::TestRequest::post()
+                .uri(&format!("/rooms/{}/messages", room.id))
+                .header("Authorization", format!("Bearer {}", token))
+                .set_json(&json!({ "content": "Hello" }))
+                .to_request()
+        };
+
+        let resp = test::call_service(&mut app, post(&read_only)).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        // Not a member of the room
+        let resp = test::call_service(&mut app, post(&writer)).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let resp = test::call_service(&mut app, post("thm_unknown")).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let req = test::TestRequest::post()
+            .uri(&format!("/rooms/{}/messages", room.id))
+            .set_json(&json!({ "content": "Hello" }))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_rt::test]
+    async fn list_needs_a_caller_who_is_a_member() {
+        let repo = InMemoryRepository::default();
+        let member = repo.create_user(create_user_data("member")).unwrap();
+        let stranger = repo.create_user(create_user_data("stranger")).unwrap();
+        let room = repo.create_room(create_room_data("testRoom")).unwrap();
+        repo.add_room_users(room.id, vec![member.id]).unwrap();
+        repo.create_message(create_message_data("Hello thermit!", room.id, member.id))
+            .unwrap();
+        let (member_bearer, stranger_bearer) =
+            (bearer(&repo, member.id), bearer(&repo, stranger.id));
+        let mut app = test::init_service(
+            App::new()
+                .data(Database::Memory(repo))
+                .configure(init_routes),
+        )
+        .await;
+
+        let uri = format!("/rooms/{}/messages", room.id);
+        let req = test::TestRequest::get().uri(&uri).to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let req = test::TestRequest::get()
+            .uri(&uri)
+            .header("Authorization", stranger_bearer.as_str())
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let req = test::TestRequest::get()
+            .uri(&uri)
+            .header("Authorization", member_bearer.as_str())
+            .to_request();
+        let body: Value = test::read_response_json(&mut app, req).await;
+        assert_eq!(body["messages"][0]["content"], "Hello thermit!");
+    }
+}
@@ -0,0 +1,280 @@
+//! Prometheus metrics, served at `/metrics`.
+//!
+//! The metrics are registered in the default registry of the prometheus crate, so that every part
+//! of the server can update them without passing a registry around.
+
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::{get, web, Error, HttpRequest, HttpResponse};
+use futures::future::{ok, LocalBoxFuture, Ready};
+use lazy_static::lazy_static;
+use prometheus::{
+    exponential_buckets, register_histogram, register_histogram_vec, register_int_counter,
+    register_int_counter_vec, register_int_gauge, register_int_gauge_vec, Encoder, Histogram,
+    HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, TextEncoder,
+};
+
+use crate::db::Database;
+
+// Requests that do not match a route are grouped, so that scanners cannot create new series
+const UNMATCHED_ROUTE: &str = "unmatched";
+
+lazy_static! {
+    pub static ref HTTP_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "http_requests_total",
+        "Number of HTTP requests",
+        &["method", "route", "status"]
+    )
+    .unwrap();
+    pub static ref HTTP_REQUEST_DURATION: HistogramVec = register_histogram_vec!(
+        "http_request_duration_seconds",
+        "Time until the response of an HTTP request is ready",
+        &["method", "route", "status"]
+    )
+    .unwrap();
+    pub static ref DB_POOL_CONNECTIONS: IntGaugeVec = register_int_gauge_vec!(
+        "db_pool_connections",
+        "Connections of the database pool, by state",
+        &["state"]
+    )
+    .unwrap();
+    pub static ref DB_POOL_MAX_SIZE: IntGauge = register_int_gauge!(
+        "db_pool_max_size",
+        "Maximum number of connections of the database pool"
+    )
+    .unwrap();
+    pub static ref BLOCKING_QUEUE_DURATION: Histogram = register_histogram!(
+        "blocking_queue_duration_seconds",
+        "Time database work waits for a thread of the blocking thread pool",
+        exponential_buckets(0.0005, 2.0, 12).unwrap()
+    )
+    .unwrap();
+    pub static ref DB_POOL_WAIT_DURATION: Histogram = register_histogram!(
+        "db_pool_wait_seconds",
+        "Time database work waits for a connection of the database pool",
+        exponential_buckets(0.0005, 2.0, 14).unwrap()
+    )
+    .unwrap();
+    pub static ref USERS_REGISTERED: IntCounter =
+        register_int_counter!("users_registered_total", "Number of users created with the API")
+            .unwrap();
+    pub static ref AUTH_FAILURES: IntCounter = register_int_counter!(
+        "auth_failures_total",
+        "Number of failed logins because of a wrong username or password"
+    )
+    .unwrap();
+    pub static ref MESSAGES_CREATED: IntCounter =
+        register_int_counter!("messages_created_total", "Number of messages created").unwrap();
+    // There are no WebSockets yet, the gauge is already exported so that dashboards work
+    pub static ref WEBSOCKET_SESSIONS: IntGauge = register_int_gauge!(
+        "websocket_sessions",
+        "Number of open WebSocket sessions"
+    )
+    .unwrap();
+}
+
+/// Registers all metrics, so that they are exported with their initial value before they are
+/// updated for the first time.
+pub fn register() {
+    lazy_static::initialize(&HTTP_REQUESTS);
+    lazy_static::initialize(&HTTP_REQUEST_DURATION);
+    lazy_static::initialize(&DB_POOL_CONNECTIONS);
+    lazy_static::initialize(&DB_POOL_MAX_SIZE);
+    lazy_static::initialize(&BLOCKING_QUEUE_DURATION);
+    lazy_static::initialize(&DB_POOL_WAIT_DURATION);
+    lazy_static::initialize(&USERS_REGISTERED);
+    lazy_static::initialize(&AUTH_FAILURES);
+    lazy_static::initialize(&MESSAGES_CREATED);
+    lazy_static::initialize(&WEBSOCKET_SESSIONS);
+}
+
+/// Records the number and latency of requests per route and status.
+///
+/// The route is the pattern of the matched resource, e.g. `/api/v1/users/{id}`, so that ids do
+/// not end up in the labels.
+pub struct RequestMetrics;
+
+impl<S, B> Transform<S> for RequestMetrics
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RequestMetricsMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RequestMetricsMiddleware { service })
+    }
+}
+
+pub struct RequestMetricsMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service for RequestMetricsMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let start = Instant::now();
+        let method = req.method().to_string();
+        let route = req
+            .match_pattern()
+            .unwrap_or_else(|| String::from(UNMATCHED_ROUTE));
+        let response = self.service.call(req);
+
+        Box::pin(async move {
+            let response = response.await;
+            let status = match response {
+                Ok(ref response) => response.status(),
+                Err(ref error) => error.as_response_error().status_code(),
+            };
+
+            let labels = [method.as_str(), route.as_str(), status.as_str()];
+            HTTP_REQUESTS.with_label_values(&labels).inc();
+            HTTP_REQUEST_DURATION
+                .with_label_values(&labels)
+                .observe(start.elapsed().as_secs_f64());
+            response
+        })
+    }
+}
+
+/// Records how long `f` waited for a thread of the blocking pool, then runs it.
+pub fn observe_queue_time<T>(submitted: Instant, f: impl FnOnce() -> T) -> T {
+    BLOCKING_QUEUE_DURATION.observe(submitted.elapsed().as_secs_f64());
+    f()
+}
+
+/// Records how long `get_connection` took to return a connection of the pool, or to give up.
+pub fn observe_pool_wait<T>(get_connection: impl FnOnce() -> T) -> T {
+    let start = Instant::now();
+    let connection = get_connection();
+    DB_POOL_WAIT_DURATION.observe(start.elapsed().as_secs_f64());
+    connection
+}
+
+#[get("/metrics")]
+async fn metrics(req: HttpRequest) -> HttpResponse {
+    // The pool state is only read when scraping, instead of on every request
+    if let Some(database) = req.app_data::<web::Data<Database>>() {
+        if let Some((state, max_size)) = database.pool_state() {
+            let idle = i64::from(state.idle_connections);
+            let open = i64::from(state.connections);
+            DB_POOL_CONNECTIONS.with_label_values(&["idle"]).set(idle);
+            DB_POOL_CONNECTIONS
+                .with_label_values(&["active"])
+                .set(open - idle);
+            DB_POOL_MAX_SIZE.set(i64::from(max_size));
+        }
+    }
+
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    if let Err(e) = encoder.encode(&prometheus::gather(), &mut buffer) {
+        tracing::error!("Could not encode metrics: {}", e);
+        return HttpResponse::InternalServerError().finish();
+    }
+    HttpResponse::Ok()
+        .content_type(encoder.format_type())
+        .body(buffer)
+}
+
+pub fn init_routes(config: &mut web::ServiceConfig) {
+    config.service(metrics);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{AuthConfig, FeatureConfig};
+    use crate::test_helpers::*;
+    use actix_web::{test, App};
+    use serde_json::json;
+    use uuid::Uuid;
+
+    #[actix_rt::test]
+    async fn requests_are_counted_per_route_and_status() {
+        let mut app = test::init_service(
+            App::new()
+                .wrap(RequestMetrics)
+                .data(memory_database())
+                .data(FeatureConfig::default())
+                .data(AuthConfig::default())
+                .data(crate::user::LoginThrottle::default())
+                .configure(init_routes)
+                .service(web::scope("/api/v1").configure(crate::user::init_routes)),
+        )
+        .await;
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/api/v1/users/{}", Uuid::new_v4()))
+            .to_request();
+        test::call_service(&mut app, req).await;
+        let req = test::TestRequest::post()
+            .uri("/api/v1/auth")
+            .set_json(&json!({ "username": "unknownUser", "password": "12345678" }))
+            .to_request();
+        test::call_service(&mut app, req).await;
+        let req = test::TestRequest::post()
+            .uri("/api/v1/users")
+            .set_json(&json!({ "username": "metricsUser", "password": "12345678" }))
+            .to_request();
+        test::call_service(&mut app, req).await;
+
+        let req = test::TestRequest::get().uri("/metrics").to_request();
+        let body = String::from_utf8(test::read_response(&mut app, req).await.to_vec()).unwrap();
+        assert!(body.contains(
+            r#"http_requests_total{method="GET",route="/api/v1/users/{id}",status="404"}"#
+        ));
+        assert!(body
+            .contains(r#"http_requests_total{method="POST",route="/api/v1/users",status="200"}"#));
+        assert!(body.contains("users_registered_total"));
+        assert!(body.contains("auth_failures_total"));
+        assert!(body.contains("blocking_queue_duration_seconds_count"));
+    }
+
+    #[actix_rt::test]
+    async fn registered_metrics_are_exported_before_first_update() {
+        register();
+        let mut app = test::init_service(App::new().configure(init_routes)).await;
+
+        let req = test::TestRequest::get().uri("/metrics").to_request();
+        let body = String::from_utf8(test::read_response(&mut app, req).await.to_vec()).unwrap();
+        assert!(body.contains("websocket_sessions 0"));
+        assert!(body.contains("messages_created_total"));
+        assert!(body.contains("db_pool_wait_seconds_count"));
+    }
+
+    #[actix_rt::test]
+    async fn unknown_paths_share_one_route_label() {
+        let mut app =
+            test::init_service(App::new().wrap(RequestMetrics).configure(init_routes)).await;
+
+        let req = test::TestRequest::get().uri("/does/not/exist").to_request();
+        test::call_service(&mut app, req).await;
+
+        let req = test::TestRequest::get().uri("/metrics").to_request();
+        let body = String::from_utf8(test::read_response(&mut app, req).await.to_vec()).unwrap();
+        assert!(body.contains(r#"route="unmatched",status="404""#));
+        assert!(!body.contains("/does/not/exist"));
+    }
+}
@@ -0,0 +1,259 @@
+//! Database migrations, embedded in the binary and applied at startup.
+//!
+//! On every start the server checks the migrations recorded in the database against the ones it
+//! was built with. If the database contains migrations this binary does not know, the schema is
+//! newer than the binary and the server refuses to start. Pending migrations are only applied if
+//! `database.run_migrations` is enabled, otherwise they are logged and have to be run with the
+//! diesel cli.
+
+use derive_more::{Display, From};
+use diesel::prelude::*;
+use diesel::sql_query;
+use diesel::sql_types::BigInt;
+use diesel_migrations::{MigrationConnection, RunMigrationsError};
+
+use crate::db::Database;
+
+// The SQLite versions are only used with the sqlite feature
+#[allow(dead_code)]
+mod versions {
+    include!(concat!(env!("OUT_DIR"), "/migration_versions.rs"));
+}
+
+mod postgres {
+    embed_migrations!("migrations");
+    pub use self::embedded_migrations::run;
+}
+
+#[cfg(feature = "sqlite")]
+mod sqlite {
+    embed_migrations!("migrations_sqlite");
+    pub use self::embedded_migrations::run;
+}
+
+// Key of the Postgres advisory lock held while migrating, "thermit" in ASCII
+const ADVISORY_LOCK_KEY: i64 = 0x0074_6865_726d_6974;
+
+#[derive(Debug, Display, From)]
+pub enum MigrationError {
+    #[display(
+        fmt = "the database schema is newer than this binary, unknown migrations: {}",
+        "_0.join(\", \")"
+    )]
+    #[from(ignore)]
+    SchemaTooNew(Vec<String>),
+    #[display(fmt = "could not get a database connection: {}", _0)]
+    Pool(r2d2::Error),
+    #[display(fmt = "{}", _0)]
+    Database(diesel::result::Error),
+    #[display(fmt = "{}", _0)]
+    Migration(RunMigrationsError),
+}
+
+/// Checks the schema of the database and applies pending migrations if `run_pending` is set.
+pub fn migrate(database: &Database, run_pending: bool) -> Result<(), MigrationError> {
+    match database {
+        Database::Postgres(pool) => migrate_postgres(&*pool.get()?, run_pending),
+        #[cfg(feature = "sqlite")]
+        Database::Sqlite(pool) => migrate_sqlite(&*pool.get()?, run_pending),
+        #[cfg(test)]
+        Database::Memory(_) => Ok(()),
+    }
+}
+
+/// Migrates a Postgres database while holding an advisory lock, so that several instances
+/// starting at the same time do not run the same migrations.
+pub fn migrate_postgres(conn: &PgConnection, run_pending: bool) -> Result<(), MigrationError> {
+    sql_query("SELECT pg_advisory_lock($1)")
+        .bind::<BigInt, _>(ADVISORY_LOCK_KEY)
+        .execute(conn)?;
+
+    let result = migrate_connection(conn, versions::POSTGRES, run_pending, |conn| {
+        postgres::run(conn)
+    });
+
+    sql_query("SELECT pg_advisory_unlock($1)")
+        .bind::<BigInt, _>(ADVISORY_LOCK_KEY)
+        .execute(conn)?;
+    result
+}
+
+/// Migrates a SQLite database. SQLite locks the whole file while writing, so no extra lock is
+/// needed.
+#[cfg(feature = "sqlite")]
+pub fn migrate_sqlite(conn: &SqliteConnection, run_pending: bool) -> Result<(), MigrationError> {
+    migrate_connection(conn, versions::SQLITE, run_pending, |conn| {
+        sqlite::run(conn)
+    })
+}
+
+/// The version of the newest migration this binary knows.
+pub fn schema_version() -> &'static str {
+    versions::POSTGRES.last().copied().unwrap_or_default()
+}
+
+/// Returns the migrations that have not been applied yet, without changing the database.
+pub fn pending(database: &Database) -> Result<Vec<&'static str>, MigrationError> {
+    match database {
+        Database::Postgres(pool) => pending_versions(&*pool.get()?, versions::POSTGRES),
+        #[cfg(feature = "sqlite")]
+        Database::Sqlite(pool) => pending_versions(&*pool.get()?, versions::SQLITE),
+        #[cfg(test)]
+        Database::Memory(_) => Ok(Vec::new()),
+    }
+}
+
+fn pending_versions<C: MigrationConnection>(
+    conn: &C,
+    known_versions: &[&'static str],
+) -> Result<Vec<&'static str>, MigrationError> {
+    let applied = conn.previously_run_migration_versions()?;
+
+    let mut unknown: Vec<String> = applied
+        .iter()
+        .filter(|version| !known_versions.contains(&version.as_str()))
+        .cloned()
+        .collect();
+    if !unknown.is_empty() {
+        unknown.sort();
+        return Err(MigrationError::SchemaTooNew(unknown));
+    }
+
+    Ok(known_versions
+        .iter()
+        .filter(|version| !applied.contains(**version))
+        .copied()
+        .collect())
+}
+
+fn migrate_connection<C, F>(
+    conn: &C,
+    known_versions: &[&'static str],
+    run_pending: bool,
+    run: F,
+) -> Result<(), MigrationError>
+where
+    C: MigrationConnection,
+    F: FnOnce(&C) -> Result<(), RunMigrationsError>,
+{
+    diesel_migrations::setup_database(conn)?;
+    let pending = pending_versions(conn, known_versions)?;
+    if pending.is_empty() {
+        return Ok(());
+    }
+
+    if run_pending {
+        tracing::info!("Running migrations {}", pending.join(", "));
+        run(conn)?;
+    } else {
+        tracing::warn!(
+            "Migrations {} are pending, enable database.run_migrations to apply them at startup",
+            pending.join(", ")
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers;
+
+    const FUTURE_VERSION: &str = "99991231235959";
+
+    fn insert_version(conn: &impl Connection, version: &str) {
+        conn.execute(&format!(
+            "INSERT INTO __diesel_schema_migrations (version) VALUES ('{}')",
+            version
+        ))
+        .unwrap();
+    }
+
+    #[test]
+    fn backends_have_the_same_migrations() {
+        assert_eq!(versions::POSTGRES, versions::SQLITE);
+    }
+
+    #[test]
+    fn schema_version_is_newest_migration() {
+        assert_eq!(Some(&schema_version()), versions::POSTGRES.iter().max());
+    }
+
+    #[test]
+    fn migrate_postgres_accepts_current_schema() {
+        let Some(conn) = test_helpers::postgres_connection() else {
+            return;
+        };
+
+        assert!(migrate_postgres(&conn, true).is_ok());
+    }
+
+    #[test]
+    fn migrate_postgres_refuses_newer_schema() {
+        let Some(conn) = test_helpers::postgres_connection() else {
+            return;
+        };
+        insert_version(&conn, FUTURE_VERSION);
+
+        let result = migrate_postgres(&conn, true);
+        assert!(matches!(
+            result,
+            Err(MigrationError::SchemaTooNew(versions)) if versions == vec![FUTURE_VERSION]
+        ));
+    }
+
+    #[test]
+    fn migrate_postgres_releases_the_lock_after_an_error() {
+        let Some(conn) = test_helpers::postgres_connection() else {
+            return;
+        };
+        insert_version(&conn, FUTURE_VERSION);
+        assert!(migrate_postgres(&conn, true).is_err());
+
+        let held: i64 = diesel::select(diesel::dsl::sql::<BigInt>(
+            "count(*) FROM pg_locks WHERE locktype = 'advisory' AND pid = pg_backend_pid()",
+        ))
+        .get_result(&conn)
+        .unwrap();
+        assert_eq!(held, 0);
+    }
+
+    #[cfg(feature = "sqlite")]
+    mod sqlite {
+        use super::*;
+
+        fn connection() -> SqliteConnection {
+            SqliteConnection::establish(":memory:").unwrap()
+        }
+
+        #[test]
+        fn pending_migrations_only_run_when_enabled() {
+            let conn = connection();
+
+            migrate_sqlite(&conn, false).unwrap();
+            assert!(conn.execute("SELECT * FROM users").is_err());
+            assert_eq!(
+                pending_versions(&conn, versions::SQLITE).unwrap(),
+                versions::SQLITE
+            );
+
+            migrate_sqlite(&conn, true).unwrap();
+            assert!(conn.execute("SELECT * FROM users").is_ok());
+            assert!(pending_versions(&conn, versions::SQLITE)
+                .unwrap()
+                .is_empty());
+        }
+
+        #[test]
+        fn refuses_newer_schema() {
+            let conn = connection();
+            migrate_sqlite(&conn, true).unwrap();
+            insert_version(&conn, FUTURE_VERSION);
+
+            assert!(matches!(
+                migrate_sqlite(&conn, false),
+                Err(MigrationError::SchemaTooNew(_))
+            ));
+        }
+    }
+}
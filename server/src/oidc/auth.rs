@@ -0,0 +1,176 @@
+use chrono::Utc;
+use uuid::Uuid;
+
+use crate::oidc::id_token::Claims;
+use crate::oidc::{ExternalIdentity, OidcError};
+use crate::repository::{IdentityRepository, UserRepository};
+use crate::session::token;
+use crate::user::{UserData, UserError};
+
+const MAX_USERNAME_LENGTH: usize = 32;
+// After this many taken usernames with a number, a random suffix is used instead
+const MAX_NUMBERED_USERNAMES: u32 = 100;
+
+/// Makes a username from a claim like "jane.doe" or an email like "jane.doe@example.com".
+fn base_username(claims: &Claims, username_claim: &str) -> String {
+    let value = claims
+        .string(username_claim)
+        .or_else(|| {
+            claims
+                .string("email")
+                .and_then(|email| email.split('@').next())
+        })
+        .unwrap_or("");
+    let username: String = value
+        .chars()
+        .filter(|c| c.is_alphanumeric() || matches!(c, '.' | '-' | '_'))
+        .take(MAX_USERNAME_LENGTH)
+        .collect();
+    if username.is_empty() {
+        String::from("user")
+    } else {
+        username
+    }
+}
+
+/// Creates a user with the first free username of `jane`, `jane2`, `jane3` and so on.
+fn create_user<R: UserRepository + ?Sized>(repo: &R, base: &str) -> Result<Uuid, OidcError> {
+    let numbered = (1..=MAX_NUMBERED_USERNAMES).map(|n| match n {
+        1 => String::from(base),
+        n => format!("{}{}", base, n),
+    });
+    let random = std::iter::once(format!("{}-{}", base, &token::generate()[..8]));
+
+    for username in numbered.chain(random) {
+        if repo.find_user_by_username(&username)?.is_some() {
+            continue;
+        }
+        // The password is random and never shown, so the user can only log in with the provider
+        let user_data = UserData {
+            username,
+            password: token::generate(),
+        };
+        match repo.create_user(user_data) {
+            Ok(user) => return Ok(user.id),
+            // Taken by a concurrent registration
+            Err(UserError::UsernameTaken) => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Err(OidcError::GenericError)
+}
+
+impl ExternalIdentity {
+    /// Returns the user linked to the account at the provider, creating the user on their first
+    /// login.
+    pub fn login<R: UserRepository + IdentityRepository + ?Sized>(
+        repo: &R,
+        issuer: &str,
+        claims: &Claims,
+        username_claim: &str,
+    ) -> Result<Uuid, OidcError> {
+        if let Some(identity) = repo.find_identity(issuer, &claims.subject)? {
+            return Ok(identity.user_id);
+        }
+
+        let user_id = create_user(repo, &base_username(claims, username_claim))?;
+        let linked = repo.create_identity(ExternalIdentity {
+            issuer: String::from(issuer),
+            subject: claims.subject.clone(),
+            user_id,
+            created: Utc::now().naive_utc(),
+        });
+        match linked {
+            Ok(identity) => Ok(identity.user_id),
+            Err(e) => {
+                // A concurrent first login of the same account linked another user first
+                repo.destroy_user(user_id)?;
+                match repo.find_identity(issuer, &claims.subject)? {
+                    Some(identity) => Ok(identity.user_id),
+                    None => Err(e),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::oidc::id_token::{self, Expected};
+    use crate::oidc::mock::MockKey;
+    use crate::repository::InMemoryRepository;
+    use crate::test_helpers::*;
+    use serde_json::{json, Value};
+
+    const ISSUER: &str = "https://login.example.com";
+
+    fn verified_claims(values: Value) -> Claims {
+        let mut values = values;
+        values["iss"] = json!(ISSUER);
+        values["aud"] = json!("thermit");
+        values["exp"] = json!(Utc::now().timestamp() + 60);
+        values["nonce"] = json!("nonce");
+        let key = MockKey::generate();
+        let expected = Expected {
+            issuer: ISSUER,
+            client_id: "thermit",
+            nonce: "nonce",
+            now: Utc::now().timestamp(),
+        };
+        id_token::verify(&key.sign(&values), &key.jwks(), &expected).unwrap()
+    }
+
+    #[test]
+    fn first_login_creates_a_user_and_later_logins_find_it() {
+        let repo = InMemoryRepository::default();
+        let claims = verified_claims(json!({ "sub": "1", "preferred_username": "jane" }));
+
+        let user_id =
+            ExternalIdentity::login(&repo, ISSUER, &claims, "preferred_username").unwrap();
+        let user = repo.find_user(user_id).unwrap().unwrap();
+        assert_eq!(user.username, "jane");
+
+        let again = ExternalIdentity::login(&repo, ISSUER, &claims, "preferred_username").unwrap();
+        assert_eq!(again, user_id);
+        assert_eq!(repo.find_all_users().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn new_users_get_a_free_username() {
+        let repo = InMemoryRepository::default();
+        repo.create_user(create_user_data("jane")).unwrap();
+        repo.create_user(create_user_data("jane2")).unwrap();
+
+        let claims = verified_claims(json!({ "sub": "1", "email": "Jane <x>@example.com" }));
+        let user_id =
+            ExternalIdentity::login(&repo, ISSUER, &claims, "preferred_username").unwrap();
+        assert_eq!(repo.find_user(user_id).unwrap().unwrap().username, "Janex");
+
+        let claims = verified_claims(json!({ "sub": "2", "preferred_username": "jane" }));
+        let user_id =
+            ExternalIdentity::login(&repo, ISSUER, &claims, "preferred_username").unwrap();
+        assert_eq!(repo.find_user(user_id).unwrap().unwrap().username, "jane3");
+
+        let claims = verified_claims(json!({ "sub": "3" }));
+        let user_id =
+            ExternalIdentity::login(&repo, ISSUER, &claims, "preferred_username").unwrap();
+        assert_eq!(repo.find_user(user_id).unwrap().unwrap().username, "user");
+    }
+
+    #[test]
+    fn accounts_of_other_issuers_are_other_users() {
+        let repo = InMemoryRepository::default();
+        let claims = verified_claims(json!({ "sub": "1", "preferred_username": "jane" }));
+
+        let first = ExternalIdentity::login(&repo, ISSUER, &claims, "preferred_username").unwrap();
+        let second = ExternalIdentity::login(
+            &repo,
+            "https://other.example.com",
+            &claims,
+            "preferred_username",
+        )
+        .unwrap();
+        assert_ne!(first, second);
+    }
+}
@@ -0,0 +1,281 @@
+//! Verification of the ID tokens returned by a provider.
+//!
+//! ID tokens are JSON Web Tokens signed with one of the RSA keys the provider publishes at its
+//! `jwks_uri`. Only RSA signatures are accepted, which every provider has to support.
+
+use openssl::bn::BigNum;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::rsa::Rsa;
+use openssl::sign::Verifier;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+/// Expired tokens are still accepted this long, in seconds, as clocks are never quite in sync.
+const CLOCK_SKEW: i64 = 60;
+
+/// A public key of the provider.
+#[derive(Deserialize, Clone, Debug)]
+pub struct Jwk {
+    pub kty: String,
+    pub kid: Option<String>,
+    pub alg: Option<String>,
+    #[serde(rename = "use")]
+    pub usage: Option<String>,
+    pub n: Option<String>,
+    pub e: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct JwkSet {
+    pub keys: Vec<Jwk>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Header {
+    alg: String,
+    kid: Option<String>,
+}
+
+/// What the claims of a token have to match.
+pub struct Expected<'a> {
+    pub issuer: &'a str,
+    pub client_id: &'a str,
+    pub nonce: &'a str,
+    /// The current time as a Unix timestamp.
+    pub now: i64,
+}
+
+/// The verified claims of an ID token.
+#[derive(Debug)]
+pub struct Claims {
+    pub subject: String,
+    values: Map<String, Value>,
+}
+
+impl Claims {
+    /// A claim that is a non-empty string.
+    pub fn string(&self, name: &str) -> Option<&str> {
+        self.values
+            .get(name)
+            .and_then(Value::as_str)
+            .filter(|value| !value.is_empty())
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum IdTokenError {
+    /// None of the keys matches the key id of the token, the keys of the provider may have
+    /// changed.
+    UnknownKey,
+    Invalid(&'static str),
+}
+
+fn decode_part(part: &str) -> Result<Vec<u8>, IdTokenError> {
+    base64::decode_config(part, base64::URL_SAFE_NO_PAD)
+        .map_err(|_| IdTokenError::Invalid("the token is not base64url encoded"))
+}
+
+fn digest(alg: &str) -> Option<MessageDigest> {
+    match alg {
+        "RS256" => Some(MessageDigest::sha256()),
+        "RS384" => Some(MessageDigest::sha384()),
+        "RS512" => Some(MessageDigest::sha512()),
+        _ => None,
+    }
+}
+
+fn find_key<'a>(keys: &'a JwkSet, header: &Header) -> Option<&'a Jwk> {
+    keys.keys.iter().find(|key| {
+        key.kty == "RSA"
+            && key.usage.as_deref().is_none_or(|usage| usage == "sig")
+            && key.alg.as_deref().is_none_or(|alg| alg == header.alg)
+            && (header.kid.is_none() || key.kid == header.kid)
+    })
+}
+
+fn signature_is_valid(key: &Jwk, digest: MessageDigest, message: &[u8], signature: &[u8]) -> bool {
+    let public_key = (|| {
+        let n = decode_part(key.n.as_deref()?).ok()?;
+        let e = decode_part(key.e.as_deref()?).ok()?;
+        let rsa =
+            Rsa::from_public_components(BigNum::from_slice(&n).ok()?, BigNum::from_slice(&e).ok()?)
+                .ok()?;
+        PKey::from_rsa(rsa).ok()
+    })();
+
+    public_key.is_some_and(|public_key| {
+        Verifier::new(digest, &public_key)
+            .and_then(|mut verifier| {
+                verifier.update(message)?;
+                verifier.verify(signature)
+            })
+            .unwrap_or(false)
+    })
+}
+
+fn audience_contains(audience: Option<&Value>, client_id: &str) -> bool {
+    match audience {
+        Some(Value::String(audience)) => audience == client_id,
+        Some(Value::Array(audiences)) => audiences.iter().any(|a| a.as_str() == Some(client_id)),
+        _ => false,
+    }
+}
+
+/// Checks the signature and the claims of an ID token.
+pub fn verify(token: &str, keys: &JwkSet, expected: &Expected) -> Result<Claims, IdTokenError> {
+    let parts: Vec<&str> = token.split('.').collect();
+    if parts.len() != 3 {
+        return Err(IdTokenError::Invalid("the token is not a signed JWT"));
+    }
+    let header: Header = serde_json::from_slice(&decode_part(parts[0])?)
+        .map_err(|_| IdTokenError::Invalid("the header of the token is malformed"))?;
+    // Never "none" or a symmetric algorithm, which would make anyone able to sign tokens
+    let digest =
+        digest(&header.alg).ok_or(IdTokenError::Invalid("the token is not signed with RSA"))?;
+    let key = find_key(keys, &header).ok_or(IdTokenError::UnknownKey)?;
+    let message = &token[..parts[0].len() + 1 + parts[1].len()];
+    if !signature_is_valid(key, digest, message.as_bytes(), &decode_part(parts[2])?) {
+        return Err(IdTokenError::Invalid(
+            "the signature of the token is invalid",
+        ));
+    }
+
+    let values: Map<String, Value> = serde_json::from_slice(&decode_part(parts[1])?)
+        .map_err(|_| IdTokenError::Invalid("the claims of the token are malformed"))?;
+    if values.get("iss").and_then(Value::as_str) != Some(expected.issuer) {
+        return Err(IdTokenError::Invalid("the token is from another issuer"));
+    }
+    if !audience_contains(values.get("aud"), expected.client_id) {
+        return Err(IdTokenError::Invalid(
+            "the token is meant for another client",
+        ));
+    }
+    if values
+        .get("azp")
+        .is_some_and(|azp| azp.as_str() != Some(expected.client_id))
+    {
+        return Err(IdTokenError::Invalid(
+            "the token was issued to another client",
+        ));
+    }
+    match values.get("exp").and_then(Value::as_i64) {
+        Some(expires) if expires + CLOCK_SKEW > expected.now => (),
+        _ => return Err(IdTokenError::Invalid("the token is expired")),
+    }
+    if values.get("nonce").and_then(Value::as_str) != Some(expected.nonce) {
+        return Err(IdTokenError::Invalid(
+            "the nonce of the token does not match",
+        ));
+    }
+    let subject = match values.get("sub").and_then(Value::as_str) {
+        Some(subject) if !subject.is_empty() => subject.to_string(),
+        _ => return Err(IdTokenError::Invalid("the token has no subject")),
+    };
+
+    Ok(Claims { subject, values })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::oidc::mock::{self, MockKey};
+    use serde_json::json;
+
+    const NOW: i64 = 1_700_000_000;
+
+    fn expected() -> Expected<'static> {
+        Expected {
+            issuer: "https://login.example.com",
+            client_id: "thermit",
+            nonce: "n-0S6_WzA2Mj",
+            now: NOW,
+        }
+    }
+
+    fn claims() -> Value {
+        json!({
+            "iss": "https://login.example.com",
+            "aud": "thermit",
+            "sub": "248289761001",
+            "exp": NOW + 300,
+            "iat": NOW,
+            "nonce": "n-0S6_WzA2Mj",
+            "preferred_username": "jane",
+        })
+    }
+
+    #[test]
+    fn accepts_a_valid_token() {
+        let key = MockKey::generate();
+        let token = key.sign(&claims());
+
+        let claims = verify(&token, &key.jwks(), &expected()).unwrap();
+        assert_eq!(claims.subject, "248289761001");
+        assert_eq!(claims.string("preferred_username"), Some("jane"));
+        assert_eq!(claims.string("email"), None);
+    }
+
+    #[test]
+    fn rejects_tokens_with_wrong_claims() {
+        let key = MockKey::generate();
+        let cases = vec![
+            ("iss", json!("https://evil.example.com")),
+            ("aud", json!(["other-client"])),
+            ("azp", json!("other-client")),
+            ("exp", json!(NOW - CLOCK_SKEW - 1)),
+            ("nonce", json!("replayed")),
+            ("sub", json!("")),
+        ];
+
+        for (claim, value) in cases {
+            let mut claims = claims();
+            claims[claim] = value;
+            let result = verify(&key.sign(&claims), &key.jwks(), &expected());
+            assert!(
+                matches!(result, Err(IdTokenError::Invalid(_))),
+                "accepted a token with a wrong {}",
+                claim
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_tokens_signed_with_another_key() {
+        let key = MockKey::generate();
+        let other_key = MockKey::generate();
+
+        let result = verify(&other_key.sign(&claims()), &key.jwks(), &expected());
+        assert!(matches!(result, Err(IdTokenError::Invalid(_))));
+
+        let mut tampered = claims();
+        tampered["sub"] = json!("admin");
+        let token = key.sign(&claims());
+        let parts: Vec<&str> = token.split('.').collect();
+        let token = format!(
+            "{}.{}.{}",
+            parts[0],
+            mock::encode(&tampered.to_string()),
+            parts[2]
+        );
+        let result = verify(&token, &key.jwks(), &expected());
+        assert!(matches!(result, Err(IdTokenError::Invalid(_))));
+    }
+
+    #[test]
+    fn rejects_unsigned_tokens_and_unknown_keys() {
+        let key = MockKey::generate();
+        let unsigned = format!(
+            "{}.{}.",
+            mock::encode(r#"{"alg":"none"}"#),
+            mock::encode(&claims().to_string())
+        );
+        assert!(matches!(
+            verify(&unsigned, &key.jwks(), &expected()),
+            Err(IdTokenError::Invalid(_))
+        ));
+
+        let result = verify(&key.sign(&claims()), &JwkSet::default(), &expected());
+        assert_eq!(result.unwrap_err(), IdTokenError::UnknownKey);
+    }
+}
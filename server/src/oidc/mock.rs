@@ -0,0 +1,208 @@
+//! A local identity provider for tests, which logs in every user without asking.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use actix_web::{web, App, HttpRequest, HttpResponse};
+use chrono::Utc;
+use openssl::hash::MessageDigest;
+use openssl::pkey::{PKey, Private};
+use openssl::rsa::Rsa;
+use openssl::sign::Signer;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use crate::config::OidcConfig;
+use crate::oidc::id_token::{Jwk, JwkSet};
+
+const KEY_ID: &str = "test-key";
+pub const CLIENT_ID: &str = "thermit";
+pub const REDIRECT_URI: &str = "http://localhost/callback";
+
+pub fn encode(value: &str) -> String {
+    base64::encode_config(value, base64::URL_SAFE_NO_PAD)
+}
+
+#[derive(Clone)]
+pub struct MockKey {
+    key: PKey<Private>,
+}
+
+impl MockKey {
+    pub fn generate() -> Self {
+        MockKey {
+            key: PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap(),
+        }
+    }
+
+    /// Signs the claims as an ID token with RS256.
+    pub fn sign(&self, claims: &Value) -> String {
+        let header = json!({ "alg": "RS256", "typ": "JWT", "kid": KEY_ID });
+        let message = format!(
+            "{}.{}",
+            encode(&header.to_string()),
+            encode(&claims.to_string())
+        );
+        let mut signer = Signer::new(MessageDigest::sha256(), &self.key).unwrap();
+        signer.update(message.as_bytes()).unwrap();
+        let signature = signer.sign_to_vec().unwrap();
+        format!(
+            "{}.{}",
+            message,
+            base64::encode_config(signature, base64::URL_SAFE_NO_PAD)
+        )
+    }
+
+    pub fn jwks(&self) -> JwkSet {
+        let rsa = self.key.rsa().unwrap();
+        JwkSet {
+            keys: vec![Jwk {
+                kty: String::from("RSA"),
+                kid: Some(String::from(KEY_ID)),
+                alg: Some(String::from("RS256")),
+                usage: Some(String::from("sig")),
+                n: Some(base64::encode_config(
+                    rsa.n().to_vec(),
+                    base64::URL_SAFE_NO_PAD,
+                )),
+                e: Some(base64::encode_config(
+                    rsa.e().to_vec(),
+                    base64::URL_SAFE_NO_PAD,
+                )),
+            }],
+        }
+    }
+}
+
+/// A login the user finished at the provider, waiting for the code to be exchanged.
+struct Grant {
+    claims: Value,
+    nonce: String,
+    code_challenge: String,
+}
+
+#[derive(Clone)]
+struct MockState {
+    key: MockKey,
+    grants: Arc<Mutex<HashMap<String, Grant>>>,
+}
+
+fn issuer(req: &HttpRequest) -> String {
+    format!("http://{}", req.connection_info().host())
+}
+
+async fn discovery(req: HttpRequest) -> HttpResponse {
+    let issuer = issuer(&req);
+    HttpResponse::Ok().json(json!({
+        "issuer": issuer,
+        "authorization_endpoint": format!("{}/authorize", issuer),
+        "token_endpoint": format!("{}/token", issuer),
+        "jwks_uri": format!("{}/jwks", issuer),
+        "token_endpoint_auth_methods_supported": ["client_secret_basic"],
+    }))
+}
+
+async fn jwks(state: web::Data<MockState>) -> HttpResponse {
+    let key = state.key.jwks().keys.remove(0);
+    HttpResponse::Ok()
+        .content_type("application/jwk-set+json")
+        .json(json!({ "keys": [{
+            "kty": key.kty, "kid": key.kid, "alg": key.alg, "use": key.usage, "n": key.n, "e": key.e,
+        }] }))
+}
+
+async fn token(
+    req: HttpRequest,
+    state: web::Data<MockState>,
+    form: web::Form<HashMap<String, String>>,
+) -> HttpResponse {
+    let grant = form
+        .get("code")
+        .and_then(|code| state.grants.lock().unwrap().remove(code));
+    let grant = match grant {
+        Some(grant) => grant,
+        None => return HttpResponse::BadRequest().json(json!({ "error": "invalid_grant" })),
+    };
+    let verifier = form.get("code_verifier").map(String::as_str).unwrap_or("");
+    let challenge =
+        base64::encode_config(Sha256::digest(verifier.as_bytes()), base64::URL_SAFE_NO_PAD);
+    if challenge != grant.code_challenge
+        || form.get("client_id").map(String::as_str) != Some(CLIENT_ID)
+        || form.get("redirect_uri").map(String::as_str) != Some(REDIRECT_URI)
+    {
+        return HttpResponse::BadRequest().json(json!({ "error": "invalid_grant" }));
+    }
+
+    let mut claims = grant.claims;
+    let now = Utc::now().timestamp();
+    claims["iss"] = json!(issuer(&req));
+    claims["aud"] = json!(CLIENT_ID);
+    claims["iat"] = json!(now);
+    claims["exp"] = json!(now + 300);
+    claims["nonce"] = json!(grant.nonce);
+    HttpResponse::Ok().json(json!({
+        "access_token": "unused",
+        "token_type": "Bearer",
+        "id_token": state.key.sign(&claims),
+    }))
+}
+
+/// An identity provider listening on a local port.
+pub struct MockIdp {
+    server: actix_web::test::TestServer,
+    grants: Arc<Mutex<HashMap<String, Grant>>>,
+}
+
+impl MockIdp {
+    pub fn start() -> Self {
+        let state = MockState {
+            key: MockKey::generate(),
+            grants: Arc::new(Mutex::new(HashMap::new())),
+        };
+        let grants = state.grants.clone();
+        let server = actix_web::test::start(move || {
+            App::new()
+                .data(state.clone())
+                .route(
+                    "/.well-known/openid-configuration",
+                    web::get().to(discovery),
+                )
+                .route("/jwks", web::get().to(jwks))
+                .route("/token", web::post().to(token))
+        });
+        MockIdp { server, grants }
+    }
+
+    pub fn config(&self) -> OidcConfig {
+        OidcConfig {
+            enabled: true,
+            issuer: self.server.url("").trim_end_matches('/').to_string(),
+            client_id: String::from(CLIENT_ID),
+            redirect_uri: String::from(REDIRECT_URI),
+            ..OidcConfig::default()
+        }
+    }
+
+    /// Plays the user logging in at the authorization URL and returns the code the provider
+    /// sends the user back with.
+    pub fn log_in(&self, authorization_url: &str, claims: Value) -> String {
+        let (_, query) = authorization_url.split_once('?').unwrap();
+        let params: HashMap<String, String> = form_urlencoded::parse(query.as_bytes())
+            .into_owned()
+            .collect();
+        assert_eq!(params["response_type"], "code");
+        assert_eq!(params["client_id"], CLIENT_ID);
+        assert_eq!(params["code_challenge_method"], "S256");
+
+        let code = crate::session::token::generate();
+        self.grants.lock().unwrap().insert(
+            code.clone(),
+            Grant {
+                claims,
+                nonce: params["nonce"].clone(),
+                code_challenge: params["code_challenge"].clone(),
+            },
+        );
+        code
+    }
+}
@@ -0,0 +1,11 @@
+pub(crate) mod auth;
+pub(crate) mod id_token;
+#[cfg(test)]
+pub(crate) mod mock;
+mod model;
+mod provider;
+mod routes;
+
+pub use model::*;
+pub use provider::OidcProvider;
+pub use routes::init_routes;
@@ -0,0 +1,78 @@
+use chrono::NaiveDateTime;
+use diesel::result::Error as DieselError;
+use uuid::Uuid;
+
+use crate::query::prelude::*;
+use crate::schema::identities;
+use crate::user::UserError;
+
+/// Links the account of a user at an OpenID Connect provider, known by its `sub` claim, to a
+/// user of thermit.
+#[derive(Clone, Queryable, PartialEq, Debug)]
+pub struct ExternalIdentity {
+    pub issuer: String,
+    /// The `sub` claim, which never changes for an account of the provider, unlike its name.
+    pub subject: String,
+    pub user_id: Uuid,
+    pub created: NaiveDateTime,
+}
+
+#[derive(Debug)]
+pub enum OidcError {
+    /// Logins with a provider are not configured.
+    Disabled,
+    /// The provider could not be reached or answered with something unexpected.
+    ProviderUnavailable,
+    /// The state is unknown, expired or was used already.
+    InvalidState,
+    /// The provider did not accept the code or returned an invalid ID token.
+    InvalidLogin(String),
+    DatabaseError,
+    GenericError,
+}
+
+impl ExternalIdentity {
+    pub fn find(
+        conn: &impl SqlConnection,
+        issuer: &str,
+        subject: &str,
+    ) -> Result<Option<ExternalIdentity>, OidcError> {
+        Ok(identities::table
+            .find((issuer, subject))
+            .first(conn)
+            .optional()?)
+    }
+
+    pub fn create(
+        conn: &impl SqlConnection,
+        identity: ExternalIdentity,
+    ) -> Result<ExternalIdentity, OidcError> {
+        diesel::insert_into(identities::table)
+            .values((
+                identities::issuer.eq(&identity.issuer),
+                identities::subject.eq(&identity.subject),
+                identities::user_id.eq(Id(identity.user_id)),
+                identities::created.eq(identity.created),
+            ))
+            .execute(conn)?;
+        Ok(identity)
+    }
+}
+
+impl From<DieselError> for OidcError {
+    fn from(error: DieselError) -> OidcError {
+        match error {
+            DieselError::DatabaseError(_, _) => OidcError::DatabaseError,
+            _ => OidcError::GenericError,
+        }
+    }
+}
+
+impl From<UserError> for OidcError {
+    fn from(error: UserError) -> OidcError {
+        match error {
+            UserError::DatabaseError => OidcError::DatabaseError,
+            _ => OidcError::GenericError,
+        }
+    }
+}
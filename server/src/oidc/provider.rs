@@ -0,0 +1,336 @@
+//! The client side of the authorization code flow with an OpenID Connect provider.
+//!
+//! The provider is discovered on first use. Logins that were started but not finished are kept in
+//! memory, so they are shared by the workers of one server, but a login has to finish at the same
+//! server it was started at.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use actix_web::client::Client;
+use chrono::Utc;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::config::OidcConfig;
+use crate::oidc::id_token::{self, Claims, Expected, IdTokenError, JwkSet};
+use crate::oidc::OidcError;
+use crate::session::token;
+
+const DISCOVERY_PATH: &str = "/.well-known/openid-configuration";
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_RESPONSE_SIZE: usize = 256 * 1024;
+/// Unfinished logins, the oldest is dropped for a new one if there are more.
+const MAX_PENDING_LOGINS: usize = 10_000;
+
+/// The part of the provider metadata that is used.
+#[derive(Deserialize, Clone, Debug)]
+struct Metadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+    #[serde(default)]
+    token_endpoint_auth_methods_supported: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Debug)]
+struct TokenResponse {
+    id_token: String,
+}
+
+/// Where to send the user to log in at the provider.
+#[derive(Serialize, Debug)]
+pub struct Authorization {
+    pub authorization_url: String,
+    /// Returned by the provider together with the code.
+    pub state: String,
+}
+
+struct PendingLogin {
+    code_verifier: String,
+    nonce: String,
+    expires: Instant,
+}
+
+#[derive(Default)]
+struct State {
+    metadata: Option<Metadata>,
+    keys: Option<Arc<JwkSet>>,
+    pending: HashMap<String, PendingLogin>,
+}
+
+impl State {
+    /// Drops the expired logins and, if there are still too many, the one that expires first, so
+    /// that logins that are never finished cannot block new ones.
+    fn add_pending(&mut self, state: String, login: PendingLogin, now: Instant) {
+        self.pending.retain(|_, login| login.expires > now);
+        if self.pending.len() >= MAX_PENDING_LOGINS {
+            let oldest = self
+                .pending
+                .iter()
+                .min_by_key(|(_, login)| login.expires)
+                .map(|(state, _)| state.clone());
+            if let Some(oldest) = oldest {
+                self.pending.remove(&oldest);
+            }
+        }
+        self.pending.insert(state, login);
+    }
+}
+
+#[derive(Clone)]
+pub struct OidcProvider {
+    config: Arc<OidcConfig>,
+    state: Arc<Mutex<State>>,
+}
+
+/// The PKCE challenge of a verifier with the method S256.
+fn code_challenge(code_verifier: &str) -> String {
+    base64::encode_config(
+        Sha256::digest(code_verifier.as_bytes()),
+        base64::URL_SAFE_NO_PAD,
+    )
+}
+
+fn unavailable<E: std::fmt::Display>(error: E) -> OidcError {
+    tracing::warn!("The OIDC provider is unavailable: {}", error);
+    OidcError::ProviderUnavailable
+}
+
+fn client() -> Client {
+    Client::builder().timeout(HTTP_TIMEOUT).finish()
+}
+
+async fn get_json<T: DeserializeOwned>(url: &str) -> Result<T, OidcError> {
+    let mut response = client().get(url).send().await.map_err(unavailable)?;
+    if !response.status().is_success() {
+        return Err(unavailable(format!(
+            "{} returned {}",
+            url,
+            response.status()
+        )));
+    }
+    // Read as bytes, as providers do not agree on the content type, e.g. of key sets
+    let body = response
+        .body()
+        .limit(MAX_RESPONSE_SIZE)
+        .await
+        .map_err(unavailable)?;
+    serde_json::from_slice(&body).map_err(unavailable)
+}
+
+impl OidcProvider {
+    pub fn new(config: &OidcConfig) -> Self {
+        OidcProvider {
+            config: Arc::new(config.clone()),
+            state: Arc::new(Mutex::new(State::default())),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().expect("OIDC state is poisoned")
+    }
+
+    pub fn issuer(&self) -> &str {
+        &self.config.issuer
+    }
+
+    pub fn username_claim(&self) -> &str {
+        &self.config.username_claim
+    }
+
+    async fn metadata(&self) -> Result<Metadata, OidcError> {
+        if !self.config.enabled {
+            return Err(OidcError::Disabled);
+        }
+        if let Some(ref metadata) = self.state().metadata {
+            return Ok(metadata.clone());
+        }
+
+        let url = format!(
+            "{}{}",
+            self.config.issuer.trim_end_matches('/'),
+            DISCOVERY_PATH
+        );
+        let metadata: Metadata = get_json(&url).await?;
+        if metadata.issuer != self.config.issuer {
+            return Err(unavailable(format!(
+                "the provider calls itself {} instead of {}",
+                metadata.issuer, self.config.issuer
+            )));
+        }
+        self.state().metadata = Some(metadata.clone());
+        Ok(metadata)
+    }
+
+    /// The keys of the provider, fetched again with `refresh`, e.g. when the provider rotated
+    /// its keys.
+    async fn keys(&self, metadata: &Metadata, refresh: bool) -> Result<Arc<JwkSet>, OidcError> {
+        if !refresh {
+            if let Some(ref keys) = self.state().keys {
+                return Ok(keys.clone());
+            }
+        }
+        let keys: Arc<JwkSet> = Arc::new(get_json(&metadata.jwks_uri).await?);
+        self.state().keys = Some(keys.clone());
+        Ok(keys)
+    }
+
+    /// Starts a login, the user is sent to the returned URL.
+    pub async fn authorize(&self) -> Result<Authorization, OidcError> {
+        let metadata = self.metadata().await?;
+        let state = token::generate();
+        let nonce = token::generate();
+        let code_verifier = token::generate();
+
+        let query = form_urlencoded::Serializer::new(String::new())
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &self.config.client_id)
+            .append_pair("redirect_uri", &self.config.redirect_uri)
+            .append_pair("scope", &self.config.scopes.join(" "))
+            .append_pair("state", &state)
+            .append_pair("nonce", &nonce)
+            .append_pair("code_challenge", &code_challenge(&code_verifier))
+            .append_pair("code_challenge_method", "S256")
+            .finish();
+        let separator = if metadata.authorization_endpoint.contains('?') {
+            '&'
+        } else {
+            '?'
+        };
+
+        let now = Instant::now();
+        self.state().add_pending(
+            state.clone(),
+            PendingLogin {
+                code_verifier,
+                nonce,
+                expires: now + self.config.login_timeout,
+            },
+            now,
+        );
+
+        Ok(Authorization {
+            authorization_url: format!("{}{}{}", metadata.authorization_endpoint, separator, query),
+            state,
+        })
+    }
+
+    /// Finishes a login with the code the provider sent the user back with and returns the
+    /// verified claims of the user.
+    pub async fn exchange(&self, code: &str, state: &str) -> Result<Claims, OidcError> {
+        let metadata = self.metadata().await?;
+        // Every state can only be used once
+        let login = self
+            .state()
+            .pending
+            .remove(state)
+            .filter(|login| login.expires > Instant::now())
+            .ok_or(OidcError::InvalidState)?;
+
+        let mut form = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", &self.config.redirect_uri),
+            ("client_id", &self.config.client_id),
+            ("code_verifier", &login.code_verifier),
+        ];
+        let mut request = client().post(&metadata.token_endpoint);
+        if let Some(ref secret) = self.config.client_secret {
+            // client_secret_basic is the default of the specification
+            let basic = metadata
+                .token_endpoint_auth_methods_supported
+                .as_ref()
+                .is_none_or(|methods| methods.iter().any(|m| m == "client_secret_basic"));
+            if basic {
+                let encode = |value: &str| {
+                    form_urlencoded::byte_serialize(value.as_bytes()).collect::<String>()
+                };
+                request = request.basic_auth(encode(&self.config.client_id), Some(&encode(secret)));
+            } else {
+                form.push(("client_secret", secret));
+            }
+        }
+
+        let mut response = request.send_form(&form).await.map_err(unavailable)?;
+        if response.status().is_client_error() {
+            return Err(OidcError::InvalidLogin(format!(
+                "the provider rejected the code with {}",
+                response.status()
+            )));
+        }
+        if !response.status().is_success() {
+            return Err(unavailable(format!(
+                "the token endpoint returned {}",
+                response.status()
+            )));
+        }
+        let body = response
+            .body()
+            .limit(MAX_RESPONSE_SIZE)
+            .await
+            .map_err(unavailable)?;
+        let tokens: TokenResponse = serde_json::from_slice(&body).map_err(unavailable)?;
+
+        let expected = Expected {
+            issuer: &metadata.issuer,
+            client_id: &self.config.client_id,
+            nonce: &login.nonce,
+            now: Utc::now().timestamp(),
+        };
+        let keys = self.keys(&metadata, false).await?;
+        let claims = match id_token::verify(&tokens.id_token, &keys, &expected) {
+            Err(IdTokenError::UnknownKey) => {
+                let keys = self.keys(&metadata, true).await?;
+                id_token::verify(&tokens.id_token, &keys, &expected)
+            }
+            result => result,
+        };
+        claims.map_err(|e| match e {
+            IdTokenError::UnknownKey => {
+                OidcError::InvalidLogin(String::from("the token is signed with an unknown key"))
+            }
+            IdTokenError::Invalid(reason) => OidcError::InvalidLogin(String::from(reason)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn code_challenge_uses_s256() {
+        // The example of RFC 7636, appendix B
+        assert_eq!(
+            code_challenge("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk"),
+            "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM"
+        );
+    }
+
+    #[test]
+    fn new_logins_replace_the_oldest_when_full() {
+        let now = Instant::now();
+        let login = |seconds| PendingLogin {
+            code_verifier: String::new(),
+            nonce: String::new(),
+            expires: now + Duration::from_secs(seconds),
+        };
+        let mut state = State::default();
+        state.add_pending("expired".to_string(), login(0), now);
+        for i in 0..MAX_PENDING_LOGINS {
+            state.add_pending(i.to_string(), login(i as u64 + 1), now);
+        }
+        assert_eq!(state.pending.len(), MAX_PENDING_LOGINS);
+        assert!(!state.pending.contains_key("expired"));
+
+        state.add_pending("new".to_string(), login(600), now);
+        assert_eq!(state.pending.len(), MAX_PENDING_LOGINS);
+        assert!(!state.pending.contains_key("0"));
+        assert!(state.pending.contains_key("1"));
+        assert!(state.pending.contains_key("new"));
+    }
+}
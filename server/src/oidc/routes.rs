@@ -0,0 +1,265 @@
+use actix_web::{post, web, HttpRequest, HttpResponse};
+use serde::Deserialize;
+
+use crate::audit::AuditContext;
+use crate::config::AuthConfig;
+use crate::db::DbConn;
+use crate::errors::ServiceError;
+use crate::metrics;
+use crate::oidc::{ExternalIdentity, OidcError, OidcProvider};
+use crate::session::auth::ClientInfo;
+use crate::session::Session;
+use crate::two_factor::auth::LoginResponse;
+use crate::two_factor::{LoginChallenge, TotpSecret};
+
+#[derive(Deserialize, Debug)]
+pub struct CallbackData {
+    /// The code and the state the provider sent the user back with.
+    pub code: String,
+    pub state: String,
+    pub device_name: Option<String>,
+}
+
+/// Starts a login with the provider, the client sends the user to the returned URL.
+#[post("/auth/oidc/authorize")]
+pub async fn authorize(provider: web::Data<OidcProvider>) -> Result<HttpResponse, ServiceError> {
+    let authorization = provider.authorize().await?;
+    Ok(HttpResponse::Ok().json(authorization))
+}
+
+/// Finishes a login with the provider. Users with two-factor authentication at thermit get a
+/// challenge as with a password, the others are not asked for a code.
+#[post("/auth/oidc/callback")]
+pub async fn callback(
+    req: HttpRequest,
+    conn: DbConn,
+    audit: AuditContext,
+    config: web::Data<AuthConfig>,
+    provider: web::Data<OidcProvider>,
+    data: web::Json<CallbackData>,
+) -> Result<HttpResponse, ServiceError> {
+    let CallbackData {
+        code,
+        state,
+        device_name,
+    } = data.into_inner();
+    let client = ClientInfo::from_request(&req, device_name)?;
+
+    // Failed exchanges are audited as well, so the connection is taken either way
+    let claims = provider.exchange(&code, &state).await.map_err(|e| {
+        match e {
+            OidcError::InvalidLogin(ref reason) => {
+                tracing::info!("Login with the OIDC provider failed: {}", reason);
+                metrics::AUTH_FAILURES.inc();
+            }
+            OidcError::InvalidState => metrics::AUTH_FAILURES.inc(),
+            _ => (),
+        }
+        ServiceError::from(e)
+    });
+
+    let provider = provider.into_inner();
+    let response = conn
+        .run(move |repo| -> Result<LoginResponse, ServiceError> {
+            let user_id = claims.and_then(|claims| {
+                Ok(ExternalIdentity::login(
+                    repo,
+                    provider.issuer(),
+                    &claims,
+                    provider.username_claim(),
+                )?)
+            });
+            // Not audited yet, as with a password
+            if let Ok(user_id) = user_id {
+                if TotpSecret::is_enabled(repo, user_id)? {
+                    let challenge = LoginChallenge::start(repo, user_id)?;
+                    return Ok(LoginResponse::Challenge(challenge));
+                }
+            }
+
+            let result = user_id.and_then(|user_id| {
+                let tokens = Session::start(repo, user_id, client, &config)?;
+                Ok((user_id, tokens))
+            });
+            audit
+                .record_login(repo, "oidc", None, result)
+                .map(LoginResponse::Tokens)
+        })
+        .await?;
+    Ok(HttpResponse::Ok().json(response))
+}
+
+pub fn init_routes(config: &mut web::ServiceConfig) {
+    config.service(authorize);
+    config.service(callback);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::OidcConfig;
+    use crate::db::Database;
+    use crate::oidc::mock::MockIdp;
+    use crate::repository::{InMemoryRepository, SessionRepository, UserRepository};
+    use crate::test_helpers::*;
+    use crate::two_factor::totp;
+    use crate::user::LoginThrottle;
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App};
+    use chrono::Utc;
+    use serde_json::{json, Value};
+
+    #[actix_rt::test]
+    async fn login_with_the_provider() {
+        let idp = MockIdp::start();
+        let repo = InMemoryRepository::default();
+        repo.create_user(create_user_data("jane")).unwrap();
+        let mut app = test::init_service(
+            App::new()
+                .data(Database::Memory(repo.clone()))
+                .data(AuthConfig::default())
+                .data(OidcProvider::new(&idp.config()))
+                .configure(init_routes),
+        )
+        .await;
+
+        for _ in 0..2 {
+            let req = test::TestRequest::post()
+                .uri("/auth/oidc/authorize")
+                .to_request();
+            let authorization: Value = test::read_response_json(&mut app, req).await;
+            let url = authorization["authorization_url"].as_str().unwrap();
+            assert!(url.starts_with(&format!("{}/authorize?", idp.config().issuer)));
+            let code = idp.log_in(url, json!({ "sub": "1", "preferred_username": "jane" }));
+
+            let req = test::TestRequest::post()
+                .uri("/auth/oidc/callback")
+                .set_json(&json!({ "code": code, "state": authorization["state"] }))
+                .to_request();
+            let tokens: Value = test::read_response_json(&mut app, req).await;
+            assert_eq!(tokens["token_type"], "Bearer");
+        }
+
+        // The taken username got a number and the second login found the linked user
+        let users = repo.find_all_users().unwrap();
+        assert_eq!(users.len(), 2);
+        let linked = repo.find_user_by_username("jane2").unwrap().unwrap();
+        assert_eq!(repo.find_user_sessions(linked.id).unwrap().len(), 2);
+    }
+
+    #[actix_rt::test]
+    async fn users_with_two_factor_authentication_get_a_challenge() {
+        let idp = MockIdp::start();
+        let repo = InMemoryRepository::default();
+        let mut app = test::init_service(
+            App::new()
+                .data(Database::Memory(repo.clone()))
+                .data(AuthConfig::default())
+                .data(LoginThrottle::default())
+                .data(OidcProvider::new(&idp.config()))
+                .configure(init_routes)
+                .configure(crate::two_factor::init_routes),
+        )
+        .await;
+
+        let mut enrollment = None;
+        for _ in 0..2 {
+            let req = test::TestRequest::post()
+                .uri("/auth/oidc/authorize")
+                .to_request();
+            let authorization: Value = test::read_response_json(&mut app, req).await;
+            let url = authorization["authorization_url"].as_str().unwrap();
+            let code = idp.log_in(url, json!({ "sub": "1", "preferred_username": "jane" }));
+            let req = test::TestRequest::post()
+                .uri("/auth/oidc/callback")
+                .set_json(&json!({ "code": code, "state": authorization["state"] }))
+                .to_request();
+            let response: Value = test::read_response_json(&mut app, req).await;
+
+            if enrollment.is_none() {
+                // The first login created the user, who then enrolls
+                assert_eq!(response["token_type"], "Bearer");
+                let user = repo.find_user_by_username("jane").unwrap().unwrap();
+                let enrolled = TotpSecret::enroll(&repo, user.id, "jane").unwrap();
+                let code = totp::code(&enrolled.secret, Utc::now().timestamp());
+                TotpSecret::confirm(&repo, user.id, &code).unwrap();
+                enrollment = Some(enrolled);
+                continue;
+            }
+
+            assert_eq!(response["second_factor"], "totp");
+            assert!(response.get("access_token").is_none());
+            let req = test::TestRequest::post()
+                .uri("/auth/2fa")
+                .set_json(&json!({
+                    "challenge_token": response["challenge_token"],
+                    "code": enrollment.as_ref().unwrap().recovery_codes[0],
+                }))
+                .to_request();
+            let tokens: Value = test::read_response_json(&mut app, req).await;
+            assert_eq!(tokens["token_type"], "Bearer");
+        }
+    }
+
+    #[actix_rt::test]
+    async fn states_and_codes_are_single_use() {
+        let idp = MockIdp::start();
+        let mut app = test::init_service(
+            App::new()
+                .data(memory_database())
+                .data(AuthConfig::default())
+                .data(OidcProvider::new(&idp.config()))
+                .configure(init_routes),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/auth/oidc/authorize")
+            .to_request();
+        let authorization: Value = test::read_response_json(&mut app, req).await;
+        let url = authorization["authorization_url"].as_str().unwrap();
+        let code = idp.log_in(url, json!({ "sub": "1" }));
+
+        // A state that was not started here
+        let req = test::TestRequest::post()
+            .uri("/auth/oidc/callback")
+            .set_json(&json!({ "code": code, "state": "forged" }))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        // A code the provider does not know
+        let req = test::TestRequest::post()
+            .uri("/auth/oidc/callback")
+            .set_json(&json!({ "code": "guessed", "state": authorization["state"] }))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        // The state was used up by the failed attempt
+        let req = test::TestRequest::post()
+            .uri("/auth/oidc/callback")
+            .set_json(&json!({ "code": code, "state": authorization["state"] }))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_rt::test]
+    async fn routes_are_not_found_without_a_provider() {
+        let mut app = test::init_service(
+            App::new()
+                .data(memory_database())
+                .data(AuthConfig::default())
+                .data(OidcProvider::new(&OidcConfig::default()))
+                .configure(init_routes),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/auth/oidc/authorize")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+}
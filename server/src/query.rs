@@ -0,0 +1,148 @@
+//! Runs the queries of the models on Postgres and on SQLite, so that every query is written once.
+//!
+//! Diesel 1.4 has no connection for several backends, and a query that is generic over the
+//! backend needs its own trait bounds. The models therefore take any `SqlConnection` and run their
+//! queries with the methods of `RunQuery`, which require the query to work on every backend and
+//! pick the connection when it runs. Import `prelude` instead of `diesel::prelude`, whose
+//! `RunQueryDsl` has methods of the same names.
+
+use diesel::connection::Connection;
+use diesel::dsl::AsExprOf;
+use diesel::expression::IntoSql;
+use diesel::query_dsl::methods::{ExecuteDsl, LimitDsl};
+use diesel::query_dsl::LoadQuery;
+use diesel::result::Error as DieselError;
+use diesel::sql_types::Bool;
+use diesel::PgConnection;
+use diesel::QueryResult;
+#[cfg(feature = "sqlite")]
+use diesel::SqliteConnection;
+
+pub mod prelude {
+    pub use super::{not_given, RunQuery, SqlConnection};
+    pub use crate::sql_types::Id;
+    pub use diesel::{
+        BoolExpressionMethods, EscapeExpressionMethods, ExpressionMethods,
+        NullableExpressionMethods, OptionalExtension, QueryDsl, TextExpressionMethods,
+    };
+}
+
+/// A connection to one of the backends.
+pub enum AnyConnection<'a> {
+    Postgres(&'a PgConnection),
+    #[cfg(feature = "sqlite")]
+    Sqlite(&'a SqliteConnection),
+}
+
+pub trait SqlConnection {
+    fn any(&self) -> AnyConnection<'_>;
+
+    /// Runs `f` in a transaction, which is rolled back if `f` returns an error.
+    fn transaction<T, E, F>(&self, f: F) -> Result<T, E>
+    where
+        F: FnOnce() -> Result<T, E>,
+        E: From<DieselError>,
+    {
+        match self.any() {
+            AnyConnection::Postgres(conn) => Connection::transaction(conn, f),
+            #[cfg(feature = "sqlite")]
+            AnyConnection::Sqlite(conn) => Connection::transaction(conn, f),
+        }
+    }
+}
+
+impl SqlConnection for PgConnection {
+    fn any(&self) -> AnyConnection<'_> {
+        AnyConnection::Postgres(self)
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl SqlConnection for SqliteConnection {
+    fn any(&self) -> AnyConnection<'_> {
+        AnyConnection::Sqlite(self)
+    }
+}
+
+/// True if an optional filter is not given, e.g. `column.eq(value).or(not_given(&value))`.
+///
+/// Boxed queries are built for one backend, so queries with optional filters add all of them and
+/// let the filters that are not given match every row.
+pub fn not_given<T>(filter: &Option<T>) -> AsExprOf<bool, Bool> {
+    filter.is_none().into_sql::<Bool>()
+}
+
+/// Queries that load rows of `U` on every backend.
+#[cfg(not(feature = "sqlite"))]
+pub trait Load<U>: LoadQuery<PgConnection, U> {}
+
+#[cfg(not(feature = "sqlite"))]
+impl<T: LoadQuery<PgConnection, U>, U> Load<U> for T {}
+
+#[cfg(feature = "sqlite")]
+pub trait Load<U>: LoadQuery<PgConnection, U> + LoadQuery<SqliteConnection, U> {}
+
+#[cfg(feature = "sqlite")]
+impl<T: LoadQuery<PgConnection, U> + LoadQuery<SqliteConnection, U>, U> Load<U> for T {}
+
+/// Statements that run on every backend.
+#[cfg(not(feature = "sqlite"))]
+pub trait Execute: ExecuteDsl<PgConnection> {}
+
+#[cfg(not(feature = "sqlite"))]
+impl<T: ExecuteDsl<PgConnection>> Execute for T {}
+
+#[cfg(feature = "sqlite")]
+pub trait Execute: ExecuteDsl<PgConnection> + ExecuteDsl<SqliteConnection> {}
+
+#[cfg(feature = "sqlite")]
+impl<T: ExecuteDsl<PgConnection> + ExecuteDsl<SqliteConnection>> Execute for T {}
+
+/// The methods of `RunQueryDsl`, for any `SqlConnection`.
+pub trait RunQuery: Sized {
+    fn load<U>(self, conn: &impl SqlConnection) -> QueryResult<Vec<U>>
+    where
+        Self: Load<U>,
+    {
+        match conn.any() {
+            AnyConnection::Postgres(conn) => {
+                LoadQuery::<PgConnection, U>::internal_load(self, conn)
+            }
+            #[cfg(feature = "sqlite")]
+            AnyConnection::Sqlite(conn) => {
+                LoadQuery::<SqliteConnection, U>::internal_load(self, conn)
+            }
+        }
+    }
+
+    fn get_result<U>(self, conn: &impl SqlConnection) -> QueryResult<U>
+    where
+        Self: Load<U>,
+    {
+        RunQuery::load(self, conn)?
+            .into_iter()
+            .next()
+            .ok_or(DieselError::NotFound)
+    }
+
+    fn first<U>(self, conn: &impl SqlConnection) -> QueryResult<U>
+    where
+        Self: LimitDsl,
+        diesel::dsl::Limit<Self>: Load<U>,
+    {
+        RunQuery::get_result(LimitDsl::limit(self, 1), conn)
+    }
+
+    fn execute(self, conn: &impl SqlConnection) -> QueryResult<usize>
+    where
+        Self: Execute,
+    {
+        match conn.any() {
+            AnyConnection::Postgres(conn) => ExecuteDsl::<PgConnection>::execute(self, conn),
+            #[cfg(feature = "sqlite")]
+            AnyConnection::Sqlite(conn) => ExecuteDsl::<SqliteConnection>::execute(self, conn),
+        }
+    }
+}
+
+impl<T> RunQuery for T {}
@@ -0,0 +1,952 @@
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use chrono::{NaiveDateTime, Utc};
+use uuid::Uuid;
+
+use crate::admin::{self, AdminError, ServerStats, UserQuery, UserSummary};
+use crate::api_token::{ApiToken, ApiTokenError};
+use crate::audit::{AuditError, AuditEvent, AuditQuery};
+use crate::email::{EmailError, EmailToken, Purpose};
+use crate::message::{Message, MessageData, MessageError};
+use crate::oidc::{ExternalIdentity, OidcError};
+use crate::repository::{
+    AdminRepository, ApiTokenRepository, AuditRepository, EmailTokenRepository, IdentityRepository,
+    MessageRepository, RoomRepository, SessionRepository, TwoFactorRepository, UserRepository,
+};
+use crate::room::{Room, RoomData, RoomError};
+use crate::session::{Session, SessionError};
+use crate::two_factor::{LoginChallenge, RecoveryCode, TotpSecret, TwoFactorError};
+use crate::user::{Role, User, UserData, UserError, UserResponse};
+
+/// Keeps all data in memory and mimics the constraints of the database, e.g. deleting a user
+/// also removes the user from all rooms.
+#[derive(Clone, Default)]
+pub struct InMemoryRepository {
+    data: Arc<Mutex<Data>>,
+}
+
+#[derive(Default)]
+struct Data {
+    users: Vec<User>,
+    rooms: Vec<Room>,
+    // Pairs of (room_id, user_id)
+    rooms_users: Vec<(Uuid, Uuid)>,
+    messages: Vec<Message>,
+    sessions: Vec<Session>,
+    totp_secrets: Vec<TotpSecret>,
+    recovery_codes: Vec<RecoveryCode>,
+    login_challenges: Vec<LoginChallenge>,
+    api_tokens: Vec<ApiToken>,
+    identities: Vec<ExternalIdentity>,
+    email_tokens: Vec<EmailToken>,
+    audit_events: Vec<AuditEvent>,
+}
+
+impl InMemoryRepository {
+    fn data(&self) -> MutexGuard<'_, Data> {
+        self.data.lock().expect("in-memory repository is poisoned")
+    }
+}
+
+fn now() -> NaiveDateTime {
+    Utc::now().naive_utc()
+}
+
+impl Data {
+    fn user_exists(&self, user_id: Uuid) -> bool {
+        self.users.iter().any(|user| user.id == user_id)
+    }
+
+    fn room_exists(&self, room_id: Uuid) -> bool {
+        self.rooms.iter().any(|room| room.id == room_id)
+    }
+}
+
+impl UserRepository for InMemoryRepository {
+    fn find_all_users(&self) -> Result<Vec<UserResponse>, UserError> {
+        Ok(self
+            .data()
+            .users
+            .iter()
+            .filter(|user| user.deleted.is_none())
+            .cloned()
+            .map(UserResponse::from)
+            .collect())
+    }
+
+    fn find_user(&self, user_id: Uuid) -> Result<Option<UserResponse>, UserError> {
+        Ok(self
+            .data()
+            .users
+            .iter()
+            .find(|user| user.id == user_id && user.deleted.is_none())
+            .cloned()
+            .map(UserResponse::from))
+    }
+
+    fn find_user_by_id(&self, user_id: Uuid) -> Result<Option<User>, UserError> {
+        Ok(self
+            .data()
+            .users
+            .iter()
+            .find(|user| user.id == user_id)
+            .cloned())
+    }
+
+    fn find_user_by_username(&self, username: &str) -> Result<Option<User>, UserError> {
+        Ok(self
+            .data()
+            .users
+            .iter()
+            .find(|user| user.username == username)
+            .cloned())
+    }
+
+    fn create_user(&self, user_data: UserData) -> Result<UserResponse, UserError> {
+        if self.find_user_by_username(&user_data.username)?.is_some() {
+            return Err(UserError::UsernameTaken);
+        }
+
+        let created = now();
+        let user = User {
+            id: Uuid::new_v4(),
+            username: user_data.username,
+            password: User::generate_password(&user_data.password)?,
+            created,
+            updated: created,
+            role: Role::default(),
+            bot: false,
+            email: None,
+            email_verified: false,
+            locked: false,
+            deleted: None,
+        };
+        self.data().users.push(user.clone());
+        Ok(UserResponse::from(user))
+    }
+
+    fn update_user(&self, user_id: Uuid, user_data: UserData) -> Result<UserResponse, UserError> {
+        // A user may keep their own username
+        if let Some(other) = self.find_user_by_username(&user_data.username)? {
+            if other.id != user_id {
+                return Err(UserError::UsernameTaken);
+            }
+        }
+
+        let mut data = self.data();
+        let user = data
+            .users
+            .iter_mut()
+            .find(|user| user.id == user_id)
+            .ok_or(UserError::UserNotFound)?;
+
+        user.username = user_data.username;
+        // If no password is specified, do not update it
+        let password_changed = !user_data.password.is_empty();
+        if password_changed {
+            user.password = User::generate_password(&user_data.password)?;
+        }
+        user.updated = now();
+        let user = UserResponse::from(user.clone());
+
+        if password_changed {
+            data.sessions.retain(|session| session.user_id != user_id);
+        }
+        Ok(user)
+    }
+
+    fn set_user_role(&self, user_id: Uuid, role: Role) -> Result<UserResponse, UserError> {
+        let mut data = self.data();
+        let user = data
+            .users
+            .iter_mut()
+            .find(|user| user.id == user_id)
+            .ok_or(UserError::UserNotFound)?;
+
+        user.role = role;
+        user.updated = now();
+        Ok(UserResponse::from(user.clone()))
+    }
+
+    fn set_user_bot(&self, user_id: Uuid, bot: bool) -> Result<UserResponse, UserError> {
+        let mut data = self.data();
+        let user = data
+            .users
+            .iter_mut()
+            .find(|user| user.id == user_id)
+            .ok_or(UserError::UserNotFound)?;
+
+        user.bot = bot;
+        user.updated = now();
+        Ok(UserResponse::from(user.clone()))
+    }
+
+    fn set_user_locked(&self, user_id: Uuid, locked: bool) -> Result<(), UserError> {
+        let mut data = self.data();
+        let user = data
+            .users
+            .iter_mut()
+            .find(|user| user.id == user_id)
+            .ok_or(UserError::UserNotFound)?;
+
+        user.locked = locked;
+        user.updated = now();
+        if locked {
+            data.sessions.retain(|session| session.user_id != user_id);
+        }
+        Ok(())
+    }
+
+    fn set_user_deleted(
+        &self,
+        user_id: Uuid,
+        deleted: Option<NaiveDateTime>,
+    ) -> Result<(), UserError> {
+        let mut data = self.data();
+        let user = data
+            .users
+            .iter_mut()
+            .find(|user| user.id == user_id)
+            .ok_or(UserError::UserNotFound)?;
+
+        user.deleted = deleted;
+        user.updated = now();
+        if deleted.is_some() {
+            data.sessions.retain(|session| session.user_id != user_id);
+        }
+        Ok(())
+    }
+
+    fn purge_deleted_users(&self, deleted_before: NaiveDateTime) -> Result<Vec<Uuid>, UserError> {
+        let user_ids: Vec<Uuid> = self
+            .data()
+            .users
+            .iter()
+            .filter(|user| user.deleted.is_some_and(|deleted| deleted < deleted_before))
+            .map(|user| user.id)
+            .collect();
+        for user_id in &user_ids {
+            self.destroy_user(*user_id)?;
+        }
+        Ok(user_ids)
+    }
+
+    fn set_user_password(&self, user_id: Uuid, password_hash: &str) -> Result<(), UserError> {
+        let mut data = self.data();
+        let user = data
+            .users
+            .iter_mut()
+            .find(|user| user.id == user_id)
+            .ok_or(UserError::UserNotFound)?;
+
+        user.password = String::from(password_hash);
+        Ok(())
+    }
+
+    fn find_user_by_email(&self, email: &str) -> Result<Option<User>, UserError> {
+        Ok(self
+            .data()
+            .users
+            .iter()
+            .find(|user| user.email.as_deref() == Some(email))
+            .cloned())
+    }
+
+    fn set_user_email(&self, user_id: Uuid, email: Option<String>) -> Result<(), UserError> {
+        let mut data = self.data();
+        // Mimic the unique index on the email column
+        let taken = data
+            .users
+            .iter()
+            .any(|user| user.id != user_id && email.is_some() && user.email == email);
+        if taken {
+            return Err(UserError::EmailTaken);
+        }
+        let user = data
+            .users
+            .iter_mut()
+            .find(|user| user.id == user_id)
+            .ok_or(UserError::UserNotFound)?;
+
+        user.email = email;
+        user.email_verified = false;
+        user.updated = now();
+        Ok(())
+    }
+
+    fn verify_user_email(&self, user_id: Uuid, email: &str) -> Result<bool, UserError> {
+        let mut data = self.data();
+        match data
+            .users
+            .iter_mut()
+            .find(|user| user.id == user_id && user.email.as_deref() == Some(email))
+        {
+            Some(user) => {
+                user.email_verified = true;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn destroy_user(&self, user_id: Uuid) -> Result<usize, UserError> {
+        let mut data = self.data();
+        let count = data.users.len();
+        data.users.retain(|user| user.id != user_id);
+        let count = count - data.users.len();
+
+        data.rooms_users
+            .retain(|(_, room_user)| *room_user != user_id);
+        // The messages stay in the conversations, like `ON DELETE SET NULL` does
+        for message in data
+            .messages
+            .iter_mut()
+            .filter(|message| message.author == Some(user_id))
+        {
+            message.author = None;
+        }
+        data.sessions.retain(|session| session.user_id != user_id);
+        data.totp_secrets.retain(|secret| secret.user_id != user_id);
+        data.recovery_codes.retain(|code| code.user_id != user_id);
+        data.login_challenges
+            .retain(|challenge| challenge.user_id != user_id);
+        data.api_tokens.retain(|token| token.user_id != user_id);
+        data.identities
+            .retain(|identity| identity.user_id != user_id);
+        data.email_tokens.retain(|token| token.user_id != user_id);
+        Ok(count)
+    }
+}
+
+impl RoomRepository for InMemoryRepository {
+    fn find_all_rooms(&self) -> Result<Vec<Room>, RoomError> {
+        Ok(self.data().rooms.clone())
+    }
+
+    fn find_room(&self, room_id: Uuid) -> Result<Option<Room>, RoomError> {
+        Ok(self
+            .data()
+            .rooms
+            .iter()
+            .find(|room| room.id == room_id)
+            .cloned())
+    }
+
+    fn get_room_user_ids(&self, room_id: Uuid) -> Result<Vec<Uuid>, RoomError> {
+        let data = self.data();
+        if !data.room_exists(room_id) {
+            return Err(RoomError::RoomNotFound);
+        }
+
+        Ok(data
+            .rooms_users
+            .iter()
+            .filter(|(room, _)| *room == room_id)
+            .map(|(_, user)| *user)
+            .collect())
+    }
+
+    fn add_room_users(&self, room_id: Uuid, user_ids: Vec<Uuid>) -> Result<Vec<Uuid>, RoomError> {
+        let mut data = self.data();
+        if !data.room_exists(room_id) {
+            return Err(RoomError::RoomNotFound);
+        }
+
+        let mut added_users = vec![];
+        for user_id in user_ids {
+            let pair = (room_id, user_id);
+            // Deleted users are only kept for a possible restore
+            let active = data
+                .users
+                .iter()
+                .any(|user| user.id == user_id && user.deleted.is_none());
+            if !active || data.rooms_users.contains(&pair) {
+                continue; // Do not add user
+            }
+            data.rooms_users.push(pair);
+            added_users.push(user_id);
+        }
+        Ok(added_users)
+    }
+
+    fn remove_room_users(&self, room_id: Uuid, user_ids: Vec<Uuid>) -> Result<usize, RoomError> {
+        let mut data = self.data();
+        if !data.room_exists(room_id) {
+            return Err(RoomError::RoomNotFound);
+        }
+
+        let count = data.rooms_users.len();
+        data.rooms_users
+            .retain(|(room, user)| *room != room_id || !user_ids.contains(user));
+        Ok(count - data.rooms_users.len())
+    }
+
+    fn create_room(&self, room_data: RoomData) -> Result<Room, RoomError> {
+        let created = now();
+        let room = Room {
+            id: Uuid::new_v4(),
+            name: room_data.name,
+            created,
+            updated: created,
+        };
+        self.data().rooms.push(room.clone());
+        Ok(room)
+    }
+
+    fn update_room(&self, room_id: Uuid, room_data: RoomData) -> Result<Room, RoomError> {
+        let mut data = self.data();
+        let room = data
+            .rooms
+            .iter_mut()
+            .find(|room| room.id == room_id)
+            .ok_or(RoomError::RoomNotFound)?;
+
+        room.name = room_data.name;
+        room.updated = now();
+        Ok(room.clone())
+    }
+
+    fn destroy_room(&self, room_id: Uuid) -> Result<usize, RoomError> {
+        let mut data = self.data();
+        let count = data.rooms.len();
+        data.rooms.retain(|room| room.id != room_id);
+        let count = count - data.rooms.len();
+
+        data.rooms_users.retain(|(room, _)| *room != room_id);
+        data.messages.retain(|message| message.room_id != room_id);
+        Ok(count)
+    }
+}
+
+impl MessageRepository for InMemoryRepository {
+    fn find_message(&self, message_id: Uuid) -> Result<Option<Message>, MessageError> {
+        Ok(self
+            .data()
+            .messages
+            .iter()
+            .find(|message| message.id == message_id)
+            .cloned())
+    }
+
+    fn find_messages_by_room(&self, room_id: Uuid) -> Result<Vec<Message>, MessageError> {
+        Ok(self
+            .data()
+            .messages
+            .iter()
+            .filter(|message| message.room_id == room_id)
+            .cloned()
+            .collect())
+    }
+
+    fn create_message(&self, message_data: MessageData) -> Result<Message, MessageError> {
+        let mut data = self.data();
+        // Mimic the foreign keys of the messages table
+        if !data.room_exists(message_data.room_id) || !data.user_exists(message_data.author) {
+            return Err(MessageError::DatabaseError);
+        }
+
+        let created = now();
+        let message = Message {
+            id: Uuid::new_v4(),
+            room_id: message_data.room_id,
+            author: Some(message_data.author),
+            content: message_data.content,
+            created,
+            updated: created,
+            bot: message_data.bot,
+        };
+        data.messages.push(message.clone());
+        Ok(message)
+    }
+
+    fn update_message(
+        &self,
+        message_id: Uuid,
+        message_data: MessageData,
+    ) -> Result<Message, MessageError> {
+        let mut data = self.data();
+        if !data.room_exists(message_data.room_id) || !data.user_exists(message_data.author) {
+            return Err(MessageError::DatabaseError);
+        }
+
+        let message = data
+            .messages
+            .iter_mut()
+            .find(|message| message.id == message_id)
+            .ok_or(MessageError::MessageNotFound)?;
+
+        message.room_id = message_data.room_id;
+        message.author = Some(message_data.author);
+        message.content = message_data.content;
+        message.bot = message_data.bot;
+        message.updated = now();
+        Ok(message.clone())
+    }
+
+    fn destroy_message(&self, message_id: Uuid) -> Result<usize, MessageError> {
+        let mut data = self.data();
+        let count = data.messages.len();
+        data.messages.retain(|message| message.id != message_id);
+        Ok(count - data.messages.len())
+    }
+}
+
+impl SessionRepository for InMemoryRepository {
+    fn create_session(&self, session: Session) -> Result<Session, SessionError> {
+        let mut data = self.data();
+        // Mimic the foreign key and the unique token hashes of the sessions table
+        let duplicate = data.sessions.iter().any(|other| {
+            other.id == session.id
+                || other.access_token_hash == session.access_token_hash
+                || other.refresh_token_hash == session.refresh_token_hash
+        });
+        if !data.user_exists(session.user_id) || duplicate {
+            return Err(SessionError::DatabaseError);
+        }
+
+        data.sessions.push(session.clone());
+        Ok(session)
+    }
+
+    fn find_session_by_access_token(
+        &self,
+        token_hash: &str,
+    ) -> Result<Option<Session>, SessionError> {
+        Ok(self
+            .data()
+            .sessions
+            .iter()
+            .find(|session| session.access_token_hash == token_hash)
+            .cloned())
+    }
+
+    fn find_session_by_refresh_token(
+        &self,
+        token_hash: &str,
+    ) -> Result<Option<Session>, SessionError> {
+        Ok(self
+            .data()
+            .sessions
+            .iter()
+            .find(|session| session.refresh_token_hash == token_hash)
+            .cloned())
+    }
+
+    fn find_session_by_previous_refresh_token(
+        &self,
+        token_hash: &str,
+    ) -> Result<Option<Session>, SessionError> {
+        Ok(self
+            .data()
+            .sessions
+            .iter()
+            .find(|session| session.previous_refresh_token_hash.as_deref() == Some(token_hash))
+            .cloned())
+    }
+
+    fn find_user_sessions(&self, user_id: Uuid) -> Result<Vec<Session>, SessionError> {
+        let mut sessions: Vec<Session> = self
+            .data()
+            .sessions
+            .iter()
+            .filter(|session| session.user_id == user_id)
+            .cloned()
+            .collect();
+        sessions.sort_by_key(|session| std::cmp::Reverse(session.last_seen));
+        Ok(sessions)
+    }
+
+    fn replace_session_tokens(
+        &self,
+        session: &Session,
+        old_refresh_token_hash: &str,
+    ) -> Result<bool, SessionError> {
+        let mut data = self.data();
+        let stored = data.sessions.iter_mut().find(|stored| {
+            stored.id == session.id && stored.refresh_token_hash == old_refresh_token_hash
+        });
+        match stored {
+            Some(stored) => {
+                *stored = session.clone();
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn touch_session(
+        &self,
+        session_id: Uuid,
+        last_seen: NaiveDateTime,
+    ) -> Result<(), SessionError> {
+        if let Some(session) = self
+            .data()
+            .sessions
+            .iter_mut()
+            .find(|session| session.id == session_id)
+        {
+            session.last_seen = last_seen;
+        }
+        Ok(())
+    }
+
+    fn destroy_session(&self, user_id: Uuid, session_id: Uuid) -> Result<usize, SessionError> {
+        let mut data = self.data();
+        let count = data.sessions.len();
+        data.sessions
+            .retain(|session| session.id != session_id || session.user_id != user_id);
+        Ok(count - data.sessions.len())
+    }
+}
+
+impl TwoFactorRepository for InMemoryRepository {
+    fn find_totp_secret(&self, user_id: Uuid) -> Result<Option<TotpSecret>, TwoFactorError> {
+        Ok(self
+            .data()
+            .totp_secrets
+            .iter()
+            .find(|secret| secret.user_id == user_id)
+            .cloned())
+    }
+
+    fn create_totp_secret(&self, secret: TotpSecret) -> Result<TotpSecret, TwoFactorError> {
+        let mut data = self.data();
+        // Mimic the foreign key and the primary key of the totp_secrets table
+        let exists = data
+            .totp_secrets
+            .iter()
+            .any(|other| other.user_id == secret.user_id);
+        if !data.user_exists(secret.user_id) || exists {
+            return Err(TwoFactorError::DatabaseError);
+        }
+
+        data.totp_secrets.push(secret.clone());
+        Ok(secret)
+    }
+
+    fn enable_totp_secret(&self, user_id: Uuid) -> Result<(), TwoFactorError> {
+        if let Some(secret) = self
+            .data()
+            .totp_secrets
+            .iter_mut()
+            .find(|secret| secret.user_id == user_id)
+        {
+            secret.enabled = true;
+        }
+        Ok(())
+    }
+
+    fn use_totp_step(&self, user_id: Uuid, step: i64) -> Result<bool, TwoFactorError> {
+        let mut data = self.data();
+        let secret = data.totp_secrets.iter_mut().find(|secret| {
+            secret.user_id == user_id && secret.last_used_step.is_none_or(|used| used < step)
+        });
+        match secret {
+            Some(secret) => {
+                secret.last_used_step = Some(step);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn destroy_totp_secret(&self, user_id: Uuid) -> Result<usize, TwoFactorError> {
+        let mut data = self.data();
+        let count = data.totp_secrets.len();
+        data.totp_secrets.retain(|secret| secret.user_id != user_id);
+        Ok(count - data.totp_secrets.len())
+    }
+
+    fn replace_recovery_codes(
+        &self,
+        user_id: Uuid,
+        code_hashes: Vec<String>,
+    ) -> Result<(), TwoFactorError> {
+        let mut data = self.data();
+        if !data.user_exists(user_id) {
+            return Err(TwoFactorError::DatabaseError);
+        }
+
+        data.recovery_codes.retain(|code| code.user_id != user_id);
+        data.recovery_codes.extend(
+            code_hashes
+                .into_iter()
+                .map(|code_hash| RecoveryCode { user_id, code_hash }),
+        );
+        Ok(())
+    }
+
+    fn consume_recovery_code(
+        &self,
+        user_id: Uuid,
+        code_hash: &str,
+    ) -> Result<bool, TwoFactorError> {
+        let mut data = self.data();
+        let count = data.recovery_codes.len();
+        data.recovery_codes
+            .retain(|code| code.user_id != user_id || code.code_hash != code_hash);
+        Ok(data.recovery_codes.len() < count)
+    }
+
+    fn create_login_challenge(
+        &self,
+        challenge: LoginChallenge,
+    ) -> Result<LoginChallenge, TwoFactorError> {
+        let mut data = self.data();
+        if !data.user_exists(challenge.user_id) {
+            return Err(TwoFactorError::DatabaseError);
+        }
+
+        data.login_challenges.push(challenge.clone());
+        Ok(challenge)
+    }
+
+    fn find_login_challenge(
+        &self,
+        token_hash: &str,
+    ) -> Result<Option<LoginChallenge>, TwoFactorError> {
+        Ok(self
+            .data()
+            .login_challenges
+            .iter()
+            .find(|challenge| challenge.token_hash == token_hash)
+            .cloned())
+    }
+
+    fn count_login_challenge_attempt(&self, token_hash: &str) -> Result<(), TwoFactorError> {
+        if let Some(challenge) = self
+            .data()
+            .login_challenges
+            .iter_mut()
+            .find(|challenge| challenge.token_hash == token_hash)
+        {
+            challenge.attempts += 1;
+        }
+        Ok(())
+    }
+
+    fn destroy_login_challenge(&self, token_hash: &str) -> Result<usize, TwoFactorError> {
+        let mut data = self.data();
+        let count = data.login_challenges.len();
+        data.login_challenges
+            .retain(|challenge| challenge.token_hash != token_hash);
+        Ok(count - data.login_challenges.len())
+    }
+}
+
+impl ApiTokenRepository for InMemoryRepository {
+    fn create_api_token(&self, token: ApiToken) -> Result<ApiToken, ApiTokenError> {
+        let mut data = self.data();
+        // Mimic the foreign key and the unique constraint of the api_tokens table
+        let hash_taken = data
+            .api_tokens
+            .iter()
+            .any(|other| other.token_hash == token.token_hash);
+        if !data.user_exists(token.user_id) || hash_taken {
+            return Err(ApiTokenError::DatabaseError);
+        }
+
+        data.api_tokens.push(token.clone());
+        Ok(token)
+    }
+
+    fn find_api_token_by_hash(&self, token_hash: &str) -> Result<Option<ApiToken>, ApiTokenError> {
+        Ok(self
+            .data()
+            .api_tokens
+            .iter()
+            .find(|token| token.token_hash == token_hash)
+            .cloned())
+    }
+
+    fn find_user_api_tokens(&self, user_id: Uuid) -> Result<Vec<ApiToken>, ApiTokenError> {
+        let mut tokens: Vec<ApiToken> = self
+            .data()
+            .api_tokens
+            .iter()
+            .filter(|token| token.user_id == user_id)
+            .cloned()
+            .collect();
+        tokens.sort_by_key(|token| std::cmp::Reverse(token.created));
+        Ok(tokens)
+    }
+
+    fn touch_api_token(
+        &self,
+        token_id: Uuid,
+        last_used: NaiveDateTime,
+    ) -> Result<(), ApiTokenError> {
+        if let Some(token) = self
+            .data()
+            .api_tokens
+            .iter_mut()
+            .find(|token| token.id == token_id)
+        {
+            token.last_used = Some(last_used);
+        }
+        Ok(())
+    }
+
+    fn destroy_api_token(&self, user_id: Uuid, token_id: Uuid) -> Result<usize, ApiTokenError> {
+        let mut data = self.data();
+        let count = data.api_tokens.len();
+        data.api_tokens
+            .retain(|token| token.id != token_id || token.user_id != user_id);
+        Ok(count - data.api_tokens.len())
+    }
+}
+
+impl IdentityRepository for InMemoryRepository {
+    fn find_identity(
+        &self,
+        issuer: &str,
+        subject: &str,
+    ) -> Result<Option<ExternalIdentity>, OidcError> {
+        Ok(self
+            .data()
+            .identities
+            .iter()
+            .find(|identity| identity.issuer == issuer && identity.subject == subject)
+            .cloned())
+    }
+
+    fn create_identity(&self, identity: ExternalIdentity) -> Result<ExternalIdentity, OidcError> {
+        let mut data = self.data();
+        // Mimic the foreign key and the primary key of the identities table
+        let linked = data
+            .identities
+            .iter()
+            .any(|other| other.issuer == identity.issuer && other.subject == identity.subject);
+        if !data.user_exists(identity.user_id) || linked {
+            return Err(OidcError::DatabaseError);
+        }
+
+        data.identities.push(identity.clone());
+        Ok(identity)
+    }
+}
+
+impl EmailTokenRepository for InMemoryRepository {
+    fn create_email_token(&self, token: EmailToken) -> Result<EmailToken, EmailError> {
+        let mut data = self.data();
+        // Mimic the foreign key and the primary key of the email_tokens table
+        let hash_taken = data
+            .email_tokens
+            .iter()
+            .any(|other| other.token_hash == token.token_hash);
+        if !data.user_exists(token.user_id) || hash_taken {
+            return Err(EmailError::DatabaseError);
+        }
+
+        data.email_tokens.push(token.clone());
+        Ok(token)
+    }
+
+    fn find_email_token(&self, token_hash: &str) -> Result<Option<EmailToken>, EmailError> {
+        Ok(self
+            .data()
+            .email_tokens
+            .iter()
+            .find(|token| token.token_hash == token_hash)
+            .cloned())
+    }
+
+    fn find_user_email_tokens(&self, user_id: Uuid) -> Result<Vec<EmailToken>, EmailError> {
+        let mut tokens: Vec<EmailToken> = self
+            .data()
+            .email_tokens
+            .iter()
+            .filter(|token| token.user_id == user_id)
+            .cloned()
+            .collect();
+        tokens.sort_by_key(|token| std::cmp::Reverse(token.expires));
+        Ok(tokens)
+    }
+
+    fn destroy_user_email_tokens(
+        &self,
+        user_id: Uuid,
+        purpose: Purpose,
+    ) -> Result<usize, EmailError> {
+        let mut data = self.data();
+        let count = data.email_tokens.len();
+        data.email_tokens
+            .retain(|token| token.user_id != user_id || token.purpose != purpose);
+        Ok(count - data.email_tokens.len())
+    }
+}
+
+impl AdminRepository for InMemoryRepository {
+    fn find_user_summaries(&self, query: &UserQuery) -> Result<Vec<UserSummary>, AdminError> {
+        let data = self.data();
+        let q = query.q.as_deref().map(str::to_lowercase);
+        let mut users: Vec<User> = data
+            .users
+            .iter()
+            .filter(|user| match &q {
+                Some(q) => {
+                    user.username.to_lowercase().contains(q)
+                        || user.email.as_deref().is_some_and(|email| email.contains(q))
+                }
+                None => true,
+            })
+            .cloned()
+            .collect();
+        users.sort_by(|a, b| a.username.cmp(&b.username));
+        let users: Vec<User> = users
+            .into_iter()
+            .skip(query.offset as usize)
+            .take(query.limit as usize)
+            .collect();
+
+        let memberships: Vec<Uuid> = data
+            .rooms_users
+            .iter()
+            .map(|(_, user_id)| *user_id)
+            .collect();
+        Ok(admin::summarize(users, &memberships))
+    }
+
+    fn server_stats(&self) -> Result<ServerStats, AdminError> {
+        let data = self.data();
+        let count_users = |f: fn(&User) -> bool| data.users.iter().filter(|u| f(u)).count() as i64;
+        Ok(ServerStats {
+            users: data.users.len() as i64,
+            admins: count_users(|user| user.role == Role::Admin),
+            bots: count_users(|user| user.bot),
+            locked_users: count_users(|user| user.locked),
+            rooms: data.rooms.len() as i64,
+            messages: data.messages.len() as i64,
+            sessions: data.sessions.len() as i64,
+            api_tokens: data.api_tokens.len() as i64,
+        })
+    }
+}
+
+impl AuditRepository for InMemoryRepository {
+    fn create_audit_event(&self, event: AuditEvent) -> Result<AuditEvent, AuditError> {
+        self.data().audit_events.push(event.clone());
+        Ok(event)
+    }
+
+    fn find_audit_events(&self, query: &AuditQuery) -> Result<Vec<AuditEvent>, AuditError> {
+        let data = self.data();
+        // Events are appended in order, so the newest are at the end
+        Ok(data
+            .audit_events
+            .iter()
+            .rev()
+            .filter(|event| query.matches(event))
+            .skip(query.offset as usize)
+            .take(query.limit as usize)
+            .cloned()
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    repository_tests!(InMemoryRepository::default());
+}
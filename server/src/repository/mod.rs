@@ -0,0 +1,289 @@
+//! Storage independent access to users, rooms, messages, sessions, second factors, API tokens,
+//! identities at OIDC providers, tokens sent by mail, the overviews of admins and the audit log.
+//!
+//! Routes and business logic use these traits instead of the Diesel models, so that they can run
+//! against Postgres, SQLite and the in-memory store used in tests. Both SQL databases share the
+//! implementations in `sql`.
+
+#[cfg(test)]
+#[macro_use]
+mod tests;
+
+#[cfg(test)]
+mod memory;
+mod sql;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+
+#[cfg(test)]
+pub use memory::InMemoryRepository;
+
+use chrono::NaiveDateTime;
+use uuid::Uuid;
+
+use crate::admin::{AdminError, ServerStats, UserQuery, UserSummary};
+use crate::api_token::{ApiToken, ApiTokenError};
+use crate::audit::{AuditError, AuditEvent, AuditQuery};
+use crate::email::{EmailError, EmailToken, Purpose};
+use crate::message::{Message, MessageData, MessageError};
+use crate::oidc::{ExternalIdentity, OidcError};
+use crate::room::{Room, RoomData, RoomError};
+use crate::session::{Session, SessionError};
+use crate::two_factor::{LoginChallenge, TotpSecret, TwoFactorError};
+use crate::user::{Role, User, UserData, UserError, UserResponse};
+
+pub trait UserRepository {
+    fn find_all_users(&self) -> Result<Vec<UserResponse>, UserError>;
+
+    fn find_user(&self, user_id: Uuid) -> Result<Option<UserResponse>, UserError>;
+
+    // Only for internal use, do not return the result in a route, as it contains the password!
+    fn find_user_by_id(&self, user_id: Uuid) -> Result<Option<User>, UserError>;
+
+    // Only for internal use, do not return the result in a route, as it contains the password!
+    fn find_user_by_username(&self, username: &str) -> Result<Option<User>, UserError>;
+
+    fn create_user(&self, user_data: UserData) -> Result<UserResponse, UserError>;
+
+    /// Keeps the password if the password in `user_data` is empty. Changing the password ends
+    /// all sessions of the user.
+    fn update_user(&self, user_id: Uuid, user_data: UserData) -> Result<UserResponse, UserError>;
+
+    fn set_user_role(&self, user_id: Uuid, role: Role) -> Result<UserResponse, UserError>;
+
+    fn set_user_bot(&self, user_id: Uuid, bot: bool) -> Result<UserResponse, UserError>;
+
+    /// Locking a user also ends all sessions of the user.
+    fn set_user_locked(&self, user_id: Uuid, locked: bool) -> Result<(), UserError>;
+
+    /// Marks the user as deleted, which also ends all sessions of the user, or restores the user
+    /// with `None`.
+    fn set_user_deleted(
+        &self,
+        user_id: Uuid,
+        deleted: Option<NaiveDateTime>,
+    ) -> Result<(), UserError>;
+
+    /// Deletes the users marked as deleted before `deleted_before` for good and returns their
+    /// ids. Their messages are kept without an author.
+    fn purge_deleted_users(&self, deleted_before: NaiveDateTime) -> Result<Vec<Uuid>, UserError>;
+
+    /// Replaces the password hash, e.g. with one made with newer parameters. Unlike
+    /// `update_user`, the password has to be hashed already and `updated` stays the same.
+    fn set_user_password(&self, user_id: Uuid, password_hash: &str) -> Result<(), UserError>;
+
+    // Only for internal use, do not return the result in a route, as it contains the password!
+    fn find_user_by_email(&self, email: &str) -> Result<Option<User>, UserError>;
+
+    /// Replaces the email address, which is unverified afterwards. Fails with `EmailTaken` if
+    /// another user has the address.
+    fn set_user_email(&self, user_id: Uuid, email: Option<String>) -> Result<(), UserError>;
+
+    /// Marks the address of the user as verified. Returns false if the user has another address
+    /// by now.
+    fn verify_user_email(&self, user_id: Uuid, email: &str) -> Result<bool, UserError>;
+
+    fn destroy_user(&self, user_id: Uuid) -> Result<usize, UserError>;
+}
+
+pub trait RoomRepository {
+    fn find_all_rooms(&self) -> Result<Vec<Room>, RoomError>;
+
+    fn find_room(&self, room_id: Uuid) -> Result<Option<Room>, RoomError>;
+
+    /// Fails with `RoomNotFound` if the room does not exist.
+    fn get_room_user_ids(&self, room_id: Uuid) -> Result<Vec<Uuid>, RoomError>;
+
+    /// Returns the ids of the users that were added. Users that do not exist or already are in the
+    /// room are skipped.
+    fn add_room_users(&self, room_id: Uuid, user_ids: Vec<Uuid>) -> Result<Vec<Uuid>, RoomError>;
+
+    fn remove_room_users(&self, room_id: Uuid, user_ids: Vec<Uuid>) -> Result<usize, RoomError>;
+
+    fn create_room(&self, room_data: RoomData) -> Result<Room, RoomError>;
+
+    fn update_room(&self, room_id: Uuid, room_data: RoomData) -> Result<Room, RoomError>;
+
+    fn destroy_room(&self, room_id: Uuid) -> Result<usize, RoomError>;
+}
+
+// There are no message routes yet
+#[allow(dead_code)]
+pub trait MessageRepository {
+    fn find_message(&self, message_id: Uuid) -> Result<Option<Message>, MessageError>;
+
+    fn find_messages_by_room(&self, room_id: Uuid) -> Result<Vec<Message>, MessageError>;
+
+    fn create_message(&self, message_data: MessageData) -> Result<Message, MessageError>;
+
+    fn update_message(
+        &self,
+        message_id: Uuid,
+        message_data: MessageData,
+    ) -> Result<Message, MessageError>;
+
+    fn destroy_message(&self, message_id: Uuid) -> Result<usize, MessageError>;
+}
+
+pub trait SessionRepository {
+    fn create_session(&self, session: Session) -> Result<Session, SessionError>;
+
+    fn find_session_by_access_token(
+        &self,
+        token_hash: &str,
+    ) -> Result<Option<Session>, SessionError>;
+
+    fn find_session_by_refresh_token(
+        &self,
+        token_hash: &str,
+    ) -> Result<Option<Session>, SessionError>;
+
+    /// Finds the session whose refresh token was exchanged last for a new one.
+    fn find_session_by_previous_refresh_token(
+        &self,
+        token_hash: &str,
+    ) -> Result<Option<Session>, SessionError>;
+
+    /// Returns the sessions of a user, the most recently used first.
+    fn find_user_sessions(&self, user_id: Uuid) -> Result<Vec<Session>, SessionError>;
+
+    /// Stores the new tokens of `session` if its refresh token still has the old hash. Returns
+    /// false if another request replaced the tokens first.
+    fn replace_session_tokens(
+        &self,
+        session: &Session,
+        old_refresh_token_hash: &str,
+    ) -> Result<bool, SessionError>;
+
+    fn touch_session(&self, session_id: Uuid, last_seen: NaiveDateTime)
+        -> Result<(), SessionError>;
+
+    /// Only removes the session if it belongs to the user.
+    fn destroy_session(&self, user_id: Uuid, session_id: Uuid) -> Result<usize, SessionError>;
+}
+
+pub trait TwoFactorRepository {
+    fn find_totp_secret(&self, user_id: Uuid) -> Result<Option<TotpSecret>, TwoFactorError>;
+
+    fn create_totp_secret(&self, secret: TotpSecret) -> Result<TotpSecret, TwoFactorError>;
+
+    fn enable_totp_secret(&self, user_id: Uuid) -> Result<(), TwoFactorError>;
+
+    /// Records that a code of the period `step` was used. Returns false if a code of this or a
+    /// later period was used already.
+    fn use_totp_step(&self, user_id: Uuid, step: i64) -> Result<bool, TwoFactorError>;
+
+    fn destroy_totp_secret(&self, user_id: Uuid) -> Result<usize, TwoFactorError>;
+
+    fn replace_recovery_codes(
+        &self,
+        user_id: Uuid,
+        code_hashes: Vec<String>,
+    ) -> Result<(), TwoFactorError>;
+
+    /// Removes the recovery code, returns false if the user has no such code.
+    fn consume_recovery_code(&self, user_id: Uuid, code_hash: &str)
+        -> Result<bool, TwoFactorError>;
+
+    fn create_login_challenge(
+        &self,
+        challenge: LoginChallenge,
+    ) -> Result<LoginChallenge, TwoFactorError>;
+
+    fn find_login_challenge(
+        &self,
+        token_hash: &str,
+    ) -> Result<Option<LoginChallenge>, TwoFactorError>;
+
+    fn count_login_challenge_attempt(&self, token_hash: &str) -> Result<(), TwoFactorError>;
+
+    fn destroy_login_challenge(&self, token_hash: &str) -> Result<usize, TwoFactorError>;
+}
+
+pub trait ApiTokenRepository {
+    fn create_api_token(&self, token: ApiToken) -> Result<ApiToken, ApiTokenError>;
+
+    fn find_api_token_by_hash(&self, token_hash: &str) -> Result<Option<ApiToken>, ApiTokenError>;
+
+    /// The tokens of the user, the newest first.
+    fn find_user_api_tokens(&self, user_id: Uuid) -> Result<Vec<ApiToken>, ApiTokenError>;
+
+    fn touch_api_token(
+        &self,
+        token_id: Uuid,
+        last_used: NaiveDateTime,
+    ) -> Result<(), ApiTokenError>;
+
+    /// Deletes a token of the user, tokens of other users are not deleted.
+    fn destroy_api_token(&self, user_id: Uuid, token_id: Uuid) -> Result<usize, ApiTokenError>;
+}
+
+pub trait IdentityRepository {
+    fn find_identity(
+        &self,
+        issuer: &str,
+        subject: &str,
+    ) -> Result<Option<ExternalIdentity>, OidcError>;
+
+    /// Fails if the identity is linked already.
+    fn create_identity(&self, identity: ExternalIdentity) -> Result<ExternalIdentity, OidcError>;
+}
+
+pub trait EmailTokenRepository {
+    fn create_email_token(&self, token: EmailToken) -> Result<EmailToken, EmailError>;
+
+    fn find_email_token(&self, token_hash: &str) -> Result<Option<EmailToken>, EmailError>;
+
+    /// The tokens of the user, the latest to expire first.
+    fn find_user_email_tokens(&self, user_id: Uuid) -> Result<Vec<EmailToken>, EmailError>;
+
+    fn destroy_user_email_tokens(
+        &self,
+        user_id: Uuid,
+        purpose: Purpose,
+    ) -> Result<usize, EmailError>;
+}
+
+pub trait AdminRepository {
+    /// Users whose username or email address contains the query, ordered by username.
+    fn find_user_summaries(&self, query: &UserQuery) -> Result<Vec<UserSummary>, AdminError>;
+
+    fn server_stats(&self) -> Result<ServerStats, AdminError>;
+}
+
+/// The audit log is append-only, there is no way to change or delete events.
+pub trait AuditRepository {
+    fn create_audit_event(&self, event: AuditEvent) -> Result<AuditEvent, AuditError>;
+
+    /// The events matching the query, the newest first.
+    fn find_audit_events(&self, query: &AuditQuery) -> Result<Vec<AuditEvent>, AuditError>;
+}
+
+/// Everything a route can access, implemented by every storage backend.
+pub trait Repository:
+    UserRepository
+    + RoomRepository
+    + MessageRepository
+    + SessionRepository
+    + TwoFactorRepository
+    + ApiTokenRepository
+    + IdentityRepository
+    + EmailTokenRepository
+    + AdminRepository
+    + AuditRepository
+{
+}
+
+impl<T> Repository for T where
+    T: UserRepository
+        + RoomRepository
+        + MessageRepository
+        + SessionRepository
+        + TwoFactorRepository
+        + ApiTokenRepository
+        + IdentityRepository
+        + EmailTokenRepository
+        + AdminRepository
+        + AuditRepository
+{
+}
@@ -0,0 +1,370 @@
+use chrono::NaiveDateTime;
+use uuid::Uuid;
+
+use crate::admin::{AdminError, ServerStats, UserQuery, UserSummary};
+use crate::api_token::{ApiToken, ApiTokenError};
+use crate::audit::{AuditError, AuditEvent, AuditQuery};
+use crate::email::{EmailError, EmailToken, Purpose};
+use crate::message::{Message, MessageData, MessageError};
+use crate::oidc::{ExternalIdentity, OidcError};
+use crate::query::SqlConnection;
+use crate::repository::{
+    AdminRepository, ApiTokenRepository, AuditRepository, EmailTokenRepository, IdentityRepository,
+    MessageRepository, RoomRepository, SessionRepository, TwoFactorRepository, UserRepository,
+};
+use crate::room::{Room, RoomData, RoomError};
+use crate::session::{Session, SessionError};
+use crate::two_factor::{LoginChallenge, RecoveryCode, TotpSecret, TwoFactorError};
+use crate::user::{Role, User, UserData, UserError, UserResponse};
+
+impl<C: SqlConnection> UserRepository for C {
+    fn find_all_users(&self) -> Result<Vec<UserResponse>, UserError> {
+        User::find_all(self)
+    }
+
+    fn find_user(&self, user_id: Uuid) -> Result<Option<UserResponse>, UserError> {
+        User::find(self, user_id)
+    }
+
+    fn find_user_by_id(&self, user_id: Uuid) -> Result<Option<User>, UserError> {
+        User::_find(self, user_id)
+    }
+
+    fn find_user_by_username(&self, username: &str) -> Result<Option<User>, UserError> {
+        User::_find_by_username(self, username)
+    }
+
+    fn create_user(&self, user_data: UserData) -> Result<UserResponse, UserError> {
+        User::create(user_data, self)
+    }
+
+    fn update_user(&self, user_id: Uuid, user_data: UserData) -> Result<UserResponse, UserError> {
+        User::update(user_id, user_data, self)
+    }
+
+    fn set_user_role(&self, user_id: Uuid, role: Role) -> Result<UserResponse, UserError> {
+        User::set_role(self, user_id, role)
+    }
+
+    fn set_user_bot(&self, user_id: Uuid, bot: bool) -> Result<UserResponse, UserError> {
+        User::set_bot(self, user_id, bot)
+    }
+
+    fn set_user_locked(&self, user_id: Uuid, locked: bool) -> Result<(), UserError> {
+        User::set_locked(self, user_id, locked)
+    }
+
+    fn set_user_deleted(
+        &self,
+        user_id: Uuid,
+        deleted: Option<NaiveDateTime>,
+    ) -> Result<(), UserError> {
+        User::set_deleted(self, user_id, deleted)
+    }
+
+    fn purge_deleted_users(&self, deleted_before: NaiveDateTime) -> Result<Vec<Uuid>, UserError> {
+        User::purge_deleted(self, deleted_before)
+    }
+
+    fn set_user_password(&self, user_id: Uuid, password_hash: &str) -> Result<(), UserError> {
+        User::set_password(self, user_id, password_hash)
+    }
+
+    fn find_user_by_email(&self, email: &str) -> Result<Option<User>, UserError> {
+        User::_find_by_email(self, email)
+    }
+
+    fn set_user_email(&self, user_id: Uuid, email: Option<String>) -> Result<(), UserError> {
+        User::set_email(self, user_id, email)
+    }
+
+    fn verify_user_email(&self, user_id: Uuid, email: &str) -> Result<bool, UserError> {
+        User::verify_email(self, user_id, email)
+    }
+
+    fn destroy_user(&self, user_id: Uuid) -> Result<usize, UserError> {
+        User::destroy(self, user_id)
+    }
+}
+
+impl<C: SqlConnection> RoomRepository for C {
+    fn find_all_rooms(&self) -> Result<Vec<Room>, RoomError> {
+        Room::find_all(self)
+    }
+
+    fn find_room(&self, room_id: Uuid) -> Result<Option<Room>, RoomError> {
+        Room::find(self, room_id)
+    }
+
+    fn get_room_user_ids(&self, room_id: Uuid) -> Result<Vec<Uuid>, RoomError> {
+        Room::get_user_ids(self, room_id)
+    }
+
+    fn add_room_users(&self, room_id: Uuid, user_ids: Vec<Uuid>) -> Result<Vec<Uuid>, RoomError> {
+        Room::add_users(self, room_id, user_ids)
+    }
+
+    fn remove_room_users(&self, room_id: Uuid, user_ids: Vec<Uuid>) -> Result<usize, RoomError> {
+        Room::remove_users(self, room_id, user_ids)
+    }
+
+    fn create_room(&self, room_data: RoomData) -> Result<Room, RoomError> {
+        Room::create(room_data, self)
+    }
+
+    fn update_room(&self, room_id: Uuid, room_data: RoomData) -> Result<Room, RoomError> {
+        Room::update(self, room_id, room_data)
+    }
+
+    fn destroy_room(&self, room_id: Uuid) -> Result<usize, RoomError> {
+        Room::destroy(self, room_id)
+    }
+}
+
+impl<C: SqlConnection> MessageRepository for C {
+    fn find_message(&self, message_id: Uuid) -> Result<Option<Message>, MessageError> {
+        Message::find(message_id, self)
+    }
+
+    fn find_messages_by_room(&self, room_id: Uuid) -> Result<Vec<Message>, MessageError> {
+        Message::find_all_by_room(room_id, self)
+    }
+
+    fn create_message(&self, message_data: MessageData) -> Result<Message, MessageError> {
+        Message::create(message_data, self)
+    }
+
+    fn update_message(
+        &self,
+        message_id: Uuid,
+        message_data: MessageData,
+    ) -> Result<Message, MessageError> {
+        Message::update(message_id, message_data, self)
+    }
+
+    fn destroy_message(&self, message_id: Uuid) -> Result<usize, MessageError> {
+        Message::destroy(message_id, self)
+    }
+}
+
+impl<C: SqlConnection> SessionRepository for C {
+    fn create_session(&self, session: Session) -> Result<Session, SessionError> {
+        Session::create(self, session)
+    }
+
+    fn find_session_by_access_token(
+        &self,
+        token_hash: &str,
+    ) -> Result<Option<Session>, SessionError> {
+        Session::find_by_access_token(self, token_hash)
+    }
+
+    fn find_session_by_refresh_token(
+        &self,
+        token_hash: &str,
+    ) -> Result<Option<Session>, SessionError> {
+        Session::find_by_refresh_token(self, token_hash)
+    }
+
+    fn find_session_by_previous_refresh_token(
+        &self,
+        token_hash: &str,
+    ) -> Result<Option<Session>, SessionError> {
+        Session::find_by_previous_refresh_token(self, token_hash)
+    }
+
+    fn find_user_sessions(&self, user_id: Uuid) -> Result<Vec<Session>, SessionError> {
+        Session::find_by_user(self, user_id)
+    }
+
+    fn replace_session_tokens(
+        &self,
+        session: &Session,
+        old_refresh_token_hash: &str,
+    ) -> Result<bool, SessionError> {
+        Session::replace_tokens(self, session, old_refresh_token_hash)
+    }
+
+    fn touch_session(
+        &self,
+        session_id: Uuid,
+        last_seen: NaiveDateTime,
+    ) -> Result<(), SessionError> {
+        Session::touch(self, session_id, last_seen)
+    }
+
+    fn destroy_session(&self, user_id: Uuid, session_id: Uuid) -> Result<usize, SessionError> {
+        Session::destroy(self, user_id, session_id)
+    }
+}
+
+impl<C: SqlConnection> TwoFactorRepository for C {
+    fn find_totp_secret(&self, user_id: Uuid) -> Result<Option<TotpSecret>, TwoFactorError> {
+        TotpSecret::find(self, user_id)
+    }
+
+    fn create_totp_secret(&self, secret: TotpSecret) -> Result<TotpSecret, TwoFactorError> {
+        TotpSecret::create(self, secret)
+    }
+
+    fn enable_totp_secret(&self, user_id: Uuid) -> Result<(), TwoFactorError> {
+        TotpSecret::enable(self, user_id)
+    }
+
+    fn use_totp_step(&self, user_id: Uuid, step: i64) -> Result<bool, TwoFactorError> {
+        TotpSecret::use_step(self, user_id, step)
+    }
+
+    fn destroy_totp_secret(&self, user_id: Uuid) -> Result<usize, TwoFactorError> {
+        TotpSecret::destroy(self, user_id)
+    }
+
+    fn replace_recovery_codes(
+        &self,
+        user_id: Uuid,
+        code_hashes: Vec<String>,
+    ) -> Result<(), TwoFactorError> {
+        RecoveryCode::replace(self, user_id, code_hashes)
+    }
+
+    fn consume_recovery_code(
+        &self,
+        user_id: Uuid,
+        code_hash: &str,
+    ) -> Result<bool, TwoFactorError> {
+        RecoveryCode::consume(self, user_id, code_hash)
+    }
+
+    fn create_login_challenge(
+        &self,
+        challenge: LoginChallenge,
+    ) -> Result<LoginChallenge, TwoFactorError> {
+        LoginChallenge::create(self, challenge)
+    }
+
+    fn find_login_challenge(
+        &self,
+        token_hash: &str,
+    ) -> Result<Option<LoginChallenge>, TwoFactorError> {
+        LoginChallenge::find(self, token_hash)
+    }
+
+    fn count_login_challenge_attempt(&self, token_hash: &str) -> Result<(), TwoFactorError> {
+        LoginChallenge::count_attempt(self, token_hash)
+    }
+
+    fn destroy_login_challenge(&self, token_hash: &str) -> Result<usize, TwoFactorError> {
+        LoginChallenge::destroy(self, token_hash)
+    }
+}
+
+impl<C: SqlConnection> ApiTokenRepository for C {
+    fn create_api_token(&self, token: ApiToken) -> Result<ApiToken, ApiTokenError> {
+        ApiToken::create(self, token)
+    }
+
+    fn find_api_token_by_hash(&self, token_hash: &str) -> Result<Option<ApiToken>, ApiTokenError> {
+        ApiToken::find_by_hash(self, token_hash)
+    }
+
+    fn find_user_api_tokens(&self, user_id: Uuid) -> Result<Vec<ApiToken>, ApiTokenError> {
+        ApiToken::find_by_user(self, user_id)
+    }
+
+    fn touch_api_token(
+        &self,
+        token_id: Uuid,
+        last_used: NaiveDateTime,
+    ) -> Result<(), ApiTokenError> {
+        ApiToken::touch(self, token_id, last_used)
+    }
+
+    fn destroy_api_token(&self, user_id: Uuid, token_id: Uuid) -> Result<usize, ApiTokenError> {
+        ApiToken::destroy(self, user_id, token_id)
+    }
+}
+
+impl<C: SqlConnection> IdentityRepository for C {
+    fn find_identity(
+        &self,
+        issuer: &str,
+        subject: &str,
+    ) -> Result<Option<ExternalIdentity>, OidcError> {
+        ExternalIdentity::find(self, issuer, subject)
+    }
+
+    fn create_identity(&self, identity: ExternalIdentity) -> Result<ExternalIdentity, OidcError> {
+        ExternalIdentity::create(self, identity)
+    }
+}
+
+impl<C: SqlConnection> EmailTokenRepository for C {
+    fn create_email_token(&self, token: EmailToken) -> Result<EmailToken, EmailError> {
+        EmailToken::create(self, token)
+    }
+
+    fn find_email_token(&self, token_hash: &str) -> Result<Option<EmailToken>, EmailError> {
+        EmailToken::find(self, token_hash)
+    }
+
+    fn find_user_email_tokens(&self, user_id: Uuid) -> Result<Vec<EmailToken>, EmailError> {
+        EmailToken::find_by_user(self, user_id)
+    }
+
+    fn destroy_user_email_tokens(
+        &self,
+        user_id: Uuid,
+        purpose: Purpose,
+    ) -> Result<usize, EmailError> {
+        EmailToken::destroy_by_user(self, user_id, purpose)
+    }
+}
+
+impl<C: SqlConnection> AdminRepository for C {
+    fn find_user_summaries(&self, query: &UserQuery) -> Result<Vec<UserSummary>, AdminError> {
+        UserSummary::search(self, query)
+    }
+
+    fn server_stats(&self) -> Result<ServerStats, AdminError> {
+        ServerStats::collect(self)
+    }
+}
+
+impl<C: SqlConnection> AuditRepository for C {
+    fn create_audit_event(&self, event: AuditEvent) -> Result<AuditEvent, AuditError> {
+        AuditEvent::create(self, event)
+    }
+
+    fn find_audit_events(&self, query: &AuditQuery) -> Result<Vec<AuditEvent>, AuditError> {
+        AuditEvent::find(self, query)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    repository_tests!(crate::test_helpers::connection());
+
+    #[test]
+    fn audit_events_cannot_be_changed_or_deleted() {
+        use crate::query::{RunQuery, SqlConnection};
+
+        let conn = crate::test_helpers::connection();
+        let event = AuditEvent::new(crate::audit::Action::LoginFailed, None, None);
+        conn.create_audit_event(event).unwrap();
+
+        // A failed statement aborts the test transaction on Postgres, so each runs in a savepoint
+        for statement in &[
+            "UPDATE audit_events SET action = 'login.succeeded'",
+            "DELETE FROM audit_events",
+        ] {
+            let result = conn.transaction(|| diesel::sql_query(*statement).execute(&conn));
+            assert!(result.is_err(), "{}", statement);
+        }
+        assert_eq!(
+            conn.find_audit_events(&AuditQuery::default())
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+}
@@ -0,0 +1,7 @@
+use diesel::connection::SimpleConnection;
+use diesel::{QueryResult, SqliteConnection};
+
+/// Has to be called for every new connection, as SQLite does not enforce foreign keys otherwise.
+pub fn prepare_connection(conn: &SqliteConnection) -> QueryResult<()> {
+    conn.batch_execute("PRAGMA foreign_keys = ON; PRAGMA busy_timeout = 5000;")
+}
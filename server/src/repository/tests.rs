@@ -0,0 +1,846 @@
+//! Tests that every repository implementation has to pass, so that all backends behave the same.
+
+/// Generates the tests for the repository returned by the given expression.
+macro_rules! repository_tests {
+    ($repo:expr) => {
+        use crate::admin::{ServerStats, UserQuery};
+        use crate::api_token::{ApiToken, ApiTokenError};
+        use crate::audit::{Action, AuditEvent, AuditQuery};
+        use crate::config::AuthConfig;
+        use crate::email::{EmailToken, Purpose};
+        use crate::message::MessageError;
+        use crate::oidc::{ExternalIdentity, OidcError};
+        use crate::repository::{
+            AdminRepository, ApiTokenRepository, AuditRepository, EmailTokenRepository, IdentityRepository,
+            MessageRepository, RoomRepository, SessionRepository, TwoFactorRepository,
+            UserRepository,
+        };
+        use crate::room::RoomError;
+        use crate::session::SessionError;
+        use crate::test_helpers::*;
+        use crate::two_factor::{LoginChallenge, TotpSecret, TwoFactorError};
+        use crate::user::{Role, UserData, UserError};
+        use uuid::Uuid;
+
+        #[test]
+        fn create_user_fails_when_username_is_taken() {
+            let repo = $repo;
+
+            repo.create_user(create_user_data("testUser")).unwrap();
+            let result = repo.create_user(create_user_data("testUser"));
+
+            assert!(matches!(result, Err(UserError::UsernameTaken)));
+        }
+
+        #[test]
+        fn create_user_hashes_password() {
+            let repo = $repo;
+
+            let user_data = create_user_data("testUser");
+            repo.create_user(user_data.clone()).unwrap();
+            let user = repo.find_user_by_username("testUser").unwrap().unwrap();
+
+            assert_ne!(user.password, user_data.password);
+        }
+
+        #[test]
+        fn destroy_user_removes_user_from_rooms_and_anonymizes_messages() {
+            let repo = $repo;
+
+            let room = repo.create_room(create_room_data("testRoom")).unwrap();
+            let user = repo.create_user(create_user_data("testUser")).unwrap();
+            repo.add_room_users(room.id, vec![user.id]).unwrap();
+            repo.create_message(create_message_data("Hello thermit!", room.id, user.id))
+                .unwrap();
+
+            assert_eq!(repo.destroy_user(user.id).unwrap(), 1);
+            assert_eq!(repo.get_room_user_ids(room.id).unwrap().len(), 0);
+            let messages = repo.find_messages_by_room(room.id).unwrap();
+            assert_eq!(messages.len(), 1);
+            assert_eq!(messages[0].author, None);
+            assert_eq!(messages[0].content, "Hello thermit!");
+        }
+
+        #[test]
+        fn add_room_users_skips_unknown_and_existing_users() {
+            let repo = $repo;
+
+            let room = repo.create_room(create_room_data("testRoom")).unwrap();
+            let user = repo.create_user(create_user_data("testUser")).unwrap();
+
+            let added = repo
+                .add_room_users(room.id, vec![user.id, Uuid::new_v4()])
+                .unwrap();
+            assert_eq!(added, vec![user.id]);
+
+            let added = repo.add_room_users(room.id, vec![user.id]).unwrap();
+            assert_eq!(added.len(), 0);
+        }
+
+        #[test]
+        fn deleted_users_are_hidden_and_not_added_to_rooms() {
+            let repo = $repo;
+
+            let room = repo.create_room(create_room_data("testRoom")).unwrap();
+            let user = repo.create_user(create_user_data("testUser")).unwrap();
+            let other = repo.create_user(create_user_data("otherUser")).unwrap();
+            repo.set_user_deleted(user.id, Some(chrono::Utc::now().naive_utc()))
+                .unwrap();
+
+            let users = repo.find_all_users().unwrap();
+            assert_eq!(users.len(), 1);
+            assert_eq!(users[0].id, other.id);
+            assert!(repo.find_user(user.id).unwrap().is_none());
+            let added = repo
+                .add_room_users(room.id, vec![user.id, other.id])
+                .unwrap();
+            assert_eq!(added, vec![other.id]);
+
+            // Still there for a restore
+            assert!(repo.find_user_by_id(user.id).unwrap().is_some());
+        }
+
+        #[test]
+        fn room_operations_fail_when_room_does_not_exist() {
+            let repo = $repo;
+
+            assert!(matches!(
+                repo.get_room_user_ids(Uuid::new_v4()),
+                Err(RoomError::RoomNotFound)
+            ));
+            assert!(matches!(
+                repo.add_room_users(Uuid::new_v4(), vec![]),
+                Err(RoomError::RoomNotFound)
+            ));
+            assert!(matches!(
+                repo.update_room(Uuid::new_v4(), create_room_data("testRoom")),
+                Err(RoomError::RoomNotFound)
+            ));
+        }
+
+        #[test]
+        fn create_message_fails_when_user_and_room_do_not_exist() {
+            let repo = $repo;
+
+            let message_data =
+                create_message_data("Hello thermit!", Uuid::new_v4(), Uuid::new_v4());
+            assert!(matches!(
+                repo.create_message(message_data),
+                Err(MessageError::DatabaseError)
+            ));
+        }
+        #[test]
+        fn find_user_returns_created_user() {
+            let repo = $repo;
+
+            let user = repo.create_user(create_user_data("testUser")).unwrap();
+            let found = repo.find_user(user.id).unwrap().unwrap();
+
+            assert_eq!(found.username, "testUser");
+            assert!(repo.find_user(Uuid::new_v4()).unwrap().is_none());
+        }
+
+        #[test]
+        fn update_user_updates_username_and_timestamp() {
+            let repo = $repo;
+
+            let user = repo.create_user(create_user_data("testUser")).unwrap();
+            assert_eq!(user.created, user.updated);
+
+            let user_data = UserData {
+                username: String::from("newName"),
+                password: String::new(),
+            };
+            let updated = repo.update_user(user.id, user_data).unwrap();
+
+            assert_eq!(updated.username, "newName");
+            assert!(updated.updated > updated.created);
+        }
+
+        #[test]
+        fn update_user_fails_when_user_does_not_exist() {
+            let repo = $repo;
+
+            let result = repo.update_user(Uuid::new_v4(), create_user_data("testUser"));
+            assert!(matches!(result, Err(UserError::UserNotFound)));
+        }
+
+        #[test]
+        fn update_user_keeps_own_username_and_changes_password() {
+            let repo = $repo;
+
+            let user = repo.create_user(create_user_data("testUser")).unwrap();
+            let old_password = repo
+                .find_user_by_username("testUser")
+                .unwrap()
+                .unwrap()
+                .password;
+
+            let user_data = UserData {
+                username: String::from("testUser"),
+                password: String::from("newPassword"),
+            };
+            repo.update_user(user.id, user_data).unwrap();
+
+            let updated = repo.find_user_by_username("testUser").unwrap().unwrap();
+            assert_ne!(updated.password, old_password);
+
+            repo.create_user(create_user_data("otherUser")).unwrap();
+            let result = repo.update_user(user.id, create_user_data("otherUser"));
+            assert!(matches!(result, Err(UserError::UsernameTaken)));
+        }
+
+        #[test]
+        fn update_user_ends_sessions_only_when_password_changes() {
+            let repo = $repo;
+
+            let user = repo.create_user(create_user_data("testUser")).unwrap();
+            let config = AuthConfig::default();
+            crate::session::Session::start(&repo, user.id, Default::default(), &config).unwrap();
+
+            let user_data = UserData {
+                username: String::from("renamed"),
+                password: String::new(),
+            };
+            repo.update_user(user.id, user_data).unwrap();
+            assert_eq!(repo.find_user_sessions(user.id).unwrap().len(), 1);
+
+            repo.update_user(user.id, create_user_data("renamed"))
+                .unwrap();
+            assert!(repo.find_user_sessions(user.id).unwrap().is_empty());
+        }
+
+        #[test]
+        fn user_emails_are_unique_and_verified_per_address() {
+            let repo = $repo;
+
+            let user = repo.create_user(create_user_data("testUser")).unwrap();
+            let other = repo.create_user(create_user_data("otherUser")).unwrap();
+            repo.set_user_email(user.id, Some(String::from("jane@example.com")))
+                .unwrap();
+            let result = repo.set_user_email(other.id, Some(String::from("jane@example.com")));
+            assert!(matches!(result, Err(UserError::EmailTaken)));
+
+            assert!(!repo.verify_user_email(user.id, "old@example.com").unwrap());
+            assert!(repo.verify_user_email(user.id, "jane@example.com").unwrap());
+            let found = repo
+                .find_user_by_email("jane@example.com")
+                .unwrap()
+                .unwrap();
+            assert_eq!(found.id, user.id);
+            assert!(found.email_verified);
+
+            // A new address has to be verified again
+            repo.set_user_email(user.id, Some(String::from("jane@example.org")))
+                .unwrap();
+            let found = repo
+                .find_user_by_email("jane@example.org")
+                .unwrap()
+                .unwrap();
+            assert!(!found.email_verified);
+            assert!(repo
+                .find_user_by_email("jane@example.com")
+                .unwrap()
+                .is_none());
+
+            let result = repo.set_user_email(Uuid::new_v4(), None);
+            assert!(matches!(result, Err(UserError::UserNotFound)));
+        }
+
+        #[test]
+        fn set_user_role_changes_role() {
+            let repo = $repo;
+
+            repo.create_user(create_user_data("testUser")).unwrap();
+            let user = repo.find_user_by_username("testUser").unwrap().unwrap();
+            assert_eq!(user.role, Role::User);
+
+            repo.set_user_role(user.id, Role::Admin).unwrap();
+            let user = repo.find_user_by_username("testUser").unwrap().unwrap();
+            assert_eq!(user.role, Role::Admin);
+
+            let result = repo.set_user_role(Uuid::new_v4(), Role::Admin);
+            assert!(matches!(result, Err(UserError::UserNotFound)));
+        }
+
+        #[test]
+        fn set_user_bot_marks_user_as_bot() {
+            let repo = $repo;
+
+            let user = repo.create_user(create_user_data("ciBot")).unwrap();
+            assert!(!user.bot);
+
+            assert!(repo.set_user_bot(user.id, true).unwrap().bot);
+            assert!(repo.find_user(user.id).unwrap().unwrap().bot);
+
+            let result = repo.set_user_bot(Uuid::new_v4(), true);
+            assert!(matches!(result, Err(UserError::UserNotFound)));
+        }
+
+        #[test]
+        fn locked_users_lose_their_sessions_and_cannot_log_in() {
+            let repo = $repo;
+
+            let user = repo.create_user(create_user_data("testUser")).unwrap();
+            let config = AuthConfig::default();
+            crate::session::Session::start(&repo, user.id, Default::default(), &config).unwrap();
+
+            repo.set_user_locked(user.id, true).unwrap();
+            assert!(repo.find_user_by_id(user.id).unwrap().unwrap().locked);
+            assert!(repo.find_user_sessions(user.id).unwrap().is_empty());
+            let result =
+                crate::session::Session::start(&repo, user.id, Default::default(), &config);
+            assert!(matches!(result, Err(SessionError::AccountLocked)));
+
+            repo.set_user_locked(user.id, false).unwrap();
+            crate::session::Session::start(&repo, user.id, Default::default(), &config).unwrap();
+
+            let result = repo.set_user_locked(Uuid::new_v4(), true);
+            assert!(matches!(result, Err(UserError::UserNotFound)));
+        }
+
+        #[test]
+        fn deleted_users_cannot_log_in_and_are_purged_after_the_cutoff() {
+            let repo = $repo;
+
+            let room = repo.create_room(create_room_data("testRoom")).unwrap();
+            let user = repo.create_user(create_user_data("testUser")).unwrap();
+            let other = repo.create_user(create_user_data("otherUser")).unwrap();
+            repo.create_message(create_message_data("Hello thermit!", room.id, user.id))
+                .unwrap();
+            let config = AuthConfig::default();
+            crate::session::Session::start(&repo, user.id, Default::default(), &config).unwrap();
+
+            let deleted = chrono::Utc::now().naive_utc() - chrono::Duration::days(2);
+            repo.set_user_deleted(user.id, Some(deleted)).unwrap();
+            repo.set_user_deleted(other.id, Some(chrono::Utc::now().naive_utc()))
+                .unwrap();
+            let found = repo.find_user_by_id(user.id).unwrap().unwrap();
+            assert!(found.deleted.is_some());
+            assert!(repo.find_user_sessions(user.id).unwrap().is_empty());
+            let result =
+                crate::session::Session::start(&repo, user.id, Default::default(), &config);
+            assert!(matches!(result, Err(SessionError::AccountDeleted)));
+
+            let cutoff = chrono::Utc::now().naive_utc() - chrono::Duration::days(1);
+            assert_eq!(repo.purge_deleted_users(cutoff).unwrap(), vec![user.id]);
+            assert!(repo.find_user_by_id(user.id).unwrap().is_none());
+            assert!(repo.find_user_by_id(other.id).unwrap().is_some());
+            let messages = repo.find_messages_by_room(room.id).unwrap();
+            assert_eq!(messages.len(), 1);
+            assert_eq!(messages[0].author, None);
+
+            repo.set_user_deleted(other.id, None).unwrap();
+            assert!(repo.find_user_by_id(other.id).unwrap().unwrap().deleted.is_none());
+            let result = repo.set_user_deleted(Uuid::new_v4(), None);
+            assert!(matches!(result, Err(UserError::UserNotFound)));
+        }
+
+        #[test]
+        fn remove_room_users_returns_number_of_removed_users() {
+            let repo = $repo;
+
+            let room = repo.create_room(create_room_data("testRoom")).unwrap();
+            let user1 = repo.create_user(create_user_data("user1")).unwrap();
+            let user2 = repo.create_user(create_user_data("user2")).unwrap();
+            repo.add_room_users(room.id, vec![user1.id, user2.id])
+                .unwrap();
+
+            let count = repo
+                .remove_room_users(room.id, vec![user1.id, Uuid::new_v4()])
+                .unwrap();
+
+            assert_eq!(count, 1);
+            assert_eq!(repo.get_room_user_ids(room.id).unwrap(), vec![user2.id]);
+        }
+
+        #[test]
+        fn destroy_room_removes_messages() {
+            let repo = $repo;
+
+            let room = repo.create_room(create_room_data("testRoom")).unwrap();
+            let user = repo.create_user(create_user_data("testUser")).unwrap();
+            let message = repo
+                .create_message(create_message_data("Hello thermit!", room.id, user.id))
+                .unwrap();
+
+            assert_eq!(repo.destroy_room(room.id).unwrap(), 1);
+            assert!(repo.find_room(room.id).unwrap().is_none());
+            assert!(repo.find_message(message.id).unwrap().is_none());
+        }
+
+        #[test]
+        fn update_message_updates_content() {
+            let repo = $repo;
+
+            let room = repo.create_room(create_room_data("testRoom")).unwrap();
+            let user = repo.create_user(create_user_data("testUser")).unwrap();
+            let message = repo
+                .create_message(create_message_data("Hello thermit!", room.id, user.id))
+                .unwrap();
+
+            let updated = repo
+                .update_message(
+                    message.id,
+                    create_message_data("Bye thermit!", room.id, user.id),
+                )
+                .unwrap();
+            assert_eq!(updated.content, "Bye thermit!");
+
+            let result = repo.update_message(
+                Uuid::new_v4(),
+                create_message_data("Bye thermit!", room.id, user.id),
+            );
+            assert!(matches!(result, Err(MessageError::MessageNotFound)));
+        }
+
+        #[test]
+        fn sessions_are_found_by_token_hashes() {
+            let repo = $repo;
+
+            let user = repo.create_user(create_user_data("testUser")).unwrap();
+            let session = repo.create_session(create_session(user.id)).unwrap();
+
+            let found = repo
+                .find_session_by_access_token(&session.access_token_hash)
+                .unwrap()
+                .unwrap();
+            assert_eq!(found.id, session.id);
+            let found = repo
+                .find_session_by_refresh_token(&session.refresh_token_hash)
+                .unwrap()
+                .unwrap();
+            assert_eq!(found.id, session.id);
+            assert!(repo
+                .find_session_by_access_token(&session.refresh_token_hash)
+                .unwrap()
+                .is_none());
+        }
+
+        #[test]
+        fn create_session_fails_when_user_does_not_exist() {
+            let repo = $repo;
+
+            let result = repo.create_session(create_session(Uuid::new_v4()));
+
+            assert!(matches!(result, Err(SessionError::DatabaseError)));
+        }
+
+        #[test]
+        fn replace_session_tokens_requires_current_refresh_token() {
+            let repo = $repo;
+
+            let user = repo.create_user(create_user_data("testUser")).unwrap();
+            let session = repo.create_session(create_session(user.id)).unwrap();
+            let old_hash = session.refresh_token_hash.clone();
+
+            let mut rotated = create_session(user.id);
+            rotated.id = session.id;
+            rotated.previous_refresh_token_hash = Some(old_hash.clone());
+            assert!(repo.replace_session_tokens(&rotated, &old_hash).unwrap());
+            // The old token was already exchanged
+            assert!(!repo.replace_session_tokens(&rotated, &old_hash).unwrap());
+
+            let found = repo
+                .find_session_by_previous_refresh_token(&old_hash)
+                .unwrap()
+                .unwrap();
+            assert_eq!(found.refresh_token_hash, rotated.refresh_token_hash);
+        }
+
+        #[test]
+        fn destroy_session_only_removes_own_sessions() {
+            let repo = $repo;
+
+            let user = repo.create_user(create_user_data("testUser")).unwrap();
+            let other = repo.create_user(create_user_data("otherUser")).unwrap();
+            let session = repo.create_session(create_session(user.id)).unwrap();
+
+            assert_eq!(repo.destroy_session(other.id, session.id).unwrap(), 0);
+            assert_eq!(repo.find_user_sessions(user.id).unwrap().len(), 1);
+            assert_eq!(repo.destroy_session(user.id, session.id).unwrap(), 1);
+            assert_eq!(repo.find_user_sessions(user.id).unwrap().len(), 0);
+        }
+
+        #[test]
+        fn destroy_user_removes_sessions() {
+            let repo = $repo;
+
+            let user = repo.create_user(create_user_data("testUser")).unwrap();
+            let session = repo.create_session(create_session(user.id)).unwrap();
+
+            repo.destroy_user(user.id).unwrap();
+            assert!(repo
+                .find_session_by_access_token(&session.access_token_hash)
+                .unwrap()
+                .is_none());
+        }
+
+        fn create_totp_secret(user_id: Uuid) -> TotpSecret {
+            TotpSecret {
+                user_id,
+                secret: String::from("JBSWY3DPEHPK3PXP"),
+                enabled: false,
+                last_used_step: None,
+                created: chrono::Utc::now().naive_utc(),
+            }
+        }
+
+        #[test]
+        fn totp_steps_cannot_be_used_twice() {
+            let repo = $repo;
+
+            let user = repo.create_user(create_user_data("testUser")).unwrap();
+            repo.create_totp_secret(create_totp_secret(user.id))
+                .unwrap();
+            repo.enable_totp_secret(user.id).unwrap();
+
+            assert!(repo.use_totp_step(user.id, 10).unwrap());
+            assert!(!repo.use_totp_step(user.id, 10).unwrap());
+            assert!(!repo.use_totp_step(user.id, 9).unwrap());
+            assert!(repo.use_totp_step(user.id, 11).unwrap());
+
+            let secret = repo.find_totp_secret(user.id).unwrap().unwrap();
+            assert!(secret.enabled);
+            assert_eq!(secret.last_used_step, Some(11));
+        }
+
+        #[test]
+        fn create_totp_secret_fails_when_user_does_not_exist() {
+            let repo = $repo;
+
+            let result = repo.create_totp_secret(create_totp_secret(Uuid::new_v4()));
+
+            assert!(matches!(result, Err(TwoFactorError::DatabaseError)));
+        }
+
+        #[test]
+        fn recovery_codes_are_consumed_once() {
+            let repo = $repo;
+
+            let user = repo.create_user(create_user_data("testUser")).unwrap();
+            let other = repo.create_user(create_user_data("otherUser")).unwrap();
+            repo.replace_recovery_codes(user.id, vec![String::from("a"), String::from("b")])
+                .unwrap();
+
+            assert!(!repo.consume_recovery_code(other.id, "a").unwrap());
+            assert!(repo.consume_recovery_code(user.id, "a").unwrap());
+            assert!(!repo.consume_recovery_code(user.id, "a").unwrap());
+
+            repo.replace_recovery_codes(user.id, vec![String::from("c")])
+                .unwrap();
+            assert!(!repo.consume_recovery_code(user.id, "b").unwrap());
+            assert!(repo.consume_recovery_code(user.id, "c").unwrap());
+        }
+
+        #[test]
+        fn login_challenge_attempts_are_counted() {
+            let repo = $repo;
+
+            let user = repo.create_user(create_user_data("testUser")).unwrap();
+            repo.create_login_challenge(LoginChallenge {
+                token_hash: String::from("hash"),
+                user_id: user.id,
+                attempts: 0,
+                expires: chrono::Utc::now().naive_utc(),
+            })
+            .unwrap();
+
+            repo.count_login_challenge_attempt("hash").unwrap();
+            let challenge = repo.find_login_challenge("hash").unwrap().unwrap();
+            assert_eq!(challenge.attempts, 1);
+            assert_eq!(repo.destroy_login_challenge("hash").unwrap(), 1);
+            assert_eq!(repo.destroy_login_challenge("hash").unwrap(), 0);
+        }
+
+        #[test]
+        fn destroy_user_removes_second_factors() {
+            let repo = $repo;
+
+            let user = repo.create_user(create_user_data("testUser")).unwrap();
+            repo.create_totp_secret(create_totp_secret(user.id))
+                .unwrap();
+            repo.replace_recovery_codes(user.id, vec![String::from("a")])
+                .unwrap();
+
+            repo.destroy_user(user.id).unwrap();
+            assert!(repo.find_totp_secret(user.id).unwrap().is_none());
+            assert!(!repo.consume_recovery_code(user.id, "a").unwrap());
+        }
+
+        fn create_api_token(user_id: Uuid, token_hash: &str) -> ApiToken {
+            ApiToken {
+                id: Uuid::new_v4(),
+                user_id,
+                name: String::from("ci"),
+                token_hash: String::from(token_hash),
+                scopes: String::from("rooms:read"),
+                created: chrono::Utc::now().naive_utc(),
+                last_used: None,
+            }
+        }
+
+        #[test]
+        fn api_tokens_are_found_by_hash_and_user() {
+            let repo = $repo;
+
+            let user = repo.create_user(create_user_data("ciBot")).unwrap();
+            let token = repo
+                .create_api_token(create_api_token(user.id, "hash"))
+                .unwrap();
+            repo.touch_api_token(token.id, chrono::Utc::now().naive_utc())
+                .unwrap();
+
+            let found = repo.find_api_token_by_hash("hash").unwrap().unwrap();
+            assert_eq!(found.id, token.id);
+            assert!(found.last_used.is_some());
+            assert_eq!(repo.find_user_api_tokens(user.id).unwrap().len(), 1);
+
+            let result = repo.create_api_token(create_api_token(user.id, "hash"));
+            assert!(matches!(result, Err(ApiTokenError::DatabaseError)));
+        }
+
+        #[test]
+        fn destroy_api_token_only_removes_own_tokens() {
+            let repo = $repo;
+
+            let user = repo.create_user(create_user_data("ciBot")).unwrap();
+            let other = repo.create_user(create_user_data("otherUser")).unwrap();
+            let token = repo
+                .create_api_token(create_api_token(user.id, "hash"))
+                .unwrap();
+
+            assert_eq!(repo.destroy_api_token(other.id, token.id).unwrap(), 0);
+            assert_eq!(repo.destroy_api_token(user.id, token.id).unwrap(), 1);
+            assert!(repo.find_api_token_by_hash("hash").unwrap().is_none());
+        }
+
+        #[test]
+        fn destroy_user_removes_api_tokens() {
+            let repo = $repo;
+
+            let user = repo.create_user(create_user_data("ciBot")).unwrap();
+            repo.create_api_token(create_api_token(user.id, "hash"))
+                .unwrap();
+
+            repo.destroy_user(user.id).unwrap();
+            assert!(repo.find_api_token_by_hash("hash").unwrap().is_none());
+        }
+
+        fn create_identity(user_id: Uuid, subject: &str) -> ExternalIdentity {
+            ExternalIdentity {
+                issuer: String::from("https://login.example.com"),
+                subject: String::from(subject),
+                user_id,
+                created: chrono::Utc::now().naive_utc(),
+            }
+        }
+
+        #[test]
+        fn identities_are_found_by_issuer_and_subject() {
+            let repo = $repo;
+
+            let user = repo.create_user(create_user_data("testUser")).unwrap();
+            repo.create_identity(create_identity(user.id, "1")).unwrap();
+
+            let found = repo
+                .find_identity("https://login.example.com", "1")
+                .unwrap()
+                .unwrap();
+            assert_eq!(found.user_id, user.id);
+            assert!(repo
+                .find_identity("https://other.example.com", "1")
+                .unwrap()
+                .is_none());
+
+            let result = repo.create_identity(create_identity(user.id, "1"));
+            assert!(matches!(result, Err(OidcError::DatabaseError)));
+        }
+
+        #[test]
+        fn destroy_user_removes_identities() {
+            let repo = $repo;
+
+            let user = repo.create_user(create_user_data("testUser")).unwrap();
+            repo.create_identity(create_identity(user.id, "1")).unwrap();
+
+            repo.destroy_user(user.id).unwrap();
+            assert!(repo
+                .find_identity("https://login.example.com", "1")
+                .unwrap()
+                .is_none());
+        }
+
+        fn create_email_token(user_id: Uuid, token_hash: &str, purpose: Purpose) -> EmailToken {
+            EmailToken {
+                token_hash: String::from(token_hash),
+                user_id,
+                purpose,
+                email: String::from("jane@example.com"),
+                expires: chrono::Utc::now().naive_utc(),
+            }
+        }
+
+        #[test]
+        fn email_tokens_are_found_and_destroyed_by_purpose() {
+            let repo = $repo;
+
+            let user = repo.create_user(create_user_data("testUser")).unwrap();
+            let verify = create_email_token(user.id, "verify", Purpose::VerifyEmail);
+            repo.create_email_token(verify.clone()).unwrap();
+            repo.create_email_token(create_email_token(user.id, "reset", Purpose::ResetPassword))
+                .unwrap();
+
+            let found = repo.find_email_token("verify").unwrap().unwrap();
+            assert_eq!(found.purpose, Purpose::VerifyEmail);
+            assert_eq!(found.email, verify.email);
+            assert_eq!(repo.find_user_email_tokens(user.id).unwrap().len(), 2);
+
+            let count = repo
+                .destroy_user_email_tokens(user.id, Purpose::ResetPassword)
+                .unwrap();
+            assert_eq!(count, 1);
+            assert!(repo.find_email_token("reset").unwrap().is_none());
+            assert!(repo.find_email_token("verify").unwrap().is_some());
+        }
+
+        #[test]
+        fn destroy_user_removes_email_tokens() {
+            let repo = $repo;
+
+            let user = repo.create_user(create_user_data("testUser")).unwrap();
+            repo.create_email_token(create_email_token(user.id, "verify", Purpose::VerifyEmail))
+                .unwrap();
+
+            repo.destroy_user(user.id).unwrap();
+            assert!(repo.find_email_token("verify").unwrap().is_none());
+        }
+
+        #[test]
+        fn find_user_summaries_searches_names_and_emails() {
+            let repo = $repo;
+
+            let jane = repo.create_user(create_user_data("jane")).unwrap();
+            repo.create_user(create_user_data("janet")).unwrap();
+            let bob = repo.create_user(create_user_data("bob")).unwrap();
+            repo.set_user_email(bob.id, Some(String::from("bob@jane.org")))
+                .unwrap();
+            for name in &["firstRoom", "secondRoom"] {
+                let room = repo.create_room(create_room_data(name)).unwrap();
+                repo.add_room_users(room.id, vec![jane.id]).unwrap();
+            }
+
+            let query = UserQuery {
+                q: Some(String::from("JAN")),
+                ..UserQuery::default()
+            };
+            let users = repo.find_user_summaries(&query).unwrap();
+            let names: Vec<&str> = users.iter().map(|user| user.username.as_str()).collect();
+            assert_eq!(names, vec!["bob", "jane", "janet"]);
+            assert_eq!(users[0].email.as_deref(), Some("bob@jane.org"));
+            assert_eq!(users[0].room_count, 0);
+            assert_eq!(users[1].room_count, 2);
+
+            let query = UserQuery {
+                q: Some(String::from("jan")),
+                limit: 1,
+                offset: 1,
+            };
+            let users = repo.find_user_summaries(&query).unwrap();
+            assert_eq!(users.len(), 1);
+            assert_eq!(users[0].id, jane.id);
+
+            // Wildcards are matched literally
+            let query = UserQuery {
+                q: Some(String::from("%")),
+                ..UserQuery::default()
+            };
+            assert!(repo.find_user_summaries(&query).unwrap().is_empty());
+        }
+
+        #[test]
+        fn server_stats_counts_users_and_content() {
+            let repo = $repo;
+
+            let admin = repo.create_user(create_user_data("admin")).unwrap();
+            repo.set_user_role(admin.id, Role::Admin).unwrap();
+            let bot = repo.create_user(create_user_data("ciBot")).unwrap();
+            repo.set_user_bot(bot.id, true).unwrap();
+            repo.set_user_locked(bot.id, true).unwrap();
+            let room = repo.create_room(create_room_data("testRoom")).unwrap();
+            repo.create_message(create_message_data("Hello thermit!", room.id, admin.id))
+                .unwrap();
+            repo.create_session(create_session(admin.id)).unwrap();
+
+            assert_eq!(
+                repo.server_stats().unwrap(),
+                ServerStats {
+                    users: 2,
+                    admins: 1,
+                    bots: 1,
+                    locked_users: 1,
+                    rooms: 1,
+                    messages: 1,
+                    sessions: 1,
+                    api_tokens: 0,
+                }
+            );
+        }
+
+        #[test]
+        fn audit_events_are_found_newest_first_and_outlive_users() {
+            let repo = $repo;
+
+            let admin = repo.create_user(create_user_data("admin")).unwrap();
+            let user = repo.create_user(create_user_data("testUser")).unwrap();
+            let start = chrono::NaiveDate::from_ymd(2026, 10, 19).and_hms(12, 0, 0);
+            let events: Vec<AuditEvent> = [
+                (Action::LoginSucceeded, Some(user.id), Some(user.id)),
+                (Action::UserLocked, Some(admin.id), Some(user.id)),
+                (Action::LoginFailed, None, None),
+            ]
+            .iter()
+            .enumerate()
+            .map(|(minutes, &(action, actor_id, target_id))| AuditEvent {
+                created: start + chrono::Duration::minutes(minutes as i64),
+                ip: Some(String::from("192.0.2.1")),
+                request_id: Some(format!("request-{}", minutes)),
+                ..AuditEvent::new(action, actor_id, target_id)
+                    .with_details(serde_json::json!({ "method": "password" }))
+            })
+            .collect();
+            for event in &events {
+                repo.create_audit_event(event.clone()).unwrap();
+            }
+            repo.destroy_user(user.id).unwrap();
+
+            let all = repo.find_audit_events(&AuditQuery::default()).unwrap();
+            assert_eq!(all, events.iter().rev().cloned().collect::<Vec<_>>());
+
+            let by_target = AuditQuery {
+                target_id: Some(user.id),
+                ..AuditQuery::default()
+            };
+            let found = repo.find_audit_events(&by_target).unwrap();
+            assert_eq!(found, vec![events[1].clone(), events[0].clone()]);
+
+            let filtered = AuditQuery {
+                action: Some(Action::UserLocked),
+                actor_id: Some(admin.id),
+                since: Some(start),
+                until: Some(start + chrono::Duration::minutes(2)),
+                ..AuditQuery::default()
+            };
+            let found = repo.find_audit_events(&filtered).unwrap();
+            assert_eq!(found, vec![events[1].clone()]);
+
+            let page = AuditQuery {
+                limit: 1,
+                offset: 1,
+                ..AuditQuery::default()
+            };
+            let found = repo.find_audit_events(&page).unwrap();
+            assert_eq!(found, vec![events[1].clone()]);
+        }
+    };
+}
@@ -1,16 +1,14 @@
-use diesel::prelude::*;
 use diesel::result::Error as DieselError;
-use diesel::BelongingToDsl;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+use crate::query::prelude::*;
 use crate::room::RoomError::*;
 use crate::schema::rooms;
 use crate::schema::rooms_users;
 use chrono::{NaiveDateTime, Utc};
 
-#[derive(Serialize, Identifiable, Deserialize, Queryable, Insertable, PartialEq, Debug)]
-#[table_name = "rooms"]
+#[derive(Clone, Serialize, Deserialize, Queryable, PartialEq, Debug)]
 pub struct Room {
     pub id: Uuid,
     pub name: Option<String>,
@@ -24,10 +22,7 @@ pub struct RoomData {
     pub name: Option<String>,
 }
 
-#[derive(Identifiable, Queryable, Associations, Insertable, PartialEq, Debug)]
-#[belongs_to(Room)]
-#[primary_key(user_id, room_id)]
-#[table_name = "rooms_users"]
+#[derive(Queryable, PartialEq, Debug)]
 pub struct RoomUser {
     pub(crate) user_id: Uuid,
     room_id: Uuid,
@@ -44,26 +39,34 @@ pub enum RoomError {
 }
 
 impl Room {
-    pub fn find_all(conn: &PgConnection) -> Result<Vec<Room>, RoomError> {
+    pub fn find_all(conn: &impl SqlConnection) -> Result<Vec<Room>, RoomError> {
         use crate::schema::rooms::dsl::*;
 
         Ok(rooms.load::<Room>(conn)?)
     }
 
-    pub fn find(conn: &PgConnection, room_id: Uuid) -> Result<Option<Room>, RoomError> {
+    pub fn find(conn: &impl SqlConnection, room_id: Uuid) -> Result<Option<Room>, RoomError> {
         use crate::schema::rooms::dsl::*;
 
-        Ok(rooms.find(room_id).get_result::<Room>(conn).optional()?)
+        Ok(rooms
+            .find(Id(room_id))
+            .get_result::<Room>(conn)
+            .optional()?)
     }
 
-    pub fn get_room_users(conn: &PgConnection, room: &Room) -> Result<Vec<RoomUser>, RoomError> {
-        Ok(RoomUser::belonging_to(room).load::<RoomUser>(conn)?)
+    pub fn get_room_users(
+        conn: &impl SqlConnection,
+        room: &Room,
+    ) -> Result<Vec<RoomUser>, RoomError> {
+        Ok(rooms_users::table
+            .filter(rooms_users::room_id.eq(Id(room.id)))
+            .load::<RoomUser>(conn)?)
     }
 
-    pub fn get_user_ids(conn: &PgConnection, room_id: Uuid) -> Result<Vec<Uuid>, RoomError> {
+    pub fn get_user_ids(conn: &impl SqlConnection, room_id: Uuid) -> Result<Vec<Uuid>, RoomError> {
         use crate::schema::rooms::dsl::*;
 
-        let room = rooms.find(room_id).get_result::<Room>(conn)?;
+        let room = rooms.find(Id(room_id)).get_result::<Room>(conn)?;
         let room_users = Room::get_room_users(conn, &room)?;
         Ok(room_users
             .into_iter()
@@ -71,7 +74,7 @@ impl Room {
             .collect::<Vec<Uuid>>())
     }
 
-    pub fn exists(conn: &PgConnection, room_id: Uuid) -> Result<bool, RoomError> {
+    pub fn exists(conn: &impl SqlConnection, room_id: Uuid) -> Result<bool, RoomError> {
         let room = Room::find(conn, room_id)?;
         match room {
             None => Ok(false),
@@ -80,7 +83,7 @@ impl Room {
     }
 
     pub fn add_users(
-        conn: &PgConnection,
+        conn: &impl SqlConnection,
         existing_room_id: Uuid,
         user_ids: Vec<Uuid>,
     ) -> Result<Vec<Uuid>, RoomError> {
@@ -103,12 +106,12 @@ impl Room {
                 updated: Utc::now().naive_utc(),
             };
 
-            // Check if the user to add exists
+            // Check if the user to add exists, deleted users are only kept for a possible restore
             use crate::user::User;
-            match User::exists(conn, user_id_to_add) {
+            match User::_find(conn, user_id_to_add) {
                 Err(_) => return Err(DatabaseError),
-                Ok(bool) => {
-                    if !bool {
+                Ok(user) => {
+                    if user.is_none_or(|user| user.deleted.is_some()) {
                         continue; // Do not add user
                     }
                 }
@@ -120,7 +123,13 @@ impl Room {
             }
 
             diesel::insert_into(rooms_users)
-                .values(room_user)
+                .values((
+                    user_id.eq(Id(room_user.user_id)),
+                    room_id.eq(Id(room_user.room_id)),
+                    status.eq(room_user.status),
+                    created.eq(room_user.created),
+                    updated.eq(room_user.updated),
+                ))
                 .execute(conn)?;
             added_users.push(user_id_to_add);
         }
@@ -128,7 +137,7 @@ impl Room {
     }
 
     pub fn remove_users(
-        conn: &PgConnection,
+        conn: &impl SqlConnection,
         room_id: Uuid,
         user_ids: Vec<Uuid>,
     ) -> Result<usize, RoomError> {
@@ -145,39 +154,52 @@ impl Room {
             .collect::<Vec<RoomUser>>();
 
         for r_u in rooms_users_pairs.iter() {
-            let query = diesel::delete(r_u);
+            let query = diesel::delete(rooms_users::table.find((Id(r_u.user_id), Id(r_u.room_id))));
             count += query.execute(conn)?;
         }
         Ok(count)
     }
 
-    pub fn create(room_data: RoomData, conn: &PgConnection) -> Result<Room, RoomError> {
+    pub fn create(room_data: RoomData, conn: &impl SqlConnection) -> Result<Room, RoomError> {
         use crate::schema::rooms::dsl::*;
 
-        let new_room: Room = diesel::insert_into(rooms)
-            .values(&room_data)
-            .get_result(conn)?;
+        // The id and timestamps are set here, see `User::create`
+        let timestamp = Utc::now().naive_utc();
+        let new_room = Room {
+            id: Uuid::new_v4(),
+            name: room_data.name,
+            created: timestamp,
+            updated: timestamp,
+        };
+        diesel::insert_into(rooms)
+            .values((
+                id.eq(Id(new_room.id)),
+                name.eq(&new_room.name),
+                created.eq(new_room.created),
+                updated.eq(new_room.updated),
+            ))
+            .execute(conn)?;
         Ok(new_room)
     }
 
-    pub fn destroy(conn: &PgConnection, room_id: Uuid) -> Result<usize, RoomError> {
+    pub fn destroy(conn: &impl SqlConnection, room_id: Uuid) -> Result<usize, RoomError> {
         use crate::schema::rooms::dsl::*;
 
-        let count = diesel::delete(rooms.find(room_id)).execute(conn)?;
+        let count = diesel::delete(rooms.find(Id(room_id))).execute(conn)?;
         Ok(count)
     }
 
     pub fn update(
-        conn: &PgConnection,
+        conn: &impl SqlConnection,
         room_id: Uuid,
         room_data: RoomData,
     ) -> Result<Room, RoomError> {
         use crate::schema::rooms::dsl::*;
 
-        let room: Room = diesel::update(rooms.find(room_id))
-            .set(room_data)
-            .get_result(conn)?;
-        Ok(room)
+        diesel::update(rooms.find(Id(room_id)))
+            .set((room_data, updated.eq(Utc::now().naive_utc())))
+            .execute(conn)?;
+        Room::find(conn, room_id)?.ok_or(RoomNotFound)
     }
 }
 
@@ -276,6 +298,25 @@ mod tests {
         assert_eq!(users.len(), 0);
     }
 
+    #[test]
+    fn get_user_ids_returns_ids_of_room_users() {
+        let conn = connection();
+
+        let room = setup_room(&conn);
+        let user = setup_user(&conn);
+        Room::add_users(&conn, room.id, vec![user.id]).unwrap();
+
+        assert_eq!(Room::get_user_ids(&conn, room.id).unwrap(), vec![user.id]);
+    }
+
+    #[test]
+    fn get_user_ids_fails_when_room_does_not_exist() {
+        let conn = connection();
+
+        let result = Room::get_user_ids(&conn, Uuid::new_v4());
+        assert!(matches!(result, Err(RoomError::RoomNotFound)));
+    }
+
     #[test]
     fn users_can_be_added_to_room() {
         let conn = connection();
@@ -1,35 +1,38 @@
+use crate::api_token::{Caller, Scope};
+use crate::audit::{Action, AuditContext};
+use crate::db::DbConn;
 use crate::errors::ServiceError;
-use crate::room::{Room, RoomData};
-use crate::Pool;
+use crate::repository::Repository;
+use crate::room::{Room, RoomData, RoomError};
 use actix_web::{delete, get, post, put, web, HttpResponse};
 use serde::Deserialize;
 use serde_json::json;
 use uuid::Uuid;
 
 #[get("/rooms")]
-pub async fn list(pool: web::Data<Pool>) -> Result<HttpResponse, ServiceError> {
-    let conn = pool.get().expect("couldn't get db connection from pool");
-
-    let rooms = web::block(move || Room::find_all(&conn))
-        .await
-        .map_err(ServiceError::from)?;
+pub async fn list(conn: DbConn, caller: Caller) -> Result<HttpResponse, ServiceError> {
+    caller.require(Scope::ReadRooms)?;
+    let rooms = conn.run(|repo| repo.find_all_rooms()).await?;
 
     Ok(HttpResponse::Ok().json(json!({ "rooms": rooms })))
 }
 
 #[get("/rooms/{id}")]
 pub async fn find(
-    pool: web::Data<Pool>,
+    conn: DbConn,
+    caller: Caller,
     id: web::Path<Uuid>,
 ) -> Result<HttpResponse, ServiceError> {
-    let conn = pool.get().expect("couldn't get db connection from pool");
-
-    let room = web::block(move || Room::find(&conn, id.into_inner()))
-        .await
-        .map_err(ServiceError::from)?;
+    caller.require(Scope::ReadRooms)?;
+    let room = conn
+        .run(move |repo| match repo.find_room(id.into_inner())? {
+            Some(room) => room_json(repo, room).map(Some),
+            None => Ok(None),
+        })
+        .await?;
 
     if let Some(room) = room {
-        get_json_response(&pool, room)
+        Ok(HttpResponse::Ok().json(room))
     } else {
         Err(ServiceError::NotFound)
     }
@@ -37,42 +40,58 @@ pub async fn find(
 
 #[post("/rooms")]
 async fn create(
-    pool: web::Data<Pool>,
+    conn: DbConn,
+    caller: Caller,
     room_data: web::Json<RoomData>,
 ) -> Result<HttpResponse, ServiceError> {
-    let conn = pool.get().expect("couldn't get db connection from pool");
+    caller.require(Scope::ManageRooms)?;
+    let room = conn
+        .run(move |repo| {
+            let room = repo.create_room(room_data.into_inner())?;
+            room_json(repo, room)
+        })
+        .await?;
 
-    let room = web::block(move || Room::create(room_data.into_inner(), &conn))
-        .await
-        .map_err(ServiceError::from)?;
-
-    get_json_response(&pool, room)
+    Ok(HttpResponse::Ok().json(room))
 }
 
 #[put("/rooms/{id}")]
 pub async fn update(
-    pool: web::Data<Pool>,
+    conn: DbConn,
+    caller: Caller,
     id: web::Path<Uuid>,
     room_data: web::Json<RoomData>,
 ) -> Result<HttpResponse, ServiceError> {
-    let conn = pool.get().expect("couldn't get db connection from pool");
-    let room = web::block(move || Room::update(&conn, id.into_inner(), room_data.into_inner()))
-        .await
-        .map_err(ServiceError::from)?;
+    caller.require(Scope::ManageRooms)?;
+    let room = conn
+        .run(move |repo| {
+            let room = repo.update_room(id.into_inner(), room_data.into_inner())?;
+            room_json(repo, room)
+        })
+        .await?;
 
-    get_json_response(&pool, room)
+    Ok(HttpResponse::Ok().json(room))
 }
 
 #[delete("/rooms/{id}")]
 pub async fn delete(
-    pool: web::Data<Pool>,
+    conn: DbConn,
+    caller: Caller,
+    audit: AuditContext,
     room_id: web::Path<Uuid>,
 ) -> Result<HttpResponse, ServiceError> {
-    let conn = pool.get().expect("couldn't get db connection from pool");
-
-    let count = web::block(move || Room::destroy(&conn, room_id.into_inner()))
-        .await
-        .map_err(ServiceError::from)?;
+    caller.require(Scope::ManageRooms)?;
+    let room_id = room_id.into_inner();
+    let count = conn
+        .run(move |repo| -> Result<_, ServiceError> {
+            let count = repo.destroy_room(room_id)?;
+            if count > 0 {
+                let event = audit.event(Action::RoomDeleted, caller.user_id(), Some(room_id));
+                repo.create_audit_event(event)?;
+            }
+            Ok(count)
+        })
+        .await?;
 
     if count == 0 {
         Err(ServiceError::NotFound)
@@ -88,51 +107,65 @@ pub struct RoomUserData {
 
 #[post("/rooms/{id}/users")]
 pub async fn add_user(
-    pool: web::Data<Pool>,
+    conn: DbConn,
+    caller: Caller,
+    audit: AuditContext,
     room_id: web::Path<Uuid>,
     user_data: web::Json<RoomUserData>,
 ) -> Result<HttpResponse, ServiceError> {
-    let conn = pool.get().expect("couldn't get db connection from pool");
-
-    let added_users =
-        Room::add_users(&conn, room_id.into_inner(), vec![user_data.into_inner().id])?;
+    caller.require(Scope::ManageRooms)?;
+    let room_id = room_id.into_inner();
+    let added_users = conn
+        .run(move |repo| -> Result<_, ServiceError> {
+            let added_users = repo.add_room_users(room_id, vec![user_data.into_inner().id])?;
+            if !added_users.is_empty() {
+                let event = audit.event(Action::RoomUsersAdded, caller.user_id(), Some(room_id));
+                repo.create_audit_event(event.with_details(json!({ "user_ids": added_users })))?;
+            }
+            Ok(added_users)
+        })
+        .await?;
 
     Ok(HttpResponse::Ok().json(json!({ "users": added_users })))
 }
 
 #[get("/rooms/{id}/users")]
 pub async fn get_users(
-    pool: web::Data<Pool>,
+    conn: DbConn,
+    caller: Caller,
     room_id: web::Path<Uuid>,
 ) -> Result<HttpResponse, ServiceError> {
-    let conn = pool.get().expect("couldn't get db connection from pool");
-
-    let possible_room = Room::find(&conn, room_id.into_inner())?;
-    let room = match possible_room {
-        None => return Err(ServiceError::NotFound),
-        Some(room) => room,
-    };
-
-    let room_user_ids: Vec<Uuid> = Room::get_room_users(&conn, &room)?
-        .into_iter()
-        .map(|room_user| room_user.user_id)
-        .collect();
+    caller.require(Scope::ReadRooms)?;
+    // get_room_user_ids fails with RoomNotFound if the room does not exist
+    let room_user_ids = conn
+        .run(move |repo| repo.get_room_user_ids(room_id.into_inner()))
+        .await?;
 
     Ok(HttpResponse::Ok().json(json!({ "users": room_user_ids })))
 }
 
 #[delete("/rooms/{room_id}/users/{user_id}")]
 pub async fn remove_user(
-    pool: web::Data<Pool>,
+    conn: DbConn,
+    caller: Caller,
+    audit: AuditContext,
     ids: web::Path<(Uuid, Uuid)>,
 ) -> Result<HttpResponse, ServiceError> {
-    let conn = pool.get().expect("couldn't get db connection from pool");
-
+    caller.require(Scope::ManageRooms)?;
     let ids_content = ids.into_inner();
     let room_id = ids_content.0;
     let user_id = ids_content.1;
 
-    let count = Room::remove_users(&conn, room_id, vec![user_id])?;
+    let count = conn
+        .run(move |repo| -> Result<_, ServiceError> {
+            let count = repo.remove_room_users(room_id, vec![user_id])?;
+            if count > 0 {
+                let event = audit.event(Action::RoomUsersRemoved, caller.user_id(), Some(room_id));
+                repo.create_audit_event(event.with_details(json!({ "user_ids": [user_id] })))?;
+            }
+            Ok(count)
+        })
+        .await?;
 
     if count == 0 {
         Err(ServiceError::NotFound)
@@ -141,22 +174,16 @@ pub async fn remove_user(
     }
 }
 
-fn get_json_response(pool: &web::Data<Pool>, room: Room) -> Result<HttpResponse, ServiceError> {
-    let conn = pool.get().expect("couldn't get db connection from pool");
+fn room_json(repo: &dyn Repository, room: Room) -> Result<serde_json::Value, RoomError> {
+    let room_user_ids = repo.get_room_user_ids(room.id)?;
 
-    let room_user_ids: Vec<Uuid> = Room::get_room_users(&conn, &room)?
-        .into_iter()
-        .map(|room_user| room_user.user_id)
-        .collect();
-
-    let room_json = json!({
+    Ok(json!({
         "id": room.id,
         "name": room.name,
         "created" : room.created,
         "updated" : room.updated,
         "users": room_user_ids
-    });
-    Ok(HttpResponse::Ok().json(room_json))
+    }))
 }
 
 pub fn init_routes(config: &mut web::ServiceConfig) {
@@ -170,3 +197,137 @@ pub fn init_routes(config: &mut web::ServiceConfig) {
     config.service(get_users);
     config.service(remove_user);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api_token::ApiToken;
+    use crate::db::Database;
+    use crate::repository::{InMemoryRepository, UserRepository};
+    use crate::test_helpers::*;
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App};
+
+    #[actix_rt::test]
+    async fn create_returns_room_without_users() {
+        let repo = InMemoryRepository::default();
+        let user = repo.create_user(create_user_data("testUser")).unwrap();
+        let user_bearer = bearer(&repo, user.id);
+        let mut app = test::init_service(
+            App::new()
+                .data(Database::Memory(repo))
+                .configure(init_routes),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/rooms")
+            .set_json(&json!({ "name": "testRoom" }))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let req = test::TestRequest::post()
+            .uri("/rooms")
+            .header("Authorization", user_bearer.as_str())
+            .set_json(&json!({ "name": "testRoom" }))
+            .to_request();
+        let room: serde_json::Value = test::read_response_json(&mut app, req).await;
+
+        assert_eq!(room["name"], "testRoom");
+        assert_eq!(room["users"], json!([]));
+    }
+
+    #[actix_rt::test]
+    async fn api_tokens_need_scope_to_manage_rooms() {
+        let repo = InMemoryRepository::default();
+        let user = repo.create_user(create_user_data("ciBot")).unwrap();
+        let token = ApiToken::issue(&repo, user.id, String::from("ci"), &[Scope::ReadRooms])
+            .unwrap()
+            .token;
+        let mut app = test::init_service(
+            App::new()
+                .data(Database::Memory(repo))
+                .configure(init_routes),
+        )
+        .await;
+
+        let req = test::TestRequest::get()
+            .uri("/rooms")
+            .header("Authorization", format!("Bearer {}", token))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let req = test::TestRequest::post()
+            .uri("/rooms")
+            .header("Authorization", format!("Bearer {}", token))
+            .set_json(&json!({ "name": "testRoom" }))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[actix_rt::test]
+    async fn get_users_returns_not_found_when_room_does_not_exist() {
+        let repo = InMemoryRepository::default();
+        let user = repo.create_user(create_user_data("testUser")).unwrap();
+        let user_bearer = bearer(&repo, user.id);
+        let mut app = test::init_service(
+            App::new()
+                .data(Database::Memory(repo))
+                .configure(init_routes),
+        )
+        .await;
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/rooms/{}/users", Uuid::new_v4()))
+            .header("Authorization", user_bearer.as_str())
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_rt::test]
+    async fn users_can_be_added_to_and_removed_from_room() {
+        let repo = InMemoryRepository::default();
+        let user = repo.create_user(create_user_data("testUser")).unwrap();
+        let user_bearer = bearer(&repo, user.id);
+        let mut app = test::init_service(
+            App::new()
+                .data(Database::Memory(repo))
+                .configure(init_routes),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/rooms")
+            .header("Authorization", user_bearer.as_str())
+            .set_json(&json!({ "name": "testRoom" }))
+            .to_request();
+        let room: serde_json::Value = test::read_response_json(&mut app, req).await;
+        let room_uri = format!("/rooms/{}/users", room["id"].as_str().unwrap());
+
+        let req = test::TestRequest::post()
+            .uri(&room_uri)
+            .header("Authorization", user_bearer.as_str())
+            .set_json(&json!({ "id": user.id }))
+            .to_request();
+        let added: serde_json::Value = test::read_response_json(&mut app, req).await;
+        assert_eq!(added["users"], json!([user.id]));
+
+        let req = test::TestRequest::delete()
+            .uri(&format!("{}/{}", room_uri, user.id))
+            .header("Authorization", user_bearer.as_str())
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+        let req = test::TestRequest::get()
+            .uri(&room_uri)
+            .header("Authorization", user_bearer.as_str())
+            .to_request();
+        let users: serde_json::Value = test::read_response_json(&mut app, req).await;
+        assert_eq!(users["users"], json!([]));
+    }
+}
@@ -1,15 +1,100 @@
 table! {
+    use diesel::sql_types::*;
+    use crate::sql_types::Uuid;
+
+    api_tokens (id) {
+        id -> Uuid,
+        user_id -> Uuid,
+        name -> Varchar,
+        token_hash -> Varchar,
+        scopes -> Varchar,
+        created -> Timestamp,
+        last_used -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    use crate::sql_types::Uuid;
+
+    audit_events (id) {
+        id -> Uuid,
+        created -> Timestamp,
+        action -> Varchar,
+        actor_id -> Nullable<Uuid>,
+        target_id -> Nullable<Uuid>,
+        ip -> Nullable<Varchar>,
+        request_id -> Nullable<Varchar>,
+        details -> Nullable<Varchar>,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    use crate::sql_types::Uuid;
+
+    email_tokens (token_hash) {
+        token_hash -> Varchar,
+        user_id -> Uuid,
+        purpose -> Varchar,
+        email -> Varchar,
+        expires -> Timestamp,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    use crate::sql_types::Uuid;
+
+    identities (issuer, subject) {
+        issuer -> Varchar,
+        subject -> Varchar,
+        user_id -> Uuid,
+        created -> Timestamp,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    use crate::sql_types::Uuid;
+
+    login_challenges (token_hash) {
+        token_hash -> Varchar,
+        user_id -> Uuid,
+        attempts -> Int4,
+        expires -> Timestamp,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    use crate::sql_types::Uuid;
+
     messages (id) {
         id -> Uuid,
         room_id -> Uuid,
-        author -> Uuid,
+        author -> Nullable<Uuid>,
         content -> Varchar,
         created -> Timestamp,
         updated -> Timestamp,
+        bot -> Bool,
     }
 }
 
 table! {
+    use diesel::sql_types::*;
+    use crate::sql_types::Uuid;
+
+    recovery_codes (user_id, code_hash) {
+        user_id -> Uuid,
+        code_hash -> Varchar,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    use crate::sql_types::Uuid;
+
     rooms (id) {
         id -> Uuid,
         name -> Nullable<Varchar>,
@@ -19,6 +104,9 @@ table! {
 }
 
 table! {
+    use diesel::sql_types::*;
+    use crate::sql_types::Uuid;
+
     rooms_users (user_id, room_id) {
         user_id -> Uuid,
         room_id -> Uuid,
@@ -29,18 +117,80 @@ table! {
 }
 
 table! {
+    use diesel::sql_types::*;
+    use crate::sql_types::Uuid;
+
+    sessions (id) {
+        id -> Uuid,
+        user_id -> Uuid,
+        device_name -> Nullable<Varchar>,
+        user_agent -> Nullable<Varchar>,
+        ip -> Nullable<Varchar>,
+        access_token_hash -> Varchar,
+        access_expires -> Timestamp,
+        refresh_token_hash -> Varchar,
+        previous_refresh_token_hash -> Nullable<Varchar>,
+        refresh_expires -> Timestamp,
+        created -> Timestamp,
+        last_seen -> Timestamp,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    use crate::sql_types::Uuid;
+
+    totp_secrets (user_id) {
+        user_id -> Uuid,
+        secret -> Varchar,
+        enabled -> Bool,
+        last_used_step -> Nullable<Int8>,
+        created -> Timestamp,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    use crate::sql_types::Uuid;
+
     users (id) {
         id -> Uuid,
         username -> Varchar,
         password -> Varchar,
         created -> Timestamp,
         updated -> Timestamp,
+        role -> Varchar,
+        bot -> Bool,
+        email -> Nullable<Varchar>,
+        email_verified -> Bool,
+        locked -> Bool,
+        deleted -> Nullable<Timestamp>,
     }
 }
 
+joinable!(api_tokens -> users (user_id));
+joinable!(email_tokens -> users (user_id));
+joinable!(identities -> users (user_id));
+joinable!(login_challenges -> users (user_id));
 joinable!(messages -> rooms (room_id));
 joinable!(messages -> users (author));
+joinable!(recovery_codes -> users (user_id));
 joinable!(rooms_users -> rooms (room_id));
 joinable!(rooms_users -> users (user_id));
+joinable!(sessions -> users (user_id));
+joinable!(totp_secrets -> users (user_id));
 
-allow_tables_to_appear_in_same_query!(messages, rooms, rooms_users, users,);
+allow_tables_to_appear_in_same_query!(
+    api_tokens,
+    audit_events,
+    email_tokens,
+    identities,
+    login_challenges,
+    messages,
+    recovery_codes,
+    rooms,
+    rooms_users,
+    sessions,
+    totp_secrets,
+    users,
+);
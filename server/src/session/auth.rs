@@ -0,0 +1,299 @@
+use actix_web::http::header;
+use actix_web::HttpRequest;
+use chrono::{Duration, NaiveDateTime, Utc};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::config::AuthConfig;
+use crate::errors::ServiceError;
+use crate::repository::{SessionRepository, UserRepository};
+use crate::session::token;
+use crate::session::{Session, SessionError};
+
+const MAX_DEVICE_NAME_LENGTH: usize = 64;
+const MAX_USER_AGENT_LENGTH: usize = 256;
+
+/// The tokens handed out on login and refresh.
+///
+/// The access token authenticates requests until it expires after `expires_in` seconds, then the
+/// refresh token is exchanged for new tokens at `POST /auth/refresh`.
+#[derive(Serialize, Debug)]
+pub struct Tokens {
+    pub session_id: Uuid,
+    pub access_token: String,
+    pub refresh_token: String,
+    pub token_type: &'static str,
+    pub expires_in: u64,
+}
+
+/// Where a login comes from, shown to the user in the list of their sessions.
+#[derive(Clone, Default, Debug)]
+pub struct ClientInfo {
+    pub device_name: Option<String>,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+}
+
+impl ClientInfo {
+    /// Collects what the user sees about a new session from the login request.
+    pub fn from_request(
+        req: &HttpRequest,
+        device_name: Option<String>,
+    ) -> Result<ClientInfo, ServiceError> {
+        if device_name
+            .as_ref()
+            .is_some_and(|name| name.chars().count() > MAX_DEVICE_NAME_LENGTH)
+        {
+            return Err(ServiceError::Validation(format!(
+                "device_name must not be longer than {} characters",
+                MAX_DEVICE_NAME_LENGTH
+            )));
+        }
+
+        let user_agent = req
+            .headers()
+            .get(header::USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.chars().take(MAX_USER_AGENT_LENGTH).collect());
+        Ok(ClientInfo {
+            device_name,
+            user_agent,
+            ip: req.peer_addr().map(|addr| addr.ip().to_string()),
+        })
+    }
+}
+
+fn now() -> NaiveDateTime {
+    Utc::now().naive_utc()
+}
+
+fn lifetime(duration: std::time::Duration) -> Result<Duration, SessionError> {
+    Duration::from_std(duration).map_err(|_| SessionError::GenericError)
+}
+
+impl Session {
+    /// Starts a session for a user that just logged in. Fails with `AccountLocked` for users an
+    /// admin has locked and with `AccountDeleted` for deleted users, whichever way they logged in,
+    /// and with `InvalidToken` for users that do not exist.
+    pub fn start<R: SessionRepository + UserRepository + ?Sized>(
+        repo: &R,
+        user_id: Uuid,
+        client: ClientInfo,
+        config: &AuthConfig,
+    ) -> Result<Tokens, SessionError> {
+        let user = repo
+            .find_user_by_id(user_id)
+            .map_err(|_| SessionError::DatabaseError)?;
+        match user {
+            Some(user) if user.locked => return Err(SessionError::AccountLocked),
+            Some(user) if user.deleted.is_some() => return Err(SessionError::AccountDeleted),
+            Some(_) => (),
+            None => return Err(SessionError::InvalidToken),
+        }
+
+        let now = now();
+        let access_token = token::generate();
+        let refresh_token = token::generate();
+        let session = Session {
+            id: Uuid::new_v4(),
+            user_id,
+            device_name: client.device_name,
+            user_agent: client.user_agent,
+            ip: client.ip,
+            access_token_hash: token::hash(&access_token),
+            access_expires: now + lifetime(config.access_token_lifetime)?,
+            refresh_token_hash: token::hash(&refresh_token),
+            previous_refresh_token_hash: None,
+            refresh_expires: now + lifetime(config.refresh_token_lifetime)?,
+            created: now,
+            last_seen: now,
+        };
+
+        let session = repo.create_session(session)?;
+        Ok(Tokens {
+            session_id: session.id,
+            access_token,
+            refresh_token,
+            token_type: "Bearer",
+            expires_in: config.access_token_lifetime.as_secs(),
+        })
+    }
+
+    /// Exchanges a refresh token for new tokens.
+    ///
+    /// Every refresh token can only be used once. If the last exchanged token is used again,
+    /// either the client or an attacker holds a stolen copy, so the whole session is revoked.
+    pub fn refresh<R: SessionRepository + ?Sized>(
+        repo: &R,
+        refresh_token: &str,
+        config: &AuthConfig,
+    ) -> Result<Tokens, SessionError> {
+        let old_hash = token::hash(refresh_token);
+        let mut session = match repo.find_session_by_refresh_token(&old_hash)? {
+            Some(session) => session,
+            None => {
+                if let Some(session) = repo.find_session_by_previous_refresh_token(&old_hash)? {
+                    return Err(Session::revoke_reused(repo, &session));
+                }
+                return Err(SessionError::InvalidToken);
+            }
+        };
+
+        let now = now();
+        if session.refresh_expires <= now {
+            return Err(SessionError::InvalidToken);
+        }
+
+        let access_token = token::generate();
+        let refresh_token = token::generate();
+        session.access_token_hash = token::hash(&access_token);
+        session.access_expires = now + lifetime(config.access_token_lifetime)?;
+        session.refresh_token_hash = token::hash(&refresh_token);
+        session.previous_refresh_token_hash = Some(old_hash.clone());
+        session.refresh_expires = now + lifetime(config.refresh_token_lifetime)?;
+        session.last_seen = now;
+
+        // Fails if another request exchanged the same token in the meantime
+        if !repo.replace_session_tokens(&session, &old_hash)? {
+            return Err(Session::revoke_reused(repo, &session));
+        }
+        Ok(Tokens {
+            session_id: session.id,
+            access_token,
+            refresh_token,
+            token_type: "Bearer",
+            expires_in: config.access_token_lifetime.as_secs(),
+        })
+    }
+
+    fn revoke_reused<R: SessionRepository + ?Sized>(repo: &R, session: &Session) -> SessionError {
+        tracing::warn!(
+            session_id = %session.id,
+            user_id = %session.user_id,
+            "A refresh token was used twice, revoking the session"
+        );
+        match repo.destroy_session(session.user_id, session.id) {
+            Ok(_) => SessionError::TokenReused,
+            Err(e) => e,
+        }
+    }
+
+    /// Finds the session of an access token, failing if the token is unknown or expired.
+    pub fn authenticate<R: SessionRepository + ?Sized>(
+        repo: &R,
+        access_token: &str,
+    ) -> Result<Session, SessionError> {
+        let session = repo
+            .find_session_by_access_token(&token::hash(access_token))?
+            .ok_or(SessionError::InvalidToken)?;
+
+        let now = now();
+        if session.access_expires <= now {
+            return Err(SessionError::InvalidToken);
+        }
+        if token::last_use_outdated(session.last_seen, now) {
+            repo.touch_session(session.id, now)?;
+        }
+        Ok(session)
+    }
+
+    /// Whether the session can still be refreshed.
+    pub fn is_active(&self) -> bool {
+        self.refresh_expires > now()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::{InMemoryRepository, UserRepository};
+    use crate::test_helpers::*;
+
+    fn start_session(repo: &InMemoryRepository, config: &AuthConfig) -> Tokens {
+        let user = repo.create_user(create_user_data("testUser")).unwrap();
+        Session::start(repo, user.id, ClientInfo::default(), config).unwrap()
+    }
+
+    #[test]
+    fn access_token_authenticates_session() {
+        let repo = InMemoryRepository::default();
+        let tokens = start_session(&repo, &AuthConfig::default());
+
+        let session = Session::authenticate(&repo, &tokens.access_token).unwrap();
+        assert_eq!(session.id, tokens.session_id);
+        assert!(matches!(
+            Session::authenticate(&repo, &tokens.refresh_token),
+            Err(SessionError::InvalidToken)
+        ));
+    }
+
+    #[test]
+    fn expired_access_token_is_rejected() {
+        let repo = InMemoryRepository::default();
+        let config = AuthConfig {
+            access_token_lifetime: std::time::Duration::from_secs(0),
+            ..AuthConfig::default()
+        };
+        let tokens = start_session(&repo, &config);
+
+        assert!(matches!(
+            Session::authenticate(&repo, &tokens.access_token),
+            Err(SessionError::InvalidToken)
+        ));
+    }
+
+    #[test]
+    fn refresh_rotates_tokens() {
+        let repo = InMemoryRepository::default();
+        let config = AuthConfig::default();
+        let tokens = start_session(&repo, &config);
+
+        let refreshed = Session::refresh(&repo, &tokens.refresh_token, &config).unwrap();
+
+        assert_eq!(refreshed.session_id, tokens.session_id);
+        assert_ne!(refreshed.refresh_token, tokens.refresh_token);
+        assert!(Session::authenticate(&repo, &refreshed.access_token).is_ok());
+        assert!(Session::authenticate(&repo, &tokens.access_token).is_err());
+    }
+
+    #[test]
+    fn reused_refresh_token_revokes_session() {
+        let repo = InMemoryRepository::default();
+        let config = AuthConfig::default();
+        let tokens = start_session(&repo, &config);
+        let refreshed = Session::refresh(&repo, &tokens.refresh_token, &config).unwrap();
+
+        assert!(matches!(
+            Session::refresh(&repo, &tokens.refresh_token, &config),
+            Err(SessionError::TokenReused)
+        ));
+        assert!(Session::authenticate(&repo, &refreshed.access_token).is_err());
+        assert!(matches!(
+            Session::refresh(&repo, &refreshed.refresh_token, &config),
+            Err(SessionError::InvalidToken)
+        ));
+    }
+
+    #[test]
+    fn no_session_is_started_for_unknown_users() {
+        let repo = InMemoryRepository::default();
+
+        let result = Session::start(
+            &repo,
+            Uuid::new_v4(),
+            ClientInfo::default(),
+            &AuthConfig::default(),
+        );
+        assert!(matches!(result, Err(SessionError::InvalidToken)));
+    }
+
+    #[test]
+    fn unknown_refresh_token_is_rejected() {
+        let repo = InMemoryRepository::default();
+
+        assert!(matches!(
+            Session::refresh(&repo, &token::generate(), &AuthConfig::default()),
+            Err(SessionError::InvalidToken)
+        ));
+    }
+}
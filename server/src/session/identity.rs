@@ -0,0 +1,73 @@
+use actix_web::http::header;
+use actix_web::{dev, FromRequest, HttpRequest};
+use futures::future::LocalBoxFuture;
+use uuid::Uuid;
+
+use crate::db::DbConn;
+use crate::errors::ServiceError;
+use crate::session::Session;
+use crate::telemetry;
+
+/// The user and session of a request with a valid access token.
+///
+/// Use this as an extractor in routes that need a logged in user. Requests without a valid
+/// `Authorization: Bearer` header fail with `401 Unauthorized`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Identity {
+    pub user_id: Uuid,
+    pub session_id: Uuid,
+}
+
+pub(crate) fn bearer_token(req: &HttpRequest) -> Option<String> {
+    let value = req.headers().get(header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = value.split_at(value.find(' ')?);
+    if scheme.eq_ignore_ascii_case("Bearer") {
+        Some(token.trim().to_string())
+    } else {
+        None
+    }
+}
+
+impl FromRequest for Identity {
+    type Error = ServiceError;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, payload: &mut dev::Payload) -> Self::Future {
+        let token = bearer_token(req);
+        let conn = DbConn::from_request(req, payload);
+
+        Box::pin(async move {
+            let token = token.ok_or(ServiceError::Unauthorized)?;
+            let session = conn
+                .await?
+                .run(move |repo| Session::authenticate(repo, &token))
+                .await?;
+
+            telemetry::record_user_id(session.user_id);
+            Ok(Identity {
+                user_id: session.user_id,
+                session_id: session.id,
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn bearer_token_is_read_from_authorization_header() {
+        let req = TestRequest::default()
+            .header(header::AUTHORIZATION, "bearer abc123")
+            .to_http_request();
+        assert_eq!(bearer_token(&req).as_deref(), Some("abc123"));
+
+        let req = TestRequest::default()
+            .header(header::AUTHORIZATION, "Basic abc123")
+            .to_http_request();
+        assert_eq!(bearer_token(&req), None);
+    }
+}
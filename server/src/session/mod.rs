@@ -0,0 +1,10 @@
+pub(crate) mod auth;
+mod identity;
+mod model;
+mod routes;
+pub(crate) mod token;
+
+pub(crate) use identity::bearer_token;
+pub use identity::Identity;
+pub use model::*;
+pub use routes::init_routes;
@@ -0,0 +1,187 @@
+use chrono::NaiveDateTime;
+use diesel::result::Error as DieselError;
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::query::prelude::*;
+use crate::schema::sessions;
+
+/// A login of a user on one device, which can be revoked.
+///
+/// The tokens of a session are not stored, only their hashes.
+#[derive(Clone, Queryable, PartialEq, Debug)]
+pub struct Session {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub device_name: Option<String>,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub access_token_hash: String,
+    pub access_expires: NaiveDateTime,
+    pub refresh_token_hash: String,
+    /// The refresh token that was exchanged last, to detect when it is used again.
+    pub previous_refresh_token_hash: Option<String>,
+    pub refresh_expires: NaiveDateTime,
+    pub created: NaiveDateTime,
+    pub last_seen: NaiveDateTime,
+}
+
+// Do not return the token hashes
+#[derive(Serialize, Debug)]
+pub struct SessionResponse {
+    pub id: Uuid,
+    pub device_name: Option<String>,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub created: NaiveDateTime,
+    pub last_seen: NaiveDateTime,
+    /// Whether this is the session the request was made with.
+    pub current: bool,
+}
+
+impl SessionResponse {
+    pub fn new(session: Session, current_session_id: Uuid) -> SessionResponse {
+        SessionResponse {
+            current: session.id == current_session_id,
+            id: session.id,
+            device_name: session.device_name,
+            user_agent: session.user_agent,
+            ip: session.ip,
+            created: session.created,
+            last_seen: session.last_seen,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SessionError {
+    SessionNotFound,
+    /// The token is unknown or expired.
+    InvalidToken,
+    /// A refresh token was used a second time, the session has been revoked.
+    TokenReused,
+    /// The user is locked and may not log in.
+    AccountLocked,
+    /// The user is deleted and may not log in until an admin restores the account.
+    AccountDeleted,
+    DatabaseError,
+    GenericError,
+}
+
+impl Session {
+    pub fn create(conn: &impl SqlConnection, session: Session) -> Result<Session, SessionError> {
+        diesel::insert_into(sessions::table)
+            .values((
+                sessions::id.eq(Id(session.id)),
+                sessions::user_id.eq(Id(session.user_id)),
+                sessions::device_name.eq(&session.device_name),
+                sessions::user_agent.eq(&session.user_agent),
+                sessions::ip.eq(&session.ip),
+                sessions::access_token_hash.eq(&session.access_token_hash),
+                sessions::access_expires.eq(session.access_expires),
+                sessions::refresh_token_hash.eq(&session.refresh_token_hash),
+                sessions::previous_refresh_token_hash.eq(&session.previous_refresh_token_hash),
+                sessions::refresh_expires.eq(session.refresh_expires),
+                sessions::created.eq(session.created),
+                sessions::last_seen.eq(session.last_seen),
+            ))
+            .execute(conn)?;
+        Ok(session)
+    }
+
+    pub fn find_by_access_token(
+        conn: &impl SqlConnection,
+        token_hash: &str,
+    ) -> Result<Option<Session>, SessionError> {
+        Ok(sessions::table
+            .filter(sessions::access_token_hash.eq(token_hash))
+            .first(conn)
+            .optional()?)
+    }
+
+    pub fn find_by_refresh_token(
+        conn: &impl SqlConnection,
+        token_hash: &str,
+    ) -> Result<Option<Session>, SessionError> {
+        Ok(sessions::table
+            .filter(sessions::refresh_token_hash.eq(token_hash))
+            .first(conn)
+            .optional()?)
+    }
+
+    pub fn find_by_previous_refresh_token(
+        conn: &impl SqlConnection,
+        token_hash: &str,
+    ) -> Result<Option<Session>, SessionError> {
+        Ok(sessions::table
+            .filter(sessions::previous_refresh_token_hash.eq(token_hash))
+            .first(conn)
+            .optional()?)
+    }
+
+    pub fn find_by_user(
+        conn: &impl SqlConnection,
+        user_id: Uuid,
+    ) -> Result<Vec<Session>, SessionError> {
+        Ok(sessions::table
+            .filter(sessions::user_id.eq(Id(user_id)))
+            .order(sessions::last_seen.desc())
+            .load(conn)?)
+    }
+
+    pub fn replace_tokens(
+        conn: &impl SqlConnection,
+        session: &Session,
+        old_refresh_token_hash: &str,
+    ) -> Result<bool, SessionError> {
+        let count = diesel::update(
+            sessions::table
+                .find(Id(session.id))
+                .filter(sessions::refresh_token_hash.eq(old_refresh_token_hash)),
+        )
+        .set((
+            sessions::access_token_hash.eq(&session.access_token_hash),
+            sessions::access_expires.eq(session.access_expires),
+            sessions::refresh_token_hash.eq(&session.refresh_token_hash),
+            sessions::previous_refresh_token_hash.eq(&session.previous_refresh_token_hash),
+            sessions::refresh_expires.eq(session.refresh_expires),
+            sessions::last_seen.eq(session.last_seen),
+        ))
+        .execute(conn)?;
+        Ok(count == 1)
+    }
+
+    pub fn touch(
+        conn: &impl SqlConnection,
+        session_id: Uuid,
+        last_seen: NaiveDateTime,
+    ) -> Result<(), SessionError> {
+        diesel::update(sessions::table.find(Id(session_id)))
+            .set(sessions::last_seen.eq(last_seen))
+            .execute(conn)?;
+        Ok(())
+    }
+
+    pub fn destroy(
+        conn: &impl SqlConnection,
+        user_id: Uuid,
+        session_id: Uuid,
+    ) -> Result<usize, SessionError> {
+        Ok(diesel::delete(
+            sessions::table
+                .find(Id(session_id))
+                .filter(sessions::user_id.eq(Id(user_id))),
+        )
+        .execute(conn)?)
+    }
+}
+
+impl From<DieselError> for SessionError {
+    fn from(error: DieselError) -> SessionError {
+        match error {
+            DieselError::DatabaseError(_, _) => SessionError::DatabaseError,
+            DieselError::NotFound => SessionError::SessionNotFound,
+            _ => SessionError::GenericError,
+        }
+    }
+}
@@ -0,0 +1,234 @@
+use actix_web::{delete, get, post, web, HttpResponse};
+use serde::Deserialize;
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::config::AuthConfig;
+use crate::db::DbConn;
+use crate::errors::ServiceError;
+use crate::session::{Identity, Session, SessionResponse};
+
+#[derive(Deserialize, Debug)]
+pub struct RefreshData {
+    pub refresh_token: String,
+}
+
+#[post("/auth/refresh")]
+pub async fn refresh(
+    conn: DbConn,
+    config: web::Data<AuthConfig>,
+    data: web::Json<RefreshData>,
+) -> Result<HttpResponse, ServiceError> {
+    let tokens = conn
+        .run(move |repo| Session::refresh(repo, &data.refresh_token, &config))
+        .await?;
+    Ok(HttpResponse::Ok().json(tokens))
+}
+
+#[post("/auth/logout")]
+pub async fn logout(conn: DbConn, identity: Identity) -> Result<HttpResponse, ServiceError> {
+    conn.run(move |repo| repo.destroy_session(identity.user_id, identity.session_id))
+        .await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[get("/me/sessions")]
+pub async fn list(conn: DbConn, identity: Identity) -> Result<HttpResponse, ServiceError> {
+    let sessions = conn
+        .run(move |repo| repo.find_user_sessions(identity.user_id))
+        .await?;
+
+    let sessions: Vec<SessionResponse> = sessions
+        .into_iter()
+        .filter(Session::is_active)
+        .map(|session| SessionResponse::new(session, identity.session_id))
+        .collect();
+    Ok(HttpResponse::Ok().json(json!({ "sessions": sessions })))
+}
+
+#[delete("/me/sessions/{id}")]
+pub async fn delete(
+    conn: DbConn,
+    identity: Identity,
+    session_id: web::Path<Uuid>,
+) -> Result<HttpResponse, ServiceError> {
+    let count = conn
+        .run(move |repo| repo.destroy_session(identity.user_id, session_id.into_inner()))
+        .await?;
+
+    if count == 0 {
+        Err(ServiceError::NotFound)
+    } else {
+        Ok(HttpResponse::NoContent().finish())
+    }
+}
+
+pub fn init_routes(config: &mut web::ServiceConfig) {
+    config.service(refresh);
+    config.service(logout);
+    config.service(list);
+    config.service(delete);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Database;
+    use crate::repository::{InMemoryRepository, UserRepository};
+    use crate::session::auth::{ClientInfo, Tokens};
+    use crate::test_helpers::*;
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App};
+    use serde_json::Value;
+
+    fn login(repo: &InMemoryRepository, user_id: Uuid, device_name: &str) -> Tokens {
+        let client = ClientInfo {
+            device_name: Some(String::from(device_name)),
+            ..ClientInfo::default()
+        };
+        Session::start(repo, user_id, client, &AuthConfig::default()).unwrap()
+    }
+
+    fn bearer(tokens: &Tokens) -> String {
+        format!("Bearer {}", tokens.access_token)
+    }
+
+    #[actix_rt::test]
+    async fn refresh_returns_new_tokens() {
+        let repo = InMemoryRepository::default();
+        let user = repo.create_user(create_user_data("testUser")).unwrap();
+        let tokens = login(&repo, user.id, "laptop");
+        let mut app = test::init_service(
+            App::new()
+                .data(Database::Memory(repo))
+                .data(AuthConfig::default())
+                .configure(init_routes),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/auth/refresh")
+            .set_json(&json!({ "refresh_token": tokens.refresh_token }))
+            .to_request();
+        let refreshed: Value = test::read_response_json(&mut app, req).await;
+        assert_eq!(refreshed["session_id"], tokens.session_id.to_string());
+        assert_ne!(refreshed["refresh_token"], tokens.refresh_token.as_str());
+
+        // Using the exchanged token again revokes the session
+        let req = test::TestRequest::post()
+            .uri("/auth/refresh")
+            .set_json(&json!({ "refresh_token": tokens.refresh_token }))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let req = test::TestRequest::get()
+            .uri("/me/sessions")
+            .header(
+                "Authorization",
+                format!("Bearer {}", refreshed["access_token"].as_str().unwrap()),
+            )
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_rt::test]
+    async fn logout_ends_current_session() {
+        let repo = InMemoryRepository::default();
+        let user = repo.create_user(create_user_data("testUser")).unwrap();
+        let tokens = login(&repo, user.id, "laptop");
+        let mut app = test::init_service(
+            App::new()
+                .data(Database::Memory(repo))
+                .data(AuthConfig::default())
+                .configure(init_routes),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/auth/logout")
+            .header("Authorization", bearer(&tokens))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+        let req = test::TestRequest::post()
+            .uri("/auth/logout")
+            .header("Authorization", bearer(&tokens))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_rt::test]
+    async fn sessions_can_be_listed_and_revoked() {
+        let repo = InMemoryRepository::default();
+        let user = repo.create_user(create_user_data("testUser")).unwrap();
+        let other = repo.create_user(create_user_data("otherUser")).unwrap();
+        let laptop = login(&repo, user.id, "laptop");
+        let phone = login(&repo, user.id, "phone");
+        let foreign = login(&repo, other.id, "laptop");
+        let mut app = test::init_service(
+            App::new()
+                .data(Database::Memory(repo))
+                .data(AuthConfig::default())
+                .configure(init_routes),
+        )
+        .await;
+
+        let req = test::TestRequest::get()
+            .uri("/me/sessions")
+            .header("Authorization", bearer(&laptop))
+            .to_request();
+        let body: Value = test::read_response_json(&mut app, req).await;
+        let sessions = body["sessions"].as_array().unwrap();
+        assert_eq!(sessions.len(), 2);
+        assert!(sessions.iter().all(
+            |session| session.get("ip").is_some() && session.get("access_token_hash").is_none()
+        ));
+        let current: Vec<&Value> = sessions
+            .iter()
+            .filter(|session| session["current"] == true)
+            .collect();
+        assert_eq!(current.len(), 1);
+        assert_eq!(current[0]["device_name"], "laptop");
+
+        // Sessions of other users cannot be revoked
+        let req = test::TestRequest::delete()
+            .uri(&format!("/me/sessions/{}", foreign.session_id))
+            .header("Authorization", bearer(&laptop))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        let req = test::TestRequest::delete()
+            .uri(&format!("/me/sessions/{}", phone.session_id))
+            .header("Authorization", bearer(&laptop))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+        let req = test::TestRequest::get()
+            .uri("/me/sessions")
+            .header("Authorization", bearer(&phone))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_rt::test]
+    async fn sessions_require_access_token() {
+        let mut app = test::init_service(
+            App::new()
+                .data(memory_database())
+                .data(AuthConfig::default())
+                .configure(init_routes),
+        )
+        .await;
+
+        let req = test::TestRequest::get().uri("/me/sessions").to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,56 @@
+//! Random tokens, of which only hashes are stored.
+
+use chrono::{Duration, NaiveDateTime};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+const TOKEN_BYTES: usize = 32;
+const LAST_USE_RESOLUTION_SECONDS: i64 = 60;
+
+/// Generates a new random token that can be used in headers and URLs.
+pub fn generate() -> String {
+    let mut bytes = [0u8; TOKEN_BYTES];
+    OsRng.fill_bytes(&mut bytes);
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+/// The hash that is stored instead of the token.
+///
+/// The tokens are random and long, so a fast hash is enough, other than for passwords.
+pub fn hash(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+/// Whether the recorded last use of a session or API token should be replaced with `now`.
+///
+/// It is only written when it is older than a minute and not on every request, so that reading
+/// with a token does not always write to the database as well.
+pub fn last_use_outdated(last_use: NaiveDateTime, now: NaiveDateTime) -> bool {
+    now - last_use > Duration::seconds(LAST_USE_RESOLUTION_SECONDS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_are_unique_and_url_safe() {
+        let first = generate();
+        let second = generate();
+
+        assert_ne!(first, second);
+        assert_eq!(first.len(), 43);
+        assert!(first
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_'));
+    }
+
+    #[test]
+    fn hash_is_hex_encoded_sha256() {
+        assert_eq!(
+            hash("thermit"),
+            "196ef764a87b868458c04707c7eca0c5e8248064e0c370fd42b6047262522c8b"
+        );
+    }
+}
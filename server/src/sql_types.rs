@@ -0,0 +1,50 @@
+//! SQL types that differ between the backends, used by `schema.rs`.
+
+use std::io::Write;
+
+use diesel::deserialize::{self, FromSql};
+use diesel::pg::Pg;
+use diesel::serialize::{self, Output, ToSql};
+#[cfg(feature = "sqlite")]
+use diesel::{backend::Backend, sql_types::Text, sqlite::Sqlite};
+
+/// A uuid column, which is a `uuid` on Postgres and text on SQLite, as SQLite has no uuid type.
+#[derive(SqlType, QueryId)]
+#[postgres(oid = "2950", array_oid = "2951")]
+#[sqlite_type = "Text"]
+pub struct Uuid;
+
+/// A uuid to compare with or to store in a `Uuid` column, e.g. `users.find(Id(user_id))`.
+///
+/// Rows are loaded into `uuid::Uuid` directly, but Diesel only lets its own uuid type bind a
+/// `uuid::Uuid`, which SQLite does not support.
+#[derive(Clone, Copy, Debug, AsExpression)]
+#[sql_type = "Uuid"]
+pub struct Id(pub uuid::Uuid);
+
+impl ToSql<Uuid, Pg> for Id {
+    fn to_sql<W: Write>(&self, out: &mut Output<W, Pg>) -> serialize::Result {
+        ToSql::<diesel::sql_types::Uuid, Pg>::to_sql(&self.0, out)
+    }
+}
+
+impl FromSql<Uuid, Pg> for uuid::Uuid {
+    fn from_sql(bytes: Option<&[u8]>) -> deserialize::Result<Self> {
+        FromSql::<diesel::sql_types::Uuid, Pg>::from_sql(bytes)
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl ToSql<Uuid, Sqlite> for Id {
+    fn to_sql<W: Write>(&self, out: &mut Output<W, Sqlite>) -> serialize::Result {
+        ToSql::<Text, Sqlite>::to_sql(self.0.to_string().as_str(), out)
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl FromSql<Uuid, Sqlite> for uuid::Uuid {
+    fn from_sql(bytes: Option<&<Sqlite as Backend>::RawValue>) -> deserialize::Result<Self> {
+        let text = <String as FromSql<Text, Sqlite>>::from_sql(bytes)?;
+        Ok(uuid::Uuid::parse_str(&text)?)
+    }
+}
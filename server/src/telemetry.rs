@@ -0,0 +1,237 @@
+//! Logging with `tracing`, and the request id that ties the log lines of a request together.
+//!
+//! Every request runs in a `request` span with its id, method and route. The user id is added
+//! once the user is authenticated, the status and latency when the response is ready. The id is
+//! taken from the `X-Request-Id` header if the client or a proxy sent one, otherwise it is
+//! generated. It is returned in the same header and in the body of error responses.
+
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::{HeaderMap, HeaderName, HeaderValue};
+use actix_web::Error;
+use futures::future::{ok, LocalBoxFuture, Ready};
+use tracing::field::{display, Empty};
+use tracing::{Instrument, Span};
+use tracing_subscriber::EnvFilter;
+use uuid::Uuid;
+
+use crate::config::LogFormat;
+use crate::health::PROBE_PATHS;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+// Longer or unusual ids are replaced, so that clients cannot put arbitrary text into the logs
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+/// Installs the global subscriber. The level is read from `RUST_LOG` and defaults to `info`.
+///
+/// Records of the `log` crate, e.g. from actix and diesel, are forwarded to the subscriber.
+pub fn init(format: LogFormat) {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let subscriber = tracing_subscriber::fmt().with_env_filter(filter);
+    match format {
+        LogFormat::Text => subscriber.init(),
+        LogFormat::Json => subscriber
+            .json()
+            .with_current_span(true)
+            .with_span_list(false)
+            .init(),
+    }
+}
+
+/// The id of the request handled by the current task, if any.
+pub fn current_request_id() -> Option<String> {
+    REQUEST_ID.try_with(|id| id.clone()).ok()
+}
+
+/// Adds the id of the authenticated user to the span of the current request.
+pub fn record_user_id(user_id: Uuid) {
+    Span::current().record("user_id", &display(user_id));
+}
+
+fn request_id_from(headers: &HeaderMap) -> Option<String> {
+    let id = headers.get(REQUEST_ID_HEADER)?.to_str().ok()?;
+    let valid = !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LENGTH
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"-_.:".contains(&b));
+    if valid {
+        Some(String::from(id))
+    } else {
+        None
+    }
+}
+
+/// Runs every request in its own span and logs one line when it is done.
+///
+/// This replaces the access log. Requests of probes and metric scrapers are only logged at the
+/// `debug` level, so that they do not drown the other requests.
+pub struct RequestTracing;
+
+impl<S, B> Transform<S> for RequestTracing
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RequestTracingMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RequestTracingMiddleware { service })
+    }
+}
+
+pub struct RequestTracingMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service for RequestTracingMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let start = Instant::now();
+        let request_id =
+            request_id_from(req.headers()).unwrap_or_else(|| Uuid::new_v4().to_string());
+        let quiet = PROBE_PATHS.contains(&req.path()) || req.path() == "/metrics";
+        let span = tracing::info_span!(
+            "request",
+            request_id = %request_id,
+            method = %req.method(),
+            route = %req.match_pattern().unwrap_or_else(|| req.path().to_string()),
+            user_id = Empty,
+        );
+
+        let response = span.in_scope(|| self.service.call(req));
+        let response = REQUEST_ID
+            .scope(request_id.clone(), response)
+            .instrument(span.clone());
+
+        Box::pin(async move {
+            let mut response = response.await;
+            let latency_ms = start.elapsed().as_secs_f64() * 1000.0;
+            let status = match response {
+                Ok(ref response) => response.status(),
+                Err(ref error) => error.as_response_error().status_code(),
+            };
+
+            span.in_scope(|| {
+                if quiet {
+                    tracing::debug!(status = status.as_u16(), latency_ms, "request finished");
+                } else if status.is_server_error() {
+                    tracing::warn!(status = status.as_u16(), latency_ms, "request finished");
+                } else {
+                    tracing::info!(status = status.as_u16(), latency_ms, "request finished");
+                }
+            });
+
+            if let Ok(ref mut response) = response {
+                if let Ok(value) = HeaderValue::from_str(&request_id) {
+                    response
+                        .headers_mut()
+                        .insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+                }
+            }
+            response
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::FeatureConfig;
+    use crate::test_helpers::*;
+    use actix_web::{test, web, App};
+    use serde_json::Value;
+
+    #[test]
+    fn valid_request_ids_are_propagated() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            HeaderName::from_static(REQUEST_ID_HEADER),
+            HeaderValue::from_static("abc-123_x.y:z"),
+        );
+        assert_eq!(request_id_from(&headers).as_deref(), Some("abc-123_x.y:z"));
+    }
+
+    #[test]
+    fn invalid_request_ids_are_ignored() {
+        let long_id = "a".repeat(MAX_REQUEST_ID_LENGTH + 1);
+        for id in &["", "two words", "line\tbreak", long_id.as_str()] {
+            let mut headers = HeaderMap::new();
+            headers.insert(
+                HeaderName::from_static(REQUEST_ID_HEADER),
+                HeaderValue::from_str(id).unwrap(),
+            );
+            assert_eq!(request_id_from(&headers), None, "{:?}", id);
+        }
+    }
+
+    #[test]
+    fn no_request_id_outside_of_requests() {
+        assert_eq!(current_request_id(), None);
+    }
+
+    #[actix_rt::test]
+    async fn request_id_is_generated_and_returned() {
+        let mut app = test::init_service(
+            App::new()
+                .wrap(RequestTracing)
+                .configure(crate::health::init_routes),
+        )
+        .await;
+
+        let req = test::TestRequest::get().uri("/healthz").to_request();
+        let resp = test::call_service(&mut app, req).await;
+
+        let id = resp.headers().get(REQUEST_ID_HEADER).unwrap();
+        assert!(Uuid::parse_str(id.to_str().unwrap()).is_ok());
+    }
+
+    #[actix_rt::test]
+    async fn request_id_is_put_into_error_responses() {
+        let mut app = test::init_service(
+            App::new()
+                .wrap(RequestTracing)
+                .data(memory_database())
+                .data(FeatureConfig::default())
+                .service(web::scope("/api/v1").configure(crate::user::init_routes)),
+        )
+        .await;
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/api/v1/users/{}", Uuid::new_v4()))
+            .header(REQUEST_ID_HEADER, "trace-42")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.headers().get(REQUEST_ID_HEADER).unwrap(), "trace-42");
+
+        let body: Value = test::read_body_json(resp).await;
+        assert_eq!(body["code"], "not_found");
+        assert_eq!(body["request_id"], "trace-42");
+    }
+}
@@ -1,18 +1,71 @@
 use crate::{
+    config::AuthConfig,
+    db::Database,
     message::MessageData,
+    migrations,
+    query::{AnyConnection, SqlConnection},
+    repository::InMemoryRepository,
     room::{Room, RoomData},
+    session::{auth::ClientInfo, token, Session},
     user::{User, UserData},
 };
+use chrono::{Duration, Utc};
 use diesel::prelude::*;
-use diesel_migrations::*;
+#[cfg(feature = "sqlite")]
+use diesel::SqliteConnection;
 use uuid::Uuid;
 
-pub fn connection() -> PgConnection {
-    let url = dotenv::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL must be set");
+/// The database of the model tests, see `connection`.
+pub enum TestConnection {
+    Postgres(PgConnection),
+    #[cfg(feature = "sqlite")]
+    Sqlite(SqliteConnection),
+}
+
+impl SqlConnection for TestConnection {
+    fn any(&self) -> AnyConnection<'_> {
+        match self {
+            TestConnection::Postgres(conn) => conn.any(),
+            #[cfg(feature = "sqlite")]
+            TestConnection::Sqlite(conn) => conn.any(),
+        }
+    }
+}
+
+/// The Postgres database at `TEST_DATABASE_URL` in a transaction that is never committed, or a
+/// new in-memory SQLite database if the variable is not set and the sqlite feature is enabled.
+pub fn connection() -> TestConnection {
+    match postgres_connection() {
+        Some(conn) => TestConnection::Postgres(conn),
+        None => sqlite_connection(),
+    }
+}
+
+/// The Postgres database at `TEST_DATABASE_URL` in a transaction that is never committed, `None`
+/// if the variable is not set. Tests of what only Postgres has are skipped without it.
+pub fn postgres_connection() -> Option<PgConnection> {
+    let url = dotenv::var("TEST_DATABASE_URL").ok()?;
     let conn = PgConnection::establish(&url).unwrap();
-    run_pending_migrations(&conn).unwrap();
+    migrations::migrate_postgres(&conn, true).unwrap();
     conn.begin_test_transaction().unwrap();
-    conn
+    Some(conn)
+}
+
+#[cfg(feature = "sqlite")]
+fn sqlite_connection() -> TestConnection {
+    let conn = SqliteConnection::establish(":memory:").unwrap();
+    crate::repository::sqlite::prepare_connection(&conn).unwrap();
+    migrations::migrate_sqlite(&conn, true).unwrap();
+    TestConnection::Sqlite(conn)
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn sqlite_connection() -> TestConnection {
+    panic!("TEST_DATABASE_URL must be set, or the sqlite feature enabled, to run the model tests")
+}
+
+pub fn memory_database() -> Database {
+    Database::Memory(InMemoryRepository::default())
 }
 
 pub fn create_user_data(username: &str) -> UserData {
@@ -33,18 +86,45 @@ pub fn create_message_data(content: &str, room_id: Uuid, author: Uuid) -> Messag
         content: String::from(content),
         room_id,
         author,
+        bot: false,
     }
 }
 
-pub(crate) fn setup_user(conn: &PgConnection) -> User {
+/// A session of the user that expires in an hour, with fresh token hashes.
+pub fn create_session(user_id: Uuid) -> Session {
+    let now = Utc::now().naive_utc();
+    Session {
+        id: Uuid::new_v4(),
+        user_id,
+        device_name: Some(String::from("testDevice")),
+        user_agent: None,
+        ip: None,
+        access_token_hash: token::hash(&token::generate()),
+        access_expires: now + Duration::hours(1),
+        refresh_token_hash: token::hash(&token::generate()),
+        previous_refresh_token_hash: None,
+        refresh_expires: now + Duration::hours(1),
+        created: now,
+        last_seen: now,
+    }
+}
+
+/// The `Authorization` header of a new session of the user.
+pub fn bearer(repo: &InMemoryRepository, user_id: Uuid) -> String {
+    let tokens =
+        Session::start(repo, user_id, ClientInfo::default(), &AuthConfig::default()).unwrap();
+    format!("Bearer {}", tokens.access_token)
+}
+
+pub(crate) fn setup_user(conn: &impl SqlConnection) -> User {
     setup_user_with_username(conn, "testUser")
 }
 
-pub(crate) fn setup_user_with_username(conn: &PgConnection, username: &str) -> User {
+pub(crate) fn setup_user_with_username(conn: &impl SqlConnection, username: &str) -> User {
     let response = User::create(create_user_data(username), conn).unwrap();
     User::_find(conn, response.id).unwrap().unwrap()
 }
 
-pub(crate) fn setup_room(conn: &PgConnection) -> Room {
+pub(crate) fn setup_room(conn: &impl SqlConnection) -> Room {
     Room::create(create_room_data("testRoom"), conn).unwrap()
 }
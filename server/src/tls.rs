@@ -0,0 +1,376 @@
+//! TLS with certificates that can be replaced while the server is running.
+//!
+//! The certificate and key are loaded into an `SslContext` that is shared by all workers. Every
+//! handshake picks the current context, so a reload only affects new connections and open
+//! connections keep the certificate they started with. The files are reloaded on `SIGHUP` and
+//! when their modification time changes. If the new files cannot be loaded, the error is logged
+//! and the old certificate stays in use.
+//!
+//! If `tls.client_ca_path` is set, clients may send a certificate signed by that CA. Its common
+//! name identifies the user, so that machine clients can log in without a password.
+
+use std::any::Any;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use actix_tls::openssl::SslStream;
+use actix_web::dev::Extensions;
+use actix_web::rt::net::TcpStream;
+use actix_web::rt::signal::unix::{signal, SignalKind};
+use actix_web::rt::time::interval;
+use derive_more::{Display, From};
+use openssl::error::ErrorStack;
+use openssl::nid::Nid;
+use openssl::ssl::{
+    SniError, SslAcceptor, SslAcceptorBuilder, SslContext, SslFiletype, SslMethod, SslRef,
+    SslVerifyMode,
+};
+use openssl::x509::{X509Name, X509VerifyResult};
+
+use crate::config::TlsConfig;
+
+#[derive(Debug, Display, From)]
+pub enum TlsError {
+    #[display(fmt = "could not load the TLS certificate or key: {}", _0)]
+    Ssl(ErrorStack),
+}
+
+impl From<TlsError> for io::Error {
+    fn from(error: TlsError) -> io::Error {
+        io::Error::other(error.to_string())
+    }
+}
+
+/// The identity of a client that presented a certificate signed by `tls.client_ca_path`.
+///
+/// It is added to the extensions of every request on the connection.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClientCertificate {
+    /// The common name of the subject, which is the username of the client.
+    pub common_name: String,
+}
+
+/// Adds the `ClientCertificate` to the requests of a connection, if the client sent one.
+pub fn client_certificate(connection: &dyn Any, data: &mut Extensions) {
+    if let Some(stream) = connection.downcast_ref::<SslStream<TcpStream>>() {
+        if let Some(certificate) = verified_client_certificate(stream.ssl()) {
+            data.insert(certificate);
+        }
+    }
+}
+
+fn verified_client_certificate(ssl: &SslRef) -> Option<ClientCertificate> {
+    if ssl.verify_result() != X509VerifyResult::OK {
+        return None;
+    }
+    let certificate = ssl.peer_certificate()?;
+    let entry = certificate
+        .subject_name()
+        .entries_by_nid(Nid::COMMONNAME)
+        .next()?;
+    let common_name = entry.data().as_utf8().ok()?.to_string();
+    Some(ClientCertificate { common_name })
+}
+
+#[derive(Clone)]
+struct CertificateFiles {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    client_ca_path: Option<PathBuf>,
+}
+
+impl CertificateFiles {
+    fn builder(&self) -> Result<SslAcceptorBuilder, TlsError> {
+        let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
+        builder.set_private_key_file(&self.key_path, SslFiletype::PEM)?;
+        builder.set_certificate_chain_file(&self.cert_path)?;
+        builder.check_private_key()?;
+
+        if let Some(ref client_ca_path) = self.client_ca_path {
+            builder.set_ca_file(client_ca_path)?;
+            builder.set_client_ca_list(X509Name::load_client_ca_file(client_ca_path)?);
+            // Client certificates are optional, clients without one log in with a password
+            builder.set_verify(SslVerifyMode::PEER);
+            // Needed to resume sessions of clients that sent a certificate
+            builder.set_session_id_context(b"thermit")?;
+        }
+        Ok(builder)
+    }
+
+    fn modified(&self) -> Vec<Option<SystemTime>> {
+        let modified = |path: &Path| fs::metadata(path).and_then(|m| m.modified()).ok();
+        let mut times = vec![modified(&self.cert_path), modified(&self.key_path)];
+        times.extend(self.client_ca_path.as_deref().map(modified));
+        times
+    }
+}
+
+/// The certificate currently served, together with the files it was loaded from.
+#[derive(Clone)]
+pub struct CertificateStore {
+    context: Arc<RwLock<SslContext>>,
+    files: CertificateFiles,
+}
+
+impl CertificateStore {
+    /// Loads the certificate, key and client CA, failing if they cannot be used.
+    pub fn load(config: &TlsConfig) -> Result<CertificateStore, TlsError> {
+        let files = CertificateFiles {
+            cert_path: config.cert_path.clone(),
+            key_path: config.key_path.clone(),
+            client_ca_path: config.client_ca_path.clone(),
+        };
+        let context = files.builder()?.build().into_context();
+        Ok(CertificateStore {
+            context: Arc::new(RwLock::new(context)),
+            files,
+        })
+    }
+
+    /// Loads the files again. On failure the old certificate is kept.
+    pub fn reload(&self) -> Result<(), TlsError> {
+        let context = self.files.builder()?.build().into_context();
+        *self.context.write().unwrap() = context;
+        Ok(())
+    }
+
+    /// Builds the acceptor for the server. It switches every handshake to the current context.
+    pub fn acceptor(&self) -> Result<SslAcceptorBuilder, TlsError> {
+        // The initial context needs a certificate as well, it is replaced in the callback
+        let mut builder = self.files.builder()?;
+
+        let context = self.context.clone();
+        // OpenSSL calls this for every handshake, also if the client sends no server name
+        builder.set_servername_callback(move |ssl, _alert| {
+            let context = context.read().unwrap();
+            ssl.set_ssl_context(&context).map_err(|e| {
+                tracing::error!("Could not switch to the current TLS certificate: {}", e);
+                SniError::ALERT_FATAL
+            })
+        });
+        Ok(builder)
+    }
+
+    fn reload_and_log(&self, reason: &str) {
+        match self.reload() {
+            Ok(()) => tracing::info!("Reloaded the TLS certificate after {}", reason),
+            Err(e) => tracing::error!("Keeping the old TLS certificate, {}", e),
+        }
+    }
+
+    /// Reloads the certificate on `SIGHUP`, and when the files changed if `watch_interval` is not
+    /// zero. The tasks run until the server stops.
+    pub fn watch(&self, watch_interval: Duration) {
+        let store = self.clone();
+        actix_web::rt::spawn(async move {
+            let mut hangups = match signal(SignalKind::hangup()) {
+                Ok(hangups) => hangups,
+                Err(e) => {
+                    tracing::error!("Could not listen for SIGHUP: {}", e);
+                    return;
+                }
+            };
+            while hangups.recv().await.is_some() {
+                store.reload_and_log("SIGHUP");
+            }
+        });
+
+        if watch_interval.as_secs() == 0 {
+            return;
+        }
+        let store = self.clone();
+        actix_web::rt::spawn(async move {
+            let mut ticks = interval(watch_interval);
+            let mut modified = store.files.modified();
+            loop {
+                ticks.tick().await;
+                let current = store.files.modified();
+                // Reload only once per change, so that broken files are not logged forever
+                if current != modified {
+                    modified = current;
+                    store.reload_and_log("a change of the files");
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::asn1::Asn1Time;
+    use openssl::hash::MessageDigest;
+    use openssl::pkey::{PKey, Private};
+    use openssl::rsa::Rsa;
+    use openssl::ssl::{HandshakeError, SslConnector};
+    use openssl::x509::extension::BasicConstraints;
+    use openssl::x509::{X509NameBuilder, X509};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use uuid::Uuid;
+
+    type Certificate = (X509, PKey<Private>);
+
+    /// Creates a certificate, a self-signed CA if there is no issuer.
+    fn certificate(common_name: &str, issuer: Option<&Certificate>) -> Certificate {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", common_name).unwrap();
+        let name = name.build();
+
+        let mut cert = X509::builder().unwrap();
+        cert.set_version(2).unwrap();
+        cert.set_subject_name(&name).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        match issuer {
+            Some((issuer_cert, issuer_key)) => {
+                cert.set_issuer_name(issuer_cert.subject_name()).unwrap();
+                cert.sign(issuer_key, MessageDigest::sha256()).unwrap();
+            }
+            None => {
+                let ca = BasicConstraints::new().critical().ca().build().unwrap();
+                cert.append_extension(ca).unwrap();
+                cert.set_issuer_name(&name).unwrap();
+                cert.sign(&key, MessageDigest::sha256()).unwrap();
+            }
+        }
+        (cert.build(), key)
+    }
+
+    /// Writes a new self-signed server certificate and its key into `dir`.
+    fn write_certificate(dir: &Path, common_name: &str) {
+        let (cert, key) = certificate(common_name, None);
+        fs::write(dir.join("cert.pem"), cert.to_pem().unwrap()).unwrap();
+        fs::write(dir.join("key.pem"), key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+    }
+
+    fn test_config() -> TlsConfig {
+        let dir = std::env::temp_dir().join(format!("thermit-tls-{}", Uuid::new_v4()));
+        fs::create_dir(&dir).unwrap();
+        write_certificate(&dir, "first");
+        TlsConfig {
+            enabled: true,
+            cert_path: dir.join("cert.pem"),
+            key_path: dir.join("key.pem"),
+            ..TlsConfig::default()
+        }
+    }
+
+    fn common_name(store: &CertificateStore) -> String {
+        let context = store.context.read().unwrap();
+        let cert = context.certificate().unwrap();
+        let entry = cert.subject_name().entries().next().unwrap();
+        entry.data().as_utf8().unwrap().to_string()
+    }
+
+    /// Connects to a server using `store`, returns what the server saw of the client.
+    fn handshake(
+        store: &CertificateStore,
+        client: Option<&Certificate>,
+    ) -> Result<Option<ClientCertificate>, ()> {
+        let acceptor = store.acceptor().unwrap().build();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            match acceptor.accept(stream) {
+                Ok(stream) => Ok(verified_client_certificate(stream.ssl())),
+                Err(HandshakeError::Failure(_)) => Err(()),
+                Err(e) => panic!("{}", e),
+            }
+        });
+
+        let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+        connector.set_verify(SslVerifyMode::NONE);
+        if let Some((cert, key)) = client {
+            connector.set_certificate(cert).unwrap();
+            connector.set_private_key(key).unwrap();
+        }
+        let stream = TcpStream::connect(address).unwrap();
+        // The client does not learn about a rejected certificate before it reads
+        let _ = connector.build().connect("localhost", stream);
+        server.join().unwrap()
+    }
+
+    #[test]
+    fn reload_switches_to_new_certificate() {
+        let config = test_config();
+        let store = CertificateStore::load(&config).unwrap();
+        assert!(store.acceptor().is_ok());
+
+        write_certificate(config.cert_path.parent().unwrap(), "second");
+        store.reload().unwrap();
+
+        assert_eq!(common_name(&store), "second");
+        fs::remove_dir_all(config.cert_path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn reload_keeps_old_certificate_when_files_are_broken() {
+        let config = test_config();
+        let store = CertificateStore::load(&config).unwrap();
+
+        fs::write(&config.cert_path, "not a certificate").unwrap();
+        assert!(matches!(store.reload(), Err(TlsError::Ssl(_))));
+
+        assert_eq!(common_name(&store), "first");
+        fs::remove_dir_all(config.cert_path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn load_rejects_key_of_another_certificate() {
+        let config = test_config();
+        let other = test_config();
+        let mismatched = TlsConfig {
+            key_path: other.key_path.clone(),
+            ..config.clone()
+        };
+
+        assert!(CertificateStore::load(&mismatched).is_err());
+        fs::remove_dir_all(config.cert_path.parent().unwrap()).unwrap();
+        fs::remove_dir_all(other.cert_path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn client_certificates_are_verified_against_client_ca() {
+        let mut config = test_config();
+        let dir = config.cert_path.parent().unwrap().to_path_buf();
+        let ca = certificate("thermit clients", None);
+        fs::write(dir.join("ca.pem"), ca.0.to_pem().unwrap()).unwrap();
+        config.client_ca_path = Some(dir.join("ca.pem"));
+        let store = CertificateStore::load(&config).unwrap();
+
+        let bot = certificate("deployBot", Some(&ca));
+        let expected = ClientCertificate {
+            common_name: String::from("deployBot"),
+        };
+        assert_eq!(handshake(&store, Some(&bot)), Ok(Some(expected)));
+
+        // Clients without a certificate log in with a password
+        assert_eq!(handshake(&store, None), Ok(None));
+
+        let other_ca = certificate("someone else", None);
+        let impostor = certificate("deployBot", Some(&other_ca));
+        assert_eq!(handshake(&store, Some(&impostor)), Err(()));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn client_certificates_are_ignored_without_client_ca() {
+        let config = test_config();
+        let store = CertificateStore::load(&config).unwrap();
+
+        let self_signed = certificate("deployBot", None);
+        assert_eq!(handshake(&store, Some(&self_signed)), Ok(None));
+
+        fs::remove_dir_all(config.cert_path.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,369 @@
+use chrono::{Duration, NaiveDateTime, Utc};
+use rand::rngs::OsRng;
+use rand::Rng;
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::repository::TwoFactorRepository;
+use crate::session::auth::Tokens;
+use crate::session::token;
+use crate::two_factor::totp;
+use crate::two_factor::{LoginChallenge, TotpSecret, TwoFactorError};
+
+const RECOVERY_CODE_COUNT: usize = 10;
+// Without characters that are easily confused, like 0 and o or 1 and l
+const RECOVERY_CODE_ALPHABET: &[u8] = b"abcdefghijkmnpqrstuvwxyz23456789";
+const RECOVERY_CODE_LENGTH: usize = 10;
+const CHALLENGE_LIFETIME_SECONDS: i64 = 5 * 60;
+const MAX_CHALLENGE_ATTEMPTS: i32 = 5;
+
+/// Returned once when two-factor authentication is set up, the secret cannot be read later.
+#[derive(Serialize, Debug)]
+pub struct Enrollment {
+    pub secret: String,
+    pub provisioning_uri: String,
+    /// Each code can be used once instead of a TOTP code, e.g. when the phone is lost.
+    pub recovery_codes: Vec<String>,
+}
+
+/// Returned by the login instead of tokens when the user has to send a code as well.
+#[derive(Serialize, Debug)]
+pub struct Challenge {
+    pub challenge_token: String,
+    pub second_factor: &'static str,
+    pub expires_in: u64,
+}
+
+/// The response of a login, the challenge if the user has two-factor authentication enabled.
+#[derive(Serialize, Debug)]
+#[serde(untagged)]
+pub enum LoginResponse {
+    Tokens(Tokens),
+    Challenge(Challenge),
+}
+
+fn now() -> NaiveDateTime {
+    Utc::now().naive_utc()
+}
+
+fn generate_recovery_code() -> String {
+    let code: String = (0..RECOVERY_CODE_LENGTH)
+        .map(|_| {
+            let index = OsRng.gen_range(0..RECOVERY_CODE_ALPHABET.len());
+            char::from(RECOVERY_CODE_ALPHABET[index])
+        })
+        .collect();
+    let (first, second) = code.split_at(RECOVERY_CODE_LENGTH / 2);
+    format!("{}-{}", first, second)
+}
+
+// Users may type the codes with spaces, without the dash or in upper case
+fn recovery_code_hash(code: &str) -> String {
+    let code: String = code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    token::hash(&code)
+}
+
+impl TotpSecret {
+    /// Creates a new secret and recovery codes. Logins only need a code once the user confirmed
+    /// the secret with `confirm`. An enrollment that was not confirmed is replaced.
+    pub fn enroll<R: TwoFactorRepository + ?Sized>(
+        repo: &R,
+        user_id: Uuid,
+        username: &str,
+    ) -> Result<Enrollment, TwoFactorError> {
+        if let Some(existing) = repo.find_totp_secret(user_id)? {
+            if existing.enabled {
+                return Err(TwoFactorError::AlreadyEnabled);
+            }
+            repo.destroy_totp_secret(user_id)?;
+        }
+
+        let secret = repo.create_totp_secret(TotpSecret {
+            user_id,
+            secret: totp::generate_secret(),
+            enabled: false,
+            last_used_step: None,
+            created: now(),
+        })?;
+        let recovery_codes: Vec<String> = (0..RECOVERY_CODE_COUNT)
+            .map(|_| generate_recovery_code())
+            .collect();
+        repo.replace_recovery_codes(
+            user_id,
+            recovery_codes
+                .iter()
+                .map(|code| recovery_code_hash(code))
+                .collect(),
+        )?;
+
+        Ok(Enrollment {
+            provisioning_uri: totp::provisioning_uri(&secret.secret, username),
+            secret: secret.secret,
+            recovery_codes,
+        })
+    }
+
+    /// Turns on two-factor authentication, once the user proved that their app has the secret.
+    pub fn confirm<R: TwoFactorRepository + ?Sized>(
+        repo: &R,
+        user_id: Uuid,
+        code: &str,
+    ) -> Result<(), TwoFactorError> {
+        let secret = repo
+            .find_totp_secret(user_id)?
+            .ok_or(TwoFactorError::NotEnrolled)?;
+        if secret.enabled {
+            return Err(TwoFactorError::AlreadyEnabled);
+        }
+        if !secret.check_totp_code(repo, code)? {
+            return Err(TwoFactorError::InvalidCode);
+        }
+        repo.enable_totp_secret(user_id)
+    }
+
+    /// Turns off two-factor authentication, which needs a valid TOTP or recovery code.
+    pub fn disable<R: TwoFactorRepository + ?Sized>(
+        repo: &R,
+        user_id: Uuid,
+        code: &str,
+    ) -> Result<(), TwoFactorError> {
+        let secret = repo
+            .find_totp_secret(user_id)?
+            .filter(|secret| secret.enabled)
+            .ok_or(TwoFactorError::NotEnrolled)?;
+        if !secret.check_code(repo, code)? {
+            return Err(TwoFactorError::InvalidCode);
+        }
+
+        repo.destroy_totp_secret(user_id)?;
+        repo.replace_recovery_codes(user_id, vec![])
+    }
+
+    // Every code is only accepted once, even within its period
+    fn check_totp_code<R: TwoFactorRepository + ?Sized>(
+        &self,
+        repo: &R,
+        code: &str,
+    ) -> Result<bool, TwoFactorError> {
+        match totp::verify(&self.secret, code.trim(), Utc::now().timestamp()) {
+            Some(step) => repo.use_totp_step(self.user_id, step),
+            None => Ok(false),
+        }
+    }
+
+    /// Accepts a TOTP code or a recovery code, which is used up.
+    fn check_code<R: TwoFactorRepository + ?Sized>(
+        &self,
+        repo: &R,
+        code: &str,
+    ) -> Result<bool, TwoFactorError> {
+        if self.check_totp_code(repo, code)? {
+            return Ok(true);
+        }
+        let used = repo.consume_recovery_code(self.user_id, &recovery_code_hash(code))?;
+        if used {
+            tracing::info!(user_id = %self.user_id, "A recovery code was used");
+        }
+        Ok(used)
+    }
+
+    pub fn is_enabled<R: TwoFactorRepository + ?Sized>(
+        repo: &R,
+        user_id: Uuid,
+    ) -> Result<bool, TwoFactorError> {
+        Ok(repo
+            .find_totp_secret(user_id)?
+            .is_some_and(|secret| secret.enabled))
+    }
+}
+
+impl LoginChallenge {
+    /// Starts the second step of a login, after the password was verified.
+    pub fn start<R: TwoFactorRepository + ?Sized>(
+        repo: &R,
+        user_id: Uuid,
+    ) -> Result<Challenge, TwoFactorError> {
+        let challenge_token = token::generate();
+        repo.create_login_challenge(LoginChallenge {
+            token_hash: token::hash(&challenge_token),
+            user_id,
+            attempts: 0,
+            expires: now() + Duration::seconds(CHALLENGE_LIFETIME_SECONDS),
+        })?;
+
+        Ok(Challenge {
+            challenge_token,
+            second_factor: "totp",
+            expires_in: CHALLENGE_LIFETIME_SECONDS as u64,
+        })
+    }
+
+    /// The user who started the challenge, also after it expired.
+    pub fn user_id<R: TwoFactorRepository + ?Sized>(
+        repo: &R,
+        challenge_token: &str,
+    ) -> Result<Option<Uuid>, TwoFactorError> {
+        let challenge = repo.find_login_challenge(&token::hash(challenge_token))?;
+        Ok(challenge.map(|challenge| challenge.user_id))
+    }
+
+    /// Checks the code sent for a challenge and returns the id of the user who logs in.
+    ///
+    /// A challenge can only be completed once, and only a few wrong codes are accepted.
+    pub fn complete<R: TwoFactorRepository + ?Sized>(
+        repo: &R,
+        challenge_token: &str,
+        code: &str,
+    ) -> Result<Uuid, TwoFactorError> {
+        let token_hash = token::hash(challenge_token);
+        let challenge = repo
+            .find_login_challenge(&token_hash)?
+            .ok_or(TwoFactorError::InvalidChallenge)?;
+        if challenge.expires <= now() || challenge.attempts >= MAX_CHALLENGE_ATTEMPTS {
+            repo.destroy_login_challenge(&token_hash)?;
+            return Err(TwoFactorError::InvalidChallenge);
+        }
+
+        // Two-factor authentication may have been turned off in the meantime
+        let secret = match repo.find_totp_secret(challenge.user_id)? {
+            Some(secret) if secret.enabled => secret,
+            _ => {
+                repo.destroy_login_challenge(&token_hash)?;
+                return Err(TwoFactorError::InvalidChallenge);
+            }
+        };
+        if !secret.check_code(repo, code)? {
+            repo.count_login_challenge_attempt(&token_hash)?;
+            return Err(TwoFactorError::InvalidCode);
+        }
+
+        // Fails if a concurrent request completed the challenge first
+        if repo.destroy_login_challenge(&token_hash)? == 0 {
+            return Err(TwoFactorError::InvalidChallenge);
+        }
+        Ok(challenge.user_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::{InMemoryRepository, UserRepository};
+    use crate::test_helpers::*;
+
+    fn enrolled_user(repo: &InMemoryRepository) -> (Uuid, Enrollment) {
+        let user = repo.create_user(create_user_data("testUser")).unwrap();
+        let enrollment = TotpSecret::enroll(repo, user.id, &user.username).unwrap();
+        (user.id, enrollment)
+    }
+
+    fn current_code(secret: &str) -> String {
+        totp::code(secret, Utc::now().timestamp())
+    }
+
+    #[test]
+    fn enrollment_is_enabled_by_a_valid_code() {
+        let repo = InMemoryRepository::default();
+        let (user_id, enrollment) = enrolled_user(&repo);
+
+        assert_eq!(enrollment.recovery_codes.len(), RECOVERY_CODE_COUNT);
+        assert!(!TotpSecret::is_enabled(&repo, user_id).unwrap());
+        assert!(matches!(
+            TotpSecret::confirm(&repo, user_id, "000000"),
+            Err(TwoFactorError::InvalidCode)
+        ));
+
+        TotpSecret::confirm(&repo, user_id, &current_code(&enrollment.secret)).unwrap();
+        assert!(TotpSecret::is_enabled(&repo, user_id).unwrap());
+        assert!(matches!(
+            TotpSecret::enroll(&repo, user_id, "testUser"),
+            Err(TwoFactorError::AlreadyEnabled)
+        ));
+    }
+
+    #[test]
+    fn challenge_is_completed_once_with_recovery_code() {
+        let repo = InMemoryRepository::default();
+        let (user_id, enrollment) = enrolled_user(&repo);
+        TotpSecret::confirm(&repo, user_id, &current_code(&enrollment.secret)).unwrap();
+
+        let challenge = LoginChallenge::start(&repo, user_id).unwrap();
+        let code = enrollment.recovery_codes[0].to_uppercase();
+        assert_eq!(
+            LoginChallenge::complete(&repo, &challenge.challenge_token, &code).unwrap(),
+            user_id
+        );
+        assert!(matches!(
+            LoginChallenge::complete(&repo, &challenge.challenge_token, &code),
+            Err(TwoFactorError::InvalidChallenge)
+        ));
+
+        // The recovery code is used up
+        let challenge = LoginChallenge::start(&repo, user_id).unwrap();
+        assert!(matches!(
+            LoginChallenge::complete(&repo, &challenge.challenge_token, &code),
+            Err(TwoFactorError::InvalidCode)
+        ));
+    }
+
+    #[test]
+    fn totp_codes_cannot_be_replayed() {
+        let repo = InMemoryRepository::default();
+        let (user_id, enrollment) = enrolled_user(&repo);
+        let code = current_code(&enrollment.secret);
+        TotpSecret::confirm(&repo, user_id, &code).unwrap();
+
+        let challenge = LoginChallenge::start(&repo, user_id).unwrap();
+        assert!(matches!(
+            LoginChallenge::complete(&repo, &challenge.challenge_token, &code),
+            Err(TwoFactorError::InvalidCode)
+        ));
+    }
+
+    #[test]
+    fn challenge_is_revoked_after_too_many_wrong_codes() {
+        let repo = InMemoryRepository::default();
+        let (user_id, enrollment) = enrolled_user(&repo);
+        TotpSecret::confirm(&repo, user_id, &current_code(&enrollment.secret)).unwrap();
+
+        let challenge = LoginChallenge::start(&repo, user_id).unwrap();
+        for _ in 0..MAX_CHALLENGE_ATTEMPTS {
+            assert!(matches!(
+                LoginChallenge::complete(&repo, &challenge.challenge_token, "wrong-code"),
+                Err(TwoFactorError::InvalidCode)
+            ));
+        }
+        assert!(matches!(
+            LoginChallenge::complete(
+                &repo,
+                &challenge.challenge_token,
+                &enrollment.recovery_codes[0]
+            ),
+            Err(TwoFactorError::InvalidChallenge)
+        ));
+    }
+
+    #[test]
+    fn disable_requires_valid_code() {
+        let repo = InMemoryRepository::default();
+        let (user_id, enrollment) = enrolled_user(&repo);
+        TotpSecret::confirm(&repo, user_id, &current_code(&enrollment.secret)).unwrap();
+
+        assert!(matches!(
+            TotpSecret::disable(&repo, user_id, "wrong-code"),
+            Err(TwoFactorError::InvalidCode)
+        ));
+        assert!(TotpSecret::is_enabled(&repo, user_id).unwrap());
+
+        TotpSecret::disable(&repo, user_id, &enrollment.recovery_codes[1]).unwrap();
+        assert!(!TotpSecret::is_enabled(&repo, user_id).unwrap());
+        assert!(matches!(
+            TotpSecret::disable(&repo, user_id, &enrollment.recovery_codes[2]),
+            Err(TwoFactorError::NotEnrolled)
+        ));
+    }
+}
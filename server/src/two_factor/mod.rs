@@ -0,0 +1,7 @@
+pub(crate) mod auth;
+mod model;
+mod routes;
+pub(crate) mod totp;
+
+pub use model::*;
+pub use routes::init_routes;
@@ -0,0 +1,191 @@
+use chrono::NaiveDateTime;
+use diesel::result::Error as DieselError;
+use uuid::Uuid;
+
+use crate::query::prelude::*;
+use crate::schema::{login_challenges, recovery_codes, totp_secrets};
+
+/// The TOTP secret of a user. It is only used for logins once the user confirmed it with a code.
+#[derive(Clone, Queryable, PartialEq, Debug)]
+pub struct TotpSecret {
+    pub user_id: Uuid,
+    /// Base32 encoded, it has to be stored in clear to compute the codes.
+    pub secret: String,
+    pub enabled: bool,
+    /// The period of the last accepted code, so that no code can be used twice.
+    pub last_used_step: Option<i64>,
+    pub created: NaiveDateTime,
+}
+
+#[derive(Clone, Queryable, PartialEq, Debug)]
+pub struct RecoveryCode {
+    pub user_id: Uuid,
+    pub code_hash: String,
+}
+
+/// The second step of a login of a user with two-factor authentication.
+#[derive(Clone, Queryable, PartialEq, Debug)]
+pub struct LoginChallenge {
+    pub token_hash: String,
+    pub user_id: Uuid,
+    /// Wrong codes sent for this challenge.
+    pub attempts: i32,
+    pub expires: NaiveDateTime,
+}
+
+#[derive(Debug)]
+pub enum TwoFactorError {
+    /// The user has not set up two-factor authentication.
+    NotEnrolled,
+    AlreadyEnabled,
+    InvalidCode,
+    /// The challenge token is unknown, expired or was used too often.
+    InvalidChallenge,
+    DatabaseError,
+    GenericError,
+}
+
+impl TotpSecret {
+    pub fn find(
+        conn: &impl SqlConnection,
+        user_id: Uuid,
+    ) -> Result<Option<TotpSecret>, TwoFactorError> {
+        Ok(totp_secrets::table
+            .find(Id(user_id))
+            .first(conn)
+            .optional()?)
+    }
+
+    pub fn create(
+        conn: &impl SqlConnection,
+        secret: TotpSecret,
+    ) -> Result<TotpSecret, TwoFactorError> {
+        diesel::insert_into(totp_secrets::table)
+            .values((
+                totp_secrets::user_id.eq(Id(secret.user_id)),
+                totp_secrets::secret.eq(&secret.secret),
+                totp_secrets::enabled.eq(secret.enabled),
+                totp_secrets::last_used_step.eq(secret.last_used_step),
+                totp_secrets::created.eq(secret.created),
+            ))
+            .execute(conn)?;
+        Ok(secret)
+    }
+
+    pub fn enable(conn: &impl SqlConnection, user_id: Uuid) -> Result<(), TwoFactorError> {
+        diesel::update(totp_secrets::table.find(Id(user_id)))
+            .set(totp_secrets::enabled.eq(true))
+            .execute(conn)?;
+        Ok(())
+    }
+
+    pub fn use_step(
+        conn: &impl SqlConnection,
+        user_id: Uuid,
+        step: i64,
+    ) -> Result<bool, TwoFactorError> {
+        let count = diesel::update(
+            totp_secrets::table.find(Id(user_id)).filter(
+                totp_secrets::last_used_step
+                    .is_null()
+                    .or(totp_secrets::last_used_step.lt(step)),
+            ),
+        )
+        .set(totp_secrets::last_used_step.eq(step))
+        .execute(conn)?;
+        Ok(count == 1)
+    }
+
+    pub fn destroy(conn: &impl SqlConnection, user_id: Uuid) -> Result<usize, TwoFactorError> {
+        Ok(diesel::delete(totp_secrets::table.find(Id(user_id))).execute(conn)?)
+    }
+}
+
+impl RecoveryCode {
+    pub fn replace(
+        conn: &impl SqlConnection,
+        user_id: Uuid,
+        code_hashes: Vec<String>,
+    ) -> Result<(), TwoFactorError> {
+        let codes: Vec<_> = code_hashes
+            .iter()
+            .map(|code_hash| {
+                (
+                    recovery_codes::user_id.eq(Id(user_id)),
+                    recovery_codes::code_hash.eq(code_hash),
+                )
+            })
+            .collect();
+
+        conn.transaction::<_, TwoFactorError, _>(|| {
+            diesel::delete(recovery_codes::table.filter(recovery_codes::user_id.eq(Id(user_id))))
+                .execute(conn)?;
+            diesel::insert_into(recovery_codes::table)
+                .values(&codes)
+                .execute(conn)?;
+            Ok(())
+        })
+    }
+
+    /// Deletes the code, returns false if it does not exist.
+    pub fn consume(
+        conn: &impl SqlConnection,
+        user_id: Uuid,
+        code_hash: &str,
+    ) -> Result<bool, TwoFactorError> {
+        let count =
+            diesel::delete(recovery_codes::table.find((Id(user_id), code_hash))).execute(conn)?;
+        Ok(count == 1)
+    }
+}
+
+impl LoginChallenge {
+    pub fn create(
+        conn: &impl SqlConnection,
+        challenge: LoginChallenge,
+    ) -> Result<LoginChallenge, TwoFactorError> {
+        diesel::insert_into(login_challenges::table)
+            .values((
+                login_challenges::token_hash.eq(&challenge.token_hash),
+                login_challenges::user_id.eq(Id(challenge.user_id)),
+                login_challenges::attempts.eq(challenge.attempts),
+                login_challenges::expires.eq(challenge.expires),
+            ))
+            .execute(conn)?;
+        Ok(challenge)
+    }
+
+    pub fn find(
+        conn: &impl SqlConnection,
+        token_hash: &str,
+    ) -> Result<Option<LoginChallenge>, TwoFactorError> {
+        Ok(login_challenges::table
+            .find(token_hash)
+            .first(conn)
+            .optional()?)
+    }
+
+    pub fn count_attempt(
+        conn: &impl SqlConnection,
+        token_hash: &str,
+    ) -> Result<(), TwoFactorError> {
+        diesel::update(login_challenges::table.find(token_hash))
+            .set(login_challenges::attempts.eq(login_challenges::attempts + 1))
+            .execute(conn)?;
+        Ok(())
+    }
+
+    pub fn destroy(conn: &impl SqlConnection, token_hash: &str) -> Result<usize, TwoFactorError> {
+        Ok(diesel::delete(login_challenges::table.find(token_hash)).execute(conn)?)
+    }
+}
+
+impl From<DieselError> for TwoFactorError {
+    fn from(error: DieselError) -> TwoFactorError {
+        match error {
+            DieselError::DatabaseError(_, _) => TwoFactorError::DatabaseError,
+            DieselError::NotFound => TwoFactorError::NotEnrolled,
+            _ => TwoFactorError::GenericError,
+        }
+    }
+}
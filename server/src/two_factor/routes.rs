@@ -0,0 +1,342 @@
+use actix_web::{delete, post, web, HttpRequest, HttpResponse};
+use serde::Deserialize;
+
+use crate::audit::AuditContext;
+use crate::config::AuthConfig;
+use crate::db::DbConn;
+use crate::errors::ServiceError;
+use crate::metrics;
+use crate::session::auth::{ClientInfo, Tokens};
+use crate::session::{Identity, Session};
+use crate::two_factor::{LoginChallenge, TotpSecret, TwoFactorError};
+use crate::user::LoginThrottle;
+
+#[derive(Deserialize, Debug)]
+pub struct ChallengeResponse {
+    pub challenge_token: String,
+    /// A code of the authenticator app or one of the recovery codes.
+    pub code: String,
+    pub device_name: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CodeData {
+    pub code: String,
+}
+
+/// A wrong code of a logged in user is not a reason to drop their access token.
+fn code_error(error: TwoFactorError) -> ServiceError {
+    match error {
+        TwoFactorError::InvalidCode => ServiceError::Forbidden,
+        e => ServiceError::from(e),
+    }
+}
+
+/// The second step of a login with two-factor authentication. Wrong codes are throttled like wrong
+/// passwords, against the account of the challenge and the IP.
+#[post("/auth/2fa")]
+pub async fn complete(
+    req: HttpRequest,
+    conn: DbConn,
+    audit: AuditContext,
+    config: web::Data<AuthConfig>,
+    throttle: web::Data<LoginThrottle>,
+    data: web::Json<ChallengeResponse>,
+) -> Result<HttpResponse, ServiceError> {
+    let ChallengeResponse {
+        challenge_token,
+        code,
+        device_name,
+    } = data.into_inner();
+    let ip = req.peer_addr().map(|addr| addr.ip());
+    let client = ClientInfo::from_request(&req, device_name)?;
+
+    let tokens = conn
+        .run(move |repo| -> Result<Tokens, ServiceError> {
+            let username = match LoginChallenge::user_id(repo, &challenge_token)? {
+                Some(user_id) => repo.find_user_by_id(user_id)?.map(|user| user.username),
+                None => None,
+            };
+            if let Some(lockout) = throttle.lockout(username.as_deref(), ip) {
+                return Err(LoginThrottle::rate_limited(lockout));
+            }
+
+            let result = LoginChallenge::complete(repo, &challenge_token, &code)
+                .map_err(ServiceError::from)
+                .and_then(|user_id| {
+                    let tokens = Session::start(repo, user_id, client, &config)?;
+                    Ok((user_id, tokens))
+                });
+            match (&result, &username) {
+                (Ok(_), Some(username)) => throttle.record_success(username),
+                (Err(ServiceError::Unauthorized), _) => {
+                    throttle.record_failure(username.as_deref(), ip)
+                }
+                _ => (),
+            }
+            audit.record_login(repo, "totp", None, result)
+        })
+        .await
+        .map_err(|e| {
+            if let ServiceError::Unauthorized = e {
+                metrics::AUTH_FAILURES.inc();
+            }
+            e
+        })?;
+    Ok(HttpResponse::Ok().json(tokens))
+}
+
+#[post("/me/2fa/totp")]
+pub async fn enroll(conn: DbConn, identity: Identity) -> Result<HttpResponse, ServiceError> {
+    let enrollment = conn
+        .run(move |repo| -> Result<_, ServiceError> {
+            let user = repo
+                .find_user(identity.user_id)?
+                .ok_or(ServiceError::NotFound)?;
+            Ok(TotpSecret::enroll(repo, user.id, &user.username)?)
+        })
+        .await?;
+    Ok(HttpResponse::Ok().json(enrollment))
+}
+
+#[post("/me/2fa/totp/confirm")]
+pub async fn confirm(
+    conn: DbConn,
+    identity: Identity,
+    data: web::Json<CodeData>,
+) -> Result<HttpResponse, ServiceError> {
+    conn.run(move |repo| {
+        TotpSecret::confirm(repo, identity.user_id, &data.code).map_err(code_error)
+    })
+    .await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[delete("/me/2fa/totp")]
+pub async fn disable(
+    conn: DbConn,
+    identity: Identity,
+    data: web::Json<CodeData>,
+) -> Result<HttpResponse, ServiceError> {
+    conn.run(move |repo| {
+        TotpSecret::disable(repo, identity.user_id, &data.code).map_err(code_error)
+    })
+    .await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub fn init_routes(config: &mut web::ServiceConfig) {
+    config.service(complete);
+    config.service(enroll);
+    config.service(confirm);
+    config.service(disable);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ThrottleConfig;
+    use crate::db::Database;
+    use crate::repository::{InMemoryRepository, UserRepository};
+    use crate::test_helpers::*;
+    use crate::two_factor::totp;
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App};
+    use chrono::Utc;
+    use serde_json::{json, Value};
+
+    fn current_code(secret: &str) -> String {
+        totp::code(secret, Utc::now().timestamp())
+    }
+
+    #[actix_rt::test]
+    async fn login_with_two_factor_authentication() {
+        let repo = InMemoryRepository::default();
+        let user = repo.create_user(create_user_data("testUser")).unwrap();
+        let tokens = Session::start(
+            &repo,
+            user.id,
+            ClientInfo::default(),
+            &AuthConfig::default(),
+        )
+        .unwrap();
+        let bearer = format!("Bearer {}", tokens.access_token);
+        let mut app = test::init_service(
+            App::new()
+                .data(Database::Memory(repo))
+                .data(AuthConfig::default())
+                .data(LoginThrottle::default())
+                .configure(init_routes)
+                .configure(crate::user::init_routes),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/me/2fa/totp")
+            .header("Authorization", bearer.as_str())
+            .to_request();
+        let enrollment: Value = test::read_response_json(&mut app, req).await;
+        let secret = enrollment["secret"].as_str().unwrap().to_string();
+        assert!(enrollment["provisioning_uri"]
+            .as_str()
+            .unwrap()
+            .starts_with("otpauth://totp/thermit:testUser?"));
+        assert_eq!(enrollment["recovery_codes"].as_array().unwrap().len(), 10);
+
+        let req = test::TestRequest::post()
+            .uri("/me/2fa/totp/confirm")
+            .header("Authorization", bearer.as_str())
+            .set_json(&json!({ "code": "000000" }))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let req = test::TestRequest::post()
+            .uri("/me/2fa/totp/confirm")
+            .header("Authorization", bearer.as_str())
+            .set_json(&json!({ "code": current_code(&secret) }))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+        // The password alone only gives a challenge
+        let req = test::TestRequest::post()
+            .uri("/auth")
+            .set_json(&json!({ "username": "testUser", "password": "12345678" }))
+            .to_request();
+        let challenge: Value = test::read_response_json(&mut app, req).await;
+        assert_eq!(challenge["second_factor"], "totp");
+        assert!(challenge.get("access_token").is_none());
+
+        let recovery_code = enrollment["recovery_codes"][0].as_str().unwrap();
+        let req = test::TestRequest::post()
+            .uri("/auth/2fa")
+            .set_json(&json!({
+                "challenge_token": challenge["challenge_token"],
+                "code": recovery_code,
+                "device_name": "phone",
+            }))
+            .to_request();
+        let tokens: Value = test::read_response_json(&mut app, req).await;
+        assert_eq!(tokens["token_type"], "Bearer");
+
+        // Challenges are single use
+        let req = test::TestRequest::post()
+            .uri("/auth/2fa")
+            .set_json(&json!({
+                "challenge_token": challenge["challenge_token"],
+                "code": recovery_code,
+            }))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_rt::test]
+    async fn repeated_challenges_end_in_a_lockout() {
+        let repo = InMemoryRepository::default();
+        let user = repo.create_user(create_user_data("testUser")).unwrap();
+        let enrollment = TotpSecret::enroll(&repo, user.id, "testUser").unwrap();
+        TotpSecret::confirm(&repo, user.id, &current_code(&enrollment.secret)).unwrap();
+        let throttle = LoginThrottle::new(&ThrottleConfig {
+            account_attempts: 3,
+            ..ThrottleConfig::default()
+        });
+        let mut app = test::init_service(
+            App::new()
+                .data(Database::Memory(repo))
+                .data(AuthConfig::default())
+                .data(throttle)
+                .configure(init_routes)
+                .configure(crate::user::init_routes),
+        )
+        .await;
+
+        let mut challenges = Vec::new();
+        for _ in 0..4 {
+            let req = test::TestRequest::post()
+                .uri("/auth")
+                .set_json(&json!({ "username": "testUser", "password": "12345678" }))
+                .to_request();
+            let challenge: Value = test::read_response_json(&mut app, req).await;
+            challenges.push(challenge["challenge_token"].clone());
+        }
+
+        // A new challenge does not reset the failures of the previous ones
+        for challenge_token in &challenges[..3] {
+            let req = test::TestRequest::post()
+                .uri("/auth/2fa")
+                .set_json(&json!({ "challenge_token": challenge_token, "code": "000000" }))
+                .to_request();
+            let resp = test::call_service(&mut app, req).await;
+            assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        }
+
+        let req = test::TestRequest::post()
+            .uri("/auth/2fa")
+            .set_json(&json!({
+                "challenge_token": challenges[3],
+                "code": enrollment.recovery_codes[0],
+            }))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        let req = test::TestRequest::post()
+            .uri("/auth")
+            .set_json(&json!({ "username": "testUser", "password": "12345678" }))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[actix_rt::test]
+    async fn disable_needs_a_valid_code() {
+        let repo = InMemoryRepository::default();
+        let user = repo.create_user(create_user_data("testUser")).unwrap();
+        let enrollment = TotpSecret::enroll(&repo, user.id, "testUser").unwrap();
+        let tokens = Session::start(
+            &repo,
+            user.id,
+            ClientInfo::default(),
+            &AuthConfig::default(),
+        )
+        .unwrap();
+        let bearer = format!("Bearer {}", tokens.access_token);
+        let mut app = test::init_service(
+            App::new()
+                .data(Database::Memory(repo.clone()))
+                .data(AuthConfig::default())
+                .configure(init_routes),
+        )
+        .await;
+
+        // Not confirmed yet, so there is nothing to disable
+        let req = test::TestRequest::delete()
+            .uri("/me/2fa/totp")
+            .header("Authorization", bearer.as_str())
+            .set_json(&json!({ "code": enrollment.recovery_codes[0] }))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        TotpSecret::confirm(&repo, user.id, &current_code(&enrollment.secret)).unwrap();
+
+        let req = test::TestRequest::delete()
+            .uri("/me/2fa/totp")
+            .header("Authorization", bearer.as_str())
+            .set_json(&json!({ "code": "aaaaa-aaaaa" }))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let req = test::TestRequest::delete()
+            .uri("/me/2fa/totp")
+            .header("Authorization", bearer.as_str())
+            .set_json(&json!({ "code": enrollment.recovery_codes[0] }))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert!(!TotpSecret::is_enabled(&repo, user.id).unwrap());
+    }
+}
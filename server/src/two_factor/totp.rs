@@ -0,0 +1,140 @@
+//! Time-based one-time passwords as in RFC 6238, with the parameters that authenticator apps
+//! support: HMAC-SHA1, 6 digits and a period of 30 seconds.
+
+use base32::Alphabet;
+use hmac::{Hmac, Mac, NewMac};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use sha1::Sha1;
+
+const ISSUER: &str = "thermit";
+const SECRET_BYTES: usize = 20;
+const DIGITS: u32 = 6;
+const PERIOD_SECONDS: i64 = 30;
+// Codes of the previous and the next period are accepted as well, as clocks are never exact
+const ALLOWED_DRIFT: i64 = 1;
+
+const BASE32: Alphabet = Alphabet::RFC4648 { padding: false };
+
+/// Generates a new secret, base32 encoded as authenticator apps expect it.
+pub fn generate_secret() -> String {
+    let mut bytes = [0u8; SECRET_BYTES];
+    OsRng.fill_bytes(&mut bytes);
+    base32::encode(BASE32, &bytes)
+}
+
+/// The URI that authenticator apps read from a QR code.
+pub fn provisioning_uri(secret: &str, username: &str) -> String {
+    format!(
+        "otpauth://totp/{issuer}:{username}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={digits}&period={period}",
+        issuer = ISSUER,
+        username = percent_encode(username),
+        secret = secret,
+        digits = DIGITS,
+        period = PERIOD_SECONDS,
+    )
+}
+
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| {
+            if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+                char::from(b).to_string()
+            } else {
+                format!("%{:02X}", b)
+            }
+        })
+        .collect()
+}
+
+/// The number of the period a unix timestamp is in.
+pub fn step(timestamp: i64) -> i64 {
+    timestamp.div_euclid(PERIOD_SECONDS)
+}
+
+fn code_at(key: &[u8], step: i64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(&step.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+
+    // The dynamic truncation of RFC 4226
+    let offset = usize::from(hash[hash.len() - 1] & 0x0f);
+    let binary = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+    binary % 10u32.pow(DIGITS)
+}
+
+/// Finds the step at `timestamp` or next to it in which the code is valid.
+///
+/// Returns `None` for wrong codes, malformed codes and secrets that are not valid base32.
+pub fn verify(secret: &str, code: &str, timestamp: i64) -> Option<i64> {
+    if code.len() != DIGITS as usize || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let code: u32 = code.parse().ok()?;
+    let key = base32::decode(BASE32, secret)?;
+
+    let current = step(timestamp);
+    (current - ALLOWED_DRIFT..=current + ALLOWED_DRIFT).find(|&step| code_at(&key, step) == code)
+}
+
+#[cfg(test)]
+pub fn code(secret: &str, timestamp: i64) -> String {
+    let key = base32::decode(BASE32, secret).unwrap();
+    format!(
+        "{:0width$}",
+        code_at(&key, step(timestamp)),
+        width = DIGITS as usize
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codes_match_rfc_6238_test_vectors() {
+        let key = b"12345678901234567890";
+        // The RFC lists 8 digits, these are the last 6
+        assert_eq!(code_at(key, step(59)), 287_082);
+        assert_eq!(code_at(key, step(1_111_111_109)), 81_804);
+        assert_eq!(code_at(key, step(2_000_000_000)), 279_037);
+    }
+
+    #[test]
+    fn verify_accepts_codes_of_neighbouring_periods() {
+        let secret = generate_secret();
+        let now = 1_700_000_000;
+
+        assert_eq!(verify(&secret, &code(&secret, now), now), Some(step(now)));
+        assert_eq!(
+            verify(&secret, &code(&secret, now - 30), now),
+            Some(step(now) - 1)
+        );
+        assert_eq!(verify(&secret, &code(&secret, now - 90), now), None);
+    }
+
+    #[test]
+    fn verify_rejects_malformed_codes() {
+        let secret = generate_secret();
+
+        assert_eq!(verify(&secret, "12345", 0), None);
+        assert_eq!(verify(&secret, "12a456", 0), None);
+        assert_eq!(verify("not base32!", "123456", 0), None);
+    }
+
+    #[test]
+    fn provisioning_uri_encodes_username() {
+        let uri = provisioning_uri("JBSWY3DPEHPK3PXP", "tom smith");
+
+        assert_eq!(
+            uri,
+            "otpauth://totp/thermit:tom%20smith?secret=JBSWY3DPEHPK3PXP&issuer=thermit&algorithm=SHA1&digits=6&period=30"
+        );
+    }
+}
@@ -1,58 +1,158 @@
-use crate::user::auth::AuthenticationError::{DatabaseError, UserNotFound};
+use crate::repository::{TwoFactorRepository, UserRepository};
+use crate::telemetry;
+use crate::tls::ClientCertificate;
+use crate::two_factor::auth::Challenge;
+use crate::two_factor::{LoginChallenge, TotpSecret, TwoFactorError};
+use crate::user::auth::AuthenticationError::{DatabaseError, InvalidCredentials};
+use crate::user::password::{self, PasswordError};
 use crate::user::{User, UserData, UserError};
-use diesel::PgConnection;
-use pwhash::bcrypt;
 
 #[derive(Debug)]
 pub enum AuthenticationError {
-    IncorrectPassword,
-    UserNotFound,
-    BcryptError(pwhash::error::Error),
+    /// The username is unknown or the password is wrong. Both are the same error, so that
+    /// clients cannot find out which usernames exist.
+    InvalidCredentials,
+    PasswordHashError(PasswordError),
     DatabaseError(UserError),
+    SecondFactorError(TwoFactorError),
+}
+
+/// The outcome of a correct password.
+#[derive(Debug)]
+pub enum Authentication {
+    Authenticated(User),
+    /// The user has two-factor authentication enabled, the login is completed with a code for
+    /// the challenge at `POST /auth/2fa`.
+    SecondFactorRequired(Challenge),
 }
 
 impl User {
-    pub fn generate_password(clear_password: &str) -> String {
-        bcrypt::hash(clear_password).unwrap()
+    pub fn generate_password(clear_password: &str) -> Result<String, UserError> {
+        Ok(password::hash(clear_password)?)
     }
 
-    fn check_password(self: &User, clear_password: &str) -> bool {
-        bcrypt::verify(clear_password, &*self.password)
+    fn check_password(self: &User, clear_password: &str) -> Result<bool, AuthenticationError> {
+        password::verify(clear_password, &self.password)
+            .map_err(AuthenticationError::PasswordHashError)
+    }
+
+    /// Replaces an outdated hash of the password, which was just verified.
+    ///
+    /// The login still succeeds if this fails, the hash is replaced on the next login instead.
+    fn rehash_password<R: UserRepository + ?Sized>(&self, repo: &R, clear_password: &str) {
+        if !password::needs_rehash(&self.password) {
+            return;
+        }
+        let result = User::generate_password(clear_password)
+            .and_then(|hash| repo.set_user_password(self.id, &hash));
+        if let Err(e) = result {
+            tracing::warn!(user_id = %self.id, "Could not rehash the password: {:?}", e);
+        }
     }
 
-    pub fn authenticate(
-        conn: &PgConnection,
+    pub fn authenticate<R: UserRepository + TwoFactorRepository + ?Sized>(
+        repo: &R,
         user_data: UserData,
-    ) -> Result<String, AuthenticationError> {
-        let user = User::_find_by_username(conn, &*user_data.username);
+    ) -> Result<Authentication, AuthenticationError> {
+        let user = repo.find_user_by_username(&user_data.username);
         let user = match user {
             Err(e) => return Err(DatabaseError(e)),
             Ok(u) => u,
         };
         let user = match user {
-            None => return Err(UserNotFound),
+            None => {
+                // Takes as long as checking a real password, so that the response time does not
+                // tell whether the user exists
+                password::verify_dummy(&user_data.password);
+                return Err(InvalidCredentials);
+            }
             Some(u) => u,
         };
-        if user.check_password(&*user_data.password) && user_data.username == user.username {
-            Ok(String::from("AUTH_TOKEN_NOT_IMPLEMENTED"))
+        if user.check_password(&user_data.password)? && user_data.username == user.username {
+            telemetry::record_user_id(user.id);
+            user.rehash_password(repo, &user_data.password);
+            if TotpSecret::is_enabled(repo, user.id)? {
+                let challenge = LoginChallenge::start(repo, user.id)?;
+                return Ok(Authentication::SecondFactorRequired(challenge));
+            }
+            Ok(Authentication::Authenticated(user))
         } else {
-            Err(AuthenticationError::IncorrectPassword)
+            Err(InvalidCredentials)
+        }
+    }
+
+    /// Authenticates a client by the common name of its verified TLS client certificate.
+    ///
+    /// Certificates are a second factor of their own, so there is no two-factor challenge.
+    pub fn authenticate_certificate<R: UserRepository + ?Sized>(
+        repo: &R,
+        certificate: &ClientCertificate,
+    ) -> Result<User, AuthenticationError> {
+        match repo.find_user_by_username(&certificate.common_name) {
+            Err(e) => Err(DatabaseError(e)),
+            Ok(None) => Err(InvalidCredentials),
+            Ok(Some(user)) => {
+                telemetry::record_user_id(user.id);
+                Ok(user)
+            }
         }
     }
 }
 
+impl From<TwoFactorError> for AuthenticationError {
+    fn from(error: TwoFactorError) -> AuthenticationError {
+        AuthenticationError::SecondFactorError(error)
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::repository::InMemoryRepository;
     use crate::test_helpers::*;
+    use crate::two_factor::totp;
     use pwhash::bcrypt;
 
     #[test]
     fn generate_password_hashes_password() {
         let clear_password = "p455w0rd!";
-        let actual = User::generate_password(clear_password);
+        let actual = User::generate_password(clear_password).unwrap();
         assert_ne!(clear_password, actual);
-        assert!(bcrypt::verify(clear_password, &actual));
+        assert!(password::verify(clear_password, &actual).unwrap());
+    }
+
+    #[test]
+    fn bcrypt_password_is_rehashed_on_login() {
+        let repo = InMemoryRepository::default();
+        let user = repo.create_user(create_user_data("testUser")).unwrap();
+        let bcrypt_hash = bcrypt::hash("12345678").unwrap();
+        repo.set_user_password(user.id, &bcrypt_hash).unwrap();
+
+        assert!(User::authenticate(&repo, create_user_data("testUser")).is_ok());
+
+        let user = repo.find_user_by_username("testUser").unwrap().unwrap();
+        assert!(user.password.starts_with("$argon2id$"));
+        assert!(User::authenticate(&repo, create_user_data("testUser")).is_ok());
+    }
+
+    #[test]
+    fn two_factor_user_gets_challenge_instead_of_session() {
+        let repo = InMemoryRepository::default();
+        let user = repo.create_user(create_user_data("testUser")).unwrap();
+        let enrollment = TotpSecret::enroll(&repo, user.id, &user.username).unwrap();
+        let code = totp::code(&enrollment.secret, chrono::Utc::now().timestamp());
+        TotpSecret::confirm(&repo, user.id, &code).unwrap();
+
+        let challenge = match User::authenticate(&repo, create_user_data("testUser")) {
+            Ok(Authentication::SecondFactorRequired(challenge)) => challenge,
+            other => panic!("expected a challenge, got {:?}", other),
+        };
+        let recovery_code = &enrollment.recovery_codes[0];
+
+        assert_eq!(
+            LoginChallenge::complete(&repo, &challenge.challenge_token, recovery_code).unwrap(),
+            user.id
+        );
     }
 
     #[test]
@@ -61,11 +161,14 @@ mod test {
         setup_user(&conn);
         let user_data = create_user_data("testUser");
 
-        assert!(User::authenticate(&conn, user_data).is_ok());
+        assert!(matches!(
+            User::authenticate(&conn, user_data),
+            Ok(Authentication::Authenticated(user)) if user.username == "testUser"
+        ));
     }
 
     #[test]
-    fn incorrect_password_gives_incorrect_password() {
+    fn incorrect_password_gives_invalid_credentials() {
         let conn = connection();
         setup_user(&conn);
         let mut user_data = create_user_data("testUser");
@@ -73,19 +176,19 @@ mod test {
 
         assert!(matches!(
             User::authenticate(&conn, user_data),
-            Err(AuthenticationError::IncorrectPassword)
+            Err(AuthenticationError::InvalidCredentials)
         ));
     }
 
     #[test]
-    fn authentication_with_unknown_username_gives_user_not_found() {
+    fn authentication_with_unknown_username_gives_invalid_credentials() {
         let conn = connection();
         setup_user(&conn);
         let user_data = create_user_data("USER_NAME_DOES_NOT_EXIST");
 
         assert!(matches!(
             User::authenticate(&conn, user_data),
-            Err(AuthenticationError::UserNotFound)
+            Err(AuthenticationError::InvalidCredentials)
         ));
     }
 }
@@ -0,0 +1,118 @@
+//! Deleted users can be restored by an admin during a grace period, then they are purged. The
+//! messages of purged users stay in the conversations without an author.
+
+use std::time::Duration;
+
+use actix_web::rt::time::interval;
+use actix_web::web;
+use chrono::{NaiveDateTime, Utc};
+use uuid::Uuid;
+
+use crate::audit::{Action, AuditEvent};
+use crate::db::Database;
+use crate::errors::ServiceError;
+use crate::repository::Repository;
+
+// Accounts are purged at most this long after their grace period is over
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Until when a user deleted at `deleted` can be restored, `None` if the grace period is too long
+/// to represent.
+pub fn restorable_until(deleted: NaiveDateTime, grace_period: Duration) -> Option<NaiveDateTime> {
+    let grace_period = chrono::Duration::from_std(grace_period).ok()?;
+    deleted.checked_add_signed(grace_period)
+}
+
+/// Purges the users whose grace period is over and returns their ids.
+pub fn purge(repo: &dyn Repository, grace_period: Duration) -> Result<Vec<Uuid>, ServiceError> {
+    let grace_period =
+        chrono::Duration::from_std(grace_period).map_err(|_| ServiceError::InternalServerError)?;
+    // A grace period longer than the time since the epoch purges no one
+    let deleted_before = match Utc::now().naive_utc().checked_sub_signed(grace_period) {
+        Some(deleted_before) => deleted_before,
+        None => return Ok(Vec::new()),
+    };
+
+    let user_ids = repo.purge_deleted_users(deleted_before)?;
+    for user_id in &user_ids {
+        repo.create_audit_event(AuditEvent::new(Action::UserPurged, None, Some(*user_id)))?;
+    }
+    Ok(user_ids)
+}
+
+/// Purges the users whose grace period is over every hour, for as long as the server runs.
+pub fn spawn_purge(database: Database, grace_period: Duration) {
+    actix_web::rt::spawn(async move {
+        let mut ticks = interval(PURGE_INTERVAL);
+        loop {
+            ticks.tick().await;
+            let database = database.clone();
+            let result = web::block(move || {
+                let purged = database.run_blocking(|repo| purge(repo, grace_period));
+                purged
+                    .map_err(|e| e.to_string())?
+                    .map_err(|e| e.to_string())
+            })
+            .await;
+            match result {
+                Ok(user_ids) if !user_ids.is_empty() => {
+                    tracing::info!("Purged {} deleted users", user_ids.len())
+                }
+                Ok(_) => (),
+                Err(e) => tracing::error!("Could not purge deleted users: {}", e),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audit::AuditQuery;
+    use crate::repository::{
+        AuditRepository, InMemoryRepository, MessageRepository, RoomRepository, UserRepository,
+    };
+    use crate::test_helpers::*;
+
+    #[test]
+    fn purges_users_after_the_grace_period() {
+        let repo = InMemoryRepository::default();
+        let room = repo.create_room(create_room_data("testRoom")).unwrap();
+        let grace_period = Duration::from_secs(24 * 60 * 60);
+        let now = Utc::now().naive_utc();
+
+        let expired = repo.create_user(create_user_data("expired")).unwrap();
+        repo.create_message(create_message_data("Hello thermit!", room.id, expired.id))
+            .unwrap();
+        repo.set_user_deleted(expired.id, Some(now - chrono::Duration::days(2)))
+            .unwrap();
+        let recent = repo.create_user(create_user_data("recent")).unwrap();
+        repo.set_user_deleted(recent.id, Some(now)).unwrap();
+        let active = repo.create_user(create_user_data("active")).unwrap();
+
+        assert_eq!(purge(&repo, grace_period).unwrap(), vec![expired.id]);
+        assert!(repo.find_user_by_id(expired.id).unwrap().is_none());
+        assert!(repo.find_user_by_id(recent.id).unwrap().is_some());
+        assert!(repo.find_user_by_id(active.id).unwrap().is_some());
+
+        let messages = repo.find_messages_by_room(room.id).unwrap();
+        assert_eq!(messages[0].author, None);
+        let events = repo.find_audit_events(&AuditQuery::default()).unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].action, Action::UserPurged);
+        assert_eq!(events[0].target_id, Some(expired.id));
+    }
+
+    #[test]
+    fn restorable_until_adds_the_grace_period() {
+        let deleted = chrono::NaiveDate::from_ymd(2026, 10, 19).and_hms(12, 0, 0);
+        assert_eq!(
+            restorable_until(deleted, Duration::from_secs(24 * 60 * 60)),
+            Some(chrono::NaiveDate::from_ymd(2026, 10, 20).and_hms(12, 0, 0))
+        );
+        assert_eq!(
+            restorable_until(deleted, Duration::from_secs(u64::MAX)),
+            None
+        );
+    }
+}
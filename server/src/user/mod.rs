@@ -1,6 +1,10 @@
 pub(crate) mod auth;
+pub mod deletion;
 mod model;
+pub mod password;
 mod routes;
+mod throttle;
 
 pub use model::*;
 pub use routes::init_routes;
+pub use throttle::LoginThrottle;
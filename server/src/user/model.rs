@@ -1,20 +1,83 @@
-use chrono::NaiveDateTime;
-use diesel::prelude::*;
-use diesel::result::Error as DieselError;
+use std::io::Write;
+
+use chrono::{NaiveDateTime, Utc};
+use diesel::backend::Backend;
+use diesel::deserialize::{self, FromSql};
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
+use diesel::serialize::{self, Output, ToSql};
+use diesel::sql_types::Text;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-use crate::schema::users;
+use crate::query::prelude::*;
+use crate::schema::{sessions, users};
+use crate::user::password::PasswordError;
 
-#[derive(Serialize, Deserialize, Queryable, Insertable, PartialEq, Debug)]
-#[table_name = "users"]
+#[derive(Clone, Serialize, Deserialize, Queryable, PartialEq, Debug)]
 pub struct User {
     pub id: Uuid,
     pub username: String,
     pub password: String,
     pub created: NaiveDateTime,
     pub updated: NaiveDateTime,
+    pub role: Role,
+    /// Bots are accounts of programs, e.g. notifiers of a CI.
+    pub bot: bool,
+    /// Stored in lower case, unique among all users.
+    pub email: Option<String>,
+    /// Only verified addresses receive password resets.
+    pub email_verified: bool,
+    /// Locked users cannot log in or use their API tokens, set by admins.
+    pub locked: bool,
+    /// When the user was deleted. The account can be restored until the grace period is over,
+    /// then it is purged.
+    pub deleted: Option<NaiveDateTime>,
+}
+
+/// The role of a user on the whole server, independent of rooms.
+#[derive(
+    Clone, Copy, Default, Serialize, Deserialize, AsExpression, FromSqlRow, PartialEq, Debug,
+)]
+#[sql_type = "Text"]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    #[default]
+    User,
+    Admin,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::User => "user",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+// The role is stored as text, so that the same column works on all backends
+impl<DB: Backend> ToSql<Text, DB> for Role
+where
+    str: ToSql<Text, DB>,
+{
+    fn to_sql<W: Write>(&self, out: &mut Output<W, DB>) -> serialize::Result {
+        self.as_str().to_sql(out)
+    }
+}
+
+impl<DB: Backend> FromSql<Text, DB> for Role
+where
+    String: FromSql<Text, DB>,
+{
+    fn from_sql(bytes: Option<&DB::RawValue>) -> deserialize::Result<Self> {
+        match String::from_sql(bytes)?.as_str() {
+            "user" => Ok(Role::User),
+            "admin" => Ok(Role::Admin),
+            other => Err(format!("unknown role {}", other).into()),
+        }
+    }
 }
+
 // decode request data
 #[derive(Clone, Deserialize, Insertable, AsChangeset, Debug)]
 #[table_name = "users"]
@@ -30,21 +93,29 @@ pub struct UserResponse {
     pub username: String,
     pub created: NaiveDateTime,
     pub updated: NaiveDateTime,
+    pub bot: bool,
 }
 
 #[derive(Debug)]
 pub enum UserError {
     UserNotFound,
     UsernameTaken,
+    EmailTaken,
+    PasswordHashError,
     DatabaseError,
     GenericError,
 }
 
 impl User {
-    pub fn find_all(conn: &PgConnection) -> Result<Vec<UserResponse>, UserError> {
+    /// Locked and deleted users can neither log in nor use their API tokens.
+    pub fn is_disabled(&self) -> bool {
+        self.locked || self.deleted.is_some()
+    }
+
+    pub fn find_all(conn: &impl SqlConnection) -> Result<Vec<UserResponse>, UserError> {
         use crate::schema::users::dsl::*;
 
-        let all_users = users.load::<User>(conn)?;
+        let all_users = users.filter(deleted.is_null()).load::<User>(conn)?;
         let items = all_users
             .into_iter()
             .map(UserResponse::from)
@@ -52,8 +123,11 @@ impl User {
         Ok(items)
     }
 
-    pub fn find(conn: &PgConnection, user_id: Uuid) -> Result<Option<UserResponse>, UserError> {
-        let user = User::_find(conn, user_id)?;
+    pub fn find(
+        conn: &impl SqlConnection,
+        user_id: Uuid,
+    ) -> Result<Option<UserResponse>, UserError> {
+        let user = User::_find(conn, user_id)?.filter(|u| u.deleted.is_none());
 
         if let Some(u) = user {
             Ok(Some(UserResponse::from(u)))
@@ -63,20 +137,27 @@ impl User {
     }
 
     // Internal find, do not use in routes, as it returns the password
-    pub(crate) fn _find(conn: &PgConnection, user_id: Uuid) -> Result<Option<User>, UserError> {
+    pub(crate) fn _find(
+        conn: &impl SqlConnection,
+        user_id: Uuid,
+    ) -> Result<Option<User>, UserError> {
         use crate::schema::users::dsl::*;
 
-        Ok(users.find(user_id).get_result::<User>(conn).optional()?)
+        Ok(users
+            .find(Id(user_id))
+            .get_result::<User>(conn)
+            .optional()?)
     }
 
-    pub fn exists(conn: &PgConnection, user_id: Uuid) -> Result<bool, UserError> {
-        use crate::schema::users::dsl::*;
-
-        Ok(users.find(user_id).first::<User>(conn).is_ok())
+    // Statements without RETURNING do not fail for missing rows, so the user is looked up after
+    fn find_updated(conn: &impl SqlConnection, user_id: Uuid) -> Result<UserResponse, UserError> {
+        User::_find(conn, user_id)?
+            .map(UserResponse::from)
+            .ok_or(UserError::UserNotFound)
     }
 
     pub fn find_by_username(
-        conn: &PgConnection,
+        conn: &impl SqlConnection,
         u: &str,
     ) -> Result<Option<UserResponse>, UserError> {
         match User::_find_by_username(conn, u) {
@@ -91,7 +172,10 @@ impl User {
     }
 
     // Only for internal use, do not use this in a route, as it returns the password!
-    pub fn _find_by_username(conn: &PgConnection, u: &str) -> Result<Option<User>, UserError> {
+    pub fn _find_by_username(
+        conn: &impl SqlConnection,
+        u: &str,
+    ) -> Result<Option<User>, UserError> {
         use crate::schema::users::dsl::*;
 
         let user = users
@@ -102,61 +186,274 @@ impl User {
         Ok(user)
     }
 
-    fn username_taken(conn: &PgConnection, u: &str) -> Result<bool, UserError> {
+    fn username_taken(conn: &impl SqlConnection, u: &str) -> Result<bool, UserError> {
         let user = User::find_by_username(conn, u)?;
         Ok(user.is_some())
     }
 
-    pub fn create(mut user_data: UserData, conn: &PgConnection) -> Result<UserResponse, UserError> {
+    // A user may keep their own username when updating
+    fn username_taken_by_other(
+        conn: &impl SqlConnection,
+        u: &str,
+        user_id: Uuid,
+    ) -> Result<bool, UserError> {
+        let user = User::find_by_username(conn, u)?;
+        Ok(user.is_some_and(|user| user.id != user_id))
+    }
+
+    pub fn create(
+        mut user_data: UserData,
+        conn: &impl SqlConnection,
+    ) -> Result<UserResponse, UserError> {
         use crate::schema::users::dsl::*;
 
-        user_data.password = User::generate_password(&*user_data.password);
+        user_data.password = User::generate_password(&user_data.password)?;
 
-        if User::username_taken(conn, &*user_data.username)? {
+        if User::username_taken(conn, &user_data.username)? {
             return Err(UserError::UsernameTaken);
         }
 
-        let new_user: User = diesel::insert_into(users)
-            .values(&user_data)
-            .get_result(conn)?;
+        // The id and timestamps are set here and not in the database, so that all backends behave
+        // the same
+        let timestamp = Utc::now().naive_utc();
+        let new_user = User {
+            id: Uuid::new_v4(),
+            username: user_data.username,
+            password: user_data.password,
+            created: timestamp,
+            updated: timestamp,
+            role: Role::default(),
+            bot: false,
+            email: None,
+            email_verified: false,
+            locked: false,
+            deleted: None,
+        };
+        diesel::insert_into(users)
+            .values((
+                id.eq(Id(new_user.id)),
+                username.eq(&new_user.username),
+                password.eq(&new_user.password),
+                created.eq(new_user.created),
+                updated.eq(new_user.updated),
+                role.eq(new_user.role),
+            ))
+            .execute(conn)?;
         Ok(UserResponse::from(new_user))
     }
 
     pub fn update(
         user_id: Uuid,
         mut user_data: UserData,
-        conn: &PgConnection,
+        conn: &impl SqlConnection,
     ) -> Result<UserResponse, UserError> {
         use crate::schema::users::dsl::*;
 
-        if User::username_taken(conn, &*user_data.username)? {
+        if User::username_taken_by_other(conn, &user_data.username, user_id)? {
             return Err(UserError::UsernameTaken);
         }
 
         // If no password is specified, do not update it
-        if !user_data.password.is_empty() {
-            user_data.password = User::generate_password(&*user_data.password);
+        let password_changed = !user_data.password.is_empty();
+        if password_changed {
+            user_data.password = User::generate_password(&user_data.password)?;
         } else {
-            let old_password = User::_find(conn, user_id).unwrap().unwrap().password;
-            user_data.password = old_password;
+            let user = User::_find(conn, user_id)?.ok_or(UserError::UserNotFound)?;
+            user_data.password = user.password;
         }
 
-        let user: User = diesel::update(users.find(user_id))
-            .set(user_data)
-            .get_result(conn)?;
+        conn.transaction::<_, UserError, _>(|| {
+            diesel::update(users.find(Id(user_id)))
+                .set((user_data, updated.eq(Utc::now().naive_utc())))
+                .execute(conn)?;
+            // Whoever knew the old password may have logged in with it
+            if password_changed {
+                diesel::delete(sessions::table.filter(sessions::user_id.eq(Id(user_id))))
+                    .execute(conn)?;
+            }
+            User::find_updated(conn, user_id)
+        })
+    }
+
+    pub fn set_role(
+        conn: &impl SqlConnection,
+        user_id: Uuid,
+        new_role: Role,
+    ) -> Result<UserResponse, UserError> {
+        use crate::schema::users::dsl::*;
+
+        diesel::update(users.find(Id(user_id)))
+            .set((role.eq(new_role), updated.eq(Utc::now().naive_utc())))
+            .execute(conn)?;
+        User::find_updated(conn, user_id)
+    }
+
+    pub fn set_bot(
+        conn: &impl SqlConnection,
+        user_id: Uuid,
+        is_bot: bool,
+    ) -> Result<UserResponse, UserError> {
+        use crate::schema::users::dsl::*;
 
-        Ok(UserResponse::from(user))
+        diesel::update(users.find(Id(user_id)))
+            .set((bot.eq(is_bot), updated.eq(Utc::now().naive_utc())))
+            .execute(conn)?;
+        User::find_updated(conn, user_id)
     }
 
-    pub fn destroy(conn: &PgConnection, user_id: Uuid) -> Result<usize, UserError> {
+    /// Locks or unlocks the user, locking ends all sessions of the user.
+    pub fn set_locked(
+        conn: &impl SqlConnection,
+        user_id: Uuid,
+        is_locked: bool,
+    ) -> Result<(), UserError> {
         use crate::schema::users::dsl::*;
 
-        let count = diesel::delete(users.find(user_id)).execute(conn)?;
+        conn.transaction::<_, UserError, _>(|| {
+            let count = diesel::update(users.find(Id(user_id)))
+                .set((locked.eq(is_locked), updated.eq(Utc::now().naive_utc())))
+                .execute(conn)?;
+            if count == 0 {
+                return Err(UserError::UserNotFound);
+            }
+            if is_locked {
+                diesel::delete(sessions::table.filter(sessions::user_id.eq(Id(user_id))))
+                    .execute(conn)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Marks the user as deleted at the given time, which ends all sessions of the user, or
+    /// restores the user with `None`.
+    pub fn set_deleted(
+        conn: &impl SqlConnection,
+        user_id: Uuid,
+        deleted_at: Option<NaiveDateTime>,
+    ) -> Result<(), UserError> {
+        use crate::schema::users::dsl::*;
+
+        conn.transaction::<_, UserError, _>(|| {
+            let count = diesel::update(users.find(Id(user_id)))
+                .set((deleted.eq(deleted_at), updated.eq(Utc::now().naive_utc())))
+                .execute(conn)?;
+            if count == 0 {
+                return Err(UserError::UserNotFound);
+            }
+            if deleted_at.is_some() {
+                diesel::delete(sessions::table.filter(sessions::user_id.eq(Id(user_id))))
+                    .execute(conn)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Deletes the users that were marked as deleted before the given time for good. Their
+    /// messages are kept without an author.
+    pub fn purge_deleted(
+        conn: &impl SqlConnection,
+        deleted_before: NaiveDateTime,
+    ) -> Result<Vec<Uuid>, UserError> {
+        use crate::schema::users::dsl::*;
+
+        // SQLite has no RETURNING in this version of Diesel, so the ids are selected first
+        conn.transaction::<_, UserError, _>(|| {
+            let purged = users.filter(deleted.lt(deleted_before));
+            let user_ids = purged.select(id).load(conn)?;
+            diesel::delete(purged).execute(conn)?;
+            Ok(user_ids)
+        })
+    }
+
+    /// Stores a password that is already hashed.
+    pub fn set_password(
+        conn: &impl SqlConnection,
+        user_id: Uuid,
+        password_hash: &str,
+    ) -> Result<(), UserError> {
+        use crate::schema::users::dsl::*;
+
+        let count = diesel::update(users.find(Id(user_id)))
+            .set(password.eq(password_hash))
+            .execute(conn)?;
+        if count == 0 {
+            return Err(UserError::UserNotFound);
+        }
+        Ok(())
+    }
+
+    // Only for internal use, do not use this in a route, as it returns the password!
+    pub fn _find_by_email(
+        conn: &impl SqlConnection,
+        address: &str,
+    ) -> Result<Option<User>, UserError> {
+        use crate::schema::users::dsl::*;
+
+        Ok(users
+            .filter(email.eq(address))
+            .first::<User>(conn)
+            .optional()?)
+    }
+
+    /// Replaces the email address, the new address is not verified yet.
+    pub fn set_email(
+        conn: &impl SqlConnection,
+        user_id: Uuid,
+        address: Option<String>,
+    ) -> Result<(), UserError> {
+        use crate::schema::users::dsl::*;
+
+        if let Some(ref address) = address {
+            let taken = User::_find_by_email(conn, address)?;
+            if taken.is_some_and(|user| user.id != user_id) {
+                return Err(UserError::EmailTaken);
+            }
+        }
+
+        let count = diesel::update(users.find(Id(user_id)))
+            .set((
+                email.eq(address),
+                email_verified.eq(false),
+                updated.eq(Utc::now().naive_utc()),
+            ))
+            .execute(conn)
+            .map_err(|e| match e {
+                // Taken by a concurrent request
+                DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _) => {
+                    UserError::EmailTaken
+                }
+                e => UserError::from(e),
+            })?;
+        if count == 0 {
+            return Err(UserError::UserNotFound);
+        }
+        Ok(())
+    }
+
+    /// Marks the address as verified, returns false if the user has another address by now.
+    pub fn verify_email(
+        conn: &impl SqlConnection,
+        user_id: Uuid,
+        address: &str,
+    ) -> Result<bool, UserError> {
+        use crate::schema::users::dsl::*;
+
+        let count = diesel::update(users.find(Id(user_id)).filter(email.eq(address)))
+            .set(email_verified.eq(true))
+            .execute(conn)?;
+        Ok(count == 1)
+    }
+
+    pub fn destroy(conn: &impl SqlConnection, user_id: Uuid) -> Result<usize, UserError> {
+        use crate::schema::users::dsl::*;
+
+        let count = diesel::delete(users.find(Id(user_id))).execute(conn)?;
         Ok(count)
     }
 }
 
 #[cfg(test)]
+#[allow(clippy::items_after_test_module)]
 mod tests {
     use super::*;
     use crate::test_helpers::*;
@@ -239,6 +536,12 @@ mod tests {
 
         let user = User::update(Uuid::new_v4(), user, &conn);
         assert!(matches!(user, Err(UserError::UserNotFound)));
+
+        // Without a password the old one is kept, which needs the user as well
+        let mut user = create_user_data("testUser");
+        user.password = String::new();
+        let user = User::update(Uuid::new_v4(), user, &conn);
+        assert!(matches!(user, Err(UserError::UserNotFound)));
     }
 
     #[test]
@@ -306,6 +609,13 @@ impl From<DieselError> for UserError {
     }
 }
 
+impl From<PasswordError> for UserError {
+    fn from(error: PasswordError) -> UserError {
+        tracing::error!("{}", error);
+        UserError::PasswordHashError
+    }
+}
+
 impl From<User> for UserResponse {
     fn from(user: User) -> UserResponse {
         UserResponse {
@@ -313,6 +623,7 @@ impl From<User> for UserResponse {
             username: user.username,
             created: user.created,
             updated: user.updated,
+            bot: user.bot,
         }
     }
 }
@@ -0,0 +1,179 @@
+//! Password hashing with Argon2id.
+//!
+//! Passwords used to be hashed with bcrypt. These hashes still verify, `User::authenticate`
+//! replaces them with Argon2id hashes when their users log in. The same happens for Argon2id
+//! hashes made with other parameters than the configured ones.
+
+use std::convert::TryFrom;
+use std::sync::OnceLock;
+
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::{Algorithm, Argon2, Params, Version};
+use derive_more::Display;
+use pwhash::bcrypt;
+
+use crate::config::PasswordConfig;
+
+static PARAMS: OnceLock<Params> = OnceLock::new();
+static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+
+#[derive(Debug, Display)]
+pub enum PasswordError {
+    #[display(fmt = "invalid Argon2 parameters: {}", _0)]
+    InvalidParams(argon2::Error),
+    #[display(fmt = "could not hash the password: {}", _0)]
+    Hash(argon2::password_hash::Error),
+}
+
+/// Checks the configured parameters.
+pub fn params(config: &PasswordConfig) -> Result<Params, PasswordError> {
+    Params::new(
+        config.memory_cost,
+        config.time_cost,
+        config.parallelism,
+        None,
+    )
+    .map_err(PasswordError::InvalidParams)
+}
+
+/// Sets the parameters of new hashes. Has to be called before the first password is hashed,
+/// otherwise the defaults are used.
+pub fn configure(config: &PasswordConfig) -> Result<(), PasswordError> {
+    let params = params(config)?;
+    // Only fails if the parameters are already set
+    let _ = PARAMS.set(params);
+    // Not done lazily, so that the first login with an unknown username is not slower
+    dummy_hash()?;
+    Ok(())
+}
+
+fn current_params() -> &'static Params {
+    PARAMS.get_or_init(|| {
+        params(&PasswordConfig::default()).expect("the default Argon2 parameters are invalid")
+    })
+}
+
+fn hasher() -> Argon2<'static> {
+    Argon2::new(
+        Algorithm::Argon2id,
+        Version::V0x13,
+        current_params().clone(),
+    )
+}
+
+pub fn hash(clear_password: &str) -> Result<String, PasswordError> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = hasher()
+        .hash_password(clear_password.as_bytes(), &salt)
+        .map_err(PasswordError::Hash)?;
+    Ok(hash.to_string())
+}
+
+/// Checks a password against an Argon2 or a bcrypt hash.
+pub fn verify(clear_password: &str, hash: &str) -> Result<bool, PasswordError> {
+    if !hash.starts_with("$argon2") {
+        return Ok(bcrypt::verify(clear_password, hash));
+    }
+
+    let hash = PasswordHash::new(hash).map_err(PasswordError::Hash)?;
+    match hasher().verify_password(clear_password.as_bytes(), &hash) {
+        Ok(()) => Ok(true),
+        Err(argon2::password_hash::Error::Password) => Ok(false),
+        Err(e) => Err(PasswordError::Hash(e)),
+    }
+}
+
+fn dummy_hash() -> Result<&'static str, PasswordError> {
+    if let Some(hash) = DUMMY_HASH.get() {
+        return Ok(hash);
+    }
+    let hash = hash("not the password of anyone")?;
+    Ok(DUMMY_HASH.get_or_init(|| hash))
+}
+
+/// Takes as long as verifying a password against a real hash, for logins of unknown users.
+pub fn verify_dummy(clear_password: &str) {
+    match dummy_hash() {
+        Ok(hash) => {
+            let _ = verify(clear_password, hash);
+        }
+        Err(e) => tracing::error!("{}", e),
+    }
+}
+
+/// Whether the hash was not made with Argon2id and the configured parameters.
+pub fn needs_rehash(hash: &str) -> bool {
+    let hash = match PasswordHash::new(hash) {
+        Ok(hash) => hash,
+        Err(_) => return true,
+    };
+    if hash.algorithm != Algorithm::Argon2id.ident() {
+        return true;
+    }
+
+    let params = current_params();
+    match Params::try_from(&hash) {
+        Ok(used) => {
+            used.m_cost() != params.m_cost()
+                || used.t_cost() != params.t_cost()
+                || used.p_cost() != params.p_cost()
+        }
+        Err(_) => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hash_verifies_password() {
+        let hash = hash("p455w0rd!").unwrap();
+
+        assert!(hash.starts_with("$argon2id$"));
+        assert!(verify("p455w0rd!", &hash).unwrap());
+        assert!(!verify("wrong password", &hash).unwrap());
+        assert!(!needs_rehash(&hash));
+    }
+
+    #[test]
+    fn bcrypt_hashes_verify_and_need_rehash() {
+        let hash = bcrypt::hash("p455w0rd!").unwrap();
+
+        assert!(verify("p455w0rd!", &hash).unwrap());
+        assert!(!verify("wrong password", &hash).unwrap());
+        assert!(needs_rehash(&hash));
+    }
+
+    #[test]
+    fn hashes_with_other_parameters_need_rehash() {
+        let weaker = Params::new(8 * 1024, 1, 1, None).unwrap();
+        let salt = SaltString::generate(&mut OsRng);
+        let hash = Argon2::new(Algorithm::Argon2id, Version::V0x13, weaker)
+            .hash_password(b"p455w0rd!", &salt)
+            .unwrap()
+            .to_string();
+
+        assert!(verify("p455w0rd!", &hash).unwrap());
+        assert!(needs_rehash(&hash));
+    }
+
+    #[test]
+    fn malformed_argon2_hash_is_an_error() {
+        assert!(verify("p455w0rd!", "$argon2id$v=19$m=many,t=2,p=1$c2FsdA$aGFzaA").is_err());
+    }
+
+    #[test]
+    fn invalid_parameters_are_rejected() {
+        let config = PasswordConfig {
+            memory_cost: 1,
+            ..PasswordConfig::default()
+        };
+
+        assert!(matches!(
+            params(&config),
+            Err(PasswordError::InvalidParams(_))
+        ));
+    }
+}
@@ -1,31 +1,43 @@
+use crate::audit::{Action, AuditContext};
+use crate::config::{AuthConfig, FeatureConfig};
+use crate::db::DbConn;
 use crate::errors::ServiceError;
-use crate::user::model::{User, UserData};
-use crate::Pool;
-use actix_web::error::BlockingError;
-use actix_web::{delete, get, post, put, web, HttpResponse};
+use crate::metrics;
+use crate::repository::Repository;
+use crate::session::auth::ClientInfo;
+use crate::session::{Identity, Session};
+use crate::tls::ClientCertificate;
+use crate::two_factor::auth::LoginResponse;
+use crate::user::auth::{Authentication, AuthenticationError};
+use crate::user::deletion;
+use crate::user::model::{Role, User, UserData};
+use crate::user::LoginThrottle;
+use actix_web::dev::RequestHead;
+use actix_web::{delete, get, post, put, web, HttpRequest, HttpResponse};
+use chrono::Utc;
+use serde::Deserialize;
 use serde_json::json;
 use uuid::Uuid;
 
+#[derive(Deserialize, Debug)]
+pub struct LoginData {
+    #[serde(flatten)]
+    pub credentials: UserData,
+    /// Shown in the list of sessions, e.g. "Laptop".
+    pub device_name: Option<String>,
+}
+
 #[get("/users")]
-pub async fn list(pool: web::Data<Pool>) -> Result<HttpResponse, ServiceError> {
-    let conn = pool.get().expect("couldn't get db connection from pool");
-    // use web::block to offload blocking Diesel code without blocking server thread
-    let users = web::block(move || User::find_all(&conn))
-        .await
-        .map_err(ServiceError::from)?;
+pub async fn list(conn: DbConn) -> Result<HttpResponse, ServiceError> {
+    let users = conn.run(|repo| repo.find_all_users()).await?;
     Ok(HttpResponse::Ok().json(json!({ "users": users })))
 }
 
 #[get("/users/{id}")]
-pub async fn find(
-    pool: web::Data<Pool>,
-    id: web::Path<Uuid>,
-) -> Result<HttpResponse, ServiceError> {
-    let conn = pool.get().expect("couldn't get db connection from pool");
-
-    let user = web::block(move || User::find(&conn, id.into_inner()))
-        .await
-        .map_err(ServiceError::from)?;
+pub async fn find(conn: DbConn, id: web::Path<Uuid>) -> Result<HttpResponse, ServiceError> {
+    let user = conn
+        .run(move |repo| repo.find_user(id.into_inner()))
+        .await?;
 
     if let Some(user) = user {
         Ok(HttpResponse::Ok().json(user))
@@ -36,64 +48,213 @@ pub async fn find(
 
 #[post("/users")]
 async fn create(
-    pool: web::Data<Pool>,
+    conn: DbConn,
+    features: web::Data<FeatureConfig>,
     user_data: web::Json<UserData>,
 ) -> Result<HttpResponse, ServiceError> {
-    let conn = pool.get().expect("couldn't get db connection from pool");
+    if !features.registration {
+        return Err(ServiceError::Forbidden);
+    }
 
-    // use web::block to offload blocking Diesel code without blocking server thread
-    let user = web::block(move || User::create(user_data.into_inner(), &conn))
-        .await
-        .map_err(ServiceError::from)?;
+    let user = conn
+        .run(move |repo| repo.create_user(user_data.into_inner()))
+        .await?;
 
+    metrics::USERS_REGISTERED.inc();
     Ok(HttpResponse::Ok().json(user))
 }
 
+/// Users may only change their own account, admins may change any account.
+fn may_change(
+    repo: &dyn Repository,
+    identity: Identity,
+    user_id: Uuid,
+) -> Result<(), ServiceError> {
+    if identity.user_id == user_id {
+        return Ok(());
+    }
+    match repo.find_user_by_id(identity.user_id)? {
+        Some(user) if user.role == Role::Admin && !user.is_disabled() => Ok(()),
+        _ => Err(ServiceError::Forbidden),
+    }
+}
+
+/// Updates the username and, unless it is empty, the password. Only a new password is audited.
 #[put("/users/{id}")]
 pub async fn update(
-    pool: web::Data<Pool>,
+    conn: DbConn,
+    identity: Identity,
+    audit: AuditContext,
     id: web::Path<Uuid>,
     user_data: web::Json<UserData>,
 ) -> Result<HttpResponse, ServiceError> {
-    let conn = pool.get().expect("couldn't get db connection from pool");
-    let user = web::block(move || User::update(id.into_inner(), user_data.into_inner(), &conn))
-        .await
-        .map_err(ServiceError::from)?;
+    let user_id = id.into_inner();
+    let user = conn
+        .run(move |repo| -> Result<_, ServiceError> {
+            may_change(repo, identity, user_id)?;
+            let user_data = user_data.into_inner();
+            let password_changed = !user_data.password.is_empty();
+            let user = repo.update_user(user_id, user_data)?;
+            if password_changed {
+                let event = audit.event(
+                    Action::PasswordChanged,
+                    Some(identity.user_id),
+                    Some(user_id),
+                );
+                repo.create_audit_event(event)?;
+            }
+            Ok(user)
+        })
+        .await?;
     Ok(HttpResponse::Ok().json(user))
 }
 
+/// Deletes the user after the grace period, until then an admin can restore the account. The
+/// sessions end right away, the messages of the user stay in the conversations.
 #[delete("/users/{id}")]
 pub async fn delete(
-    pool: web::Data<Pool>,
+    conn: DbConn,
+    config: web::Data<AuthConfig>,
+    identity: Identity,
+    audit: AuditContext,
     user_id: web::Path<Uuid>,
 ) -> Result<HttpResponse, ServiceError> {
-    let conn = pool.get().expect("couldn't get db connection from pool");
+    let user_id = user_id.into_inner();
+    let deleted = Utc::now().naive_utc();
+    let restorable_until = deletion::restorable_until(deleted, config.deletion_grace_period)
+        .ok_or(ServiceError::InternalServerError)?;
+    conn.run(move |repo| -> Result<_, ServiceError> {
+        may_change(repo, identity, user_id)?;
+        match repo.find_user_by_id(user_id)? {
+            Some(user) if user.deleted.is_none() => (),
+            _ => return Err(ServiceError::NotFound),
+        }
+        repo.set_user_deleted(user_id, Some(deleted))?;
+        let event = audit
+            .event(Action::UserDeleted, Some(identity.user_id), Some(user_id))
+            .with_details(json!({ "restorable_until": restorable_until }));
+        repo.create_audit_event(event)?;
+        Ok(())
+    })
+    .await?;
 
-    let count = web::block(move || User::destroy(&conn, user_id.into_inner()))
-        .await
-        .map_err(ServiceError::from)?;
+    Ok(HttpResponse::Accepted().json(json!({ "restorable_until": restorable_until })))
+}
 
-    if count == 0 {
-        Err(ServiceError::NotFound)
-    } else {
-        Ok(HttpResponse::NoContent().finish())
-    }
+/// Starts a session for the user returned by `authenticate_user`, or hands out the challenge if
+/// the user has to send a second factor first. Both outcomes but the challenge are audited.
+async fn login<F>(
+    conn: DbConn,
+    config: web::Data<AuthConfig>,
+    client: ClientInfo,
+    audit: AuditContext,
+    method: &'static str,
+    username: String,
+    authenticate_user: F,
+) -> Result<LoginResponse, ServiceError>
+where
+    F: FnOnce(&dyn Repository) -> Result<Authentication, AuthenticationError> + Send + 'static,
+{
+    conn.run(move |repo| -> Result<LoginResponse, ServiceError> {
+        let result = match authenticate_user(repo) {
+            Ok(Authentication::Authenticated(user)) => {
+                Session::start(repo, user.id, client, &config)
+                    .map(|tokens| (user.id, LoginResponse::Tokens(tokens)))
+                    .map_err(ServiceError::from)
+            }
+            Ok(Authentication::SecondFactorRequired(challenge)) => {
+                return Ok(LoginResponse::Challenge(challenge));
+            }
+            Err(e) => Err(ServiceError::from(e)),
+        };
+        audit.record_login(repo, method, Some(&username), result)
+    })
+    .await
+    .map_err(|e| {
+        if let ServiceError::Unauthorized = e {
+            metrics::AUTH_FAILURES.inc();
+        }
+        e
+    })
 }
 
 #[post("/auth")]
 pub async fn authenticate(
-    pool: web::Data<Pool>,
-    user_data: web::Json<UserData>,
+    req: HttpRequest,
+    conn: DbConn,
+    audit: AuditContext,
+    config: web::Data<AuthConfig>,
+    throttle: web::Data<LoginThrottle>,
+    login_data: web::Json<LoginData>,
 ) -> Result<HttpResponse, ServiceError> {
-    let conn = pool.get().expect("couldn't get db connection from pool");
-    let auth_token = web::block(move || User::authenticate(&conn, user_data.into_inner())).await;
-    match auth_token {
-        Ok(token) => Ok(HttpResponse::Ok().json(json!({ "token": token }))),
-        Err(e) => match e {
-            BlockingError::Error(e) => Err(ServiceError::from(e)),
-            BlockingError::Canceled => Err(ServiceError::InternalServerError),
-        },
+    let LoginData {
+        credentials,
+        device_name,
+    } = login_data.into_inner();
+    let username = credentials.username.clone();
+    let ip = req.peer_addr().map(|addr| addr.ip());
+    if let Some(lockout) = throttle.lockout(Some(&username), ip) {
+        return Err(LoginThrottle::rate_limited(lockout));
+    }
+
+    let client = ClientInfo::from_request(&req, device_name)?;
+    let login_username = username.clone();
+    let result = login(
+        conn,
+        config,
+        client,
+        audit,
+        "password",
+        login_username,
+        move |repo| User::authenticate(repo, credentials),
+    )
+    .await;
+    match result {
+        // The account stays counted until the second factor is sent as well
+        Ok(LoginResponse::Tokens(_)) => throttle.record_success(&username),
+        Ok(LoginResponse::Challenge(_)) => (),
+        Err(ServiceError::Unauthorized) => throttle.record_failure(Some(&username), ip),
+        Err(_) => (),
     }
+    Ok(HttpResponse::Ok().json(result?))
+}
+
+fn has_client_certificate(head: &RequestHead) -> bool {
+    head.extensions().contains::<ClientCertificate>()
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct CertificateLoginData {
+    pub device_name: Option<String>,
+}
+
+/// Clients with a verified TLS client certificate log in without a password.
+#[post("/auth", guard = "has_client_certificate")]
+pub async fn authenticate_certificate(
+    req: HttpRequest,
+    conn: DbConn,
+    audit: AuditContext,
+    config: web::Data<AuthConfig>,
+    certificate: web::ReqData<ClientCertificate>,
+    login_data: Option<web::Json<CertificateLoginData>>,
+) -> Result<HttpResponse, ServiceError> {
+    let certificate = certificate.into_inner();
+    let device_name = login_data.and_then(|data| data.into_inner().device_name);
+    let client = ClientInfo::from_request(&req, device_name)?;
+    let username = certificate.common_name.clone();
+    let response = login(
+        conn,
+        config,
+        client,
+        audit,
+        "certificate",
+        username,
+        move |repo| {
+            User::authenticate_certificate(repo, &certificate).map(Authentication::Authenticated)
+        },
+    )
+    .await?;
+    Ok(HttpResponse::Ok().json(response))
 }
 
 pub fn init_routes(config: &mut web::ServiceConfig) {
@@ -102,5 +263,327 @@ pub fn init_routes(config: &mut web::ServiceConfig) {
     config.service(create);
     config.service(update);
     config.service(delete);
+    // Must come first, requests without a client certificate fall through to the password login
+    config.service(authenticate_certificate);
     config.service(authenticate);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audit::AuditQuery;
+    use crate::config::ThrottleConfig;
+    use crate::db::Database;
+    use crate::repository::{AuditRepository, InMemoryRepository, UserRepository};
+    use crate::test_helpers::*;
+    use actix_web::dev::Service;
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App, HttpMessage};
+
+    #[actix_rt::test]
+    async fn create_returns_new_user() {
+        let mut app = test::init_service(
+            App::new()
+                .data(memory_database())
+                .data(FeatureConfig::default())
+                .configure(init_routes),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/users")
+            .set_json(&json!({ "username": "testUser", "password": "12345678" }))
+            .to_request();
+        let user: serde_json::Value = test::read_response_json(&mut app, req).await;
+
+        assert_eq!(user["username"], "testUser");
+        assert!(user.get("password").is_none());
+    }
+
+    #[actix_rt::test]
+    async fn create_returns_conflict_when_username_is_taken() {
+        let mut app = test::init_service(
+            App::new()
+                .data(memory_database())
+                .data(FeatureConfig::default())
+                .configure(init_routes),
+        )
+        .await;
+
+        for expected in &[StatusCode::OK, StatusCode::CONFLICT] {
+            let req = test::TestRequest::post()
+                .uri("/users")
+                .set_json(&json!({ "username": "testUser", "password": "12345678" }))
+                .to_request();
+            let resp = test::call_service(&mut app, req).await;
+            assert_eq!(resp.status(), *expected);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn create_returns_forbidden_when_registration_is_disabled() {
+        let mut app = test::init_service(
+            App::new()
+                .data(memory_database())
+                .data(FeatureConfig {
+                    registration: false,
+                    ..FeatureConfig::default()
+                })
+                .configure(init_routes),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/users")
+            .set_json(&json!({ "username": "testUser", "password": "12345678" }))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[actix_rt::test]
+    async fn find_returns_not_found_when_user_does_not_exist() {
+        let mut app = test::init_service(
+            App::new()
+                .data(memory_database())
+                .data(FeatureConfig::default())
+                .configure(init_routes),
+        )
+        .await;
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/users/{}", Uuid::new_v4()))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["code"], "not_found");
+    }
+
+    #[actix_rt::test]
+    async fn authenticate_returns_unauthorized_for_wrong_password() {
+        let mut app = test::init_service(
+            App::new()
+                .data(memory_database())
+                .data(FeatureConfig::default())
+                .data(AuthConfig::default())
+                .data(LoginThrottle::default())
+                .configure(init_routes),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/users")
+            .set_json(&json!({ "username": "testUser", "password": "12345678" }))
+            .to_request();
+        test::call_service(&mut app, req).await;
+
+        let req = test::TestRequest::post()
+            .uri("/auth")
+            .set_json(&json!({ "username": "testUser", "password": "wrong password" }))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let wrong_password: serde_json::Value = test::read_body_json(resp).await;
+
+        // Unknown usernames cannot be told apart from wrong passwords
+        let req = test::TestRequest::post()
+            .uri("/auth")
+            .set_json(&json!({ "username": "unknownUser", "password": "12345678" }))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let unknown_user: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(wrong_password["code"], unknown_user["code"]);
+        assert_eq!(wrong_password["message"], unknown_user["message"]);
+    }
+
+    #[actix_rt::test]
+    async fn authenticate_locks_account_after_failed_logins() {
+        let throttle = LoginThrottle::new(&ThrottleConfig {
+            account_attempts: 2,
+            ..ThrottleConfig::default()
+        });
+        let mut app = test::init_service(
+            App::new()
+                .data(memory_database())
+                .data(FeatureConfig::default())
+                .data(AuthConfig::default())
+                .data(throttle)
+                .configure(init_routes),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/users")
+            .set_json(&json!({ "username": "testUser", "password": "12345678" }))
+            .to_request();
+        test::call_service(&mut app, req).await;
+
+        for _ in 0..2 {
+            let req = test::TestRequest::post()
+                .uri("/auth")
+                .set_json(&json!({ "username": "testUser", "password": "wrong password" }))
+                .to_request();
+            let resp = test::call_service(&mut app, req).await;
+            assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        }
+
+        // Even the correct password is rejected during the lockout
+        let req = test::TestRequest::post()
+            .uri("/auth")
+            .set_json(&json!({ "username": "testUser", "password": "12345678" }))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(resp.headers().get("retry-after").unwrap(), "1");
+    }
+
+    #[actix_rt::test]
+    async fn authenticate_accepts_client_certificate_instead_of_password() {
+        let mut app = test::init_service(
+            App::new()
+                // Stands in for `tls::client_certificate`, which needs a TLS connection
+                .wrap_fn(|req, srv| {
+                    req.extensions_mut().insert(ClientCertificate {
+                        common_name: String::from("deployBot"),
+                    });
+                    srv.call(req)
+                })
+                .data(memory_database())
+                .data(FeatureConfig::default())
+                .data(AuthConfig::default())
+                .data(LoginThrottle::default())
+                .configure(init_routes),
+        )
+        .await;
+
+        let req = test::TestRequest::post().uri("/auth").to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let req = test::TestRequest::post()
+            .uri("/users")
+            .set_json(&json!({ "username": "deployBot", "password": "12345678" }))
+            .to_request();
+        test::call_service(&mut app, req).await;
+
+        let req = test::TestRequest::post().uri("/auth").to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let tokens: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(tokens["token_type"], "Bearer");
+    }
+
+    #[actix_rt::test]
+    async fn update_is_only_allowed_for_the_user_and_admins() {
+        let repo = InMemoryRepository::default();
+        let user = repo.create_user(create_user_data("testUser")).unwrap();
+        let other = repo.create_user(create_user_data("otherUser")).unwrap();
+        let admin = repo.create_user(create_user_data("admin")).unwrap();
+        repo.set_user_role(admin.id, Role::Admin).unwrap();
+        let (user_bearer, other_bearer, admin_bearer) = (
+            bearer(&repo, user.id),
+            bearer(&repo, other.id),
+            bearer(&repo, admin.id),
+        );
+        let mut app = test::init_service(
+            App::new()
+                .data(Database::Memory(repo.clone()))
+                .configure(init_routes),
+        )
+        .await;
+
+        let uri = format!("/users/{}", user.id);
+        let user_data = json!({ "username": "renamedUser", "password": "" });
+        let req = test::TestRequest::put()
+            .uri(&uri)
+            .set_json(&user_data)
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let req = test::TestRequest::put()
+            .uri(&uri)
+            .header("Authorization", other_bearer.as_str())
+            .set_json(&user_data)
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        for bearer in &[user_bearer, admin_bearer] {
+            let req = test::TestRequest::put()
+                .uri(&uri)
+                .header("Authorization", bearer.as_str())
+                .set_json(&user_data)
+                .to_request();
+            let resp = test::call_service(&mut app, req).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+        }
+        let user = repo.find_user_by_id(user.id).unwrap().unwrap();
+        assert_eq!(user.username, "renamedUser");
+        // Only a new password is a password change
+        assert!(repo
+            .find_audit_events(&AuditQuery::default())
+            .unwrap()
+            .is_empty());
+
+        let req = test::TestRequest::put()
+            .uri(&uri)
+            .header("Authorization", bearer(&repo, user.id).as_str())
+            .set_json(&json!({ "username": "renamedUser", "password": "87654321" }))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let events = repo.find_audit_events(&AuditQuery::default()).unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].action, Action::PasswordChanged);
+    }
+
+    #[actix_rt::test]
+    async fn delete_is_only_allowed_for_the_user_and_admins() {
+        let repo = InMemoryRepository::default();
+        let user = repo.create_user(create_user_data("testUser")).unwrap();
+        let other = repo.create_user(create_user_data("otherUser")).unwrap();
+        let (user_bearer, other_bearer) = (bearer(&repo, user.id), bearer(&repo, other.id));
+        let mut app = test::init_service(
+            App::new()
+                .data(Database::Memory(repo.clone()))
+                .data(AuthConfig::default())
+                .configure(init_routes),
+        )
+        .await;
+
+        let uri = format!("/users/{}", user.id);
+        let req = test::TestRequest::delete().uri(&uri).to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let req = test::TestRequest::delete()
+            .uri(&uri)
+            .header("Authorization", other_bearer.as_str())
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        assert!(repo
+            .find_user_by_id(user.id)
+            .unwrap()
+            .unwrap()
+            .deleted
+            .is_none());
+
+        let req = test::TestRequest::delete()
+            .uri(&uri)
+            .header("Authorization", user_bearer.as_str())
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::ACCEPTED);
+        assert!(repo
+            .find_user_by_id(user.id)
+            .unwrap()
+            .unwrap()
+            .deleted
+            .is_some());
+    }
+}
@@ -0,0 +1,279 @@
+//! Throttling of failed logins, against guessing passwords and second factors.
+//!
+//! Failures are counted per username and per IP. They are kept in memory, so they are shared by
+//! the workers of one server, but not between several servers and not across restarts.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::config::ThrottleConfig;
+use crate::errors::ServiceError;
+
+const FIRST_LOCKOUT: Duration = Duration::from_secs(1);
+// Failures are forgotten after this long without another one
+const FORGET_AFTER: Duration = Duration::from_secs(24 * 60 * 60);
+// Forgotten failures are only removed once there are this many entries, not on every failure
+const PRUNE_THRESHOLD: usize = 10_000;
+
+struct Failures {
+    count: u32,
+    last: Instant,
+    locked_until: Option<Instant>,
+}
+
+struct Counter<K> {
+    limit: u32,
+    entries: HashMap<K, Failures>,
+}
+
+impl<K: Eq + Hash> Counter<K> {
+    fn new(limit: u32) -> Self {
+        Counter {
+            limit,
+            entries: HashMap::new(),
+        }
+    }
+
+    fn lockout(&self, key: &K, now: Instant) -> Option<Duration> {
+        let locked_until = self.entries.get(key)?.locked_until?;
+        if locked_until > now {
+            Some(locked_until - now)
+        } else {
+            None
+        }
+    }
+
+    /// Counts a failure and returns the lockout it caused, if any.
+    fn record_failure(&mut self, key: K, now: Instant, max_lockout: Duration) -> Option<Duration> {
+        if self.entries.len() >= PRUNE_THRESHOLD {
+            self.entries
+                .retain(|_, failures| now.duration_since(failures.last) < FORGET_AFTER);
+        }
+
+        let failures = self.entries.entry(key).or_insert(Failures {
+            count: 0,
+            last: now,
+            locked_until: None,
+        });
+        if now.duration_since(failures.last) >= FORGET_AFTER {
+            failures.count = 0;
+        }
+        failures.count = failures.count.saturating_add(1);
+        failures.last = now;
+        if failures.count < self.limit {
+            return None;
+        }
+
+        // Doubles with every failure after the allowed ones
+        let doublings = failures.count - self.limit;
+        let lockout = FIRST_LOCKOUT
+            .checked_mul(2u32.saturating_pow(doublings))
+            .map_or(max_lockout, |lockout| lockout.min(max_lockout));
+        failures.locked_until = Some(now + lockout);
+        Some(lockout)
+    }
+
+    fn reset(&mut self, key: &K) {
+        self.entries.remove(key);
+    }
+}
+
+struct State {
+    accounts: Counter<String>,
+    ips: Counter<IpAddr>,
+}
+
+/// Every worker gets a clone of the throttle made in `main`. The clones refer to the same
+/// counters, so that the limits hold for the whole server and not per worker.
+#[derive(Clone)]
+pub struct LoginThrottle {
+    max_lockout: Duration,
+    state: Arc<Mutex<State>>,
+}
+
+impl LoginThrottle {
+    pub fn new(config: &ThrottleConfig) -> Self {
+        LoginThrottle {
+            max_lockout: config.max_lockout,
+            state: Arc::new(Mutex::new(State {
+                accounts: Counter::new(config.account_attempts),
+                ips: Counter::new(config.ip_attempts),
+            })),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().expect("login throttle is poisoned")
+    }
+
+    /// How long logins for the username or from the IP are still locked. Without a username,
+    /// e.g. for an unknown two-factor challenge, only the IP is checked.
+    pub fn lockout(&self, username: Option<&str>, ip: Option<IpAddr>) -> Option<Duration> {
+        self.lockout_at(username, ip, Instant::now())
+    }
+
+    fn lockout_at(
+        &self,
+        username: Option<&str>,
+        ip: Option<IpAddr>,
+        now: Instant,
+    ) -> Option<Duration> {
+        let state = self.state();
+        let account =
+            username.and_then(|username| state.accounts.lockout(&String::from(username), now));
+        let ip = ip.and_then(|ip| state.ips.lockout(&ip, now));
+        account.max(ip)
+    }
+
+    /// Counts a failed password or second factor of the username and from the IP.
+    pub fn record_failure(&self, username: Option<&str>, ip: Option<IpAddr>) {
+        self.record_failure_at(username, ip, Instant::now())
+    }
+
+    fn record_failure_at(&self, username: Option<&str>, ip: Option<IpAddr>, now: Instant) {
+        let mut state = self.state();
+        let max_lockout = self.max_lockout;
+        if let Some(username) = username {
+            if let Some(lockout) =
+                state
+                    .accounts
+                    .record_failure(String::from(username), now, max_lockout)
+            {
+                tracing::warn!(
+                    username,
+                    lockout_secs = lockout.as_secs(),
+                    "Too many failed logins, locking the account"
+                );
+            }
+        }
+        if let Some(ip) = ip {
+            if let Some(lockout) = state.ips.record_failure(ip, now, max_lockout) {
+                tracing::warn!(
+                    %ip,
+                    lockout_secs = lockout.as_secs(),
+                    "Too many failed logins, locking the IP"
+                );
+            }
+        }
+    }
+
+    /// Forgets the failures of the account once a login is complete, i.e. not already when the
+    /// password was right but the second factor is still missing. Those of the IP stay, otherwise
+    /// an attacker could reset them by logging into their own account in between.
+    pub fn record_success(&self, username: &str) {
+        self.state().accounts.reset(&String::from(username));
+    }
+
+    /// The error for a login during a lockout.
+    pub fn rate_limited(lockout: Duration) -> ServiceError {
+        // Rounded up, so that a client that waits this long is not locked anymore
+        let retry_after = lockout.as_secs() + u64::from(lockout.subsec_nanos() > 0);
+        ServiceError::RateLimited { retry_after }
+    }
+}
+
+impl Default for LoginThrottle {
+    fn default() -> Self {
+        LoginThrottle::new(&ThrottleConfig::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn throttle(account_attempts: u32, ip_attempts: u32) -> LoginThrottle {
+        LoginThrottle::new(&ThrottleConfig {
+            account_attempts,
+            ip_attempts,
+            max_lockout: Duration::from_secs(60),
+        })
+    }
+
+    fn ip(last: u8) -> Option<IpAddr> {
+        Some(IpAddr::from([192, 0, 2, last]))
+    }
+
+    #[test]
+    fn account_is_locked_after_allowed_failures() {
+        let throttle = throttle(3, 100);
+        let now = Instant::now();
+
+        for _ in 0..2 {
+            throttle.record_failure_at(Some("testUser"), ip(1), now);
+        }
+        assert_eq!(throttle.lockout_at(Some("testUser"), ip(2), now), None);
+
+        throttle.record_failure_at(Some("testUser"), ip(1), now);
+        assert_eq!(
+            throttle.lockout_at(Some("testUser"), ip(2), now),
+            Some(Duration::from_secs(1))
+        );
+        assert_eq!(throttle.lockout_at(Some("otherUser"), ip(2), now), None);
+        assert_eq!(
+            throttle.lockout_at(Some("testUser"), ip(2), now + Duration::from_secs(1)),
+            None
+        );
+    }
+
+    #[test]
+    fn lockout_doubles_up_to_maximum() {
+        let throttle = throttle(1, 100);
+        let now = Instant::now();
+        let lockouts: Vec<u64> = (0..8)
+            .map(|_| {
+                throttle.record_failure_at(Some("testUser"), None, now);
+                throttle
+                    .lockout_at(Some("testUser"), None, now)
+                    .unwrap()
+                    .as_secs()
+            })
+            .collect();
+
+        assert_eq!(lockouts, vec![1, 2, 4, 8, 16, 32, 60, 60]);
+    }
+
+    #[test]
+    fn ip_is_locked_across_usernames() {
+        let throttle = throttle(100, 3);
+        let now = Instant::now();
+
+        for username in &["alice", "bob", "carol"] {
+            throttle.record_failure_at(Some(username), ip(1), now);
+        }
+
+        assert!(throttle.lockout_at(Some("dave"), ip(1), now).is_some());
+        assert_eq!(throttle.lockout_at(Some("dave"), ip(2), now), None);
+    }
+
+    #[test]
+    fn success_resets_account_but_not_ip() {
+        let throttle = throttle(2, 3);
+        let now = Instant::now();
+
+        throttle.record_failure_at(Some("testUser"), ip(1), now);
+        throttle.record_failure_at(Some("testUser"), ip(1), now);
+        throttle.record_success("testUser");
+        assert_eq!(throttle.lockout_at(Some("testUser"), ip(2), now), None);
+
+        throttle.record_failure_at(Some("testUser"), ip(1), now);
+        assert!(throttle.lockout_at(Some("testUser"), ip(1), now).is_some());
+    }
+
+    #[test]
+    fn failures_are_forgotten() {
+        let throttle = throttle(2, 100);
+        let now = Instant::now();
+
+        throttle.record_failure_at(Some("testUser"), None, now);
+        throttle.record_failure_at(Some("testUser"), None, now + FORGET_AFTER);
+
+        assert_eq!(
+            throttle.lockout_at(Some("testUser"), None, now + FORGET_AFTER),
+            None
+        );
+    }
+}